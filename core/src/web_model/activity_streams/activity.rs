@@ -0,0 +1,61 @@
+use crate::web_model::activity_streams::object::{as_object, AsObject, Object};
+use crate::web_model::activity_streams::property::ObjectRefs;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// An action performed by an actor on an object
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Activity {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub object: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instrument: Option<ObjectRefs>,
+    #[serde(flatten)]
+    pub base: Object,
+}
+
+/// An activity without an `object`
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IntransitiveActivity {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instrument: Option<ObjectRefs>,
+    #[serde(flatten)]
+    pub base: Object,
+}
+
+/// A question, with either exclusive or inclusive options to choose from
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Question {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub one_of: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub any_of: Option<ObjectRefs>,
+    /// Either a date, a boolean or an object
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub closed: Option<Value>,
+    #[serde(flatten)]
+    pub base: IntransitiveActivity,
+}
+
+as_object!(Activity, base);
+as_object!(IntransitiveActivity, base);
+as_object!(Question, base.base);
@@ -0,0 +1,47 @@
+use crate::web_model::activity_streams::object::{as_object, AsObject, Object, ObjectOrLink};
+use crate::web_model::activity_streams::property::{ObjectRefs, Ref};
+use serde::{Deserialize, Serialize};
+
+/// An actor as defined by ActivityPub, used for all actor types
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Actor {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inbox: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outbox: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub following: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub followers: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub liked: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub streams: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoints: Option<Ref<Endpoints>>,
+    #[serde(flatten)]
+    pub base: Object,
+}
+
+/// Endpoints useful for the actor or for anyone referencing the actor
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Endpoints {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oauth_authorization_endpoint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oauth_token_endpoint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provide_client_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sign_client_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared_inbox: Option<String>,
+}
+
+as_object!(Actor, base);
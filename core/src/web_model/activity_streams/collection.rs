@@ -0,0 +1,74 @@
+use crate::web_model::activity_streams::object::{as_object, AsObject, Object, ObjectOrLink};
+use crate::web_model::activity_streams::property::{ObjectRefs, Ref};
+use serde::{Deserialize, Serialize};
+
+/// An unordered set of objects or links
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Collection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_items: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub items: Option<ObjectRefs>,
+    #[serde(flatten)]
+    pub base: Object,
+}
+
+/// A collection where the order of the items matters
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderedCollection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_items: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ordered_items: Option<ObjectRefs>,
+    #[serde(flatten)]
+    pub base: Object,
+}
+
+/// One page of a paged collection
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CollectionPage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub part_of: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev: Option<Ref<ObjectOrLink>>,
+    #[serde(flatten)]
+    pub base: Collection,
+}
+
+/// One page of a paged ordered collection
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderedCollectionPage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub part_of: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_index: Option<u64>,
+    #[serde(flatten)]
+    pub base: OrderedCollection,
+}
+
+as_object!(Collection, base);
+as_object!(OrderedCollection, base);
+as_object!(CollectionPage, base.base);
+as_object!(OrderedCollectionPage, base.base);
@@ -0,0 +1,670 @@
+use crate::web_model::activity_streams::property::OneOrMany;
+use crate::web_model::activity_streams::ContextActivityStreams;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// A JSON-LD term definition, in either its simple or its expanded form
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum TermDefinition {
+    Iri(String),
+    Expanded(ExpandedTermDefinition),
+    /// Anything else a local context may contain, like `"@version": 1.1`
+    Other(Value),
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+pub struct ExpandedTermDefinition {
+    #[serde(rename = "@id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "@type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(rename = "@container", skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A context defined inline in the document
+///
+/// A term mapped to `null` removes the definition inherited from previous contexts.
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+pub struct LocalContext {
+    #[serde(rename = "@vocab", skip_serializing_if = "Option::is_none")]
+    pub vocab: Option<String>,
+    #[serde(rename = "@language", skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(flatten)]
+    pub terms: BTreeMap<String, Option<TermDefinition>>,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ContextEntry {
+    Iri(String),
+    Local(LocalContext),
+}
+
+impl ContextEntry {
+    fn is_activity_streams(&self) -> bool {
+        match self {
+            ContextEntry::Iri(iri) => ContextActivityStreams::from_str(iri).is_ok(),
+            ContextEntry::Local(local) => local
+                .vocab
+                .as_deref()
+                .is_some_and(|vocab| ContextActivityStreams::from_str(vocab).is_ok()),
+        }
+    }
+}
+
+/// The `@context` of an ActivityStreams document, with all of its entries as received
+#[derive(Clone, Eq, PartialEq, Debug, Serialize)]
+#[serde(transparent)]
+pub struct Context(pub OneOrMany<ContextEntry>);
+
+impl Context {
+    pub fn entries(&self) -> std::slice::Iter<'_, ContextEntry> {
+        self.0.iter()
+    }
+
+    /// The context of documents sent by Magnetar, which [`compact_to_canonical`] compacts to
+    pub fn magnetar() -> Self {
+        Context(OneOrMany::Many(vec![
+            ContextEntry::Iri(ContextActivityStreams.as_ref().to_owned()),
+            ContextEntry::Iri(SECURITY_V1.to_owned()),
+            ContextEntry::Local(magnetar_extensions().clone()),
+        ]))
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Context(OneOrMany::One(ContextEntry::Iri(
+            ContextActivityStreams.as_ref().to_owned(),
+        )))
+    }
+}
+
+impl<'de> Deserialize<'de> for Context {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = OneOrMany::<ContextEntry>::deserialize(deserializer)?;
+
+        if !entries.iter().any(ContextEntry::is_activity_streams) {
+            return Err(Error::custom(
+                "The context does not include ActivityStreams",
+            ));
+        }
+
+        Ok(Context(entries))
+    }
+}
+
+const SECURITY_V1: &str = "https://w3id.org/security/v1";
+
+/// Remote contexts shipped with Magnetar, these are never fetched over the network
+const BUNDLED_CONTEXTS: &[(&[&str], &str)] = &[
+    (
+        &[
+            "https://www.w3.org/ns/activitystreams",
+            "http://www.w3.org/ns/activitystreams",
+            "https://www.w3.org/ns/activitystreams.jsonld",
+        ],
+        include_str!("../../../contexts/activitystreams.jsonld"),
+    ),
+    (
+        &[
+            SECURITY_V1,
+            "http://w3id.org/security/v1",
+            "https://w3id.org/security/v1.jsonld",
+        ],
+        include_str!("../../../contexts/security-v1.jsonld"),
+    ),
+];
+
+#[derive(Deserialize)]
+struct ContextDocument {
+    #[serde(rename = "@context")]
+    context: LocalContext,
+}
+
+fn bundled_context(iri: &str) -> Option<&'static LocalContext> {
+    static CONTEXTS: OnceLock<HashMap<&'static str, LocalContext>> = OnceLock::new();
+
+    CONTEXTS
+        .get_or_init(|| {
+            BUNDLED_CONTEXTS
+                .iter()
+                .flat_map(|(iris, document)| {
+                    let document: ContextDocument =
+                        serde_json::from_str(document).expect("Invalid bundled context");
+                    iris.iter().map(move |iri| (*iri, document.context.clone()))
+                })
+                .collect()
+        })
+        .get(iri)
+}
+
+/// Terms used by Mastodon, Misskey and their forks on top of ActivityStreams
+fn magnetar_extensions() -> &'static LocalContext {
+    static EXTENSIONS: OnceLock<LocalContext> = OnceLock::new();
+
+    EXTENSIONS.get_or_init(|| {
+        serde_json::from_value(serde_json::json!({
+            "manuallyApprovesFollowers": "as:manuallyApprovesFollowers",
+            "sensitive": "as:sensitive",
+            "Hashtag": "as:Hashtag",
+            "quoteUrl": "as:quoteUrl",
+            "movedTo": { "@id": "as:movedTo", "@type": "@id" },
+            "alsoKnownAs": { "@id": "as:alsoKnownAs", "@type": "@id" },
+            "toot": "http://joinmastodon.org/ns#",
+            "Emoji": "toot:Emoji",
+            "featured": { "@id": "toot:featured", "@type": "@id" },
+            "featuredTags": { "@id": "toot:featuredTags", "@type": "@id" },
+            "discoverable": "toot:discoverable",
+            "blurhash": "toot:blurhash",
+            "focalPoint": { "@id": "toot:focalPoint", "@container": "@list" },
+            "votersCount": "toot:votersCount",
+            "schema": "http://schema.org#",
+            "PropertyValue": "schema:PropertyValue",
+            "value": "schema:value",
+            "misskey": "https://misskey-hub.net/ns#",
+            "_misskey_content": "misskey:_misskey_content",
+            "_misskey_quote": "misskey:_misskey_quote",
+            "_misskey_reaction": "misskey:_misskey_reaction",
+            "_misskey_votes": "misskey:_misskey_votes",
+            "_misskey_summary": "misskey:_misskey_summary",
+            "isCat": "misskey:isCat",
+            "vcard": "http://www.w3.org/2006/vcard/ns#"
+        }))
+        .expect("Invalid extension context")
+    })
+}
+
+#[derive(Clone, Debug)]
+struct Term {
+    /// The `@id` as written in the definition, possibly a compact IRI
+    id: String,
+    container: Option<String>,
+}
+
+/// Term definitions in effect at some point of a document, used to expand and compact IRIs
+#[derive(Clone, Debug, Default)]
+pub struct ActiveContext {
+    vocab: Option<String>,
+    language: Option<String>,
+    terms: HashMap<String, Term>,
+    /// Remote contexts that are not bundled, their terms stay unknown
+    unresolved: Vec<String>,
+    /// The preferred term for an expanded IRI and container
+    inverse: HashMap<(String, Option<String>), String>,
+    /// Terms usable as prefixes of compact IRIs, longest IRI first
+    prefixes: Vec<(String, String)>,
+}
+
+impl ActiveContext {
+    pub fn new(context: &Context) -> Self {
+        let mut active = ActiveContext::default();
+        active.apply(context);
+        active
+    }
+
+    /// The context Magnetar compacts received documents to
+    pub fn magnetar() -> &'static ActiveContext {
+        static MAGNETAR: OnceLock<ActiveContext> = OnceLock::new();
+
+        MAGNETAR.get_or_init(|| ActiveContext::new(&Context::magnetar()))
+    }
+
+    /// Processes the entries of a context on top of the current definitions
+    pub fn apply(&mut self, context: &Context) {
+        for entry in context.entries() {
+            match entry {
+                ContextEntry::Iri(iri) => match bundled_context(iri) {
+                    Some(local) => self.apply_local(local),
+                    None => self.unresolved.push(iri.clone()),
+                },
+                ContextEntry::Local(local) => self.apply_local(local),
+            }
+        }
+
+        self.rebuild_inverse();
+    }
+
+    fn apply_local(&mut self, local: &LocalContext) {
+        if local.vocab.is_some() {
+            self.vocab = local.vocab.clone();
+        }
+
+        if local.language.is_some() {
+            self.language = local.language.clone();
+        }
+
+        for (name, definition) in &local.terms {
+            let term = match definition {
+                Some(TermDefinition::Iri(id)) => Term {
+                    id: id.clone(),
+                    container: None,
+                },
+                Some(TermDefinition::Expanded(ExpandedTermDefinition {
+                    id: Some(id),
+                    container,
+                    ..
+                })) => Term {
+                    id: id.clone(),
+                    container: container.clone(),
+                },
+                // Keywords like `@version` are not terms
+                Some(TermDefinition::Other(_)) if name.starts_with('@') => continue,
+                _ => {
+                    self.terms.remove(name);
+                    continue;
+                }
+            };
+
+            self.terms.insert(name.clone(), term);
+        }
+    }
+
+    fn rebuild_inverse(&mut self) {
+        self.inverse.clear();
+        self.prefixes.clear();
+
+        for (name, term) in &self.terms {
+            let Some(iri) = self.expand_iri(&term.id) else {
+                continue;
+            };
+
+            if term.container.is_none() && (iri.ends_with('#') || iri.ends_with('/')) {
+                self.prefixes.push((iri.clone(), name.clone()));
+            }
+
+            self.inverse
+                .entry((iri, term.container.clone()))
+                .and_modify(|preferred| {
+                    if (name.len(), name) < (preferred.len(), preferred) {
+                        preferred.clone_from(name);
+                    }
+                })
+                .or_insert_with(|| name.clone());
+        }
+
+        self.prefixes
+            .sort_by(|(a, a_name), (b, b_name)| b.len().cmp(&a.len()).then(a_name.cmp(b_name)));
+    }
+
+    pub fn vocab(&self) -> Option<&str> {
+        self.vocab.as_deref()
+    }
+
+    pub fn language(&self) -> Option<&str> {
+        self.language.as_deref()
+    }
+
+    /// Remote contexts that were referenced but not processed, as they are not bundled
+    pub fn unresolved(&self) -> &[String] {
+        &self.unresolved
+    }
+
+    /// Expands a term, a compact IRI or a vocabulary-relative IRI
+    ///
+    /// Returns `None` for values that are neither, when the context has no vocabulary.
+    pub fn expand_iri(&self, value: &str) -> Option<String> {
+        if value.starts_with('@') {
+            return Some(value.to_owned());
+        }
+
+        if let Some(term) = self.terms.get(value) {
+            return self.expand_compact(&term.id);
+        }
+
+        self.expand_compact(value)
+            .or_else(|| Some(format!("{}{value}", self.vocab.as_ref()?)))
+    }
+
+    /// The expanded IRI and container of a property
+    fn expand_property(&self, property: &str) -> Option<(String, Option<String>)> {
+        match self.terms.get(property) {
+            Some(term) => Some((self.expand_compact(&term.id)?, term.container.clone())),
+            None => Some((self.expand_iri(property)?, None)),
+        }
+    }
+
+    /// Expands `prefix:suffix` IRIs, absolute IRIs and keywords are returned as they are
+    fn expand_compact(&self, value: &str) -> Option<String> {
+        if value.starts_with('@') {
+            return Some(value.to_owned());
+        }
+
+        let (prefix, suffix) = value.split_once(':')?;
+
+        if suffix.starts_with("//") || prefix == "_" {
+            return Some(value.to_owned());
+        }
+
+        // Prefixes must map to absolute IRIs, which also rules out definition cycles
+        match self.terms.get(prefix) {
+            Some(term) if term.container.is_none() && is_absolute_iri(&term.id) => {
+                Some(format!("{}{suffix}", term.id))
+            }
+            _ => Some(value.to_owned()),
+        }
+    }
+
+    /// Compacts an IRI used as a type or vocabulary value
+    pub fn compact_iri(&self, iri: &str) -> String {
+        self.compact_property(iri, None)
+    }
+
+    /// Compacts an IRI to a term with the same container, or to a compact IRI using a prefix
+    ///
+    /// IRIs without any matching term or prefix are returned as they are.
+    pub fn compact_property(&self, iri: &str, container: Option<&str>) -> String {
+        let key = (iri.to_owned(), container.map(str::to_owned));
+
+        if let Some(term) = self.inverse.get(&key) {
+            return term.clone();
+        }
+
+        if container.is_some() {
+            if let Some(term) = self.inverse.get(&(key.0, None)) {
+                return term.clone();
+            }
+        }
+
+        self.prefixes
+            .iter()
+            .find_map(|(prefix, name)| {
+                iri.strip_prefix(prefix.as_str())
+                    .filter(|suffix| !suffix.is_empty())
+                    .map(|suffix| format!("{name}:{suffix}"))
+            })
+            .unwrap_or_else(|| iri.to_owned())
+    }
+}
+
+fn is_absolute_iri(value: &str) -> bool {
+    value
+        .split_once(':')
+        .is_some_and(|(_, rest)| rest.starts_with("//"))
+}
+
+/// Rewrites a JSON-LD document to use the terms of [`Context::magnetar`]
+///
+/// Properties and types are expanded with the document's own context, including contexts
+/// nested in embedded objects, and compacted again with Magnetar's. Remote contexts are only
+/// resolved from the bundled copies; terms of any other remote context cannot be expanded and
+/// are kept verbatim.
+pub fn compact_to_canonical(document: &mut Value) -> Result<(), serde_json::Error> {
+    let Value::Object(object) = document else {
+        return Ok(());
+    };
+
+    let context = match object.remove("@context") {
+        Some(context) => serde_json::from_value(context)?,
+        None => Context::default(),
+    };
+
+    let active = ActiveContext::new(&context);
+    compact_object(object, &active);
+
+    let mut compacted = Map::with_capacity(object.len() + 1);
+    compacted.insert(
+        "@context".to_owned(),
+        serde_json::to_value(Context::magnetar())?,
+    );
+    compacted.append(object);
+    *object = compacted;
+
+    Ok(())
+}
+
+fn compact_value(value: &mut Value, active: &ActiveContext) {
+    match value {
+        Value::Object(object) => {
+            let nested;
+            let active = match object
+                .remove("@context")
+                .and_then(|context| serde_json::from_value::<OneOrMany<ContextEntry>>(context).ok())
+            {
+                Some(entries) => {
+                    let mut scoped = active.clone();
+                    scoped.apply(&Context(entries));
+                    nested = scoped;
+                    &nested
+                }
+                None => active,
+            };
+
+            compact_object(object, active);
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| compact_value(value, active)),
+        _ => {}
+    }
+}
+
+fn compact_object(object: &mut Map<String, Value>, active: &ActiveContext) {
+    let canonical = ActiveContext::magnetar();
+
+    *object = std::mem::take(object)
+        .into_iter()
+        .map(|(key, mut value)| {
+            let expanded = active.expand_property(&key);
+
+            if matches!(&expanded, Some((iri, _)) if iri == "@type") {
+                compact_types(&mut value, active);
+            } else {
+                compact_value(&mut value, active);
+            }
+
+            let key = match expanded {
+                // Undefined terms fall into the blank node vocabulary of ActivityStreams
+                Some((iri, _)) if iri.starts_with("_:") => key,
+                Some((iri, container)) => canonical.compact_property(&iri, container.as_deref()),
+                None => key,
+            };
+
+            (key, value)
+        })
+        .collect();
+}
+
+fn compact_types(value: &mut Value, active: &ActiveContext) {
+    match value {
+        Value::String(kind) => {
+            if let Some(iri) = active.expand_iri(kind).filter(|iri| !iri.starts_with("_:")) {
+                *kind = ActiveContext::magnetar().compact_iri(&iri);
+            }
+        }
+        Value::Array(kinds) => kinds
+            .iter_mut()
+            .for_each(|kind| compact_types(kind, active)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::web_model::activity_streams::context::{
+        compact_to_canonical, ActiveContext, Context, ContextEntry, TermDefinition,
+    };
+    use serde_json::json;
+
+    #[test]
+    fn should_expand_and_compact_bundled_terms() {
+        let active = ActiveContext::magnetar();
+
+        assert_eq!(
+            active.expand_iri("Note").as_deref(),
+            Some("https://www.w3.org/ns/activitystreams#Note")
+        );
+        assert_eq!(
+            active.expand_iri("publicKeyPem").as_deref(),
+            Some("https://w3id.org/security#publicKeyPem")
+        );
+        assert_eq!(
+            active.expand_iri("toot:featured").as_deref(),
+            Some("http://joinmastodon.org/ns#featured")
+        );
+        assert_eq!(
+            active.compact_iri("http://joinmastodon.org/ns#featured"),
+            "featured"
+        );
+        assert_eq!(
+            active.compact_iri("http://joinmastodon.org/ns#indexable"),
+            "toot:indexable"
+        );
+        assert_eq!(
+            active.compact_property("https://www.w3.org/ns/activitystreams#items", Some("@list")),
+            "orderedItems"
+        );
+        assert!(active.unresolved().is_empty());
+    }
+
+    #[test]
+    fn should_retain_term_definitions() {
+        let context: Context = serde_json::from_value(json!([
+            "https://www.w3.org/ns/activitystreams",
+            {
+                "@version": 1.1,
+                "pinned": { "@id": "toot:featured", "@type": "@id" },
+                "toot": "http://joinmastodon.org/ns#",
+                "removed": null
+            }
+        ]))
+        .unwrap();
+
+        let ContextEntry::Local(local) = context.entries().nth(1).unwrap() else {
+            panic!("Expected a local context");
+        };
+
+        assert!(matches!(
+            local.terms.get("pinned"),
+            Some(Some(TermDefinition::Expanded(definition)))
+                if definition.id.as_deref() == Some("toot:featured")
+        ));
+        assert_eq!(
+            local.terms.get("toot"),
+            Some(&Some(TermDefinition::Iri(
+                "http://joinmastodon.org/ns#".to_owned()
+            )))
+        );
+        assert_eq!(local.terms.get("removed"), Some(&None));
+    }
+
+    #[test]
+    fn should_reject_context_without_activity_streams() {
+        let result = serde_json::from_value::<Context>(json!(["https://w3id.org/security/v1"]));
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn should_compact_aliased_terms() {
+        let mut document = json!({
+            "@context": [
+                "https://www.w3.org/ns/activitystreams",
+                "https://w3id.org/security/v1",
+                {
+                    "mastodon": "http://joinmastodon.org/ns#",
+                    "pinned": { "@id": "mastodon:featured", "@type": "@id" },
+                    "locked": "as:manuallyApprovesFollowers",
+                    "sdo": "http://schema.org#",
+                    "PropertyValue": "sdo:PropertyValue",
+                    "val": "sdo:value",
+                    "kind": "@type"
+                }
+            ],
+            "id": "https://example.com/users/alice",
+            "type": "Person",
+            "pinned": "https://example.com/users/alice/collections/featured",
+            "locked": true,
+            "publicKeyPem": "-----BEGIN PUBLIC KEY-----",
+            "attachment": [{
+                "kind": "sdo:PropertyValue",
+                "name": "Website",
+                "val": "https://example.com"
+            }],
+            "undefinedExtension": 1
+        });
+
+        compact_to_canonical(&mut document).unwrap();
+
+        assert_eq!(
+            document,
+            json!({
+                "@context": serde_json::to_value(Context::magnetar()).unwrap(),
+                "id": "https://example.com/users/alice",
+                "type": "Person",
+                "featured": "https://example.com/users/alice/collections/featured",
+                "manuallyApprovesFollowers": true,
+                "publicKeyPem": "-----BEGIN PUBLIC KEY-----",
+                "attachment": [{
+                    "type": "PropertyValue",
+                    "name": "Website",
+                    "value": "https://example.com"
+                }],
+                "undefinedExtension": 1
+            })
+        );
+    }
+
+    #[test]
+    fn should_compact_misskey_note() {
+        let mut document = json!({
+            "@context": [
+                "https://www.w3.org/ns/activitystreams",
+                "https://w3id.org/security/v1",
+                {
+                    "Key": "sec:Key",
+                    "misskey": "https://misskey-hub.net/ns#",
+                    "_misskey_content": "misskey:_misskey_content",
+                    "isCat": "misskey:isCat"
+                }
+            ],
+            "type": "Note",
+            "content": "<p>hi</p>",
+            "misskey:_misskey_quote": "https://example.com/notes/1",
+            "tag": [{
+                "@context": { "Emoji": "http://joinmastodon.org/ns#Emoji" },
+                "type": "Emoji",
+                "name": ":blobcat:"
+            }],
+            "_misskey_content": "hi"
+        });
+
+        compact_to_canonical(&mut document).unwrap();
+
+        assert_eq!(document["_misskey_quote"], "https://example.com/notes/1");
+        assert_eq!(document["_misskey_content"], "hi");
+        assert_eq!(
+            document["tag"],
+            json!([{ "type": "Emoji", "name": ":blobcat:" }])
+        );
+    }
+
+    #[test]
+    fn should_not_fetch_unknown_remote_contexts() {
+        let context: Context = serde_json::from_value(json!([
+            "https://www.w3.org/ns/activitystreams",
+            "https://example.com/unknown-context.jsonld"
+        ]))
+        .unwrap();
+
+        let active = ActiveContext::new(&context);
+
+        assert_eq!(
+            active.unresolved(),
+            &["https://example.com/unknown-context.jsonld".to_owned()]
+        );
+        assert_eq!(
+            active.expand_iri("notDefined").as_deref(),
+            Some("_:notDefined")
+        );
+    }
+}
@@ -1,8 +1,16 @@
-use crate::web_model::ListContaining;
+pub mod activity;
+pub mod actor;
+pub mod collection;
+pub mod context;
+pub mod object;
+pub mod property;
+
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::str::FromStr;
 
+pub use context::Context;
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
 pub struct ContextActivityStreams;
 
@@ -41,37 +49,53 @@ impl<'de> Deserialize<'de> for ContextActivityStreams {
     }
 }
 
+/// A JSON-LD document with its `@context`, wrapping the ActivityStreams object it describes
 #[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
-#[serde(untagged)]
-pub enum Context {
-    String(ContextActivityStreams),
-    Object {
-        #[serde(rename = "@vocab")]
-        ld_vocab: ContextActivityStreams,
-    },
-    List(ListContaining<ContextActivityStreams>),
+pub struct ActivityStreamsDocument<T> {
+    #[serde(rename = "@context", default)]
+    pub ld_context: Context,
+    #[serde(flatten)]
+    pub data: T,
 }
 
-impl Default for Context {
-    fn default() -> Self {
-        Context::String(ContextActivityStreams)
+impl<T> ActivityStreamsDocument<T> {
+    /// A document using Magnetar's standard context, for anything sent to other servers
+    pub fn new(data: T) -> Self {
+        Self::with_context(Context::magnetar(), data)
+    }
+
+    pub fn with_context(ld_context: Context, data: T) -> Self {
+        ActivityStreamsDocument { ld_context, data }
+    }
+
+    pub fn into_data(self) -> T {
+        self.data
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
-struct ActivityStreamsDocument<T> {
-    #[serde(rename = "@context", default)]
-    ld_context: Context,
-    #[serde(flatten)]
-    data: T,
+#[cfg(feature = "axum")]
+impl<T: Serialize> axum::response::IntoResponse for ActivityStreamsDocument<T> {
+    fn into_response(self) -> axum::response::Response {
+        use crate::web_model::content_type::ContentActivityStreams;
+        use axum::http::{header, StatusCode};
+
+        match serde_json::to_vec(&self) {
+            Ok(body) => (
+                [(header::CONTENT_TYPE, ContentActivityStreams.as_ref())],
+                body,
+            )
+                .into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::web_model::activity_streams::{
-        ActivityStreamsDocument, Context, ContextActivityStreams,
-    };
-    use crate::web_model::ListContaining;
+    use crate::web_model::activity_streams::context::{ContextEntry, TermDefinition};
+    use crate::web_model::activity_streams::object::ObjectOrLink;
+    use crate::web_model::activity_streams::property::OneOrMany;
+    use crate::web_model::activity_streams::{ActivityStreamsDocument, Context};
     use serde_json::json;
 
     #[test]
@@ -83,7 +107,7 @@ mod test {
 
         let doc: ActivityStreamsDocument<()> = serde_json::from_value(json).unwrap();
 
-        assert_eq!(doc.ld_context, Context::String(ContextActivityStreams));
+        assert_eq!(doc.ld_context, Context::default());
     }
 
     #[test]
@@ -94,7 +118,7 @@ mod test {
 
         let doc: ActivityStreamsDocument<()> = serde_json::from_value(json).unwrap();
 
-        assert_eq!(doc.ld_context, Context::String(ContextActivityStreams));
+        assert_eq!(doc.ld_context, Context::default());
     }
 
     #[test]
@@ -106,7 +130,12 @@ mod test {
 
         let doc: ActivityStreamsDocument<()> = serde_json::from_value(json).unwrap();
 
-        assert_eq!(doc.ld_context, Context::String(ContextActivityStreams));
+        assert_eq!(
+            doc.ld_context,
+            Context(OneOrMany::One(ContextEntry::Iri(
+                "http://www.w3.org/ns/activitystreams".to_owned()
+            )))
+        );
     }
 
     #[test]
@@ -121,11 +150,17 @@ mod test {
 
         let doc: ActivityStreamsDocument<()> = serde_json::from_value(json).unwrap();
 
+        let Context(OneOrMany::One(ContextEntry::Local(local))) = doc.ld_context else {
+            panic!("Expected a local context");
+        };
+
         assert_eq!(
-            doc.ld_context,
-            Context::Object {
-                ld_vocab: ContextActivityStreams
-            }
+            local.vocab.as_deref(),
+            Some("https://www.w3.org/ns/activitystreams")
+        );
+        assert_eq!(
+            local.terms.get("foo"),
+            Some(&Some(TermDefinition::Iri("bar".to_owned())))
         );
     }
 
@@ -143,9 +178,68 @@ mod test {
 
         let doc: ActivityStreamsDocument<()> = serde_json::from_value(json).unwrap();
 
+        assert_eq!(doc.ld_context.entries().len(), 2);
+        assert!(matches!(
+            doc.ld_context.entries().next(),
+            Some(ContextEntry::Local(local)) if local.terms.contains_key("foo")
+        ));
+    }
+
+    #[test]
+    fn should_parse_typed_document() {
+        let json = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "type": "Note",
+            "id": "https://example.com/notes/1",
+            "content": "Hello"
+        });
+
+        let doc: ActivityStreamsDocument<ObjectOrLink> =
+            serde_json::from_value(json.clone()).unwrap();
+
+        assert!(matches!(doc.data, ObjectOrLink::Note(_)));
+        assert_eq!(doc.data.iri(), Some("https://example.com/notes/1"));
+        assert_eq!(serde_json::to_value(&doc).unwrap(), json);
+    }
+
+    #[test]
+    fn should_emit_standard_context() {
+        let doc = ActivityStreamsDocument::new(json!({ "type": "Person", "isCat": true }));
+        let json = serde_json::to_value(&doc).unwrap();
+
+        let context = json["@context"].as_array().unwrap();
+        assert_eq!(context[0], "https://www.w3.org/ns/activitystreams");
+        assert_eq!(context[1], "https://w3id.org/security/v1");
+        for term in [
+            "manuallyApprovesFollowers",
+            "_misskey_content",
+            "isCat",
+            "Emoji",
+            "Hashtag",
+            "featured",
+        ] {
+            assert!(context[2].get(term).is_some(), "Missing term {term}");
+        }
+
+        assert_eq!(json["type"], "Person");
+        assert_eq!(json["isCat"], true);
+
+        let parsed: ActivityStreamsDocument<serde_json::Value> =
+            serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, doc);
+    }
+
+    #[cfg(feature = "axum")]
+    #[test]
+    fn should_respond_with_activity_streams() {
+        use axum::http::header;
+        use axum::response::IntoResponse;
+
+        let response = ActivityStreamsDocument::new(json!({ "type": "Note" })).into_response();
+
         assert_eq!(
-            doc.ld_context,
-            Context::List(ListContaining(ContextActivityStreams))
+            response.headers()[header::CONTENT_TYPE],
+            "application/activity+json"
         );
     }
 }
@@ -0,0 +1,528 @@
+use crate::web_model::activity_streams::activity::{Activity, IntransitiveActivity, Question};
+use crate::web_model::activity_streams::actor::Actor;
+use crate::web_model::activity_streams::collection::{
+    Collection, CollectionPage, OrderedCollection, OrderedCollectionPage,
+};
+use crate::web_model::activity_streams::property::{LanguageMap, ObjectRefs, OneOrMany, Ref};
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Number, Value};
+
+/// Types extending [`Object`], giving access to the common properties
+pub trait AsObject {
+    fn object(&self) -> &Object;
+
+    fn object_mut(&mut self) -> &mut Object;
+}
+
+macro_rules! as_object {
+    ($typ:ty, $($path:ident).+) => {
+        impl AsObject for $typ {
+            fn object(&self) -> &Object {
+                &self$(.$path)+
+            }
+
+            fn object_mut(&mut self) -> &mut Object {
+                &mut self$(.$path)+
+            }
+        }
+    };
+}
+
+pub(crate) use as_object;
+
+/// The base of all ActivityStreams objects that are not links
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Object {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attributed_to: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_map: Option<LanguageMap>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name_map: Option<LanguageMap>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generator: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub published: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replies: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary_map: Option<LanguageMap>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bto: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cc: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bcc: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<String>,
+    /// The source the `content` was converted from, as defined by ActivityPub
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub likes: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shares: Option<Ref<ObjectOrLink>>,
+    /// Properties outside of the ActivityStreams vocabulary, such as extension terms
+    #[serde(flatten)]
+    pub extensions: Map<String, Value>,
+}
+
+/// The original representation of the content of an object
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Source {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+}
+
+/// A reference to a resource, with information about the link itself
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Link {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub href: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rel: Option<OneOrMany<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name_map: Option<LanguageMap>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hreflang: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attributed_to: Option<ObjectRefs>,
+    /// Properties outside of the ActivityStreams vocabulary, such as extension terms
+    #[serde(flatten)]
+    pub extensions: Map<String, Value>,
+}
+
+/// A logical or physical location
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Place {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accuracy: Option<Number>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub altitude: Option<Number>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<Number>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<Number>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub radius: Option<Number>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub units: Option<String>,
+    #[serde(flatten)]
+    pub base: Object,
+}
+
+/// A content object describing another object
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub describes: Option<Ref<ObjectOrLink>>,
+    #[serde(flatten)]
+    pub base: Object,
+}
+
+/// Describes a relationship between two individuals
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Relationship {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Ref<ObjectOrLink>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub object: Option<ObjectRefs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relationship: Option<ObjectRefs>,
+    #[serde(flatten)]
+    pub base: Object,
+}
+
+/// A placeholder for an object that has been deleted
+#[derive(Clone, Eq, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tombstone {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub former_type: Option<OneOrMany<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted: Option<String>,
+    #[serde(flatten)]
+    pub base: Object,
+}
+
+impl AsObject for Object {
+    fn object(&self) -> &Object {
+        self
+    }
+
+    fn object_mut(&mut self) -> &mut Object {
+        self
+    }
+}
+
+as_object!(Place, base);
+as_object!(Profile, base);
+as_object!(Relationship, base);
+as_object!(Tombstone, base);
+
+macro_rules! object_or_link {
+    (objects { $($object:ident($object_typ:ty)),+ $(,)? } links { $($link:ident($link_typ:ty)),+ $(,)? }) => {
+        /// Any object or link, distinguished by its `type`
+        ///
+        /// Nodes of an unknown type, or with multiple types, are kept as they are.
+        #[allow(clippy::large_enum_variant)]
+        #[derive(Clone, Eq, PartialEq, Debug)]
+        pub enum ObjectOrLink {
+            $($object($object_typ),)+
+            $($link($link_typ),)+
+            Other(Map<String, Value>),
+        }
+
+        impl ObjectOrLink {
+            /// The `type` of the node
+            pub fn kind(&self) -> Option<&str> {
+                match self {
+                    $(ObjectOrLink::$object(_) => Some(stringify!($object)),)+
+                    $(ObjectOrLink::$link(_) => Some(stringify!($link)),)+
+                    ObjectOrLink::Other(map) => map.get("type").and_then(Value::as_str),
+                }
+            }
+
+            /// The common object properties, `None` for links and unknown types
+            pub fn as_object(&self) -> Option<&Object> {
+                match self {
+                    $(ObjectOrLink::$object(object) => Some(object.object()),)+
+                    _ => None,
+                }
+            }
+
+            pub fn as_link(&self) -> Option<&Link> {
+                match self {
+                    $(ObjectOrLink::$link(link) => Some(link),)+
+                    _ => None,
+                }
+            }
+
+            /// `None` for unknown types
+            fn from_map(
+                kind: &str,
+                map: Map<String, Value>,
+            ) -> Option<Result<Self, serde_json::Error>> {
+                let value = Value::Object(map);
+
+                match kind {
+                    $(stringify!($object) => {
+                        Some(serde_json::from_value(value).map(ObjectOrLink::$object))
+                    })+
+                    $(stringify!($link) => {
+                        Some(serde_json::from_value(value).map(ObjectOrLink::$link))
+                    })+
+                    _ => None,
+                }
+            }
+
+            fn to_value(&self) -> Result<Value, serde_json::Error> {
+                match self {
+                    $(ObjectOrLink::$object(object) => serde_json::to_value(object),)+
+                    $(ObjectOrLink::$link(link) => serde_json::to_value(link),)+
+                    ObjectOrLink::Other(map) => Ok(Value::Object(map.clone())),
+                }
+            }
+        }
+    };
+}
+
+object_or_link! {
+    objects {
+        Object(Object),
+        Article(Object),
+        Audio(Object),
+        Document(Object),
+        Event(Object),
+        Image(Object),
+        Note(Object),
+        Page(Object),
+        Video(Object),
+        Place(Place),
+        Profile(Profile),
+        Relationship(Relationship),
+        Tombstone(Tombstone),
+        Application(Actor),
+        Group(Actor),
+        Organization(Actor),
+        Person(Actor),
+        Service(Actor),
+        Activity(Activity),
+        Accept(Activity),
+        Add(Activity),
+        Announce(Activity),
+        Block(Activity),
+        Create(Activity),
+        Delete(Activity),
+        Dislike(Activity),
+        Flag(Activity),
+        Follow(Activity),
+        Ignore(Activity),
+        Invite(Activity),
+        Join(Activity),
+        Leave(Activity),
+        Like(Activity),
+        Listen(Activity),
+        Move(Activity),
+        Offer(Activity),
+        Read(Activity),
+        Reject(Activity),
+        Remove(Activity),
+        TentativeAccept(Activity),
+        TentativeReject(Activity),
+        Undo(Activity),
+        Update(Activity),
+        View(Activity),
+        IntransitiveActivity(IntransitiveActivity),
+        Arrive(IntransitiveActivity),
+        Travel(IntransitiveActivity),
+        Question(Question),
+        Collection(Collection),
+        OrderedCollection(OrderedCollection),
+        CollectionPage(CollectionPage),
+        OrderedCollectionPage(OrderedCollectionPage),
+    }
+    links {
+        Link(Link),
+        Mention(Link),
+    }
+}
+
+impl ObjectOrLink {
+    /// The `id` of objects or the `href` of links
+    pub fn iri(&self) -> Option<&str> {
+        if let Some(object) = self.as_object() {
+            return object.id.as_deref();
+        }
+
+        if let Some(link) = self.as_link() {
+            return link.href.as_deref();
+        }
+
+        match self {
+            ObjectOrLink::Other(map) => map
+                .get("id")
+                .or_else(|| map.get("href"))
+                .and_then(Value::as_str),
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for ObjectOrLink {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut value = self.to_value().map_err(S::Error::custom)?;
+
+        if let (Value::Object(map), Some(kind)) = (&mut value, self.kind()) {
+            map.insert("type".to_owned(), Value::String(kind.to_owned()));
+        }
+
+        value.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ObjectOrLink {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let map = Map::<String, Value>::deserialize(deserializer)?;
+
+        let Some(Value::String(kind)) = map.get("type") else {
+            return Ok(ObjectOrLink::Other(map));
+        };
+
+        let kind = kind.clone();
+        let mut typed = map.clone();
+        typed.remove("type");
+
+        // Known types with unexpected property values are kept untyped rather than rejected
+        match ObjectOrLink::from_map(&kind, typed) {
+            Some(Ok(typed)) => Ok(typed),
+            _ => Ok(ObjectOrLink::Other(map)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::web_model::activity_streams::object::{ObjectOrLink, Place};
+    use crate::web_model::activity_streams::property::{OneOrMany, Ref};
+    use serde_json::{json, Value};
+
+    fn examples() -> Vec<Value> {
+        serde_json::from_str(include_str!(
+            "../../../fixtures/as2_vocabulary_examples.json"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn should_round_trip_spec_examples() {
+        for example in examples() {
+            let parsed: ObjectOrLink = serde_json::from_value(example.clone()).unwrap();
+
+            assert!(
+                !matches!(parsed, ObjectOrLink::Other(_)),
+                "Expected a typed object: {example:#}"
+            );
+            assert_eq!(parsed.kind(), example["type"].as_str());
+            assert_eq!(serde_json::to_value(&parsed).unwrap(), example);
+        }
+    }
+
+    #[test]
+    fn should_accept_all_property_forms() {
+        let json = json!({
+            "type": "Like",
+            "actor": "http://sally.example.org",
+            "object": [
+                "http://example.org/posts/1",
+                {
+                    "type": "Note",
+                    "id": "http://example.org/posts/2"
+                },
+                {
+                    "type": "Link",
+                    "href": "http://example.org/posts/3"
+                }
+            ]
+        });
+
+        let ObjectOrLink::Like(like) = serde_json::from_value(json).unwrap() else {
+            panic!("Expected a Like activity");
+        };
+
+        assert_eq!(
+            like.actor,
+            Some(OneOrMany::One(Ref::Iri(
+                "http://sally.example.org".to_owned()
+            )))
+        );
+
+        let objects = like.object.unwrap();
+        assert_eq!(
+            objects.iter().map(Ref::iri).collect::<Vec<_>>(),
+            vec![
+                Some("http://example.org/posts/1"),
+                Some("http://example.org/posts/2"),
+                Some("http://example.org/posts/3"),
+            ]
+        );
+        assert!(matches!(
+            objects.iter().nth(1).and_then(Ref::embedded),
+            Some(ObjectOrLink::Note(_))
+        ));
+    }
+
+    #[test]
+    fn should_keep_unknown_types_and_properties() {
+        let json = json!({
+            "type": "Note",
+            "content": "Hello",
+            "sensitive": false,
+            "tag": [
+                {
+                    "type": "Emoji",
+                    "name": ":blobcat:",
+                    "icon": {
+                        "type": "Image",
+                        "url": "https://example.org/blobcat.png"
+                    }
+                }
+            ]
+        });
+
+        let parsed: ObjectOrLink = serde_json::from_value(json.clone()).unwrap();
+
+        let object = parsed.as_object().unwrap();
+        assert_eq!(object.extensions.get("sensitive"), Some(&json!(false)));
+        assert_eq!(
+            object
+                .tag
+                .as_ref()
+                .and_then(OneOrMany::first)
+                .and_then(Ref::embedded)
+                .and_then(ObjectOrLink::kind),
+            Some("Emoji")
+        );
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), json);
+    }
+
+    #[test]
+    fn should_preserve_numbers() {
+        let place: Place = serde_json::from_value(json!({
+            "latitude": 36.75,
+            "radius": 15
+        }))
+        .unwrap();
+
+        assert_eq!(
+            place.latitude.as_ref().and_then(|n| n.as_f64()),
+            Some(36.75)
+        );
+        assert_eq!(
+            serde_json::to_value(&place).unwrap(),
+            json!({
+                "latitude": 36.75,
+                "radius": 15
+            })
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use crate::web_model::activity_streams::object::ObjectOrLink;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A property value that may be given either as a single value or as an array
+///
+/// The original form is kept, so documents serialize the way they were received.
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        match self {
+            OneOrMany::One(value) => std::slice::from_ref(value).iter(),
+            OneOrMany::Many(values) => values.iter(),
+        }
+    }
+
+    pub fn first(&self) -> Option<&T> {
+        self.iter().next()
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+impl<T> From<T> for OneOrMany<T> {
+    fn from(value: T) -> Self {
+        OneOrMany::One(value)
+    }
+}
+
+impl<T> From<Vec<T>> for OneOrMany<T> {
+    fn from(values: Vec<T>) -> Self {
+        OneOrMany::Many(values)
+    }
+}
+
+impl<'a, T> IntoIterator for &'a OneOrMany<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// A reference to another node, either by its IRI or as an embedded object
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Ref<T> {
+    Iri(String),
+    Embedded(Box<T>),
+}
+
+impl<T> Ref<T> {
+    pub fn embedded(&self) -> Option<&T> {
+        match self {
+            Ref::Iri(_) => None,
+            Ref::Embedded(value) => Some(value),
+        }
+    }
+}
+
+impl Ref<ObjectOrLink> {
+    /// The IRI of the referenced node, the `id` of embedded objects or the `href` of links
+    pub fn iri(&self) -> Option<&str> {
+        match self {
+            Ref::Iri(iri) => Some(iri),
+            Ref::Embedded(object) => object.iri(),
+        }
+    }
+}
+
+impl<T> From<T> for Ref<T> {
+    fn from(value: T) -> Self {
+        Ref::Embedded(Box::new(value))
+    }
+}
+
+/// The usual type of properties referencing other objects or links
+pub type ObjectRefs = OneOrMany<Ref<ObjectOrLink>>;
+
+/// Natural language values keyed by their BCP47 language tag
+pub type LanguageMap = BTreeMap<String, String>;
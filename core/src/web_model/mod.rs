@@ -56,9 +56,22 @@ pub mod content_type {
     use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
     content_type!(pub ContentActivityStreams, "application/activity+json");
+    content_type!(
+        pub ContentLdJsonActivityStreams,
+        r#"application/ld+json; profile="https://www.w3.org/ns/activitystreams""#
+    );
     content_type!(pub ContentHtml, "text/html");
     content_type!(pub ContentJson, "application/json");
     content_type!(pub ContentJrdJson, "application/jrd+json");
+    content_type!(
+        pub ContentNodeInfo20,
+        r#"application/json; profile="http://nodeinfo.diaspora.software/ns/schema/2.0#""#
+    );
+    content_type!(
+        pub ContentNodeInfo21,
+        r#"application/json; profile="http://nodeinfo.diaspora.software/ns/schema/2.1#""#
+    );
+    content_type!(pub ContentXrdXml, "application/xrd+xml");
     content_type!(pub ContentMultipartFormData, "multipart/form-data");
     content_type!(pub ContentUrlEncoded, "application/x-www-form-urlencoded");
 }
@@ -112,8 +125,12 @@ pub mod rel {
     use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
     link_rel!(pub RelWebFingerProfilePage, "http://webfinger.net/rel/profile-page");
+    link_rel!(pub RelWebFingerAvatar, "http://webfinger.net/rel/avatar");
     link_rel!(pub RelSelf, "self");
+    link_rel!(pub RelLrdd, "lrdd");
     link_rel!(pub RelOStatusSubscribe, "http://ostatus.org/schema/1.0/subscribe");
+    link_rel!(pub RelNodeInfo10, "http://nodeinfo.diaspora.software/ns/schema/1.0");
+    link_rel!(pub RelNodeInfo11, "http://nodeinfo.diaspora.software/ns/schema/1.1");
     link_rel!(pub RelNodeInfo20, "http://nodeinfo.diaspora.software/ns/schema/2.0");
     link_rel!(pub RelNodeInfo21, "http://nodeinfo.diaspora.software/ns/schema/2.1");
 }
@@ -0,0 +1,22 @@
+-- The outgoing delivery queue, see `delivery_job`
+-- Earlier versions created the table on startup, hence `IF NOT EXISTS`.
+
+CREATE TABLE IF NOT EXISTS "magnetar_delivery_job" (
+    "id" varchar NOT NULL PRIMARY KEY,
+    "created_at" timestamp with time zone NOT NULL,
+    "user_id" varchar NOT NULL,
+    "inbox" varchar NOT NULL,
+    "host" varchar NOT NULL,
+    "activity" jsonb NOT NULL,
+    "attempts" integer NOT NULL,
+    "next_attempt_at" timestamp with time zone NOT NULL,
+    "locked_until" timestamp with time zone,
+    "last_error" varchar,
+    "dead_at" timestamp with time zone
+);
+
+CREATE INDEX IF NOT EXISTS "idx-magnetar_delivery_job-host"
+    ON "magnetar_delivery_job" ("host");
+
+CREATE INDEX IF NOT EXISTS "idx-magnetar_delivery_job-next_attempt_at"
+    ON "magnetar_delivery_job" ("next_attempt_at");
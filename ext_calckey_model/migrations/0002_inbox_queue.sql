@@ -0,0 +1,12 @@
+-- Activities received by the inboxes and waiting to be processed, see `inbox_job`
+
+CREATE TABLE "magnetar_inbox_job" (
+    "id" varchar NOT NULL PRIMARY KEY,
+    "received_at" timestamp with time zone NOT NULL,
+    "signer" varchar NOT NULL,
+    "activity" jsonb NOT NULL,
+    "locked_until" timestamp with time zone
+);
+
+CREATE INDEX "idx-magnetar_inbox_job-received_at"
+    ON "magnetar_inbox_job" ("received_at");
@@ -0,0 +1,96 @@
+//! A delivery of an activity to a remote inbox, waiting to be sent
+//!
+//! Unlike the `ck` entities, this table belongs to Magnetar and is created by a [migration](crate::migration).
+
+use crate::id::gen_aid;
+use chrono::{Duration, Utc};
+use sea_orm::entity::prelude::*;
+use sea_orm::ActiveValue;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "magnetar_delivery_job")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    pub created_at: DateTimeWithTimeZone,
+    /// The local user signing the delivery
+    pub user_id: String,
+    pub inbox: String,
+    /// The host of the inbox, used to limit concurrent deliveries and to mark failing instances
+    #[sea_orm(indexed)]
+    pub host: String,
+    #[sea_orm(column_type = "JsonBinary")]
+    pub activity: Json,
+    pub attempts: i32,
+    #[sea_orm(indexed)]
+    pub next_attempt_at: DateTimeWithTimeZone,
+    /// Claimed jobs are not claimed again before their lease runs out
+    pub locked_until: Option<DateTimeWithTimeZone>,
+    pub last_error: Option<String>,
+    /// Set when the delivery was given up, the job is kept for inspection
+    pub dead_at: Option<DateTimeWithTimeZone>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// New jobs delivering an activity of `user_id` to each of the `(inbox, host)` pairs
+///
+/// Aids only have 1296 noise values per millisecond, so every job of a batch gets
+/// its ID from its own millisecond to keep large batches free of collisions.
+pub fn new_jobs(
+    user_id: &str,
+    activity: &Json,
+    inboxes: &[(String, String)],
+    now: chrono::DateTime<Utc>,
+) -> Vec<ActiveModel> {
+    inboxes
+        .iter()
+        .enumerate()
+        .map(|(i, (inbox, host))| ActiveModel {
+            id: ActiveValue::Set(gen_aid(now + Duration::milliseconds(i as i64))),
+            created_at: ActiveValue::Set(now.into()),
+            user_id: ActiveValue::Set(user_id.to_owned()),
+            inbox: ActiveValue::Set(inbox.clone()),
+            host: ActiveValue::Set(host.to_lowercase()),
+            activity: ActiveValue::Set(activity.clone()),
+            attempts: ActiveValue::Set(0),
+            next_attempt_at: ActiveValue::Set(now.into()),
+            locked_until: ActiveValue::Set(None),
+            last_error: ActiveValue::Set(None),
+            dead_at: ActiveValue::Set(None),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::delivery_job::new_jobs;
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    #[test]
+    fn should_give_large_batches_unique_ids() {
+        let now = Utc.timestamp_millis_opt(1_678_796_991_316).unwrap();
+        let inboxes = (0..3000)
+            .map(|i| {
+                (
+                    format!("https://remote{i}.example/inbox"),
+                    format!("remote{i}.example"),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let jobs = new_jobs("9cbsp26s00", &json!({ "type": "Create" }), &inboxes, now);
+        let ids = jobs
+            .iter()
+            .map(|job| job.id.clone().unwrap())
+            .collect::<HashSet<_>>();
+
+        assert_eq!(jobs.len(), inboxes.len());
+        assert_eq!(ids.len(), inboxes.len());
+    }
+}
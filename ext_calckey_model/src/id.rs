@@ -0,0 +1,54 @@
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::OnceLock;
+
+/// Milliseconds between the Unix epoch and 2000-01-01T00:00:00Z
+const TIME_2000: i64 = 946_684_800_000;
+
+fn counter() -> &'static AtomicU16 {
+    static COUNTER: OnceLock<AtomicU16> = OnceLock::new();
+    COUNTER.get_or_init(|| AtomicU16::new(rand::thread_rng().gen()))
+}
+
+fn to_base36(mut value: u64, width: usize) -> String {
+    const DIGITS: &[u8; 36] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    let mut digits = Vec::new();
+    while value > 0 {
+        digits.push(DIGITS[(value % 36) as usize]);
+        value /= 36;
+    }
+    digits.resize(digits.len().max(width), b'0');
+    digits.reverse();
+
+    String::from_utf8(digits).expect("base36 digits are ASCII")
+}
+
+/// Generates a Calckey "aid", a time-sortable id of 8 base36 time characters and 2 noise characters
+pub fn gen_aid(time: DateTime<Utc>) -> String {
+    let millis = (time.timestamp_millis() - TIME_2000).max(0) as u64;
+    let noise = counter().fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+    let noise = to_base36(noise as u64, 2);
+
+    format!("{}{}", to_base36(millis, 8), &noise[noise.len() - 2..])
+}
+
+#[cfg(test)]
+mod test {
+    use crate::id::gen_aid;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn should_generate_sortable_aids() {
+        let earlier = Utc.timestamp_millis_opt(1_678_796_991_316).unwrap();
+        let later = Utc.timestamp_millis_opt(1_678_796_991_317).unwrap();
+
+        let earlier_aid = gen_aid(earlier);
+        let later_aid = gen_aid(later);
+
+        assert_eq!(earlier_aid.len(), 10);
+        assert!(earlier_aid.starts_with("9cbsp26s"));
+        assert!(earlier_aid < later_aid);
+    }
+}
@@ -0,0 +1,25 @@
+//! An activity received by an inbox, waiting to be processed
+//!
+//! Unlike the `ck` entities, this table belongs to Magnetar and is created by a [migration](crate::migration).
+
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "magnetar_inbox_job")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    #[sea_orm(indexed)]
+    pub received_at: DateTimeWithTimeZone,
+    /// The actor whose key signed the delivery
+    pub signer: String,
+    #[sea_orm(column_type = "JsonBinary")]
+    pub activity: Json,
+    /// Claimed jobs are not claimed again before their lease runs out
+    pub locked_until: Option<DateTimeWithTimeZone>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -1,12 +1,169 @@
-use ck::user;
+pub mod delivery_job;
+pub mod id;
+pub mod inbox_job;
+pub mod migration;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+
+use crate::id::gen_aid;
+use chrono::{DateTime, Utc};
+use ck::sea_orm_active_enums::NoteVisibilityEnum;
+use ck::{
+    abuse_user_report, blocking, drive_file, emoji, follow_request, following, instance, meta,
+    note, note_reaction, user, user_keypair, user_note_pining, user_profile, user_publickey,
+};
 use log::LevelFilter;
-use sea_orm::{ColumnTrait, ConnectOptions, DatabaseConnection, EntityTrait, QueryFilter};
+use sea_orm::sea_query::{Expr, OnConflict};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, Condition, ConnectOptions, ConnectionTrait,
+    DatabaseConnection, EntityTrait, IntoActiveModel, PaginatorTrait, QueryFilter, QueryOrder,
+    QuerySelect, Select, Statement, TransactionTrait,
+};
+
+pub use ck;
 
 #[derive(Debug)]
 pub struct ConnectorConfig {
     pub url: String,
 }
 
+/// Information about a remote instance, as gathered from its NodeInfo
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct InstanceInfo {
+    pub software_name: Option<String>,
+    pub software_version: Option<String>,
+    pub open_registrations: Option<bool>,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub maintainer_name: Option<String>,
+    pub maintainer_email: Option<String>,
+    pub icon_url: Option<String>,
+    pub theme_color: Option<String>,
+}
+
+/// Everything stored about a remote user that is taken from their actor
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RemoteUser {
+    pub uri: String,
+    pub username: String,
+    pub host: String,
+    pub name: Option<String>,
+    pub inbox: Option<String>,
+    pub shared_inbox: Option<String>,
+    pub featured: Option<String>,
+    pub followers_uri: Option<String>,
+    pub is_locked: bool,
+    pub is_bot: bool,
+    pub is_cat: bool,
+    pub is_explorable: bool,
+    pub tags: Vec<String>,
+    pub emojis: Vec<String>,
+    pub moved_to_uri: Option<String>,
+    /// Comma-separated URIs, as stored by Calckey
+    pub also_known_as: Option<String>,
+    pub description: Option<String>,
+    pub url: Option<String>,
+    pub fields: serde_json::Value,
+    pub birthday: Option<String>,
+    pub location: Option<String>,
+    /// The ID and PEM of the main public key
+    pub public_key: Option<(String, String)>,
+}
+
+/// Adds `delta` to a counter column of a user
+async fn add_to_user_count(
+    db: &impl ConnectionTrait,
+    user_id: &str,
+    column: user::Column,
+    delta: i32,
+) -> anyhow::Result<()> {
+    user::Entity::update_many()
+        .col_expr(column, Expr::col(column).add(delta))
+        .filter(user::Column::Id.eq(user_id))
+        .exec(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Adds `delta` to a counter column of a note
+async fn add_to_note_count(
+    db: &impl ConnectionTrait,
+    note_id: &str,
+    column: note::Column,
+    delta: i16,
+) -> anyhow::Result<()> {
+    note::Entity::update_many()
+        .col_expr(column, Expr::col(column).add(delta))
+        .filter(note::Column::Id.eq(note_id))
+        .exec(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Adds `delta` to the count of `reaction` in the `reactions` object of a note
+async fn add_to_note_reactions(
+    db: &impl ConnectionTrait,
+    note_id: &str,
+    reaction: &str,
+    delta: i32,
+) -> anyhow::Result<()> {
+    note::Entity::update_many()
+        .col_expr(
+            note::Column::Reactions,
+            Expr::cust_with_values(
+                r#"jsonb_set("reactions", ARRAY[$1], to_jsonb(GREATEST(COALESCE(("reactions"->>$2)::int, 0) + $3, 0)))"#,
+                [reaction.into(), reaction.into(), sea_orm::Value::from(delta)],
+            ),
+        )
+        .col_expr(
+            note::Column::Score,
+            Expr::cust_with_values(r#"GREATEST("score" + $1, 0)"#, [delta]),
+        )
+        .filter(note::Column::Id.eq(note_id))
+        .exec(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Delivery jobs inserted by a single statement
+const ENQUEUE_CHUNK_SIZE: usize = 1000;
+
+/// A position in rows ordered by their time-sortable IDs, newest first
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IdCursor {
+    Newest,
+    /// Rows older than the row with this ID
+    Before(String),
+    /// Rows newer than the row with this ID
+    After(String),
+}
+
+/// Up to `limit` rows next to `cursor`, newest first
+async fn paginate<E: EntityTrait>(
+    db: &impl ConnectionTrait,
+    select: Select<E>,
+    id: E::Column,
+    cursor: &IdCursor,
+    limit: u64,
+) -> anyhow::Result<Vec<E::Model>> {
+    let select = match cursor {
+        IdCursor::Newest => select.order_by_desc(id),
+        IdCursor::Before(before) => select.filter(id.lt(before.as_str())).order_by_desc(id),
+        IdCursor::After(after) => select.filter(id.gt(after.as_str())).order_by_asc(id),
+    };
+
+    let mut rows = select.limit(limit).all(db).await?;
+
+    if let IdCursor::After(_) = cursor {
+        rows.reverse();
+    }
+
+    Ok(rows)
+}
+
 #[derive(Clone, Debug)]
 pub struct CalckeyModel(DatabaseConnection);
 
@@ -47,10 +204,1140 @@ impl CalckeyModel {
         Ok(user)
     }
 
+    pub async fn get_user_by_id(&self, id: &str) -> anyhow::Result<Option<user::Model>> {
+        Ok(user::Entity::find_by_id(id.to_owned()).one(&self.0).await?)
+    }
+
     pub async fn get_user_by_uri(&self, uri: &str) -> anyhow::Result<Option<user::Model>> {
         Ok(user::Entity::find()
             .filter(user::Column::Uri.eq(uri))
             .one(&self.0)
             .await?)
     }
+
+    /// Users with any of the given IDs, in no particular order
+    pub async fn get_users_by_ids(&self, ids: &[String]) -> anyhow::Result<Vec<user::Model>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        Ok(user::Entity::find()
+            .filter(user::Column::Id.is_in(ids.iter().cloned()))
+            .all(&self.0)
+            .await?)
+    }
+
+    pub async fn get_user_profile_by_id(
+        &self,
+        user_id: &str,
+    ) -> anyhow::Result<Option<user_profile::Model>> {
+        Ok(user_profile::Entity::find_by_id(user_id.to_owned())
+            .one(&self.0)
+            .await?)
+    }
+
+    pub async fn get_user_keypair_by_id(
+        &self,
+        user_id: &str,
+    ) -> anyhow::Result<Option<user_keypair::Model>> {
+        Ok(user_keypair::Entity::find_by_id(user_id.to_owned())
+            .one(&self.0)
+            .await?)
+    }
+
+    pub async fn get_user_publickey_by_key_id(
+        &self,
+        key_id: &str,
+    ) -> anyhow::Result<Option<user_publickey::Model>> {
+        Ok(user_publickey::Entity::find()
+            .filter(user_publickey::Column::KeyId.eq(key_id))
+            .one(&self.0)
+            .await?)
+    }
+
+    /// Stores the public key of a remote user, replacing any previous key
+    pub async fn upsert_user_publickey(
+        &self,
+        user_id: &str,
+        key_id: &str,
+        key_pem: &str,
+    ) -> anyhow::Result<()> {
+        let key = user_publickey::ActiveModel {
+            user_id: ActiveValue::Set(user_id.to_owned()),
+            key_id: ActiveValue::Set(key_id.to_owned()),
+            key_pem: ActiveValue::Set(key_pem.to_owned()),
+        };
+
+        user_publickey::Entity::insert(key)
+            .on_conflict(
+                OnConflict::column(user_publickey::Column::UserId)
+                    .update_columns([
+                        user_publickey::Column::KeyId,
+                        user_publickey::Column::KeyPem,
+                    ])
+                    .to_owned(),
+            )
+            .exec(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Custom emojis of this instance with any of the given names
+    pub async fn get_local_emojis_by_names(
+        &self,
+        names: &[String],
+    ) -> anyhow::Result<Vec<emoji::Model>> {
+        if names.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        Ok(emoji::Entity::find()
+            .filter(emoji::Column::Name.is_in(names.iter().cloned()))
+            .filter(emoji::Column::Host.is_null())
+            .all(&self.0)
+            .await?)
+    }
+
+    pub async fn get_drive_file_by_id(
+        &self,
+        id: &str,
+    ) -> anyhow::Result<Option<drive_file::Model>> {
+        Ok(drive_file::Entity::find_by_id(id.to_owned())
+            .one(&self.0)
+            .await?)
+    }
+
+    /// Drive files with any of the given IDs, in the order of `ids`
+    pub async fn get_drive_files_by_ids(
+        &self,
+        ids: &[String],
+    ) -> anyhow::Result<Vec<drive_file::Model>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut files = drive_file::Entity::find()
+            .filter(drive_file::Column::Id.is_in(ids.iter().cloned()))
+            .all(&self.0)
+            .await?;
+
+        files.sort_by_key(|file| ids.iter().position(|id| *id == file.id));
+
+        Ok(files)
+    }
+
+    pub async fn get_note_by_id(&self, id: &str) -> anyhow::Result<Option<note::Model>> {
+        Ok(note::Entity::find_by_id(id.to_owned()).one(&self.0).await?)
+    }
+
+    /// Notes of a local user that are served publicly, like in their outbox
+    fn outbox_notes(user_id: &str) -> Select<note::Entity> {
+        note::Entity::find()
+            .filter(note::Column::UserId.eq(user_id))
+            .filter(note::Column::UserHost.is_null())
+            .filter(note::Column::LocalOnly.eq(false))
+            .filter(
+                note::Column::Visibility
+                    .is_in([NoteVisibilityEnum::Public, NoteVisibilityEnum::Home]),
+            )
+    }
+
+    pub async fn get_outbox_note_count(&self, user_id: &str) -> anyhow::Result<u64> {
+        Ok(Self::outbox_notes(user_id).count(&self.0).await?)
+    }
+
+    pub async fn get_outbox_notes(
+        &self,
+        user_id: &str,
+        cursor: &IdCursor,
+        limit: u64,
+    ) -> anyhow::Result<Vec<note::Model>> {
+        paginate(
+            &self.0,
+            Self::outbox_notes(user_id),
+            note::Column::Id,
+            cursor,
+            limit,
+        )
+        .await
+    }
+
+    /// Pinned notes of a user, most recently pinned first
+    pub async fn get_pinned_notes(&self, user_id: &str) -> anyhow::Result<Vec<note::Model>> {
+        let pinings = user_note_pining::Entity::find()
+            .filter(user_note_pining::Column::UserId.eq(user_id))
+            .order_by_desc(user_note_pining::Column::Id)
+            .all(&self.0)
+            .await?;
+
+        if pinings.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let notes = note::Entity::find()
+            .filter(note::Column::Id.is_in(pinings.iter().map(|pining| pining.note_id.clone())))
+            .all(&self.0)
+            .await?;
+
+        Ok(pinings
+            .iter()
+            .filter_map(|pining| notes.iter().find(|note| note.id == pining.note_id))
+            .cloned()
+            .collect())
+    }
+
+    fn local_users() -> Select<user::Entity> {
+        user::Entity::find()
+            .filter(user::Column::Host.is_null())
+            .filter(user::Column::IsDeleted.eq(false))
+            .filter(user::Column::IsSuspended.eq(false))
+    }
+
+    pub async fn get_local_user_count(&self) -> anyhow::Result<u64> {
+        Ok(Self::local_users().count(&self.0).await?)
+    }
+
+    pub async fn get_local_user_count_active_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> anyhow::Result<u64> {
+        Ok(Self::local_users()
+            .filter(user::Column::LastActiveDate.gt(since))
+            .count(&self.0)
+            .await?)
+    }
+
+    pub async fn get_local_note_count(&self) -> anyhow::Result<u64> {
+        Ok(note::Entity::find()
+            .filter(note::Column::UserHost.is_null())
+            .count(&self.0)
+            .await?)
+    }
+
+    pub async fn get_meta(&self) -> anyhow::Result<Option<meta::Model>> {
+        Ok(meta::Entity::find().one(&self.0).await?)
+    }
+
+    /// Instances that are not suspended and whose information is missing or older than `stale_before`
+    pub async fn get_instances_with_stale_info(
+        &self,
+        stale_before: DateTime<Utc>,
+        limit: u64,
+    ) -> anyhow::Result<Vec<instance::Model>> {
+        Ok(instance::Entity::find()
+            .filter(instance::Column::IsSuspended.eq(false))
+            .filter(
+                Condition::any()
+                    .add(instance::Column::InfoUpdatedAt.is_null())
+                    .add(instance::Column::InfoUpdatedAt.lt(stale_before)),
+            )
+            // Least recently contacted first, so instances in backoff do not starve the others
+            .order_by_asc(Expr::col(instance::Column::LatestRequestSentAt).is_not_null())
+            .order_by_asc(instance::Column::LatestRequestSentAt)
+            .limit(limit)
+            .all(&self.0)
+            .await?)
+    }
+
+    /// Creates or updates the instance of `host` after a successful information fetch
+    pub async fn upsert_instance_info(&self, host: &str, info: InstanceInfo) -> anyhow::Result<()> {
+        let now = Utc::now();
+
+        let instance = instance::ActiveModel {
+            id: ActiveValue::Set(gen_aid(now)),
+            caught_at: ActiveValue::Set(now.into()),
+            host: ActiveValue::Set(host.to_lowercase()),
+            users_count: ActiveValue::Set(0),
+            notes_count: ActiveValue::Set(0),
+            following_count: ActiveValue::Set(0),
+            followers_count: ActiveValue::Set(0),
+            latest_request_sent_at: ActiveValue::Set(Some(now.into())),
+            latest_status: ActiveValue::Set(Some(200)),
+            latest_request_received_at: ActiveValue::Set(None),
+            last_communicated_at: ActiveValue::Set(now.into()),
+            is_not_responding: ActiveValue::Set(false),
+            software_name: ActiveValue::Set(info.software_name),
+            software_version: ActiveValue::Set(info.software_version),
+            open_registrations: ActiveValue::Set(info.open_registrations),
+            name: ActiveValue::Set(info.name),
+            description: ActiveValue::Set(info.description),
+            maintainer_name: ActiveValue::Set(info.maintainer_name),
+            maintainer_email: ActiveValue::Set(info.maintainer_email),
+            info_updated_at: ActiveValue::Set(Some(now.into())),
+            is_suspended: ActiveValue::Set(false),
+            icon_url: ActiveValue::Set(info.icon_url),
+            theme_color: ActiveValue::Set(info.theme_color),
+            favicon_url: ActiveValue::Set(None),
+        };
+
+        instance::Entity::insert(instance)
+            .on_conflict(
+                OnConflict::column(instance::Column::Host)
+                    .update_columns([
+                        instance::Column::LatestRequestSentAt,
+                        instance::Column::LatestStatus,
+                        instance::Column::LastCommunicatedAt,
+                        instance::Column::IsNotResponding,
+                        instance::Column::SoftwareName,
+                        instance::Column::SoftwareVersion,
+                        instance::Column::OpenRegistrations,
+                        instance::Column::Name,
+                        instance::Column::Description,
+                        instance::Column::MaintainerName,
+                        instance::Column::MaintainerEmail,
+                        instance::Column::InfoUpdatedAt,
+                        instance::Column::IconUrl,
+                        instance::Column::ThemeColor,
+                    ])
+                    .to_owned(),
+            )
+            .exec(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Records a failed request to the instance of `host`
+    ///
+    /// `status` is the HTTP status of the response, if any was received.
+    pub async fn mark_instance_not_responding(
+        &self,
+        host: &str,
+        status: Option<i32>,
+    ) -> anyhow::Result<()> {
+        instance::Entity::update_many()
+            .col_expr(instance::Column::IsNotResponding, Expr::value(true))
+            .col_expr(instance::Column::LatestStatus, Expr::value(status))
+            .col_expr(
+                instance::Column::LatestRequestSentAt,
+                Expr::value(Utc::now()),
+            )
+            .filter(instance::Column::Host.eq(host.to_lowercase()))
+            .exec(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_note_by_uri(&self, uri: &str) -> anyhow::Result<Option<note::Model>> {
+        Ok(note::Entity::find()
+            .filter(note::Column::Uri.eq(uri))
+            .one(&self.0)
+            .await?)
+    }
+
+    /// Creates a remote user along with their profile and public key
+    pub async fn insert_remote_user(&self, remote: RemoteUser) -> anyhow::Result<user::Model> {
+        let now = Utc::now();
+        let id = gen_aid(now);
+        let txn = self.0.begin().await?;
+
+        let user = user::ActiveModel {
+            id: ActiveValue::Set(id.clone()),
+            created_at: ActiveValue::Set(now.into()),
+            last_fetched_at: ActiveValue::Set(Some(now.into())),
+            username_lower: ActiveValue::Set(remote.username.to_lowercase()),
+            username: ActiveValue::Set(remote.username),
+            host: ActiveValue::Set(Some(remote.host.to_lowercase())),
+            uri: ActiveValue::Set(Some(remote.uri)),
+            name: ActiveValue::Set(remote.name),
+            inbox: ActiveValue::Set(remote.inbox),
+            shared_inbox: ActiveValue::Set(remote.shared_inbox),
+            featured: ActiveValue::Set(remote.featured),
+            followers_uri: ActiveValue::Set(remote.followers_uri),
+            is_locked: ActiveValue::Set(remote.is_locked),
+            is_bot: ActiveValue::Set(remote.is_bot),
+            is_cat: ActiveValue::Set(remote.is_cat),
+            is_explorable: ActiveValue::Set(remote.is_explorable),
+            tags: ActiveValue::Set(remote.tags),
+            emojis: ActiveValue::Set(remote.emojis),
+            moved_to_uri: ActiveValue::Set(remote.moved_to_uri),
+            also_known_as: ActiveValue::Set(remote.also_known_as),
+            ..Default::default()
+        }
+        .insert(&txn)
+        .await?;
+
+        user_profile::ActiveModel {
+            user_id: ActiveValue::Set(id.clone()),
+            user_host: ActiveValue::Set(user.host.clone()),
+            description: ActiveValue::Set(remote.description),
+            url: ActiveValue::Set(remote.url),
+            fields: ActiveValue::Set(remote.fields),
+            birthday: ActiveValue::Set(remote.birthday),
+            location: ActiveValue::Set(remote.location),
+            ..Default::default()
+        }
+        .insert(&txn)
+        .await?;
+
+        if let Some((key_id, key_pem)) = remote.public_key {
+            user_publickey::ActiveModel {
+                user_id: ActiveValue::Set(id),
+                key_id: ActiveValue::Set(key_id),
+                key_pem: ActiveValue::Set(key_pem),
+            }
+            .insert(&txn)
+            .await?;
+        }
+
+        txn.commit().await?;
+
+        Ok(user)
+    }
+
+    /// Replaces the stored actor of a remote user, their URI and handle are kept
+    pub async fn update_remote_user(
+        &self,
+        user_id: &str,
+        remote: RemoteUser,
+    ) -> anyhow::Result<()> {
+        let now = Utc::now();
+        let txn = self.0.begin().await?;
+
+        user::ActiveModel {
+            id: ActiveValue::Unchanged(user_id.to_owned()),
+            updated_at: ActiveValue::Set(Some(now.into())),
+            last_fetched_at: ActiveValue::Set(Some(now.into())),
+            name: ActiveValue::Set(remote.name),
+            inbox: ActiveValue::Set(remote.inbox),
+            shared_inbox: ActiveValue::Set(remote.shared_inbox),
+            featured: ActiveValue::Set(remote.featured),
+            followers_uri: ActiveValue::Set(remote.followers_uri),
+            is_locked: ActiveValue::Set(remote.is_locked),
+            is_bot: ActiveValue::Set(remote.is_bot),
+            is_cat: ActiveValue::Set(remote.is_cat),
+            is_explorable: ActiveValue::Set(remote.is_explorable),
+            tags: ActiveValue::Set(remote.tags),
+            emojis: ActiveValue::Set(remote.emojis),
+            moved_to_uri: ActiveValue::Set(remote.moved_to_uri),
+            also_known_as: ActiveValue::Set(remote.also_known_as),
+            ..Default::default()
+        }
+        .update(&txn)
+        .await?;
+
+        user_profile::ActiveModel {
+            user_id: ActiveValue::Unchanged(user_id.to_owned()),
+            description: ActiveValue::Set(remote.description),
+            url: ActiveValue::Set(remote.url),
+            fields: ActiveValue::Set(remote.fields),
+            birthday: ActiveValue::Set(remote.birthday),
+            location: ActiveValue::Set(remote.location),
+            ..Default::default()
+        }
+        .update(&txn)
+        .await?;
+
+        if let Some((key_id, key_pem)) = remote.public_key {
+            user_publickey::Entity::insert(user_publickey::ActiveModel {
+                user_id: ActiveValue::Set(user_id.to_owned()),
+                key_id: ActiveValue::Set(key_id),
+                key_pem: ActiveValue::Set(key_pem),
+            })
+            .on_conflict(
+                OnConflict::column(user_publickey::Column::UserId)
+                    .update_columns([
+                        user_publickey::Column::KeyId,
+                        user_publickey::Column::KeyPem,
+                    ])
+                    .to_owned(),
+            )
+            .exec(&txn)
+            .await?;
+        }
+
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn mark_user_deleted(&self, user_id: &str) -> anyhow::Result<()> {
+        user::Entity::update_many()
+            .col_expr(user::Column::IsDeleted, Expr::value(true))
+            .filter(user::Column::Id.eq(user_id))
+            .exec(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_user_moved_to(&self, user_id: &str, moved_to_uri: &str) -> anyhow::Result<()> {
+        user::Entity::update_many()
+            .col_expr(user::Column::MovedToUri, Expr::value(moved_to_uri))
+            .filter(user::Column::Id.eq(user_id))
+            .exec(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_following(
+        &self,
+        follower_id: &str,
+        followee_id: &str,
+    ) -> anyhow::Result<Option<following::Model>> {
+        Ok(following::Entity::find()
+            .filter(following::Column::FollowerId.eq(follower_id))
+            .filter(following::Column::FolloweeId.eq(followee_id))
+            .one(&self.0)
+            .await?)
+    }
+
+    /// Makes `follower` follow `followee`, returns `false` if they already did
+    pub async fn create_following(
+        &self,
+        follower: &user::Model,
+        followee: &user::Model,
+    ) -> anyhow::Result<bool> {
+        let txn = self.0.begin().await?;
+
+        let exists = following::Entity::find()
+            .filter(following::Column::FollowerId.eq(&follower.id))
+            .filter(following::Column::FolloweeId.eq(&followee.id))
+            .one(&txn)
+            .await?
+            .is_some();
+
+        if exists {
+            return Ok(false);
+        }
+
+        let now = Utc::now();
+
+        following::ActiveModel {
+            id: ActiveValue::Set(gen_aid(now)),
+            created_at: ActiveValue::Set(now.into()),
+            followee_id: ActiveValue::Set(followee.id.clone()),
+            follower_id: ActiveValue::Set(follower.id.clone()),
+            follower_host: ActiveValue::Set(follower.host.clone()),
+            follower_inbox: ActiveValue::Set(follower.inbox.clone()),
+            follower_shared_inbox: ActiveValue::Set(follower.shared_inbox.clone()),
+            followee_host: ActiveValue::Set(followee.host.clone()),
+            followee_inbox: ActiveValue::Set(followee.inbox.clone()),
+            followee_shared_inbox: ActiveValue::Set(followee.shared_inbox.clone()),
+        }
+        .insert(&txn)
+        .await?;
+
+        add_to_user_count(&txn, &follower.id, user::Column::FollowingCount, 1).await?;
+        add_to_user_count(&txn, &followee.id, user::Column::FollowersCount, 1).await?;
+
+        txn.commit().await?;
+
+        Ok(true)
+    }
+
+    /// Returns `false` if `follower_id` did not follow `followee_id`
+    pub async fn delete_following(
+        &self,
+        follower_id: &str,
+        followee_id: &str,
+    ) -> anyhow::Result<bool> {
+        let txn = self.0.begin().await?;
+
+        let deleted = following::Entity::delete_many()
+            .filter(following::Column::FollowerId.eq(follower_id))
+            .filter(following::Column::FolloweeId.eq(followee_id))
+            .exec(&txn)
+            .await?
+            .rows_affected;
+
+        if deleted == 0 {
+            return Ok(false);
+        }
+
+        add_to_user_count(&txn, follower_id, user::Column::FollowingCount, -1).await?;
+        add_to_user_count(&txn, followee_id, user::Column::FollowersCount, -1).await?;
+
+        txn.commit().await?;
+
+        Ok(true)
+    }
+
+    pub async fn get_followers(
+        &self,
+        followee_id: &str,
+        cursor: &IdCursor,
+        limit: u64,
+    ) -> anyhow::Result<Vec<following::Model>> {
+        paginate(
+            &self.0,
+            following::Entity::find().filter(following::Column::FolloweeId.eq(followee_id)),
+            following::Column::Id,
+            cursor,
+            limit,
+        )
+        .await
+    }
+
+    pub async fn get_followees(
+        &self,
+        follower_id: &str,
+        cursor: &IdCursor,
+        limit: u64,
+    ) -> anyhow::Result<Vec<following::Model>> {
+        paginate(
+            &self.0,
+            following::Entity::find().filter(following::Column::FollowerId.eq(follower_id)),
+            following::Column::Id,
+            cursor,
+            limit,
+        )
+        .await
+    }
+
+    pub async fn get_follow_request(
+        &self,
+        follower_id: &str,
+        followee_id: &str,
+    ) -> anyhow::Result<Option<follow_request::Model>> {
+        Ok(follow_request::Entity::find()
+            .filter(follow_request::Column::FollowerId.eq(follower_id))
+            .filter(follow_request::Column::FolloweeId.eq(followee_id))
+            .one(&self.0)
+            .await?)
+    }
+
+    /// The follow request created by the remote `Follow` activity `request_id`
+    pub async fn get_follow_request_by_request_id(
+        &self,
+        request_id: &str,
+    ) -> anyhow::Result<Option<follow_request::Model>> {
+        Ok(follow_request::Entity::find()
+            .filter(follow_request::Column::RequestId.eq(request_id))
+            .one(&self.0)
+            .await?)
+    }
+
+    /// Returns `false` if there already is a request from `follower` to `followee`
+    pub async fn create_follow_request(
+        &self,
+        follower: &user::Model,
+        followee: &user::Model,
+        request_id: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        let now = Utc::now();
+
+        let request = follow_request::ActiveModel {
+            id: ActiveValue::Set(gen_aid(now)),
+            created_at: ActiveValue::Set(now.into()),
+            followee_id: ActiveValue::Set(followee.id.clone()),
+            follower_id: ActiveValue::Set(follower.id.clone()),
+            request_id: ActiveValue::Set(request_id.map(str::to_owned)),
+            follower_host: ActiveValue::Set(follower.host.clone()),
+            follower_inbox: ActiveValue::Set(follower.inbox.clone()),
+            follower_shared_inbox: ActiveValue::Set(follower.shared_inbox.clone()),
+            followee_host: ActiveValue::Set(followee.host.clone()),
+            followee_inbox: ActiveValue::Set(followee.inbox.clone()),
+            followee_shared_inbox: ActiveValue::Set(followee.shared_inbox.clone()),
+        };
+
+        let inserted = follow_request::Entity::insert(request)
+            .on_conflict(
+                OnConflict::columns([
+                    follow_request::Column::FollowerId,
+                    follow_request::Column::FolloweeId,
+                ])
+                .do_nothing()
+                .to_owned(),
+            )
+            .exec_without_returning(&self.0)
+            .await?;
+
+        Ok(inserted > 0)
+    }
+
+    /// Returns `false` if there was no request from `follower_id` to `followee_id`
+    pub async fn delete_follow_request(
+        &self,
+        follower_id: &str,
+        followee_id: &str,
+    ) -> anyhow::Result<bool> {
+        let deleted = follow_request::Entity::delete_many()
+            .filter(follow_request::Column::FollowerId.eq(follower_id))
+            .filter(follow_request::Column::FolloweeId.eq(followee_id))
+            .exec(&self.0)
+            .await?
+            .rows_affected;
+
+        Ok(deleted > 0)
+    }
+
+    pub async fn get_blocking(
+        &self,
+        blocker_id: &str,
+        blockee_id: &str,
+    ) -> anyhow::Result<Option<blocking::Model>> {
+        Ok(blocking::Entity::find()
+            .filter(blocking::Column::BlockerId.eq(blocker_id))
+            .filter(blocking::Column::BlockeeId.eq(blockee_id))
+            .one(&self.0)
+            .await?)
+    }
+
+    /// Returns `false` if `blocker_id` already blocked `blockee_id`
+    pub async fn create_blocking(
+        &self,
+        blocker_id: &str,
+        blockee_id: &str,
+    ) -> anyhow::Result<bool> {
+        let now = Utc::now();
+
+        let block = blocking::ActiveModel {
+            id: ActiveValue::Set(gen_aid(now)),
+            created_at: ActiveValue::Set(now.into()),
+            blockee_id: ActiveValue::Set(blockee_id.to_owned()),
+            blocker_id: ActiveValue::Set(blocker_id.to_owned()),
+        };
+
+        let inserted = blocking::Entity::insert(block)
+            .on_conflict(
+                OnConflict::columns([blocking::Column::BlockerId, blocking::Column::BlockeeId])
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(&self.0)
+            .await?;
+
+        Ok(inserted > 0)
+    }
+
+    /// Returns `false` if `blocker_id` did not block `blockee_id`
+    pub async fn delete_blocking(
+        &self,
+        blocker_id: &str,
+        blockee_id: &str,
+    ) -> anyhow::Result<bool> {
+        let deleted = blocking::Entity::delete_many()
+            .filter(blocking::Column::BlockerId.eq(blocker_id))
+            .filter(blocking::Column::BlockeeId.eq(blockee_id))
+            .exec(&self.0)
+            .await?
+            .rows_affected;
+
+        Ok(deleted > 0)
+    }
+
+    /// Stores a note and updates the counters of its author, reply and renote
+    pub async fn create_note(&self, note: note::Model) -> anyhow::Result<()> {
+        let txn = self.0.begin().await?;
+
+        add_to_user_count(&txn, &note.user_id, user::Column::NotesCount, 1).await?;
+
+        if let Some(ref reply_id) = note.reply_id {
+            add_to_note_count(&txn, reply_id, note::Column::RepliesCount, 1).await?;
+        }
+
+        if let Some(ref renote_id) = note.renote_id {
+            add_to_note_count(&txn, renote_id, note::Column::RenoteCount, 1).await?;
+        }
+
+        note.into_active_model().insert(&txn).await?;
+
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Replaces the content of an edited note
+    pub async fn update_note_content(
+        &self,
+        note_id: &str,
+        text: Option<String>,
+        cw: Option<String>,
+        name: Option<String>,
+    ) -> anyhow::Result<()> {
+        note::ActiveModel {
+            id: ActiveValue::Unchanged(note_id.to_owned()),
+            text: ActiveValue::Set(text),
+            cw: ActiveValue::Set(cw),
+            name: ActiveValue::Set(name),
+            ..Default::default()
+        }
+        .update(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Deletes a note and reverts the counters updated by [`CalckeyModel::create_note`]
+    pub async fn delete_note(&self, note: &note::Model) -> anyhow::Result<()> {
+        let txn = self.0.begin().await?;
+
+        let deleted = note::Entity::delete_by_id(note.id.clone())
+            .exec(&txn)
+            .await?
+            .rows_affected;
+
+        if deleted == 0 {
+            return Ok(());
+        }
+
+        add_to_user_count(&txn, &note.user_id, user::Column::NotesCount, -1).await?;
+
+        if let Some(ref reply_id) = note.reply_id {
+            add_to_note_count(&txn, reply_id, note::Column::RepliesCount, -1).await?;
+        }
+
+        if let Some(ref renote_id) = note.renote_id {
+            add_to_note_count(&txn, renote_id, note::Column::RenoteCount, -1).await?;
+        }
+
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Pure renotes of `renote_id` by `user_id`
+    pub async fn get_renotes_by_user(
+        &self,
+        user_id: &str,
+        renote_id: &str,
+    ) -> anyhow::Result<Vec<note::Model>> {
+        Ok(note::Entity::find()
+            .filter(note::Column::UserId.eq(user_id))
+            .filter(note::Column::RenoteId.eq(renote_id))
+            .filter(note::Column::Text.is_null())
+            .all(&self.0)
+            .await?)
+    }
+
+    /// Reacts to a note, returns `false` if the user already reacted to it
+    pub async fn create_note_reaction(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        reaction: &str,
+    ) -> anyhow::Result<bool> {
+        let now = Utc::now();
+        let txn = self.0.begin().await?;
+
+        let reaction_model = note_reaction::ActiveModel {
+            id: ActiveValue::Set(gen_aid(now)),
+            created_at: ActiveValue::Set(now.into()),
+            user_id: ActiveValue::Set(user_id.to_owned()),
+            note_id: ActiveValue::Set(note_id.to_owned()),
+            reaction: ActiveValue::Set(reaction.to_owned()),
+        };
+
+        let inserted = note_reaction::Entity::insert(reaction_model)
+            .on_conflict(
+                OnConflict::columns([note_reaction::Column::UserId, note_reaction::Column::NoteId])
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(&txn)
+            .await?;
+
+        if inserted == 0 {
+            return Ok(false);
+        }
+
+        add_to_note_reactions(&txn, note_id, reaction, 1).await?;
+
+        txn.commit().await?;
+
+        Ok(true)
+    }
+
+    /// Removes the reaction of a user to a note, returns `false` if there was none
+    pub async fn delete_note_reaction(&self, user_id: &str, note_id: &str) -> anyhow::Result<bool> {
+        let txn = self.0.begin().await?;
+
+        let Some(reaction) = note_reaction::Entity::find()
+            .filter(note_reaction::Column::UserId.eq(user_id))
+            .filter(note_reaction::Column::NoteId.eq(note_id))
+            .one(&txn)
+            .await?
+        else {
+            return Ok(false);
+        };
+
+        note_reaction::Entity::delete_by_id(reaction.id)
+            .exec(&txn)
+            .await?;
+
+        add_to_note_reactions(&txn, note_id, &reaction.reaction, -1).await?;
+
+        txn.commit().await?;
+
+        Ok(true)
+    }
+
+    pub async fn create_abuse_user_report(
+        &self,
+        reporter: &user::Model,
+        target: &user::Model,
+        comment: &str,
+    ) -> anyhow::Result<()> {
+        let now = Utc::now();
+
+        abuse_user_report::ActiveModel {
+            id: ActiveValue::Set(gen_aid(now)),
+            created_at: ActiveValue::Set(now.into()),
+            target_user_id: ActiveValue::Set(target.id.clone()),
+            reporter_id: ActiveValue::Set(reporter.id.clone()),
+            assignee_id: ActiveValue::Set(None),
+            resolved: ActiveValue::Set(false),
+            comment: ActiveValue::Set(comment.to_owned()),
+            target_user_host: ActiveValue::Set(target.host.clone()),
+            reporter_host: ActiveValue::Set(reporter.host.clone()),
+            forwarded: ActiveValue::Set(false),
+        }
+        .insert(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Queues the delivery of an activity of `user_id` to each of the `(inbox, host)` pairs
+    pub async fn enqueue_deliveries(
+        &self,
+        user_id: &str,
+        activity: &serde_json::Value,
+        inboxes: &[(String, String)],
+    ) -> anyhow::Result<()> {
+        if inboxes.is_empty() {
+            return Ok(());
+        }
+
+        let jobs = delivery_job::new_jobs(user_id, activity, inboxes, Utc::now());
+        let txn = self.0.begin().await?;
+
+        // Postgres takes at most 65535 parameters per statement
+        for chunk in jobs.chunks(ENQUEUE_CHUNK_SIZE) {
+            delivery_job::Entity::insert_many(chunk.to_vec())
+                .exec_without_returning(&txn)
+                .await?;
+        }
+
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Inboxes of the remote followers of a user, shared inboxes replace the inboxes of their users
+    pub async fn get_follower_inboxes(&self, followee_id: &str) -> anyhow::Result<Vec<String>> {
+        let followers = following::Entity::find()
+            .filter(following::Column::FolloweeId.eq(followee_id))
+            .filter(following::Column::FollowerHost.is_not_null())
+            .all(&self.0)
+            .await?;
+
+        let mut inboxes = followers
+            .into_iter()
+            .filter_map(|following| following.follower_shared_inbox.or(following.follower_inbox))
+            .collect::<Vec<_>>();
+
+        inboxes.sort();
+        inboxes.dedup();
+
+        Ok(inboxes)
+    }
+
+    /// Stores an activity received by an inbox until it is processed
+    pub async fn enqueue_inbox_activity(
+        &self,
+        signer: &str,
+        activity: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let now = Utc::now();
+
+        inbox_job::ActiveModel {
+            id: ActiveValue::Set(gen_aid(now)),
+            received_at: ActiveValue::Set(now.into()),
+            signer: ActiveValue::Set(signer.to_owned()),
+            activity: ActiveValue::Set(activity.clone()),
+            locked_until: ActiveValue::Set(None),
+        }
+        .insert(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Received activities waiting to be processed, including claimed ones
+    pub async fn count_inbox_activities(&self) -> anyhow::Result<u64> {
+        Ok(inbox_job::Entity::find().count(&self.0).await?)
+    }
+
+    /// Locks up to `limit` of the oldest unclaimed received activities until `locked_until`
+    pub async fn claim_inbox_activities(
+        &self,
+        limit: u64,
+        locked_until: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<inbox_job::Model>> {
+        let statement = Statement::from_sql_and_values(
+            self.0.get_database_backend(),
+            r#"UPDATE "magnetar_inbox_job" SET "locked_until" = $1
+            WHERE "id" IN (
+                SELECT "id" FROM "magnetar_inbox_job"
+                WHERE "locked_until" IS NULL OR "locked_until" < $2
+                ORDER BY "received_at", "id"
+                LIMIT $3
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING *"#,
+            [
+                locked_until.into(),
+                Utc::now().into(),
+                (limit as i64).into(),
+            ],
+        );
+
+        let mut claimed = inbox_job::Entity::find()
+            .from_raw_sql(statement)
+            .all(&self.0)
+            .await?;
+
+        // RETURNING does not keep the order of the subquery
+        claimed.sort_by(|a, b| (a.received_at, &a.id).cmp(&(b.received_at, &b.id)));
+
+        Ok(claimed)
+    }
+
+    /// Removes a received activity once it was processed
+    pub async fn complete_inbox_activity(&self, id: &str) -> anyhow::Result<()> {
+        inbox_job::Entity::delete_by_id(id.to_owned())
+            .exec(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Locks up to `limit` due deliveries until `locked_until`, skipping `busy_hosts`
+    ///
+    /// Jobs locked by other workers are skipped, so several workers can share the queue.
+    pub async fn claim_deliveries(
+        &self,
+        limit: u64,
+        busy_hosts: &[String],
+        locked_until: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<delivery_job::Model>> {
+        let statement = Statement::from_sql_and_values(
+            self.0.get_database_backend(),
+            r#"UPDATE "magnetar_delivery_job" SET "locked_until" = $1
+            WHERE "id" IN (
+                SELECT "id" FROM "magnetar_delivery_job"
+                WHERE "dead_at" IS NULL
+                    AND "next_attempt_at" <= $2
+                    AND ("locked_until" IS NULL OR "locked_until" < $2)
+                    AND NOT ("host" = ANY($3))
+                ORDER BY "next_attempt_at"
+                LIMIT $4
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING *"#,
+            [
+                locked_until.into(),
+                Utc::now().into(),
+                busy_hosts.to_vec().into(),
+                (limit as i64).into(),
+            ],
+        );
+
+        Ok(delivery_job::Entity::find()
+            .from_raw_sql(statement)
+            .all(&self.0)
+            .await?)
+    }
+
+    /// Removes a delivery that was sent successfully
+    pub async fn complete_delivery(&self, id: &str) -> anyhow::Result<()> {
+        delivery_job::Entity::delete_by_id(id.to_owned())
+            .exec(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Unlocks a claimed delivery without counting an attempt
+    pub async fn release_delivery(&self, id: &str) -> anyhow::Result<()> {
+        delivery_job::Entity::update_many()
+            .col_expr(
+                delivery_job::Column::LockedUntil,
+                Expr::value(Option::<DateTime<Utc>>::None),
+            )
+            .filter(delivery_job::Column::Id.eq(id))
+            .exec(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Records a failed attempt and schedules the next one
+    pub async fn reschedule_delivery(
+        &self,
+        id: &str,
+        next_attempt_at: DateTime<Utc>,
+        error: &str,
+    ) -> anyhow::Result<()> {
+        delivery_job::Entity::update_many()
+            .col_expr(
+                delivery_job::Column::Attempts,
+                Expr::col(delivery_job::Column::Attempts).add(1),
+            )
+            .col_expr(
+                delivery_job::Column::NextAttemptAt,
+                Expr::value(next_attempt_at),
+            )
+            .col_expr(
+                delivery_job::Column::LockedUntil,
+                Expr::value(Option::<DateTime<Utc>>::None),
+            )
+            .col_expr(delivery_job::Column::LastError, Expr::value(error))
+            .filter(delivery_job::Column::Id.eq(id))
+            .exec(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Gives up a delivery, it is kept with its last error but never attempted again
+    pub async fn dead_letter_delivery(&self, id: &str, error: &str) -> anyhow::Result<()> {
+        delivery_job::Entity::update_many()
+            .col_expr(
+                delivery_job::Column::Attempts,
+                Expr::col(delivery_job::Column::Attempts).add(1),
+            )
+            .col_expr(delivery_job::Column::DeadAt, Expr::value(Utc::now()))
+            .col_expr(
+                delivery_job::Column::LockedUntil,
+                Expr::value(Option::<DateTime<Utc>>::None),
+            )
+            .col_expr(delivery_job::Column::LastError, Expr::value(error))
+            .filter(delivery_job::Column::Id.eq(id))
+            .exec(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_instance_by_host(
+        &self,
+        host: &str,
+    ) -> anyhow::Result<Option<instance::Model>> {
+        Ok(instance::Entity::find()
+            .filter(instance::Column::Host.eq(host.to_lowercase()))
+            .one(&self.0)
+            .await?)
+    }
+
+    /// Records a successful request to the instance of `host`
+    pub async fn mark_instance_responding(&self, host: &str, status: i32) -> anyhow::Result<()> {
+        let now = Utc::now();
+
+        instance::Entity::update_many()
+            .col_expr(instance::Column::IsNotResponding, Expr::value(false))
+            .col_expr(instance::Column::LatestStatus, Expr::value(status))
+            .col_expr(instance::Column::LatestRequestSentAt, Expr::value(now))
+            .col_expr(instance::Column::LastCommunicatedAt, Expr::value(now))
+            .filter(instance::Column::Host.eq(host.to_lowercase()))
+            .exec(&self.0)
+            .await?;
+
+        Ok(())
+    }
 }
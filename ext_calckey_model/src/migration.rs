@@ -0,0 +1,126 @@
+//! Schema changes of the tables that belong to Magnetar
+//!
+//! Calckey manages its own tables, Magnetar only adds tables next to them. The migrations
+//! are plain SQL files in `migrations/` and are only applied by `magnetar migrate`.
+
+use crate::CalckeyModel;
+use sea_orm::{ConnectionTrait, Statement, TransactionTrait};
+
+pub struct Migration {
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration, in the order they are applied
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: "0001_delivery_queue",
+        sql: include_str!("../migrations/0001_delivery_queue.sql"),
+    },
+    Migration {
+        name: "0002_inbox_queue",
+        sql: include_str!("../migrations/0002_inbox_queue.sql"),
+    },
+];
+
+const CREATE_HISTORY: &str = r#"
+CREATE TABLE IF NOT EXISTS "magnetar_migration" (
+    "name" varchar NOT NULL PRIMARY KEY,
+    "applied_at" timestamp with time zone NOT NULL DEFAULT now()
+)
+"#;
+
+impl CalckeyModel {
+    async fn applied_migrations(&self) -> anyhow::Result<Vec<String>> {
+        let backend = self.0.get_database_backend();
+
+        let history_exists = self
+            .0
+            .query_one(Statement::from_string(
+                backend,
+                r#"SELECT to_regclass('magnetar_migration') IS NOT NULL AS "exists""#.to_owned(),
+            ))
+            .await?
+            .map(|row| row.try_get::<bool>("", "exists"))
+            .transpose()?
+            .unwrap_or_default();
+
+        if !history_exists {
+            return Ok(Vec::new());
+        }
+
+        self.0
+            .query_all(Statement::from_string(
+                backend,
+                r#"SELECT "name" FROM "magnetar_migration""#.to_owned(),
+            ))
+            .await?
+            .into_iter()
+            .map(|row| Ok(row.try_get::<String>("", "name")?))
+            .collect()
+    }
+
+    /// Names of the migrations not applied to the database yet
+    pub async fn pending_migrations(&self) -> anyhow::Result<Vec<&'static str>> {
+        let applied = self.applied_migrations().await?;
+
+        Ok(MIGRATIONS
+            .iter()
+            .map(|migration| migration.name)
+            .filter(|name| !applied.iter().any(|applied| applied == name))
+            .collect())
+    }
+
+    /// Applies the pending migrations, each in its own transaction, and returns their names
+    pub async fn migrate(&self) -> anyhow::Result<Vec<&'static str>> {
+        let backend = self.0.get_database_backend();
+        self.0.execute_unprepared(CREATE_HISTORY).await?;
+
+        let pending = self.pending_migrations().await?;
+
+        for migration in MIGRATIONS
+            .iter()
+            .filter(|migration| pending.contains(&migration.name))
+        {
+            let txn = self.0.begin().await?;
+
+            txn.execute_unprepared(migration.sql).await?;
+            txn.execute(Statement::from_sql_and_values(
+                backend,
+                r#"INSERT INTO "magnetar_migration" ("name") VALUES ($1)"#,
+                [migration.name.into()],
+            ))
+            .await?;
+
+            txn.commit().await?;
+        }
+
+        Ok(pending)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_support::test_database;
+    use crate::{delivery_job, inbox_job};
+    use sea_orm::EntityTrait;
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_apply_migrations_once() {
+        let ck = test_database().await.unwrap();
+
+        assert!(ck.pending_migrations().await.unwrap().is_empty());
+        assert!(ck.migrate().await.unwrap().is_empty());
+        assert!(delivery_job::Entity::find()
+            .all(&ck.0)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(inbox_job::Entity::find()
+            .all(&ck.0)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
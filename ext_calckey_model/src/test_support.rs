@@ -0,0 +1,398 @@
+//! A throwaway Calckey schema for tests that need a database
+//!
+//! Tests using it are ignored by default, run them with `cargo test -- --ignored` once
+//! `MAG_TEST_DATABASE_URL` points to a Postgres database the tests may create schemas in.
+
+use crate::{delivery_job, CalckeyModel};
+use ck::sea_orm_active_enums::{
+    AntennaSrcEnum, MetaSensitivemediadetectionEnum, MetaSensitivemediadetectionsensitivityEnum,
+    MutedNoteReasonEnum, NoteVisibilityEnum, NotificationTypeEnum, PageVisibilityEnum,
+    PollNotevisibilityEnum, RelayStatusEnum, UserProfileFfvisibilityEnum,
+};
+use ck::{
+    abuse_user_report, blocking, drive_file, emoji, follow_request, following, instance, meta,
+    note, note_reaction, user, user_keypair, user_note_pining, user_profile, user_publickey,
+};
+use log::LevelFilter;
+use rand::distributions::{Alphanumeric, DistString};
+use sea_orm::sea_query::{Alias, Index, Table, TableCreateStatement, TableRef};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ConnectOptions, ConnectionTrait, EntityTrait, IntoActiveModel,
+    QueryOrder, Schema, Statement,
+};
+
+pub const TEST_DATABASE_URL: &str = "MAG_TEST_DATABASE_URL";
+
+/// Unique indexes Calckey has that the entities do not declare, as `(table, columns)`
+const UNIQUE_INDEXES: &[(&str, &[&str])] = &[
+    ("instance", &["host"]),
+    ("user", &["usernameLower", "host"]),
+    ("user_publickey", &["keyId"]),
+    ("following", &["followerId", "followeeId"]),
+    ("follow_request", &["followerId", "followeeId"]),
+    ("blocking", &["blockerId", "blockeeId"]),
+    ("note", &["uri"]),
+    ("note_reaction", &["userId", "noteId"]),
+];
+
+/// Calckey defaults of columns the model leaves unset, as `(table, column, default)`
+const COLUMN_DEFAULTS: &[(&str, &str, &str)] = &[("user_profile", "ffVisibility", "'public'")];
+
+/// Gives the remaining `NOT NULL` columns without a default the zero value Calckey defaults them to
+const ZERO_DEFAULTS: &str = r#"
+DO $$
+DECLARE
+    col record;
+BEGIN
+    FOR col IN
+        SELECT table_name, column_name, data_type FROM information_schema.columns
+        WHERE table_schema = current_schema() AND is_nullable = 'NO' AND column_default IS NULL
+    LOOP
+        EXECUTE format('ALTER TABLE %I ALTER COLUMN %I SET DEFAULT %s', col.table_name, col.column_name,
+            CASE
+                WHEN col.data_type = 'boolean' THEN 'false'
+                WHEN col.data_type IN ('smallint', 'integer', 'bigint', 'real', 'double precision') THEN '0'
+                WHEN col.data_type IN ('character varying', 'text') THEN ''''''
+                WHEN col.data_type = 'ARRAY' THEN '''{}'''
+                WHEN col.data_type IN ('json', 'jsonb') THEN '''{}'''
+                ELSE 'NULL'
+            END);
+    END LOOP;
+END $$
+"#;
+
+fn table_name(table: &TableCreateStatement) -> Option<String> {
+    match table.get_table_name()? {
+        TableRef::Table(name) => Some(name.to_string()),
+        _ => None,
+    }
+}
+
+/// Connects to a new schema of the test database with the tables the model uses
+///
+/// Fails if no test database is configured. The schema is left behind, so the database
+/// should be a disposable one.
+pub async fn test_database() -> anyhow::Result<CalckeyModel> {
+    let url = std::env::var(TEST_DATABASE_URL)
+        .map_err(|_| anyhow::anyhow!("{TEST_DATABASE_URL} is not set"))?;
+
+    let name = format!(
+        "magnetar_test_{}",
+        Alphanumeric
+            .sample_string(&mut rand::thread_rng(), 12)
+            .to_lowercase()
+    );
+
+    let admin = sea_orm::Database::connect(url.clone()).await?;
+    admin
+        .execute_unprepared(&format!("CREATE SCHEMA \"{name}\""))
+        .await?;
+    admin.close().await?;
+
+    let opt = ConnectOptions::new(url)
+        .max_connections(8)
+        .set_schema_search_path(name)
+        .sqlx_logging(true)
+        .sqlx_logging_level(LevelFilter::Debug)
+        .to_owned();
+
+    let db = sea_orm::Database::connect(opt).await?;
+    let backend = db.get_database_backend();
+    let schema = Schema::new(backend);
+
+    for enumeration in [
+        schema.create_enum_from_active_enum::<AntennaSrcEnum>(),
+        schema.create_enum_from_active_enum::<MetaSensitivemediadetectionEnum>(),
+        schema.create_enum_from_active_enum::<MetaSensitivemediadetectionsensitivityEnum>(),
+        schema.create_enum_from_active_enum::<MutedNoteReasonEnum>(),
+        schema.create_enum_from_active_enum::<NoteVisibilityEnum>(),
+        schema.create_enum_from_active_enum::<NotificationTypeEnum>(),
+        schema.create_enum_from_active_enum::<PageVisibilityEnum>(),
+        schema.create_enum_from_active_enum::<PollNotevisibilityEnum>(),
+        schema.create_enum_from_active_enum::<RelayStatusEnum>(),
+        schema.create_enum_from_active_enum::<UserProfileFfvisibilityEnum>(),
+    ] {
+        db.execute(backend.build(&enumeration)).await?;
+    }
+
+    let tables = [
+        schema.create_table_from_entity(abuse_user_report::Entity),
+        schema.create_table_from_entity(blocking::Entity),
+        schema.create_table_from_entity(drive_file::Entity),
+        schema.create_table_from_entity(emoji::Entity),
+        schema.create_table_from_entity(follow_request::Entity),
+        schema.create_table_from_entity(following::Entity),
+        schema.create_table_from_entity(instance::Entity),
+        schema.create_table_from_entity(meta::Entity),
+        schema.create_table_from_entity(note::Entity),
+        schema.create_table_from_entity(note_reaction::Entity),
+        schema.create_table_from_entity(user::Entity),
+        schema.create_table_from_entity(user_keypair::Entity),
+        schema.create_table_from_entity(user_note_pining::Entity),
+        schema.create_table_from_entity(user_profile::Entity),
+        schema.create_table_from_entity(user_publickey::Entity),
+    ];
+
+    let names = tables.iter().filter_map(table_name).collect::<Vec<_>>();
+
+    // Users and drive files reference each other, so foreign keys are added once all tables exist
+    for created in &tables {
+        let mut without_keys = Table::create();
+        without_keys.table(created.get_table_name().unwrap().clone());
+
+        for column in created.get_columns() {
+            without_keys.col(&mut column.clone());
+        }
+
+        for index in created.get_indexes() {
+            without_keys.index(&mut index.clone());
+        }
+
+        db.execute(backend.build(&without_keys)).await?;
+    }
+
+    for created in &tables {
+        for key in created.get_foreign_key_create_stmts() {
+            let referenced = match key.get_foreign_key().get_ref_table() {
+                Some(TableRef::Table(name)) => name.to_string(),
+                _ => continue,
+            };
+
+            if names.contains(&referenced) {
+                db.execute(backend.build(key)).await?;
+            }
+        }
+    }
+
+    for (table, columns) in UNIQUE_INDEXES {
+        let mut index = Index::create();
+        index
+            .name(&format!("UQ_{table}_{}", columns.join("_")))
+            .table(Alias::new(table))
+            .unique();
+
+        for column in *columns {
+            index.col(Alias::new(column));
+        }
+
+        db.execute(backend.build(&index)).await?;
+    }
+
+    for (table, column, default) in COLUMN_DEFAULTS {
+        let statement =
+            format!(r#"ALTER TABLE "{table}" ALTER COLUMN "{column}" SET DEFAULT {default}"#);
+        db.execute(Statement::from_string(backend, statement))
+            .await?;
+    }
+
+    db.execute(Statement::from_string(backend, ZERO_DEFAULTS.to_owned()))
+        .await?;
+
+    let ck = CalckeyModel(db);
+    ck.migrate().await?;
+
+    Ok(ck)
+}
+
+/// The instance metadata Calckey creates on its first start
+pub fn meta() -> meta::Model {
+    meta::Model {
+        id: "x".to_owned(),
+        name: None,
+        description: None,
+        maintainer_name: None,
+        maintainer_email: None,
+        disable_registration: false,
+        disable_local_timeline: false,
+        disable_global_timeline: false,
+        use_star_for_reaction_fallback: false,
+        langs: Vec::new(),
+        hidden_tags: Vec::new(),
+        blocked_hosts: Vec::new(),
+        mascot_image_url: None,
+        banner_url: None,
+        error_image_url: None,
+        icon_url: None,
+        cache_remote_files: false,
+        enable_recaptcha: false,
+        recaptcha_site_key: None,
+        recaptcha_secret_key: None,
+        local_drive_capacity_mb: 1024,
+        remote_drive_capacity_mb: 32,
+        summaly_proxy: None,
+        enable_email: false,
+        email: None,
+        smtp_secure: false,
+        smtp_host: None,
+        smtp_port: None,
+        smtp_user: None,
+        smtp_pass: None,
+        enable_service_worker: false,
+        sw_public_key: None,
+        sw_private_key: None,
+        enable_twitter_integration: false,
+        twitter_consumer_key: None,
+        twitter_consumer_secret: None,
+        enable_github_integration: false,
+        github_client_id: None,
+        github_client_secret: None,
+        enable_discord_integration: false,
+        discord_client_id: None,
+        discord_client_secret: None,
+        pinned_users: Vec::new(),
+        to_s_url: None,
+        repository_url: "https://codeberg.org/calckey/calckey".to_owned(),
+        feedback_url: None,
+        use_object_storage: false,
+        object_storage_bucket: None,
+        object_storage_prefix: None,
+        object_storage_base_url: None,
+        object_storage_endpoint: None,
+        object_storage_region: None,
+        object_storage_access_key: None,
+        object_storage_secret_key: None,
+        object_storage_port: None,
+        object_storage_use_ssl: true,
+        proxy_account_id: None,
+        object_storage_use_proxy: true,
+        enable_hcaptcha: false,
+        hcaptcha_site_key: None,
+        hcaptcha_secret_key: None,
+        object_storage_set_public_read: false,
+        pinned_pages: [
+            "/featured",
+            "/channels",
+            "/explore",
+            "/pages",
+            "/about-calckey",
+        ]
+        .map(str::to_owned)
+        .to_vec(),
+        background_image_url: None,
+        logo_image_url: None,
+        pinned_clip_id: None,
+        object_storage_s3_force_path_style: true,
+        allowed_hosts: Some(Vec::new()),
+        secure_mode: Some(false),
+        private_mode: Some(false),
+        deepl_auth_key: None,
+        deepl_is_pro: false,
+        email_required_for_signup: false,
+        theme_color: None,
+        default_light_theme: None,
+        default_dark_theme: None,
+        sensitive_media_detection: MetaSensitivemediadetectionEnum::None,
+        sensitive_media_detection_sensitivity: MetaSensitivemediadetectionsensitivityEnum::Medium,
+        set_sensitive_flag_automatically: false,
+        enable_ip_logging: false,
+        enable_sensitive_media_detection_for_videos: false,
+        enable_active_email_validation: false,
+        custom_motd: Vec::new(),
+        custom_splash_icons: Vec::new(),
+        disable_recommended_timeline: false,
+        recommended_instances: Vec::new(),
+        enable_guest_timeline: false,
+        default_reaction: "⭐".to_owned(),
+    }
+}
+
+/// A local user with every column at the default of a newly registered Calckey account
+pub fn local_user(id: &str, username: &str) -> user::Model {
+    user::Model {
+        id: id.to_owned(),
+        created_at: chrono::DateTime::parse_from_rfc3339("2023-04-01T12:00:00Z").unwrap(),
+        updated_at: None,
+        last_fetched_at: None,
+        username: username.to_owned(),
+        username_lower: username.to_lowercase(),
+        name: None,
+        followers_count: 0,
+        following_count: 0,
+        notes_count: 0,
+        avatar_id: None,
+        banner_id: None,
+        tags: Vec::new(),
+        is_suspended: false,
+        is_silenced: false,
+        is_locked: false,
+        is_bot: false,
+        is_cat: false,
+        is_admin: false,
+        is_moderator: false,
+        emojis: Vec::new(),
+        host: None,
+        inbox: None,
+        shared_inbox: None,
+        featured: None,
+        uri: None,
+        token: None,
+        is_explorable: true,
+        followers_uri: None,
+        last_active_date: None,
+        hide_online_status: false,
+        is_deleted: false,
+        show_timeline_replies: false,
+        drive_capacity_override_mb: None,
+        moved_to_uri: None,
+        also_known_as: None,
+        speak_as_cat: true,
+    }
+}
+
+/// Stores the instance metadata as it is
+pub async fn insert_meta(ck: &CalckeyModel, meta: meta::Model) -> anyhow::Result<()> {
+    meta.into_active_model().insert(&ck.0).await?;
+
+    Ok(())
+}
+
+/// Stores a user as it is, along with an empty profile
+pub async fn insert_user(ck: &CalckeyModel, user: user::Model) -> anyhow::Result<()> {
+    let id = user.id.clone();
+    let host = user.host.clone();
+
+    user.into_active_model().insert(&ck.0).await?;
+
+    user_profile::ActiveModel {
+        user_id: ActiveValue::Set(id),
+        user_host: ActiveValue::Set(host),
+        ..Default::default()
+    }
+    .insert(&ck.0)
+    .await?;
+
+    Ok(())
+}
+
+/// Stores the keypair of a local user
+pub async fn insert_keypair(
+    ck: &CalckeyModel,
+    user_id: &str,
+    private_key_pem: &str,
+) -> anyhow::Result<()> {
+    user_keypair::ActiveModel {
+        user_id: ActiveValue::Set(user_id.to_owned()),
+        public_key: ActiveValue::Set(String::new()),
+        private_key: ActiveValue::Set(private_key_pem.to_owned()),
+    }
+    .insert(&ck.0)
+    .await?;
+
+    Ok(())
+}
+
+/// Every queued delivery, including the given up ones, oldest first
+pub async fn deliveries(ck: &CalckeyModel) -> anyhow::Result<Vec<delivery_job::Model>> {
+    Ok(delivery_job::Entity::find()
+        .order_by_asc(delivery_job::Column::Id)
+        .all(&ck.0)
+        .await?)
+}
+
+/// Drops a table of the test schema, making every query that uses it fail
+pub async fn drop_table(ck: &CalckeyModel, table: &str) -> anyhow::Result<()> {
+    ck.0.execute_unprepared(&format!(r#"DROP TABLE "{table}" CASCADE"#))
+        .await?;
+
+    Ok(())
+}
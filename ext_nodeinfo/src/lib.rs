@@ -1,14 +1,27 @@
-use crate::version_1_0::NodeInfo10;
+use crate::version_1_0::{NodeInfo10, NodeInfo10Services, NodeInfo10Usage};
 use crate::version_1_1::NodeInfo11;
 use crate::version_2_0::NodeInfo20;
 use crate::version_2_1::NodeInfo21;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 
 pub mod version_1_0;
 pub mod version_1_1;
 pub mod version_2_0;
 pub mod version_2_1;
 
+/// The `/.well-known/nodeinfo` discovery document, an object with a `links` array
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct NodeInfoDiscovery {
+    pub links: Vec<NodeInfoDiscoveryLink>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct NodeInfoDiscoveryLink {
+    pub rel: String,
+    pub href: String,
+}
+
 #[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
 #[serde(tag = "version")]
 pub enum NodeInfo {
@@ -22,12 +35,151 @@ pub enum NodeInfo {
     V2_1(NodeInfo21),
 }
 
+/// Version-agnostic read access
+impl NodeInfo {
+    pub fn version(&self) -> &'static str {
+        match self {
+            NodeInfo::V1_0(_) => "1.0",
+            NodeInfo::V1_1(_) => "1.1",
+            NodeInfo::V2_0(_) => "2.0",
+            NodeInfo::V2_1(_) => "2.1",
+        }
+    }
+
+    pub fn software_name(&self) -> &str {
+        match self {
+            NodeInfo::V1_0(info) => &info.software.name,
+            NodeInfo::V1_1(info) => &info.software.name,
+            NodeInfo::V2_0(info) => &info.software.name,
+            NodeInfo::V2_1(info) => &info.software.name,
+        }
+    }
+
+    pub fn software_version(&self) -> &str {
+        match self {
+            NodeInfo::V1_0(info) => &info.software.version,
+            NodeInfo::V1_1(info) => &info.software.version,
+            NodeInfo::V2_0(info) => &info.software.version,
+            NodeInfo::V2_1(info) => &info.software.version,
+        }
+    }
+
+    /// Only available since 2.1
+    pub fn software_homepage(&self) -> Option<&str> {
+        match self {
+            NodeInfo::V2_1(info) => info.software.homepage.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Only available since 2.1
+    pub fn software_repository(&self) -> Option<&str> {
+        match self {
+            NodeInfo::V2_1(info) => info.software.repository.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The supported protocols, regardless of direction for 1.x
+    pub fn protocols(&self) -> HashSet<&str> {
+        match self {
+            NodeInfo::V1_0(NodeInfo10 { protocols, .. })
+            | NodeInfo::V1_1(NodeInfo11 { protocols, .. }) => protocols
+                .inbound
+                .iter()
+                .chain(protocols.outbound.iter())
+                .map(String::as_str)
+                .collect(),
+            NodeInfo::V2_0(NodeInfo20 { protocols, .. })
+            | NodeInfo::V2_1(NodeInfo21 { protocols, .. }) => {
+                protocols.iter().map(String::as_str).collect()
+            }
+        }
+    }
+
+    pub fn services(&self) -> &NodeInfo10Services {
+        match self {
+            NodeInfo::V1_0(info) => &info.services,
+            NodeInfo::V1_1(info) => &info.services,
+            NodeInfo::V2_0(info) => &info.services,
+            NodeInfo::V2_1(info) => &info.services,
+        }
+    }
+
+    pub fn usage(&self) -> &NodeInfo10Usage {
+        match self {
+            NodeInfo::V1_0(info) => &info.usage,
+            NodeInfo::V1_1(info) => &info.usage,
+            NodeInfo::V2_0(info) => &info.usage,
+            NodeInfo::V2_1(info) => &info.usage,
+        }
+    }
+
+    pub fn open_registrations(&self) -> bool {
+        match self {
+            NodeInfo::V1_0(info) => info.open_registrations,
+            NodeInfo::V1_1(info) => info.open_registrations,
+            NodeInfo::V2_0(info) => info.open_registrations,
+            NodeInfo::V2_1(info) => info.open_registrations,
+        }
+    }
+
+    pub fn metadata(&self) -> &HashMap<String, serde_json::Value> {
+        match self {
+            NodeInfo::V1_0(info) => &info.metadata,
+            NodeInfo::V1_1(info) => &info.metadata,
+            NodeInfo::V2_0(info) => &info.metadata,
+            NodeInfo::V2_1(info) => &info.metadata,
+        }
+    }
+}
+
+/// Conversions between versions
+///
+/// Up-conversion keeps all information. The inbound and outbound protocols of 1.x are merged
+/// into a single set, differing directions are kept in the metadata so down-conversion
+/// restores them. Down-conversion drops the software homepage and repository below 2.1 and
+/// otherwise declares every protocol as both inbound and outbound below 2.0.
+impl NodeInfo {
+    pub fn into_v1_0(self) -> NodeInfo10 {
+        match self {
+            NodeInfo::V1_0(info) => info,
+            other => other.into_v1_1().into(),
+        }
+    }
+
+    pub fn into_v1_1(self) -> NodeInfo11 {
+        match self {
+            NodeInfo::V1_0(info) => info.into(),
+            NodeInfo::V1_1(info) => info,
+            other => other.into_v2_0().into(),
+        }
+    }
+
+    pub fn into_v2_0(self) -> NodeInfo20 {
+        match self {
+            NodeInfo::V2_0(info) => info,
+            NodeInfo::V2_1(info) => info.into(),
+            other => other.into_v1_1().into(),
+        }
+    }
+
+    pub fn into_v2_1(self) -> NodeInfo21 {
+        match self {
+            NodeInfo::V2_1(info) => info,
+            other => other.into_v2_0().into(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::version_1_0::{
-        NodeInfo10Services, NodeInfo10Software, NodeInfo10Usage, NodeInfo10UsageUsers,
+        NodeInfo10, NodeInfo10Protocols, NodeInfo10Services, NodeInfo10Software, NodeInfo10Usage,
+        NodeInfo10UsageUsers,
     };
-    use crate::version_2_0::NodeInfo20;
+    use crate::version_1_1::NodeInfo11;
+    use crate::version_2_0::{NodeInfo20, PROTOCOL_DIRECTIONS_KEY};
     use crate::version_2_1::{NodeInfo21, NodeInfo21Software};
     use crate::NodeInfo;
     use serde_json::json;
@@ -166,4 +318,273 @@ mod test {
             })
         );
     }
+
+    #[test]
+    fn should_parse_nodeinfo_10() {
+        let json = json!({
+            "version": "1.0",
+            "software": {
+                "name": "diaspora",
+                "version": "0.7.18.2"
+            },
+            "protocols": {
+                "inbound": ["diaspora"],
+                "outbound": ["diaspora"]
+            },
+            "services": {
+                "inbound": [],
+                "outbound": ["twitter"]
+            },
+            "openRegistrations": true,
+            "usage": {
+                "users": {
+                    "total": 347
+                },
+                "localPosts": 12204
+            },
+            "metadata": {}
+        });
+
+        let node_info: NodeInfo = serde_json::from_value(json.clone()).unwrap();
+
+        assert_eq!(
+            node_info,
+            NodeInfo::V1_0(NodeInfo10 {
+                software: NodeInfo10Software {
+                    name: "diaspora".to_owned(),
+                    version: "0.7.18.2".to_owned()
+                },
+                protocols: NodeInfo10Protocols {
+                    inbound: HashSet::from(["diaspora".to_owned()]),
+                    outbound: HashSet::from(["diaspora".to_owned()])
+                },
+                services: NodeInfo10Services {
+                    inbound: HashSet::new(),
+                    outbound: HashSet::from(["twitter".to_owned()])
+                },
+                open_registrations: true,
+                usage: NodeInfo10Usage {
+                    users: NodeInfo10UsageUsers {
+                        total: Some(347),
+                        active_halfyear: None,
+                        active_month: None
+                    },
+                    local_posts: Some(12204),
+                    local_comments: None
+                },
+                metadata: HashMap::new()
+            })
+        );
+
+        assert_eq!(serde_json::to_value(node_info).unwrap(), json);
+    }
+
+    fn fixture(json: &str) -> serde_json::Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn json_misskey() -> serde_json::Value {
+        fixture(include_str!("../fixtures/misskey_2_1.json"))
+    }
+
+    /// Sorts all arrays, as sets are serialized in an arbitrary order
+    fn normalize(value: serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Array(items) => {
+                let mut items = items.into_iter().map(normalize).collect::<Vec<_>>();
+                items.sort_by_key(|item| item.to_string());
+                serde_json::Value::Array(items)
+            }
+            serde_json::Value::Object(fields) => fields
+                .into_iter()
+                .map(|(key, value)| (key, normalize(value)))
+                .collect(),
+            other => other,
+        }
+    }
+
+    #[test]
+    fn should_round_trip_fixtures() {
+        for json in [
+            fixture(include_str!("../fixtures/mastodon_2_0.json")),
+            fixture(include_str!("../fixtures/pleroma_2_1.json")),
+            fixture(include_str!("../fixtures/akkoma_2_1.json")),
+            json_misskey(),
+        ] {
+            let node_info: NodeInfo = serde_json::from_value(json.clone()).unwrap();
+
+            assert_eq!(
+                normalize(serde_json::to_value(node_info).unwrap()),
+                normalize(json)
+            );
+        }
+    }
+
+    #[test]
+    fn should_read_fixtures_through_view() {
+        let expected = [
+            (
+                include_str!("../fixtures/mastodon_2_0.json"),
+                "2.0",
+                "mastodon",
+                "4.1.2",
+                None,
+                true,
+                Some(1172093),
+            ),
+            (
+                include_str!("../fixtures/pleroma_2_1.json"),
+                "2.1",
+                "pleroma",
+                "2.5.1",
+                Some("https://git.pleroma.social/pleroma/pleroma"),
+                false,
+                Some(48310),
+            ),
+            (
+                include_str!("../fixtures/akkoma_2_1.json"),
+                "2.1",
+                "akkoma",
+                "3.9.3-0-gd83f5f6",
+                Some("https://akkoma.dev/AkkomaGang/akkoma"),
+                false,
+                Some(20984),
+            ),
+            (
+                include_str!("../fixtures/misskey_2_1.json"),
+                "2.1",
+                "misskey",
+                "13.11.3",
+                Some("https://github.com/misskey-dev/misskey"),
+                true,
+                Some(873466),
+            ),
+        ];
+
+        for (json, version, name, software_version, repository, open, local_posts) in expected {
+            let node_info: NodeInfo = serde_json::from_str(json).unwrap();
+
+            assert_eq!(node_info.version(), version);
+            assert_eq!(node_info.software_name(), name);
+            assert_eq!(node_info.software_version(), software_version);
+            assert_eq!(node_info.software_repository(), repository);
+            assert_eq!(node_info.open_registrations(), open);
+            assert_eq!(node_info.usage().local_posts, local_posts);
+            assert_eq!(node_info.protocols(), HashSet::from(["activitypub"]));
+        }
+    }
+
+    #[test]
+    fn should_up_convert_losslessly() {
+        let mastodon = NodeInfo::V2_0(data_mastodon());
+        let upgraded = NodeInfo::V2_1(mastodon.clone().into_v2_1());
+
+        assert_eq!(upgraded.software_name(), mastodon.software_name());
+        assert_eq!(upgraded.usage(), mastodon.usage());
+        assert_eq!(upgraded.metadata(), mastodon.metadata());
+        assert_eq!(NodeInfo::V2_0(upgraded.into_v2_0()), mastodon);
+
+        let diaspora = NodeInfo::V1_0(NodeInfo10 {
+            software: NodeInfo10Software {
+                name: "diaspora".to_owned(),
+                version: "0.7.18.2".to_owned(),
+            },
+            protocols: NodeInfo10Protocols {
+                inbound: HashSet::from(["diaspora".to_owned()]),
+                outbound: HashSet::from(["diaspora".to_owned()]),
+            },
+            services: NodeInfo10Services {
+                inbound: HashSet::new(),
+                outbound: HashSet::from(["twitter".to_owned()]),
+            },
+            open_registrations: true,
+            usage: NodeInfo10Usage {
+                users: NodeInfo10UsageUsers {
+                    total: Some(347),
+                    active_halfyear: None,
+                    active_month: None,
+                },
+                local_posts: Some(12204),
+                local_comments: None,
+            },
+            metadata: HashMap::from([("nodeName".to_owned(), json!("diaspora*"))]),
+        });
+        let upgraded = NodeInfo::V2_1(diaspora.clone().into_v2_1());
+
+        assert_eq!(upgraded.protocols(), diaspora.protocols());
+        assert_eq!(upgraded.services(), diaspora.services());
+        assert_eq!(upgraded.metadata(), diaspora.metadata());
+        assert_eq!(NodeInfo::V1_0(upgraded.into_v1_0()), diaspora);
+    }
+
+    #[test]
+    fn should_down_convert_with_defined_losses() {
+        let misskey: NodeInfo = serde_json::from_value(json_misskey()).unwrap();
+
+        let node_info_20 = NodeInfo::V2_0(misskey.clone().into_v2_0());
+        assert_eq!(node_info_20.software_homepage(), None);
+        assert_eq!(node_info_20.software_repository(), None);
+        assert_eq!(node_info_20.software_version(), misskey.software_version());
+        assert_eq!(node_info_20.usage(), misskey.usage());
+        assert_eq!(node_info_20.metadata(), misskey.metadata());
+
+        let node_info_10 = misskey.into_v1_0();
+        assert_eq!(
+            node_info_10.protocols,
+            NodeInfo10Protocols {
+                inbound: HashSet::from(["activitypub".to_owned()]),
+                outbound: HashSet::from(["activitypub".to_owned()]),
+            }
+        );
+        assert_eq!(
+            node_info_10.services.outbound,
+            HashSet::from(["atom1.0".to_owned(), "rss2.0".to_owned()])
+        );
+    }
+
+    #[test]
+    fn should_keep_protocol_directions() {
+        let friendica = NodeInfo::V1_1(NodeInfo11 {
+            software: NodeInfo10Software {
+                name: "friendica".to_owned(),
+                version: "2023.05".to_owned(),
+            },
+            protocols: NodeInfo10Protocols {
+                inbound: HashSet::from(["activitypub".to_owned(), "ostatus".to_owned()]),
+                outbound: HashSet::from(["activitypub".to_owned()]),
+            },
+            services: NodeInfo10Services {
+                inbound: HashSet::new(),
+                outbound: HashSet::new(),
+            },
+            open_registrations: false,
+            usage: NodeInfo10Usage {
+                users: NodeInfo10UsageUsers::default(),
+                local_posts: None,
+                local_comments: None,
+            },
+            metadata: HashMap::from([("nodeName".to_owned(), json!("Friendica"))]),
+        });
+
+        let upgraded = NodeInfo::V2_1(friendica.clone().into_v2_1());
+        assert_eq!(
+            upgraded.protocols(),
+            HashSet::from(["activitypub", "ostatus"])
+        );
+        assert_eq!(
+            serde_json::from_value::<NodeInfo10Protocols>(
+                upgraded.metadata()[PROTOCOL_DIRECTIONS_KEY].clone()
+            )
+            .unwrap(),
+            NodeInfo10Protocols {
+                inbound: HashSet::from(["activitypub".to_owned(), "ostatus".to_owned()]),
+                outbound: HashSet::from(["activitypub".to_owned()]),
+            }
+        );
+
+        // Also when the upgraded document is passed around as JSON
+        let upgraded: NodeInfo =
+            serde_json::from_value(serde_json::to_value(upgraded).unwrap()).unwrap();
+        assert_eq!(NodeInfo::V1_1(upgraded.into_v1_1()), friendica);
+    }
 }
@@ -57,8 +57,6 @@ pub struct NodeInfo10Usage {
 
 #[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
 pub struct NodeInfo10 {
-    #[doc = "The schema version, must be 1.0."]
-    pub version: String,
     #[doc = "Free form key value pairs for software specific values. Clients should not rely on any "]
     #[doc = "specific key present."]
     pub metadata: HashMap<String, serde_json::Value>,
@@ -70,7 +68,7 @@ pub struct NodeInfo10 {
     #[doc = "The third party sites this server can connect to via their application API."]
     pub services: NodeInfo10Services,
     #[doc = "Metadata about server software in use."]
-    pub software: NodeInfo10Protocols,
+    pub software: NodeInfo10Software,
     #[doc = "Usage statistics for this server."]
     pub usage: NodeInfo10Usage,
 }
@@ -1,5 +1,5 @@
 use crate::version_1_0::{
-    NodeInfo10Protocols, NodeInfo10Services, NodeInfo10Software, NodeInfo10Usage,
+    NodeInfo10, NodeInfo10Protocols, NodeInfo10Services, NodeInfo10Software, NodeInfo10Usage,
 };
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -21,3 +21,29 @@ pub struct NodeInfo11 {
     #[doc = "Usage statistics for this server."]
     pub usage: NodeInfo10Usage,
 }
+
+impl From<NodeInfo10> for NodeInfo11 {
+    fn from(value: NodeInfo10) -> Self {
+        NodeInfo11 {
+            metadata: value.metadata,
+            open_registrations: value.open_registrations,
+            protocols: value.protocols,
+            services: value.services,
+            software: value.software,
+            usage: value.usage,
+        }
+    }
+}
+
+impl From<NodeInfo11> for NodeInfo10 {
+    fn from(value: NodeInfo11) -> Self {
+        NodeInfo10 {
+            metadata: value.metadata,
+            open_registrations: value.open_registrations,
+            protocols: value.protocols,
+            services: value.services,
+            software: value.software,
+            usage: value.usage,
+        }
+    }
+}
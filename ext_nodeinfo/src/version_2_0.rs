@@ -1,4 +1,7 @@
-use crate::version_1_0::{NodeInfo10Services, NodeInfo10Software, NodeInfo10Usage};
+use crate::version_1_0::{
+    NodeInfo10Protocols, NodeInfo10Services, NodeInfo10Software, NodeInfo10Usage,
+};
+use crate::version_1_1::NodeInfo11;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 
@@ -19,3 +22,61 @@ pub struct NodeInfo20 {
     #[doc = "Usage statistics for this server."]
     pub usage: NodeInfo10Usage,
 }
+
+/// The metadata key keeping the 1.x protocols of a 2.x document when their directions differ
+pub const PROTOCOL_DIRECTIONS_KEY: &str = "nodeInfo1Protocols";
+
+/// The inbound and outbound protocols are merged, as 2.0 no longer distinguishes them
+///
+/// When they differ, they are also kept in the metadata under [`PROTOCOL_DIRECTIONS_KEY`],
+/// so converting back restores them.
+impl From<NodeInfo11> for NodeInfo20 {
+    fn from(value: NodeInfo11) -> Self {
+        let mut metadata = value.metadata;
+
+        if value.protocols.inbound != value.protocols.outbound {
+            if let Ok(directions) = serde_json::to_value(&value.protocols) {
+                metadata.insert(PROTOCOL_DIRECTIONS_KEY.to_owned(), directions);
+            }
+        }
+
+        NodeInfo20 {
+            metadata,
+            open_registrations: value.open_registrations,
+            protocols: value
+                .protocols
+                .inbound
+                .into_iter()
+                .chain(value.protocols.outbound)
+                .collect(),
+            services: value.services,
+            software: value.software,
+            usage: value.usage,
+        }
+    }
+}
+
+/// Protocols kept under [`PROTOCOL_DIRECTIONS_KEY`] are restored, every other protocol
+/// is assumed to be supported in both directions
+impl From<NodeInfo20> for NodeInfo11 {
+    fn from(value: NodeInfo20) -> Self {
+        let mut metadata = value.metadata;
+
+        let protocols = metadata
+            .remove(PROTOCOL_DIRECTIONS_KEY)
+            .and_then(|directions| serde_json::from_value(directions).ok())
+            .unwrap_or_else(|| NodeInfo10Protocols {
+                inbound: value.protocols.clone(),
+                outbound: value.protocols,
+            });
+
+        NodeInfo11 {
+            metadata,
+            open_registrations: value.open_registrations,
+            protocols,
+            services: value.services,
+            software: value.software,
+            usage: value.usage,
+        }
+    }
+}
@@ -1,4 +1,5 @@
-use crate::version_1_0::{NodeInfo10Services, NodeInfo10Usage};
+use crate::version_1_0::{NodeInfo10Services, NodeInfo10Software, NodeInfo10Usage};
+use crate::version_2_0::NodeInfo20;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 
@@ -33,3 +34,38 @@ pub struct NodeInfo21 {
     #[doc = "Usage statistics for this server."]
     pub usage: NodeInfo10Usage,
 }
+
+impl From<NodeInfo20> for NodeInfo21 {
+    fn from(value: NodeInfo20) -> Self {
+        NodeInfo21 {
+            metadata: value.metadata,
+            open_registrations: value.open_registrations,
+            protocols: value.protocols,
+            services: value.services,
+            software: NodeInfo21Software {
+                homepage: None,
+                name: value.software.name,
+                repository: None,
+                version: value.software.version,
+            },
+            usage: value.usage,
+        }
+    }
+}
+
+/// The software homepage and repository are dropped, as 2.0 cannot represent them
+impl From<NodeInfo21> for NodeInfo20 {
+    fn from(value: NodeInfo21) -> Self {
+        NodeInfo20 {
+            metadata: value.metadata,
+            open_registrations: value.open_registrations,
+            protocols: value.protocols,
+            services: value.services,
+            software: NodeInfo10Software {
+                name: value.software.name,
+                version: value.software.version,
+            },
+            usage: value.usage,
+        }
+    }
+}
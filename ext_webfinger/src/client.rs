@@ -0,0 +1,841 @@
+use crate::host_meta::{HostMeta, Xrd};
+use crate::webfinger::{WebFinger, WebFingerRel, WebFingerSubject};
+use async_trait::async_trait;
+use magnetar_core::web_model::acct::Acct;
+use magnetar_core::web_model::content_type::{
+    ContentActivityStreams, ContentJrdJson, ContentJson, ContentXrdXml,
+};
+use magnetar_core::web_model::ContentType;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Characters to percent-encode in a query component, everything except RFC 3986 unreserved characters
+const QUERY_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// How long [`ReqwestTransport::default`] waits for a response, body included
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Responses with a larger body are refused, WebFinger and host-meta documents are tiny
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+pub type TransportError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TransportResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl TransportResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// The HTTP client used to perform WebFinger and host-meta lookups
+#[async_trait]
+pub trait HttpTransport: Send + Sync {
+    async fn get(&self, url: &str, accept: &str) -> Result<TransportResponse, TransportError>;
+}
+
+#[async_trait]
+impl<T: HttpTransport + ?Sized> HttpTransport for std::sync::Arc<T> {
+    async fn get(&self, url: &str, accept: &str) -> Result<TransportResponse, TransportError> {
+        T::get(self, url, accept).await
+    }
+}
+
+/// Performs requests with a [`reqwest::Client`], bodies over [`MAX_BODY_SIZE`] are refused
+#[derive(Clone, Debug)]
+pub struct ReqwestTransport(pub reqwest::Client);
+
+impl Default for ReqwestTransport {
+    fn default() -> Self {
+        ReqwestTransport(
+            reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("Failed to build the HTTP client"),
+        )
+    }
+}
+
+fn body_too_large() -> TransportError {
+    format!("the response body exceeds {MAX_BODY_SIZE} bytes").into()
+}
+
+#[async_trait]
+impl HttpTransport for ReqwestTransport {
+    async fn get(&self, url: &str, accept: &str) -> Result<TransportResponse, TransportError> {
+        let mut response = self
+            .0
+            .get(url)
+            .header(reqwest::header::ACCEPT, accept)
+            .send()
+            .await?;
+
+        if response
+            .content_length()
+            .is_some_and(|length| length > MAX_BODY_SIZE as u64)
+        {
+            return Err(body_too_large());
+        }
+
+        let mut body = Vec::new();
+
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > MAX_BODY_SIZE {
+                return Err(body_too_large());
+            }
+
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(TransportResponse {
+            status: response.status().as_u16(),
+            body,
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum WebFingerClientError {
+    #[error("Invalid account: {0}")]
+    InvalidAcct(String),
+    #[error("Request to {0} failed: {1}")]
+    Transport(String, TransportError),
+    #[error("Request to {0} failed with status {1}")]
+    Status(String, u16),
+    #[error("Failed to parse the response of {0}: {1}")]
+    Parse(String, serde_json::Error),
+    #[error("Failed to parse the XML response of {0}: {1}")]
+    ParseXml(String, String),
+    #[error("No LRDD template in the host-meta of {0}")]
+    NoLrddTemplate(String),
+    #[error("WebFinger subject {got:?} does not match {expected:?}")]
+    SubjectMismatch {
+        expected: Acct,
+        got: WebFingerSubject,
+    },
+    #[error("No ActivityPub self link for {0:?}")]
+    NoSelfLink(Acct),
+}
+
+/// A successfully resolved and validated account
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ResolvedAccount {
+    /// The canonical account, which may differ from the account that was looked up
+    pub acct: Acct,
+    /// The `href` of the ActivityPub `self` link
+    pub actor: String,
+    pub webfinger: WebFinger,
+}
+
+pub struct WebFingerResolver<T: HttpTransport> {
+    transport: T,
+}
+
+fn split_acct(acct: &Acct) -> Result<(&str, &str), WebFingerClientError> {
+    match acct.as_ref().rsplit_once('@') {
+        Some((name, host))
+            if !name.is_empty() && !host.is_empty() && !host.contains(['/', '?', '#', '@']) =>
+        {
+            Ok((name, host))
+        }
+        _ => Err(WebFingerClientError::InvalidAcct(acct.as_ref().to_owned())),
+    }
+}
+
+fn is_activity_streams(content_type: &str) -> bool {
+    content_type == ContentActivityStreams.mime_type()
+        || content_type.starts_with("application/ld+json")
+            && content_type.contains("https://www.w3.org/ns/activitystreams")
+}
+
+fn subject_matches(subject: &WebFingerSubject, acct: &Acct) -> bool {
+    match subject {
+        WebFingerSubject::Acct(subject) => subject.as_ref().eq_ignore_ascii_case(acct.as_ref()),
+        WebFingerSubject::Url(_) => false,
+    }
+}
+
+fn describes(webfinger: &WebFinger, acct: &Acct) -> bool {
+    subject_matches(&webfinger.subject, acct)
+        || webfinger
+            .aliases
+            .iter()
+            .flatten()
+            .any(|alias| subject_matches(alias, acct))
+}
+
+fn self_link(webfinger: &WebFinger) -> Option<&str> {
+    webfinger.links.iter().find_map(|link| match link {
+        WebFingerRel::RelSelf { href, .. } => Some(href.as_str()),
+        WebFingerRel::RelOther {
+            rel,
+            content_type: Some(content_type),
+            href: Some(href),
+            ..
+        } if rel == "self" && is_activity_streams(content_type) => Some(href.as_str()),
+        _ => None,
+    })
+}
+
+impl<T: HttpTransport> WebFingerResolver<T> {
+    pub fn new(transport: T) -> Self {
+        WebFingerResolver { transport }
+    }
+
+    async fn get_body(&self, url: &str, accept: &str) -> Result<Vec<u8>, WebFingerClientError> {
+        let response = self
+            .transport
+            .get(url, accept)
+            .await
+            .map_err(|e| WebFingerClientError::Transport(url.to_owned(), e))?;
+
+        if !response.is_success() {
+            return Err(WebFingerClientError::Status(
+                url.to_owned(),
+                response.status,
+            ));
+        }
+
+        Ok(response.body)
+    }
+
+    async fn get_json<R: DeserializeOwned>(&self, url: &str) -> Result<R, WebFingerClientError> {
+        let accept = format!("{}, {}", ContentJrdJson.as_ref(), ContentJson.as_ref());
+        let body = self.get_body(url, &accept).await?;
+
+        serde_json::from_slice(&body).map_err(|e| WebFingerClientError::Parse(url.to_owned(), e))
+    }
+
+    async fn get_xrd(&self, url: &str) -> Result<Xrd, WebFingerClientError> {
+        let body = self.get_body(url, ContentXrdXml.as_ref()).await?;
+
+        std::str::from_utf8(&body)
+            .map_err(|e| WebFingerClientError::ParseXml(url.to_owned(), e.to_string()))
+            .and_then(|xml| {
+                Xrd::from_xml(xml)
+                    .map_err(|e| WebFingerClientError::ParseXml(url.to_owned(), e.to_string()))
+            })
+    }
+
+    /// Fetches the host-meta document, preferring the JRD over the XRD representation
+    async fn host_meta(&self, host: &str) -> Result<HostMeta, WebFingerClientError> {
+        match self
+            .get_json(&format!("https://{host}/.well-known/host-meta.json"))
+            .await
+        {
+            Ok(host_meta) => Ok(host_meta),
+            Err(_) => self
+                .get_xrd(&format!("https://{host}/.well-known/host-meta"))
+                .await
+                .map(HostMeta::from),
+        }
+    }
+
+    async fn lrdd_url(&self, host: &str, resource: &str) -> Result<String, WebFingerClientError> {
+        let host_meta = self.host_meta(host).await?;
+
+        let template = host_meta
+            .lrdd_template()
+            .ok_or_else(|| WebFingerClientError::NoLrddTemplate(host.to_owned()))?;
+
+        Ok(template.replace("{uri}", resource))
+    }
+
+    /// Fetches the WebFinger document of an account without any validation
+    ///
+    /// Falls back to the LRDD template of the host-meta document if the well-known
+    /// WebFinger endpoint is not found or cannot be reached. Invalid documents are errors,
+    /// the host does serve WebFinger.
+    pub async fn fetch(&self, acct: &Acct) -> Result<WebFinger, WebFingerClientError> {
+        let (name, host) = split_acct(acct)?;
+        let resource =
+            utf8_percent_encode(&format!("acct:{name}@{host}"), QUERY_COMPONENT).to_string();
+
+        let url = format!("https://{host}/.well-known/webfinger?resource={resource}");
+
+        match self.get_json(&url).await {
+            Ok(webfinger) => Ok(webfinger),
+            Err(
+                e @ WebFingerClientError::Status(_, 404) | e @ WebFingerClientError::Transport(..),
+            ) => match self.lrdd_url(host, &resource).await {
+                Ok(lrdd_url) if lrdd_url != url => self.get_json(&lrdd_url).await,
+                _ => Err(e),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Resolves an account to its ActivityPub actor
+    ///
+    /// If the returned subject is a different account, e.g. when WebFinger is served
+    /// on a different domain than the instance itself, that account is looked up as well
+    /// and must describe itself.
+    pub async fn resolve(&self, acct: &Acct) -> Result<ResolvedAccount, WebFingerClientError> {
+        let webfinger = self.fetch(acct).await?;
+
+        let (acct, webfinger) = if describes(&webfinger, acct) {
+            (acct.clone(), webfinger)
+        } else {
+            let canonical = match webfinger.subject {
+                WebFingerSubject::Acct(ref canonical) => canonical.clone(),
+                got => {
+                    return Err(WebFingerClientError::SubjectMismatch {
+                        expected: acct.clone(),
+                        got,
+                    })
+                }
+            };
+
+            let canonical_webfinger = self.fetch(&canonical).await?;
+
+            if !subject_matches(&canonical_webfinger.subject, &canonical) {
+                return Err(WebFingerClientError::SubjectMismatch {
+                    expected: canonical,
+                    got: canonical_webfinger.subject,
+                });
+            }
+
+            (canonical, canonical_webfinger)
+        };
+
+        let actor = self_link(&webfinger)
+            .ok_or_else(|| WebFingerClientError::NoSelfLink(acct.clone()))?
+            .to_owned();
+
+        Ok(ResolvedAccount {
+            acct,
+            actor,
+            webfinger,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::client::{
+        HttpTransport, ReqwestTransport, TransportResponse, WebFingerClientError,
+        WebFingerResolver, MAX_BODY_SIZE,
+    };
+    use crate::test_support::StubTransport;
+    use magnetar_core::web_model::acct::Acct;
+    use serde_json::{json, Value};
+
+    fn webfinger_natty(subject: &str, host: &str) -> Value {
+        json!({
+            "subject": subject,
+            "aliases": [
+                format!("https://{host}/@natty")
+            ],
+            "links": [
+                {
+                    "rel": "self",
+                    "type": "application/activity+json",
+                    "href": format!("https://{host}/users/natty")
+                }
+            ]
+        })
+    }
+
+    #[tokio::test]
+    async fn should_resolve_account() {
+        let resolver = WebFingerResolver::new(StubTransport::default().with(
+            "https://example.com/.well-known/webfinger?resource=acct%3Anatty%40example.com",
+            webfinger_natty("acct:natty@example.com", "example.com"),
+        ));
+
+        let resolved = resolver
+            .resolve(&Acct::from("natty@example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(resolved.acct, Acct::from("natty@example.com"));
+        assert_eq!(resolved.actor, "https://example.com/users/natty");
+    }
+
+    #[tokio::test]
+    async fn should_resolve_account_through_host_meta() {
+        let resolver = WebFingerResolver::new(
+            StubTransport::default()
+                .with(
+                    "https://example.com/.well-known/host-meta.json",
+                    json!({
+                        "links": [
+                            {
+                                "rel": "lrdd",
+                                "type": "application/jrd+json",
+                                "template": "https://example.com/webfinger?q={uri}"
+                            }
+                        ]
+                    }),
+                )
+                .with(
+                    "https://example.com/webfinger?q=acct%3Anatty%40example.com",
+                    webfinger_natty("acct:natty@example.com", "example.com"),
+                ),
+        );
+
+        let resolved = resolver
+            .resolve(&Acct::from("natty@example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(resolved.actor, "https://example.com/users/natty");
+    }
+
+    #[tokio::test]
+    async fn should_resolve_account_through_host_meta_xrd() {
+        let resolver = WebFingerResolver::new(
+            StubTransport::default()
+                .with_raw(
+                    "https://example.com/.well-known/host-meta",
+                    r#"<?xml version="1.0" encoding="UTF-8"?>
+                    <XRD xmlns="http://docs.oasis-open.org/ns/xri/xrd-1.0">
+                        <Link rel="lrdd" template="https://example.com/webfinger?q={uri}"/>
+                    </XRD>"#,
+                )
+                .with(
+                    "https://example.com/webfinger?q=acct%3Anatty%40example.com",
+                    webfinger_natty("acct:natty@example.com", "example.com"),
+                ),
+        );
+
+        let resolved = resolver
+            .resolve(&Acct::from("natty@example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(resolved.actor, "https://example.com/users/natty");
+    }
+
+    #[tokio::test]
+    async fn should_resolve_canonical_account() {
+        let resolver = WebFingerResolver::new(
+            StubTransport::default()
+                .with(
+                    "https://example.com/.well-known/webfinger?resource=acct%3Anatty%40example.com",
+                    webfinger_natty("acct:natty@social.example.com", "social.example.com"),
+                )
+                .with(
+                    "https://social.example.com/.well-known/webfinger?resource=acct%3Anatty%40social.example.com",
+                    webfinger_natty("acct:natty@social.example.com", "social.example.com"),
+                ),
+        );
+
+        let resolved = resolver
+            .resolve(&Acct::from("natty@example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(resolved.acct, Acct::from("natty@social.example.com"));
+        assert_eq!(resolved.actor, "https://social.example.com/users/natty");
+    }
+
+    #[tokio::test]
+    async fn should_reject_mismatched_subject() {
+        let resolver = WebFingerResolver::new(
+            StubTransport::default()
+                .with(
+                    "https://example.com/.well-known/webfinger?resource=acct%3Anatty%40example.com",
+                    webfinger_natty("acct:natty@evil.example.com", "evil.example.com"),
+                )
+                .with(
+                    "https://evil.example.com/.well-known/webfinger?resource=acct%3Anatty%40evil.example.com",
+                    webfinger_natty("acct:someone@evil.example.com", "evil.example.com"),
+                ),
+        );
+
+        let resolved = resolver.resolve(&Acct::from("natty@example.com")).await;
+
+        assert!(matches!(
+            resolved,
+            Err(WebFingerClientError::SubjectMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_require_self_link() {
+        let resolver = WebFingerResolver::new(StubTransport::default().with(
+            "https://example.com/.well-known/webfinger?resource=acct%3Anatty%40example.com",
+            json!({
+                "subject": "acct:natty@example.com",
+                "links": []
+            }),
+        ));
+
+        let resolved = resolver.resolve(&Acct::from("natty@example.com")).await;
+
+        assert!(matches!(resolved, Err(WebFingerClientError::NoSelfLink(_))));
+    }
+
+    /// A minimal HTTP server on a local port, to exercise [`ReqwestTransport`] over real sockets
+    mod local {
+        use crate::client::{HttpTransport, ReqwestTransport, TransportError, TransportResponse};
+        use async_trait::async_trait;
+        use std::collections::HashMap;
+        use std::net::SocketAddr;
+        use std::sync::{Arc, Mutex};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        pub struct Response {
+            pub status: u16,
+            pub headers: Vec<(&'static str, String)>,
+            pub body: String,
+        }
+
+        impl Response {
+            pub fn ok(content_type: &str, body: impl ToString) -> Self {
+                Response {
+                    status: 200,
+                    headers: vec![("Content-Type", content_type.to_owned())],
+                    body: body.to_string(),
+                }
+            }
+
+            pub fn redirect(location: &str) -> Self {
+                Response {
+                    status: 301,
+                    headers: vec![("Location", location.to_owned())],
+                    body: String::new(),
+                }
+            }
+        }
+
+        /// The `(path, accept)` of every request the server received
+        pub type Requests = Arc<Mutex<Vec<(String, String)>>>;
+
+        /// Serves the responses by path and query, anything else is not found
+        pub async fn serve(routes: Vec<(&str, Response)>) -> (SocketAddr, Requests) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let routes = Arc::new(
+                routes
+                    .into_iter()
+                    .map(|(path, response)| (path.to_owned(), response))
+                    .collect::<HashMap<_, _>>(),
+            );
+            let requests = Requests::default();
+
+            let received = requests.clone();
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let routes = routes.clone();
+                    let received = received.clone();
+
+                    tokio::spawn(async move {
+                        let mut request = Vec::new();
+                        let mut buf = [0; 1024];
+
+                        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                            match stream.read(&mut buf).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => request.extend_from_slice(&buf[..n]),
+                            }
+                        }
+
+                        let request = String::from_utf8_lossy(&request);
+                        let mut lines = request.lines();
+                        let path = lines
+                            .next()
+                            .and_then(|line| line.split(' ').nth(1))
+                            .unwrap_or_default()
+                            .to_owned();
+                        let accept = lines
+                            .filter_map(|line| line.split_once(':'))
+                            .find(|(name, _)| name.eq_ignore_ascii_case("accept"))
+                            .map(|(_, value)| value.trim().to_owned())
+                            .unwrap_or_default();
+
+                        received.lock().unwrap().push((path.clone(), accept));
+
+                        let not_found = Response {
+                            status: 404,
+                            headers: Vec::new(),
+                            body: String::new(),
+                        };
+                        let response = routes.get(&path).unwrap_or(&not_found);
+
+                        let mut head = format!(
+                            "HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n",
+                            response.status,
+                            response.body.len()
+                        );
+
+                        for (name, value) in &response.headers {
+                            head.push_str(&format!("{name}: {value}\r\n"));
+                        }
+
+                        head.push_str("\r\n");
+
+                        let _ = stream.write_all(head.as_bytes()).await;
+                        let _ = stream.write_all(response.body.as_bytes()).await;
+                    });
+                }
+            });
+
+            (addr, requests)
+        }
+
+        /// Sends the requests for `https://example.com` to the local server instead
+        pub struct LocalTransport {
+            pub addr: SocketAddr,
+            pub inner: ReqwestTransport,
+        }
+
+        #[async_trait]
+        impl HttpTransport for LocalTransport {
+            async fn get(
+                &self,
+                url: &str,
+                accept: &str,
+            ) -> Result<TransportResponse, TransportError> {
+                let url = url.replacen("https://example.com", &format!("http://{}", self.addr), 1);
+                self.inner.get(&url, accept).await
+            }
+        }
+    }
+
+    const WEBFINGER_NATTY: &str = "/.well-known/webfinger?resource=acct%3Anatty%40example.com";
+
+    fn local_resolver(addr: std::net::SocketAddr) -> WebFingerResolver<local::LocalTransport> {
+        WebFingerResolver::new(local::LocalTransport {
+            addr,
+            inner: ReqwestTransport::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn should_fetch_with_reqwest() {
+        let (addr, requests) = local::serve(vec![(
+            "/document",
+            local::Response::ok("application/json", "{}"),
+        )])
+        .await;
+
+        let transport = ReqwestTransport::default();
+        let found = transport
+            .get(&format!("http://{addr}/document"), "application/json")
+            .await
+            .unwrap();
+        let missing = transport
+            .get(&format!("http://{addr}/missing"), "application/json")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            found,
+            TransportResponse {
+                status: 200,
+                body: b"{}".to_vec(),
+            }
+        );
+        assert_eq!(missing.status, 404);
+        assert!(!missing.is_success());
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                ("/document".to_owned(), "application/json".to_owned()),
+                ("/missing".to_owned(), "application/json".to_owned()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_resolve_account_with_reqwest() {
+        let (addr, requests) = local::serve(vec![(
+            WEBFINGER_NATTY,
+            local::Response::ok(
+                "application/jrd+json",
+                webfinger_natty("acct:natty@example.com", "example.com"),
+            ),
+        )])
+        .await;
+
+        let resolved = local_resolver(addr)
+            .resolve(&Acct::from("natty@example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(resolved.actor, "https://example.com/users/natty");
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![(
+                WEBFINGER_NATTY.to_owned(),
+                "application/jrd+json, application/json".to_owned()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_follow_redirects() {
+        let (addr, _) = local::serve(vec![
+            (
+                WEBFINGER_NATTY,
+                local::Response::redirect("/webfinger/natty"),
+            ),
+            (
+                "/webfinger/natty",
+                local::Response::ok(
+                    "application/jrd+json",
+                    webfinger_natty("acct:natty@example.com", "example.com"),
+                ),
+            ),
+        ])
+        .await;
+
+        let resolved = local_resolver(addr)
+            .resolve(&Acct::from("natty@example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(resolved.actor, "https://example.com/users/natty");
+    }
+
+    #[tokio::test]
+    async fn should_report_redirects_the_client_does_not_follow() {
+        let (addr, _) = local::serve(vec![(
+            WEBFINGER_NATTY,
+            local::Response::redirect("/webfinger/natty"),
+        )])
+        .await;
+
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let resolver = WebFingerResolver::new(local::LocalTransport {
+            addr,
+            inner: ReqwestTransport(client),
+        });
+
+        let resolved = resolver.resolve(&Acct::from("natty@example.com")).await;
+
+        assert!(matches!(
+            resolved,
+            Err(WebFingerClientError::Status(_, 301))
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_accept_plain_json() {
+        let (addr, _) = local::serve(vec![(
+            WEBFINGER_NATTY,
+            local::Response::ok(
+                "application/json; charset=utf-8",
+                webfinger_natty("acct:natty@example.com", "example.com"),
+            ),
+        )])
+        .await;
+
+        let resolved = local_resolver(addr)
+            .resolve(&Acct::from("natty@example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(resolved.actor, "https://example.com/users/natty");
+    }
+
+    #[tokio::test]
+    async fn should_fall_back_to_host_meta_xrd() {
+        let (addr, requests) = local::serve(vec![
+            (
+                "/.well-known/host-meta",
+                local::Response::ok(
+                    "application/xrd+xml",
+                    r#"<?xml version="1.0" encoding="UTF-8"?>
+                    <XRD xmlns="http://docs.oasis-open.org/ns/xri/xrd-1.0">
+                        <Link rel="lrdd" template="https://example.com/webfinger?q={uri}"/>
+                    </XRD>"#,
+                ),
+            ),
+            (
+                "/webfinger?q=acct%3Anatty%40example.com",
+                local::Response::ok(
+                    "application/jrd+json",
+                    webfinger_natty("acct:natty@example.com", "example.com"),
+                ),
+            ),
+        ])
+        .await;
+
+        let resolved = local_resolver(addr)
+            .resolve(&Acct::from("natty@example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(resolved.actor, "https://example.com/users/natty");
+        assert!(requests.lock().unwrap().contains(&(
+            "/.well-known/host-meta".to_owned(),
+            "application/xrd+xml".to_owned()
+        )));
+    }
+
+    #[tokio::test]
+    async fn should_reject_html_pages() {
+        let (addr, requests) = local::serve(vec![
+            (
+                WEBFINGER_NATTY,
+                local::Response::ok("text/html", "<!DOCTYPE html><html></html>"),
+            ),
+            (
+                "/.well-known/host-meta.json",
+                local::Response::ok(
+                    "application/json",
+                    json!({
+                        "links": [{ "rel": "lrdd", "template": "https://example.com/webfinger?q={uri}" }]
+                    }),
+                ),
+            ),
+        ])
+        .await;
+
+        let resolved = local_resolver(addr)
+            .resolve(&Acct::from("natty@example.com"))
+            .await;
+
+        // The host serves WebFinger, so its host-meta is not consulted
+        assert!(matches!(resolved, Err(WebFingerClientError::Parse(..))));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_refuse_large_bodies() {
+        let (addr, _) = local::serve(vec![
+            (
+                "/large",
+                local::Response::ok("application/json", "x".repeat(MAX_BODY_SIZE + 1)),
+            ),
+            (
+                "/limit",
+                local::Response::ok("application/json", "x".repeat(MAX_BODY_SIZE)),
+            ),
+        ])
+        .await;
+
+        let transport = ReqwestTransport::default();
+
+        assert!(transport
+            .get(&format!("http://{addr}/large"), "application/json")
+            .await
+            .is_err());
+        assert_eq!(
+            transport
+                .get(&format!("http://{addr}/limit"), "application/json")
+                .await
+                .unwrap()
+                .body
+                .len(),
+            MAX_BODY_SIZE
+        );
+    }
+}
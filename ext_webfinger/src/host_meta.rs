@@ -0,0 +1,181 @@
+use crate::webfinger::WebFingerRel;
+use magnetar_core::web_model::rel::RelLrdd;
+use magnetar_core::web_model::Rel;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const XRD_NAMESPACE: &str = "http://docs.oasis-open.org/ns/xri/xrd-1.0";
+
+/// The JRD representation of a host-meta document, as described in RFC 6415
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HostMeta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub properties: Option<HashMap<String, Option<String>>>,
+    pub links: Vec<WebFingerRel>,
+}
+
+impl HostMeta {
+    /// The first LRDD template of the document, if any
+    pub fn lrdd_template(&self) -> Option<&str> {
+        self.links.iter().find_map(|link| match link {
+            WebFingerRel::RelOther {
+                rel,
+                template: Some(template),
+                ..
+            } if rel == RelLrdd.rel() => Some(template.as_str()),
+            _ => None,
+        })
+    }
+}
+
+/// An XRD 1.0 document, the XML representation of host-meta
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename = "XRD")]
+pub struct Xrd {
+    #[serde(rename = "@xmlns")]
+    pub xmlns: String,
+    #[serde(rename = "Subject")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(rename = "Link")]
+    #[serde(default)]
+    pub links: Vec<XrdLink>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct XrdLink {
+    #[serde(rename = "@rel")]
+    pub rel: String,
+    #[serde(rename = "@type")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(rename = "@href")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub href: Option<String>,
+    #[serde(rename = "@template")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+}
+
+impl Xrd {
+    pub fn new(links: Vec<XrdLink>) -> Self {
+        Xrd {
+            xmlns: XRD_NAMESPACE.to_owned(),
+            subject: None,
+            links,
+        }
+    }
+
+    /// The first LRDD template of the document, if any
+    pub fn lrdd_template(&self) -> Option<&str> {
+        self.links
+            .iter()
+            .filter(|link| link.rel == RelLrdd.rel())
+            .find_map(|link| link.template.as_deref())
+    }
+
+    pub fn from_xml(xml: &str) -> Result<Self, quick_xml::DeError> {
+        quick_xml::de::from_str(xml)
+    }
+
+    /// Serializes the document including the XML declaration
+    pub fn to_xml(&self) -> Result<String, quick_xml::DeError> {
+        Ok(format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>{}"#,
+            quick_xml::se::to_string(self)?
+        ))
+    }
+}
+
+impl From<Xrd> for HostMeta {
+    fn from(value: Xrd) -> Self {
+        HostMeta {
+            properties: None,
+            links: value
+                .links
+                .into_iter()
+                .map(|link| WebFingerRel::RelOther {
+                    rel: link.rel,
+                    content_type: link.content_type,
+                    href: link.href,
+                    template: link.template,
+                    titles: None,
+                    properties: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::host_meta::{HostMeta, Xrd, XrdLink};
+    use serde_json::json;
+
+    #[test]
+    fn should_find_lrdd_template() {
+        let json = json!({
+            "links": [
+                {
+                    "rel": "lrdd",
+                    "type": "application/jrd+json",
+                    "template": "https://example.com/.well-known/webfinger?resource={uri}"
+                }
+            ]
+        });
+
+        let host_meta: HostMeta = serde_json::from_value(json).unwrap();
+
+        assert_eq!(
+            host_meta.lrdd_template(),
+            Some("https://example.com/.well-known/webfinger?resource={uri}")
+        );
+    }
+
+    #[test]
+    fn should_parse_xrd() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <XRD xmlns="http://docs.oasis-open.org/ns/xri/xrd-1.0">
+                <Link rel="lrdd" template="https://example.com/.well-known/webfinger?resource={uri}"/>
+            </XRD>"#;
+
+        let xrd = Xrd::from_xml(xml).unwrap();
+
+        assert_eq!(
+            xrd,
+            Xrd::new(vec![XrdLink {
+                rel: "lrdd".to_owned(),
+                content_type: None,
+                href: None,
+                template: Some(
+                    "https://example.com/.well-known/webfinger?resource={uri}".to_owned()
+                ),
+            }])
+        );
+        assert_eq!(
+            xrd.lrdd_template(),
+            Some("https://example.com/.well-known/webfinger?resource={uri}")
+        );
+    }
+
+    #[test]
+    fn should_serialize_xrd() {
+        let xrd = Xrd::new(vec![XrdLink {
+            rel: "lrdd".to_owned(),
+            content_type: Some("application/jrd+json".to_owned()),
+            href: None,
+            template: Some("https://example.com/.well-known/webfinger?resource={uri}".to_owned()),
+        }]);
+
+        assert_eq!(
+            xrd.to_xml().unwrap(),
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                r#"<XRD xmlns="http://docs.oasis-open.org/ns/xri/xrd-1.0">"#,
+                r#"<Link rel="lrdd" type="application/jrd+json" "#,
+                r#"template="https://example.com/.well-known/webfinger?resource={uri}"/>"#,
+                r#"</XRD>"#
+            )
+        );
+    }
+}
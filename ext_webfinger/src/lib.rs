@@ -1 +1,5 @@
+pub mod client;
+pub mod host_meta;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 pub mod webfinger;
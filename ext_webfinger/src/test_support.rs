@@ -0,0 +1,37 @@
+//! Helpers for testing code that performs HTTP requests through an [`HttpTransport`]
+
+use crate::client::{HttpTransport, TransportError, TransportResponse};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Serves fixed bodies by URL, any other URL is not found
+#[derive(Clone, Debug, Default)]
+pub struct StubTransport(HashMap<String, Vec<u8>>);
+
+impl StubTransport {
+    pub fn with(self, url: &str, body: Value) -> Self {
+        self.with_raw(url, &body.to_string())
+    }
+
+    pub fn with_raw(mut self, url: &str, body: &str) -> Self {
+        self.0.insert(url.to_owned(), body.as_bytes().to_vec());
+        self
+    }
+}
+
+#[async_trait]
+impl HttpTransport for StubTransport {
+    async fn get(&self, url: &str, _accept: &str) -> Result<TransportResponse, TransportError> {
+        Ok(match self.0.get(url) {
+            Some(body) => TransportResponse {
+                status: 200,
+                body: body.clone(),
+            },
+            None => TransportResponse {
+                status: 404,
+                body: Vec::new(),
+            },
+        })
+    }
+}
@@ -1,13 +1,17 @@
 use magnetar_core::web_model::acct::Acct;
 use magnetar_core::web_model::content_type::{ContentActivityStreams, ContentHtml};
 use magnetar_core::web_model::rel::{RelOStatusSubscribe, RelSelf, RelWebFingerProfilePage};
+use magnetar_core::web_model::Rel;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct WebFinger {
     pub subject: WebFingerSubject,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub aliases: Option<Vec<WebFingerSubject>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub properties: Option<HashMap<String, Option<String>>>,
     pub links: Vec<WebFingerRel>,
 }
 
@@ -19,7 +23,7 @@ pub enum WebFingerSubject {
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
-#[serde(untagged)]
+#[serde(untagged, deny_unknown_fields)]
 #[allow(clippy::enum_variant_names)]
 pub enum WebFingerRel {
     RelWebFingerProfilePage {
@@ -38,6 +42,32 @@ pub enum WebFingerRel {
         rel: RelOStatusSubscribe,
         template: String,
     },
+    /// Any other link, including known relations with additional members
+    RelOther {
+        rel: String,
+        #[serde(rename = "type")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content_type: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        href: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        template: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        titles: Option<HashMap<String, String>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        properties: Option<HashMap<String, Option<String>>>,
+    },
+}
+
+impl WebFingerRel {
+    pub fn rel(&self) -> &str {
+        match self {
+            WebFingerRel::RelWebFingerProfilePage { rel, .. } => rel.rel(),
+            WebFingerRel::RelSelf { rel, .. } => rel.rel(),
+            WebFingerRel::RelOStatusSubscribe { rel, .. } => rel.rel(),
+            WebFingerRel::RelOther { rel, .. } => rel,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -48,6 +78,7 @@ mod test {
     use magnetar_core::web_model::content_type::{ContentActivityStreams, ContentHtml};
     use magnetar_core::web_model::rel::{RelOStatusSubscribe, RelSelf, RelWebFingerProfilePage};
     use serde_json::json;
+    use std::collections::HashMap;
 
     #[test]
     fn should_parse_webfinger() {
@@ -83,6 +114,7 @@ mod test {
                 Url("https://tech.lgbt/@natty".to_owned()),
                 Url("https://tech.lgbt/users/natty".to_owned()),
             ]),
+            properties: None,
             links: vec![
                 WebFingerRel::RelWebFingerProfilePage {
                     rel: RelWebFingerProfilePage,
@@ -103,4 +135,101 @@ mod test {
 
         assert_eq!(webfinger, real)
     }
+
+    #[test]
+    fn should_parse_webfinger_other_links() {
+        let json = json!({
+            "subject": "acct:natty@example.com",
+            "properties": {
+                "http://schema.org/name": "Natty",
+                "http://example.com/ns/empty": null
+            },
+            "links": [
+                {
+                    "rel": "http://webfinger.net/rel/avatar",
+                    "type": "image/png",
+                    "href": "https://example.com/files/avatar.png"
+                },
+                {
+                    "rel": "describedby",
+                    "type": "application/rdf+xml",
+                    "href": "https://example.com/natty.rdf",
+                    "titles": {
+                        "en-us": "Natty's profile",
+                        "und": "Natty"
+                    }
+                },
+                {
+                    "rel": "http://microformats.org/profile/hcard",
+                    "type": "text/html",
+                    "href": "https://example.com/hcard/users/natty"
+                },
+                {
+                    "rel": "self",
+                    "type": "application/activity+json",
+                    "href": "https://example.com/users/natty",
+                    "properties": {
+                        "http://example.com/ns/role": "actor"
+                    }
+                }
+            ]
+        });
+
+        let webfinger: WebFinger = serde_json::from_value(json.clone()).unwrap();
+
+        let real = WebFinger {
+            subject: WebFingerSubject::Acct(Acct::new("natty@example.com".into())),
+            aliases: None,
+            properties: Some(HashMap::from([
+                (
+                    "http://schema.org/name".to_owned(),
+                    Some("Natty".to_owned()),
+                ),
+                ("http://example.com/ns/empty".to_owned(), None),
+            ])),
+            links: vec![
+                WebFingerRel::RelOther {
+                    rel: "http://webfinger.net/rel/avatar".to_owned(),
+                    content_type: Some("image/png".to_owned()),
+                    href: Some("https://example.com/files/avatar.png".to_owned()),
+                    template: None,
+                    titles: None,
+                    properties: None,
+                },
+                WebFingerRel::RelOther {
+                    rel: "describedby".to_owned(),
+                    content_type: Some("application/rdf+xml".to_owned()),
+                    href: Some("https://example.com/natty.rdf".to_owned()),
+                    template: None,
+                    titles: Some(HashMap::from([
+                        ("en-us".to_owned(), "Natty's profile".to_owned()),
+                        ("und".to_owned(), "Natty".to_owned()),
+                    ])),
+                    properties: None,
+                },
+                WebFingerRel::RelOther {
+                    rel: "http://microformats.org/profile/hcard".to_owned(),
+                    content_type: Some("text/html".to_owned()),
+                    href: Some("https://example.com/hcard/users/natty".to_owned()),
+                    template: None,
+                    titles: None,
+                    properties: None,
+                },
+                WebFingerRel::RelOther {
+                    rel: "self".to_owned(),
+                    content_type: Some("application/activity+json".to_owned()),
+                    href: Some("https://example.com/users/natty".to_owned()),
+                    template: None,
+                    titles: None,
+                    properties: Some(HashMap::from([(
+                        "http://example.com/ns/role".to_owned(),
+                        Some("actor".to_owned()),
+                    )])),
+                },
+            ],
+        };
+
+        assert_eq!(webfinger, real);
+        assert_eq!(serde_json::to_value(webfinger).unwrap(), json);
+    }
 }
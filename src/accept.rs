@@ -0,0 +1,300 @@
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode};
+use magnetar_core::web_model::content_type::{
+    ContentActivityStreams, ContentHtml, ContentLdJsonActivityStreams,
+};
+use magnetar_core::web_model::ContentType;
+use std::convert::Infallible;
+
+/// A media type or range, split into its lowercase essence and parameters
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MediaType {
+    kind: String,
+    subtype: String,
+    params: Vec<(String, String)>,
+}
+
+impl MediaType {
+    fn parse(value: &str) -> Option<(Self, u16)> {
+        let mut parts = value.split(';');
+        let (kind, subtype) = parts.next()?.trim().split_once('/')?;
+
+        if kind.is_empty() || subtype.is_empty() {
+            return None;
+        }
+
+        let mut quality = 1000;
+        let mut params = Vec::new();
+
+        for param in parts {
+            let Some((name, value)) = param.split_once('=') else {
+                continue;
+            };
+
+            let name = name.trim().to_ascii_lowercase();
+            let value = value.trim().trim_matches('"');
+
+            if name == "q" {
+                quality = parse_quality(value)?;
+            } else {
+                params.push((name, value.to_owned()));
+            }
+        }
+
+        Some((
+            MediaType {
+                kind: kind.to_ascii_lowercase(),
+                subtype: subtype.to_ascii_lowercase(),
+                params,
+            },
+            quality,
+        ))
+    }
+
+    /// How specific a range matching `media_type` is, `None` if it does not match
+    fn specificity(&self, media_type: &MediaType) -> Option<usize> {
+        if self.kind == "*" {
+            return (self.subtype == "*").then_some(0);
+        }
+
+        if self.kind != media_type.kind {
+            return None;
+        }
+
+        if self.subtype == "*" {
+            return Some(1);
+        }
+
+        if self.subtype != media_type.subtype {
+            return None;
+        }
+
+        let params_match = self
+            .params
+            .iter()
+            .all(|param| media_type.params.contains(param));
+
+        params_match.then_some(2 + self.params.len())
+    }
+}
+
+/// Parses a qvalue as thousandths, as described in RFC 9110 section 12.4.2
+fn parse_quality(value: &str) -> Option<u16> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+
+    if fraction.len() > 3 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let fraction = format!("{fraction:0<3}").parse::<u16>().ok()?;
+
+    match whole {
+        "0" => Some(fraction),
+        "1" if fraction == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// The media ranges of an `Accept` header, a missing header accepts anything
+#[derive(Debug, Clone)]
+pub struct Accept(Vec<(MediaType, u16)>);
+
+impl Accept {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let ranges = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(MediaType::parse)
+            .collect::<Vec<_>>();
+
+        if ranges.is_empty() {
+            return Accept(vec![(MediaType::parse("*/*").unwrap().0, 1000)]);
+        }
+
+        Accept(ranges)
+    }
+
+    /// The quality of a content type in thousandths, taken from the most specific matching range
+    ///
+    /// Content types that are not acceptable have a quality of zero.
+    pub fn quality(&self, content_type: &impl ContentType) -> u16 {
+        let Some((media_type, _)) = MediaType::parse(content_type.mime_type()) else {
+            return 0;
+        };
+
+        self.0
+            .iter()
+            .filter_map(|(range, quality)| Some((range.specificity(&media_type)?, *quality)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0, |(_, quality)| quality)
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Accept {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Accept::from_headers(&parts.headers))
+    }
+}
+
+/// The representations of URLs that are shared by the web frontend and ActivityPub
+///
+/// Browsers asking for anything get HTML, ActivityPub is only served when preferred.
+/// Requests accepting neither are rejected with 406 Not Acceptable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Representation {
+    ActivityPub,
+    Html,
+}
+
+impl Representation {
+    pub fn negotiate(accept: &Accept) -> Option<Self> {
+        let activity_pub = accept
+            .quality(&ContentActivityStreams)
+            .max(accept.quality(&ContentLdJsonActivityStreams));
+        let html = accept.quality(&ContentHtml);
+
+        match (activity_pub, html) {
+            (0, 0) => None,
+            (activity_pub, html) if activity_pub > html => Some(Representation::ActivityPub),
+            _ => Some(Representation::Html),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Representation {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Representation::negotiate(&Accept::from_headers(&parts.headers))
+            .ok_or(StatusCode::NOT_ACCEPTABLE)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::accept::{parse_quality, Accept, Representation};
+    use axum::body::Body;
+    use axum::extract::Path;
+    use axum::http::{header, HeaderMap, Request, StatusCode};
+    use axum::routing::get;
+    use axum::Router;
+    use magnetar_core::web_model::content_type::{
+        ContentActivityStreams, ContentHtml, ContentJson, ContentLdJsonActivityStreams,
+    };
+    use tower::ServiceExt;
+
+    fn accept(value: &'static str) -> Accept {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, value.parse().unwrap());
+        Accept::from_headers(&headers)
+    }
+
+    #[test]
+    fn should_parse_quality() {
+        assert_eq!(parse_quality("1"), Some(1000));
+        assert_eq!(parse_quality("1.000"), Some(1000));
+        assert_eq!(parse_quality("0.5"), Some(500));
+        assert_eq!(parse_quality("0.125"), Some(125));
+        assert_eq!(parse_quality("0"), Some(0));
+        assert_eq!(parse_quality("1.5"), None);
+        assert_eq!(parse_quality("0.1234"), None);
+        assert_eq!(parse_quality("abc"), None);
+    }
+
+    #[test]
+    fn should_prefer_most_specific_range() {
+        let accept = accept("text/*;q=0.3, text/html;q=0.7, */*;q=0.1");
+
+        assert_eq!(accept.quality(&ContentHtml), 700);
+        assert_eq!(accept.quality(&ContentActivityStreams), 100);
+    }
+
+    #[test]
+    fn should_match_profile_parameter() {
+        let profile =
+            accept(r#"application/ld+json; profile="https://www.w3.org/ns/activitystreams""#);
+        assert_eq!(profile.quality(&ContentLdJsonActivityStreams), 1000);
+
+        let other = accept(r#"application/ld+json; profile="https://example.com/profile""#);
+        assert_eq!(other.quality(&ContentLdJsonActivityStreams), 0);
+
+        let any = accept("application/ld+json");
+        assert_eq!(any.quality(&ContentLdJsonActivityStreams), 1000);
+    }
+
+    #[test]
+    fn should_negotiate_representation() {
+        let negotiate = |value| Representation::negotiate(&accept(value));
+
+        assert_eq!(
+            negotiate("application/activity+json"),
+            Some(Representation::ActivityPub)
+        );
+        assert_eq!(
+            negotiate(r#"application/ld+json; profile="https://www.w3.org/ns/activitystreams""#),
+            Some(Representation::ActivityPub)
+        );
+        assert_eq!(
+            negotiate("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+            Some(Representation::Html)
+        );
+        assert_eq!(
+            negotiate("application/activity+json;q=0.5, text/html"),
+            Some(Representation::Html)
+        );
+        assert_eq!(negotiate("*/*"), Some(Representation::Html));
+        assert_eq!(negotiate("image/png"), None);
+        assert_eq!(
+            negotiate("text/html;q=0, application/activity+json;q=0"),
+            None
+        );
+        assert_eq!(
+            Representation::negotiate(&Accept::from_headers(&HeaderMap::new())),
+            Some(Representation::Html)
+        );
+    }
+
+    #[tokio::test]
+    async fn should_respond_not_acceptable() {
+        let app = Router::new().route(
+            "/@:tag",
+            get(
+                |Path(tag): Path<String>, representation: Representation| async move {
+                    format!("{tag} {representation:?}")
+                },
+            ),
+        );
+
+        let request = |accept: &'static str| {
+            Request::builder()
+                .uri("/@alice")
+                .header(header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("application/activity+json"))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"alice ActivityPub");
+
+        let response = app.oneshot(request("image/png")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[test]
+    fn should_reject_unlisted_types() {
+        assert_eq!(accept("text/html").quality(&ContentJson), 0);
+    }
+}
@@ -0,0 +1,79 @@
+use crate::config::MagnetarConfig;
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use magnetar_calckey_model::ck::emoji;
+use magnetar_core::web_model::activity_streams::object::ObjectOrLink;
+use magnetar_core::web_model::activity_streams::property::Ref;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde_json::{json, Map, Value};
+
+/// The special collection addressing everyone
+pub const PUBLIC_COLLECTION: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+/// The ID of a local object from its IRI, like `abc` from `https://example.com/users/abc`
+pub fn local_id<'a>(config: &MagnetarConfig, iri: &'a str, collection: &str) -> Option<&'a str> {
+    let base = format!(
+        "{}://{}/{collection}/",
+        config.networking.protocol, config.networking.host
+    );
+
+    iri.strip_prefix(&base)
+        .filter(|id| !id.is_empty() && !id.contains(['/', '#', '?']))
+}
+
+pub fn iri(iri: impl Into<String>) -> Ref<ObjectOrLink> {
+    Ref::Iri(iri.into())
+}
+
+/// An embedded object of a type without a dedicated model, like `Hashtag` or `Emoji`
+pub fn untyped(kind: &str, value: Value) -> Ref<ObjectOrLink> {
+    let mut map = match value {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    };
+
+    map.insert("type".to_owned(), Value::String(kind.to_owned()));
+
+    Ref::from(ObjectOrLink::Other(map))
+}
+
+/// Timestamps are rendered like JavaScript's `toISOString`, as Misskey does
+pub fn format_date<Tz: TimeZone>(date: &DateTime<Tz>) -> String {
+    date.with_timezone(&Utc)
+        .to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+pub fn render_hashtag(base: &str, tag: &str) -> Ref<ObjectOrLink> {
+    untyped(
+        "Hashtag",
+        json!({
+            "href": format!("{base}/tags/{}", utf8_percent_encode(tag, NON_ALPHANUMERIC)),
+            "name": format!("#{tag}"),
+        }),
+    )
+}
+
+pub fn render_emoji(base: &str, emoji: emoji::Model) -> Ref<ObjectOrLink> {
+    let updated = match emoji.updated_at {
+        Some(updated) => format_date(&updated),
+        None => format_date(&Utc::now()),
+    };
+    let url = if emoji.public_url.is_empty() {
+        emoji.original_url
+    } else {
+        emoji.public_url
+    };
+
+    untyped(
+        "Emoji",
+        json!({
+            "id": emoji.uri.unwrap_or_else(|| format!("{base}/emojis/{}", emoji.name)),
+            "name": format!(":{}:", emoji.name),
+            "updated": updated,
+            "icon": {
+                "type": "Image",
+                "mediaType": emoji.r#type.unwrap_or_else(|| "image/png".to_owned()),
+                "url": url,
+            },
+        }),
+    )
+}
@@ -0,0 +1,47 @@
+use crate::config::ConfigOptions;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path of the configuration file, takes priority over MAG_CONFIG_PATH
+    #[arg(long, short = 'c', global = true)]
+    pub config: Option<PathBuf>,
+    /// Overrides a configuration option, e.g. `--set networking.port=4939`
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_override, global = true)]
+    pub overrides: Vec<(String, String)>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Configuration utilities
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Creates or updates the tables Magnetar keeps in the Calckey database
+    Migrate,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Validates the configuration without starting the server
+    Check,
+}
+
+fn parse_override(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .filter(|(key, _)| !key.is_empty())
+        .map(|(key, value)| (key.trim().to_owned(), value.to_owned()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got \"{arg}\""))
+}
+
+impl Cli {
+    pub fn config_options(&self) -> ConfigOptions {
+        ConfigOptions {
+            path: self.config.clone(),
+            overrides: self.overrides.clone(),
+        }
+    }
+}
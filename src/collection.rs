@@ -0,0 +1,509 @@
+use crate::activity_pub::{format_date, iri, PUBLIC_COLLECTION};
+use crate::actor::is_served;
+use crate::config::{ConfigHandle, MagnetarConfig};
+use crate::note::{is_pure_renote, load_local_note, note_iri, render_note};
+use crate::util::data_error;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum_extra::extract::Query;
+use magnetar_calckey_model::ck::sea_orm_active_enums::{
+    NoteVisibilityEnum, UserProfileFfvisibilityEnum,
+};
+use magnetar_calckey_model::ck::{following, note, user};
+use magnetar_calckey_model::{CalckeyModel, IdCursor};
+use magnetar_core::web_model::activity_streams::activity::Activity;
+use magnetar_core::web_model::activity_streams::collection::{
+    OrderedCollection, OrderedCollectionPage,
+};
+use magnetar_core::web_model::activity_streams::object::{Object, ObjectOrLink};
+use magnetar_core::web_model::activity_streams::property::{OneOrMany, Ref};
+use magnetar_core::web_model::activity_streams::ActivityStreamsDocument;
+use serde::Deserialize;
+use tracing::error;
+
+const PAGE_SIZE: usize = 20;
+
+/// Paging of a collection, using the parameters of Misskey
+///
+/// Pages are requested with `page=true`, older and newer pages with `until_id` and `since_id`.
+#[derive(Debug, Default, Deserialize)]
+pub struct PageQuery {
+    #[serde(default)]
+    page: bool,
+    until_id: Option<String>,
+    since_id: Option<String>,
+}
+
+/// Calckey's IDs are alphanumeric, anything else cannot be a cursor
+fn is_cursor_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 32 && id.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+impl PageQuery {
+    /// The requested page, `None` when the collection itself is requested
+    fn cursor(&self) -> Result<Option<IdCursor>, StatusCode> {
+        let cursor = match (&self.until_id, &self.since_id) {
+            (None, None) => IdCursor::Newest,
+            (Some(until), None) if is_cursor_id(until) => IdCursor::Before(until.clone()),
+            (None, Some(since)) if is_cursor_id(since) => IdCursor::After(since.clone()),
+            _ => return Err(StatusCode::BAD_REQUEST),
+        };
+
+        Ok((self.page || cursor != IdCursor::Newest).then_some(cursor))
+    }
+}
+
+fn page_iri(collection: &str, cursor: &IdCursor) -> String {
+    match cursor {
+        IdCursor::Newest => format!("{collection}?page=true"),
+        IdCursor::Before(id) => format!("{collection}?page=true&until_id={id}"),
+        IdCursor::After(id) => format!("{collection}?page=true&since_id={id}"),
+    }
+}
+
+/// Drops the row fetched beyond a page, telling whether there was one
+///
+/// Rows are fetched newest first, so the row furthest from the cursor is the
+/// oldest one, or the newest one when paging towards newer rows.
+fn take_page<T>(cursor: &IdCursor, mut rows: Vec<T>) -> (Vec<T>, bool) {
+    let has_more = rows.len() > PAGE_SIZE;
+
+    if has_more {
+        match cursor {
+            IdCursor::After(_) => {
+                rows.drain(..rows.len() - PAGE_SIZE);
+            }
+            _ => rows.truncate(PAGE_SIZE),
+        }
+    }
+
+    (rows, has_more)
+}
+
+/// Renders a collection, with a link to its first page unless its items are hidden
+fn render_collection(collection: &str, total_items: u64, paged: bool) -> ObjectOrLink {
+    ObjectOrLink::OrderedCollection(OrderedCollection {
+        total_items: Some(total_items),
+        first: paged.then(|| iri(page_iri(collection, &IdCursor::Newest))),
+        base: Object {
+            id: Some(collection.to_owned()),
+            ..Object::default()
+        },
+        ..OrderedCollection::default()
+    })
+}
+
+/// Renders the page of a collection at `cursor`
+///
+/// `ids` are the IDs of the rows of the page, newest first, linked from `next` and `prev`.
+fn render_page(
+    collection: &str,
+    cursor: &IdCursor,
+    ids: &[String],
+    has_more: bool,
+    items: Vec<Ref<ObjectOrLink>>,
+) -> ObjectOrLink {
+    let (has_older, has_newer) = match cursor {
+        IdCursor::Newest => (has_more, false),
+        IdCursor::Before(_) => (has_more, true),
+        IdCursor::After(_) => (true, has_more),
+    };
+
+    let next = ids
+        .last()
+        .filter(|_| has_older)
+        .map(|id| iri(page_iri(collection, &IdCursor::Before(id.clone()))));
+    let prev = ids
+        .first()
+        .filter(|_| has_newer)
+        .map(|id| iri(page_iri(collection, &IdCursor::After(id.clone()))));
+
+    ObjectOrLink::OrderedCollectionPage(OrderedCollectionPage {
+        part_of: Some(iri(collection)),
+        next,
+        prev,
+        base: OrderedCollection {
+            ordered_items: Some(OneOrMany::Many(items)),
+            base: Object {
+                id: Some(page_iri(collection, cursor)),
+                ..Object::default()
+            },
+            ..OrderedCollection::default()
+        },
+        ..OrderedCollectionPage::default()
+    })
+}
+
+async fn load_served_user(ck: &CalckeyModel, id: &str) -> Result<user::Model, StatusCode> {
+    ck.get_user_by_id(id)
+        .await
+        .map_err(data_error)?
+        .filter(is_served)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+fn actor_iri(config: &MagnetarConfig, user: &user::Model) -> Option<String> {
+    match user.host {
+        Some(_) => user.uri.clone(),
+        None => Some(format!(
+            "{}://{}/users/{}",
+            config.networking.protocol, config.networking.host, user.id
+        )),
+    }
+}
+
+/// Renders a note of the outbox as the activity that published it
+///
+/// Pure renotes are `Announce` activities, they are skipped when the renoted note is gone.
+async fn render_outbox_activity(
+    config: &MagnetarConfig,
+    ck: &CalckeyModel,
+    note: note::Model,
+) -> Result<Option<ObjectOrLink>, StatusCode> {
+    let base = format!(
+        "{}://{}",
+        config.networking.protocol, config.networking.host
+    );
+    let actor_id = format!("{base}/users/{}", note.user_id);
+    let activity_id = format!("{base}/notes/{}/activity", note.id);
+
+    if !is_pure_renote(&note) {
+        let rendered = render_note(config, load_local_note(ck, note).await?);
+        let object = rendered.as_object().cloned().unwrap_or_default();
+
+        return Ok(Some(ObjectOrLink::Create(Activity {
+            actor: Some(iri(actor_id).into()),
+            object: Some(Ref::from(rendered).into()),
+            base: Object {
+                id: Some(activity_id),
+                published: object.published,
+                to: object.to,
+                cc: object.cc,
+                ..Object::default()
+            },
+            ..Activity::default()
+        })));
+    }
+
+    let renote = match note.renote_id {
+        Some(ref renote_id) => ck.get_note_by_id(renote_id).await.map_err(data_error)?,
+        None => None,
+    };
+
+    let Some(renote) = renote else {
+        return Ok(None);
+    };
+
+    let public = vec![iri(PUBLIC_COLLECTION)];
+    let followers = vec![iri(format!("{actor_id}/followers"))];
+    let (to, cc) = match note.visibility {
+        NoteVisibilityEnum::Home => (followers, public),
+        _ => (public, followers),
+    };
+
+    Ok(Some(ObjectOrLink::Announce(Activity {
+        actor: Some(iri(actor_id).into()),
+        object: Some(iri(note_iri(config, &renote)).into()),
+        base: Object {
+            id: Some(activity_id),
+            published: Some(format_date(&note.created_at)),
+            to: Some(OneOrMany::Many(to)),
+            cc: Some(OneOrMany::Many(cc)),
+            ..Object::default()
+        },
+        ..Activity::default()
+    })))
+}
+
+pub async fn handle_outbox(
+    Path(id): Path<String>,
+    Query(query): Query<PageQuery>,
+    State((config, ck)): State<(ConfigHandle, CalckeyModel)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let config = &*config.load();
+
+    let user = load_served_user(&ck, &id).await?;
+    let collection = format!(
+        "{}://{}/users/{}/outbox",
+        config.networking.protocol, config.networking.host, user.id
+    );
+
+    let Some(cursor) = query.cursor()? else {
+        let total_items = ck
+            .get_outbox_note_count(&user.id)
+            .await
+            .map_err(data_error)?;
+
+        return Ok(ActivityStreamsDocument::new(render_collection(
+            &collection,
+            total_items,
+            true,
+        )));
+    };
+
+    let notes = ck
+        .get_outbox_notes(&user.id, &cursor, PAGE_SIZE as u64 + 1)
+        .await
+        .map_err(data_error)?;
+    let (notes, has_more) = take_page(&cursor, notes);
+    let ids = notes.iter().map(|note| note.id.clone()).collect::<Vec<_>>();
+
+    let mut items = Vec::with_capacity(notes.len());
+
+    for note in notes {
+        if let Some(activity) = render_outbox_activity(config, &ck, note).await? {
+            items.push(Ref::from(activity));
+        }
+    }
+
+    Ok(ActivityStreamsDocument::new(render_page(
+        &collection,
+        &cursor,
+        &ids,
+        has_more,
+        items,
+    )))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Follows {
+    Followers,
+    Following,
+}
+
+/// The followers or followees of a local user, as allowed by their `ff_visibility`
+///
+/// Collections hidden from everyone are forbidden, collections visible to followers
+/// only show their size since requests are not made on behalf of users.
+async fn follow_collection(
+    config: &MagnetarConfig,
+    ck: &CalckeyModel,
+    id: &str,
+    query: &PageQuery,
+    follows: Follows,
+) -> Result<ObjectOrLink, StatusCode> {
+    let user = load_served_user(ck, id).await?;
+
+    let profile = ck
+        .get_user_profile_by_id(&user.id)
+        .await
+        .map_err(data_error)?
+        .ok_or_else(|| {
+            error!("Local user {} has no profile", user.id);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let (path, total_items) = match follows {
+        Follows::Followers => ("followers", user.followers_count),
+        Follows::Following => ("following", user.following_count),
+    };
+    let collection = format!(
+        "{}://{}/users/{}/{path}",
+        config.networking.protocol, config.networking.host, user.id
+    );
+    let total_items = total_items.max(0) as u64;
+    let cursor = query.cursor()?;
+
+    let cursor = match (profile.ff_visibility, cursor) {
+        (UserProfileFfvisibilityEnum::Private, _) => return Err(StatusCode::FORBIDDEN),
+        (UserProfileFfvisibilityEnum::Followers, Some(_)) => return Err(StatusCode::FORBIDDEN),
+        (UserProfileFfvisibilityEnum::Followers, None) => {
+            return Ok(render_collection(&collection, total_items, false))
+        }
+        (UserProfileFfvisibilityEnum::Public, None) => {
+            return Ok(render_collection(&collection, total_items, true))
+        }
+        (UserProfileFfvisibilityEnum::Public, Some(cursor)) => cursor,
+    };
+
+    let limit = PAGE_SIZE as u64 + 1;
+    let rows = match follows {
+        Follows::Followers => ck.get_followers(&user.id, &cursor, limit).await,
+        Follows::Following => ck.get_followees(&user.id, &cursor, limit).await,
+    }
+    .map_err(data_error)?;
+    let (rows, has_more) = take_page(&cursor, rows);
+
+    let other_id = |row: &following::Model| match follows {
+        Follows::Followers => row.follower_id.clone(),
+        Follows::Following => row.followee_id.clone(),
+    };
+
+    let users = ck
+        .get_users_by_ids(&rows.iter().map(other_id).collect::<Vec<_>>())
+        .await
+        .map_err(data_error)?;
+
+    let items = rows
+        .iter()
+        .filter_map(|row| users.iter().find(|user| user.id == other_id(row)))
+        .filter_map(|user| actor_iri(config, user))
+        .map(iri)
+        .collect();
+    let ids = rows.into_iter().map(|row| row.id).collect::<Vec<_>>();
+
+    Ok(render_page(&collection, &cursor, &ids, has_more, items))
+}
+
+pub async fn handle_followers(
+    Path(id): Path<String>,
+    Query(query): Query<PageQuery>,
+    State((config, ck)): State<(ConfigHandle, CalckeyModel)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let config = &*config.load();
+    let followers = follow_collection(config, &ck, &id, &query, Follows::Followers).await?;
+
+    Ok(ActivityStreamsDocument::new(followers))
+}
+
+pub async fn handle_following(
+    Path(id): Path<String>,
+    Query(query): Query<PageQuery>,
+    State((config, ck)): State<(ConfigHandle, CalckeyModel)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let config = &*config.load();
+    let following = follow_collection(config, &ck, &id, &query, Follows::Following).await?;
+
+    Ok(ActivityStreamsDocument::new(following))
+}
+
+/// The pinned notes of a local user, which are few enough not to be paged
+pub async fn handle_featured(
+    Path(id): Path<String>,
+    State((config, ck)): State<(ConfigHandle, CalckeyModel)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let config = &*config.load();
+
+    let user = load_served_user(&ck, &id).await?;
+    let notes = ck.get_pinned_notes(&user.id).await.map_err(data_error)?;
+
+    let mut items = Vec::with_capacity(notes.len());
+
+    for note in notes {
+        if !crate::note::is_served(&note) || is_pure_renote(&note) {
+            continue;
+        }
+
+        let note = load_local_note(&ck, note).await?;
+        items.push(Ref::from(render_note(config, note)));
+    }
+
+    Ok(ActivityStreamsDocument::new(
+        ObjectOrLink::OrderedCollection(OrderedCollection {
+            total_items: Some(items.len() as u64),
+            ordered_items: Some(OneOrMany::Many(items)),
+            base: Object {
+                id: Some(format!(
+                    "{}://{}/users/{}/collections/featured",
+                    config.networking.protocol, config.networking.host, user.id
+                )),
+                ..Object::default()
+            },
+            ..OrderedCollection::default()
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::activity_pub::iri;
+    use crate::collection::{render_page, take_page, PageQuery, PAGE_SIZE};
+    use axum::http::StatusCode;
+    use magnetar_calckey_model::IdCursor;
+    use serde_json::json;
+
+    const OUTBOX: &str = "https://example.com/users/9fxdl2pkn3/outbox";
+
+    fn query(page: bool, until_id: Option<&str>, since_id: Option<&str>) -> PageQuery {
+        PageQuery {
+            page,
+            until_id: until_id.map(str::to_owned),
+            since_id: since_id.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn should_parse_cursors() {
+        assert_eq!(query(false, None, None).cursor(), Ok(None));
+        assert_eq!(query(true, None, None).cursor(), Ok(Some(IdCursor::Newest)));
+        assert_eq!(
+            query(true, Some("9fxdl3aaaa"), None).cursor(),
+            Ok(Some(IdCursor::Before("9fxdl3aaaa".to_owned())))
+        );
+        assert_eq!(
+            query(false, None, Some("9fxdl3aaaa")).cursor(),
+            Ok(Some(IdCursor::After("9fxdl3aaaa".to_owned())))
+        );
+        assert_eq!(
+            query(true, Some("9fxdl3aaaa"), Some("9fxdl3bbbb")).cursor(),
+            Err(StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(
+            query(true, Some("' OR 1=1"), None).cursor(),
+            Err(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[test]
+    fn should_drop_the_row_beyond_the_page() {
+        let rows = (0..=PAGE_SIZE).rev().collect::<Vec<_>>();
+
+        let (older, has_more) = take_page(&IdCursor::Newest, rows.clone());
+        assert!(has_more);
+        assert_eq!(older.first(), Some(&PAGE_SIZE));
+        assert_eq!(older.last(), Some(&1));
+
+        let (newer, has_more) = take_page(&IdCursor::After("0".to_owned()), rows);
+        assert!(has_more);
+        assert_eq!(newer.first(), Some(&(PAGE_SIZE - 1)));
+        assert_eq!(newer.last(), Some(&0));
+
+        let (rows, has_more) = take_page(&IdCursor::Newest, vec![1, 0]);
+        assert!(!has_more);
+        assert_eq!(rows, vec![1, 0]);
+    }
+
+    #[test]
+    fn should_link_pages() {
+        let ids = vec!["9fxdl3cccc".to_owned(), "9fxdl3aaaa".to_owned()];
+        let items = vec![iri("https://example.com/notes/9fxdl3cccc")];
+
+        let first = serde_json::to_value(render_page(
+            OUTBOX,
+            &IdCursor::Newest,
+            &ids,
+            true,
+            items.clone(),
+        ))
+        .unwrap();
+
+        assert_eq!(first["type"], "OrderedCollectionPage");
+        assert_eq!(first["id"], format!("{OUTBOX}?page=true"));
+        assert_eq!(first["partOf"], OUTBOX);
+        assert_eq!(
+            first["next"],
+            format!("{OUTBOX}?page=true&until_id=9fxdl3aaaa")
+        );
+        assert!(first.get("prev").is_none());
+        assert_eq!(
+            first["orderedItems"],
+            json!(["https://example.com/notes/9fxdl3cccc"])
+        );
+
+        let last = serde_json::to_value(render_page(
+            OUTBOX,
+            &IdCursor::Before("9fxdl3dddd".to_owned()),
+            &ids,
+            false,
+            items,
+        ))
+        .unwrap();
+
+        assert!(last.get("next").is_none());
+        assert_eq!(
+            last["prev"],
+            format!("{OUTBOX}?page=true&since_id=9fxdl3cccc")
+        );
+    }
+}
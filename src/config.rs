@@ -1,8 +1,43 @@
-use anyhow::anyhow;
+use arc_swap::ArcSwap;
+use ipnet::IpNet;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::{Display, Formatter};
-use std::net::IpAddr;
-use tracing::info;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::{error, info};
+
+pub const DEFAULT_CONFIG_PATH: &str = "config/default.toml";
+
+/// Environment variables and the configuration keys they override
+///
+/// Later entries take priority over earlier ones mapping to the same key.
+const ENV_VARS: &[(&str, &str)] = &[
+    ("MAG_C_HOST", "networking.host"),
+    ("MAG_C_PORT", "networking.port"),
+    ("MAG_C_BIND_ADDR", "networking.bind_addr"),
+    ("MAG_C_PROTOCOL", "networking.protocol"),
+    ("MAG_C_LISTENERS", "networking.listeners"),
+    ("MAG_C_UNIX_SOCKET_MODE", "networking.unix_socket_mode"),
+    ("MAG_C_TRUSTED_PROXIES", "networking.trusted_proxies"),
+    ("DATABASE_URL", "data.database_url"),
+    ("MAG_C_DATABASE_URL", "data.database_url"),
+    (
+        "MAG_C_NODEINFO_STATS_TTL_SECS",
+        "caching.nodeinfo_stats_ttl_secs",
+    ),
+    (
+        "MAG_C_CK_MAX_NOTE_TEXT_LENGTH",
+        "calckey_frontend.max_note_text_length",
+    ),
+    ("MAG_C_BR_NAME", "branding.name"),
+    ("MAG_C_BR_VERSION", "branding.version"),
+    ("MAG_C_BR_HOMEPAGE", "branding.homepage"),
+    ("MAG_C_BR_REPOSITORY", "branding.repository"),
+];
 
 #[derive(Deserialize, Debug)]
 #[non_exhaustive]
@@ -11,9 +46,62 @@ pub struct MagnetarNetworking {
     pub port: u16,
     pub bind_addr: IpAddr,
     pub protocol: MagnetarNetworkingProtocol,
+    /// Where to accept connections, `bind_addr` and `port` are used when empty
+    pub listeners: Vec<MagnetarListener>,
+    pub unix_socket_mode: UnixSocketMode,
+    /// Peers allowed to set the client address and scheme through forwarding headers
+    pub trusted_proxies: Vec<IpNet>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum MagnetarListener {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl TryFrom<String> for MagnetarListener {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.strip_prefix("unix:") {
+            Some("") => Err("expected a socket path after \"unix:\"".to_owned()),
+            Some(path) => Ok(MagnetarListener::Unix(PathBuf::from(path))),
+            None => value
+                .parse()
+                .map(MagnetarListener::Tcp)
+                .map_err(|e| format!("\"{value}\" is not a socket address: {e}")),
+        }
+    }
+}
+
+impl Display for MagnetarListener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MagnetarListener::Tcp(addr) => write!(f, "{addr}"),
+            MagnetarListener::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// File permissions of Unix sockets, written in octal like `chmod`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct UnixSocketMode(pub u32);
+
+impl TryFrom<String> for UnixSocketMode {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        u32::from_str_radix(&value, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o777)
+            .map(UnixSocketMode)
+            .ok_or_else(|| format!("\"{value}\" is not an octal file mode"))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum MagnetarNetworkingProtocol {
     Http,
@@ -35,46 +123,54 @@ impl Display for MagnetarNetworkingProtocol {
     }
 }
 
-fn env_host() -> String {
-    std::env::var("MAG_C_HOST")
-        .expect("MAG_C_HOST or \"networking.host\" in the default configuration must be set")
-}
+fn validate_host(host: &str) -> Result<(), String> {
+    if host.is_empty() {
+        return Err("the host must not be empty".to_owned());
+    }
 
-fn env_bind_addr() -> IpAddr {
-    std::env::var("MAG_C_BIND_ADDR")
-        .unwrap_or_else(|_| "::".to_owned())
-        .parse()
-        .map_err(|e| format!("Failed to parse \"MAG_C_BIND_ADDR\": {e}"))
-        .unwrap()
-}
+    if host.contains(['/', '?', '#', '@']) {
+        return Err("expected a bare host name without a scheme or path".to_owned());
+    }
 
-fn env_port() -> u16 {
-    std::env::var("MAG_C_PORT")
-        .unwrap_or_else(|_| "4939".to_owned())
-        .parse()
-        .expect("MAG_C_PORT must be a valid port number")
+    Ok(())
 }
 
-fn env_protocol() -> MagnetarNetworkingProtocol {
-    match std::env::var("MAG_C_PROTOCOL")
-        .unwrap_or_else(|_| "https".to_owned())
-        .to_lowercase()
-        .as_str()
-    {
-        "http" => MagnetarNetworkingProtocol::Http,
-        "https" => MagnetarNetworkingProtocol::Https,
-        _ => panic!("MAG_C_PROTOCOL must be a valid protocol"),
-    }
-}
+impl MagnetarNetworking {
+    fn read(reader: &mut ConfigReader) -> Option<Self> {
+        let host = reader.required("networking.host");
+        let host = reader.validate("networking.host", host, |host: &String| validate_host(host));
+        let port = reader.with_default("networking.port", 4939);
+        let bind_addr = reader.with_default("networking.bind_addr", IpAddr::from([0u16; 8]));
+        let protocol =
+            reader.with_default("networking.protocol", MagnetarNetworkingProtocol::Https);
+        let listeners = reader.with_default("networking.listeners", Vec::new());
+        let unix_socket_mode =
+            reader.with_default("networking.unix_socket_mode", UnixSocketMode(0o660));
+        let trusted_proxies = reader.with_default(
+            "networking.trusted_proxies",
+            vec![
+                IpNet::from(IpAddr::from([127, 0, 0, 1])),
+                IpNet::from(IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])),
+            ],
+        );
 
-impl Default for MagnetarNetworking {
-    fn default() -> Self {
-        MagnetarNetworking {
-            host: env_host(),
-            bind_addr: env_bind_addr(),
-            port: env_port(),
-            protocol: env_protocol(),
-        }
+        let (port, bind_addr) = (port?, bind_addr?);
+        let listeners = match listeners? {
+            listeners if listeners.is_empty() => {
+                vec![MagnetarListener::Tcp(SocketAddr::new(bind_addr, port))]
+            }
+            listeners => listeners,
+        };
+
+        Some(MagnetarNetworking {
+            host: host?,
+            port,
+            bind_addr,
+            protocol: protocol?,
+            listeners,
+            unix_socket_mode: unix_socket_mode?,
+            trusted_proxies: trusted_proxies?,
+        })
     }
 }
 
@@ -87,77 +183,781 @@ pub struct MagnetarBranding {
     pub repository: String,
 }
 
-fn env_brand_name() -> String {
-    std::env::var("MAG_C_BR_NAME").unwrap_or_else(|_| "magnetar".to_owned())
-}
+impl MagnetarBranding {
+    fn read(reader: &mut ConfigReader) -> Option<Self> {
+        let name = reader.with_default("branding.name", "magnetar".to_owned());
+        let version = reader.with_default("branding.version", env!("CARGO_PKG_VERSION").to_owned());
+        let homepage = reader.with_default(
+            "branding.homepage",
+            "https://git.astolfo.cool/natty/magnetar".to_owned(),
+        );
+        let repository = reader.with_default(
+            "branding.repository",
+            "https://git.astolfo.cool/natty/magnetar".to_owned(),
+        );
 
-fn env_brand_version() -> String {
-    std::env::var("MAG_C_BR_VERSION").unwrap_or_else(|_| env!("CARGO_PKG_VERSION").to_owned())
+        Some(MagnetarBranding {
+            name: name?,
+            version: version?,
+            homepage: homepage?,
+            repository: repository?,
+        })
+    }
 }
 
-fn env_brand_homepage() -> String {
-    std::env::var("MAG_C_BR_HOMEPAGE")
-        .unwrap_or_else(|_| "https://git.astolfo.cool/natty/magnetar".to_owned())
+/// A configuration value that must never end up in logs
+///
+/// Both [`Debug`](std::fmt::Debug) and [`Display`] print a placeholder,
+/// the actual value is only available through [`SecretString::expose`].
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(secret: impl Into<String>) -> Self {
+        SecretString(secret.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
 }
 
-fn env_brand_repository() -> String {
-    std::env::var("MAG_C_BR_REPOSITORY")
-        .unwrap_or_else(|_| "https://git.astolfo.cool/natty/magnetar".to_owned())
+impl std::fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretString(<redacted>)")
+    }
 }
 
-impl Default for MagnetarBranding {
-    fn default() -> Self {
-        MagnetarBranding {
-            name: env_brand_name(),
-            version: env_brand_version(),
-            homepage: env_brand_homepage(),
-            repository: env_brand_repository(),
-        }
+impl Display for SecretString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<redacted>")
     }
 }
 
 #[derive(Deserialize, Debug)]
 #[non_exhaustive]
 pub struct MagnetarData {
-    pub database_url: String,
+    pub database_url: SecretString,
 }
 
-fn env_database_url() -> String {
-    std::env::var("MAG_C_DATABASE_URL")
-        .or_else(|_| std::env::var("DATABASE_URL"))
-        .expect("MAG_C_HOST, DATABASE_URL or \"data.database_url\" in the default configuration must be set")
+impl MagnetarData {
+    fn read(reader: &mut ConfigReader) -> Option<Self> {
+        let database_url = reader.required("data.database_url");
+
+        Some(MagnetarData {
+            database_url: database_url?,
+        })
+    }
 }
 
-impl Default for MagnetarData {
-    fn default() -> Self {
-        MagnetarData {
-            database_url: env_database_url(),
-        }
+#[derive(Deserialize, Debug)]
+#[non_exhaustive]
+pub struct MagnetarCaching {
+    pub nodeinfo_stats_ttl_secs: u64,
+}
+
+impl MagnetarCaching {
+    fn read(reader: &mut ConfigReader) -> Option<Self> {
+        let nodeinfo_stats_ttl_secs = reader.with_default("caching.nodeinfo_stats_ttl_secs", 300);
+
+        Some(MagnetarCaching {
+            nodeinfo_stats_ttl_secs: nodeinfo_stats_ttl_secs?,
+        })
     }
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Debug)]
+#[non_exhaustive]
+pub struct MagnetarCalckeyFrontend {
+    pub max_note_text_length: u32,
+}
+
+impl MagnetarCalckeyFrontend {
+    fn read(reader: &mut ConfigReader) -> Option<Self> {
+        let max_note_text_length =
+            reader.with_default("calckey_frontend.max_note_text_length", 3000);
+
+        Some(MagnetarCalckeyFrontend {
+            max_note_text_length: max_note_text_length?,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
 #[non_exhaustive]
 pub struct MagnetarConfig {
-    #[serde(default)]
     pub networking: MagnetarNetworking,
-    #[serde(default)]
     pub branding: MagnetarBranding,
-    #[serde(default)]
     pub data: MagnetarData,
+    pub caching: MagnetarCaching,
+    pub calckey_frontend: MagnetarCalckeyFrontend,
+}
+
+impl MagnetarConfig {
+    fn read(reader: &mut ConfigReader) -> Option<Self> {
+        let networking = MagnetarNetworking::read(reader);
+        let branding = MagnetarBranding::read(reader);
+        let data = MagnetarData::read(reader);
+        let caching = MagnetarCaching::read(reader);
+        let calckey_frontend = MagnetarCalckeyFrontend::read(reader);
+
+        Some(MagnetarConfig {
+            networking: networking?,
+            branding: branding?,
+            data: data?,
+            caching: caching?,
+            calckey_frontend: calckey_frontend?,
+        })
+    }
+}
+
+/// Where the value of a configuration key came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    File(PathBuf),
+    Env(&'static str),
+    Cli,
+}
+
+impl Display for ConfigSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::File(path) => write!(f, "configuration file {}", path.display()),
+            ConfigSource::Env(name) => write!(f, "environment variable {name}"),
+            ConfigSource::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigProblem {
+    Missing {
+        key: String,
+    },
+    Invalid {
+        key: String,
+        source: ConfigSource,
+        message: String,
+    },
+    Unknown {
+        key: String,
+        source: ConfigSource,
+    },
+}
+
+impl Display for ConfigProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigProblem::Missing { key } => write!(f, "\"{key}\" is required but not set"),
+            ConfigProblem::Invalid {
+                key,
+                source,
+                message,
+            } => write!(f, "\"{key}\" (from {source}) is invalid: {message}"),
+            ConfigProblem::Unknown { key, source } => {
+                write!(f, "\"{key}\" (from {source}) is not a known option")
+            }
+        }
+    }
+}
+
+fn fmt_problems(problems: &[ConfigProblem]) -> String {
+    problems
+        .iter()
+        .map(|problem| format!("\n  - {problem}"))
+        .collect()
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read the configuration file {}: {1}", .0.display())]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to parse the configuration file {}: {1}", .0.display())]
+    Parse(PathBuf, toml::de::Error),
+    #[error("Invalid configuration:{}", fmt_problems(.0))]
+    Invalid(Vec<ConfigProblem>),
+}
+
+#[derive(Debug, Clone)]
+enum RawValue {
+    Toml(toml::Value),
+    Text(String),
+}
+
+impl RawValue {
+    /// Text values from the environment and command line are taken as strings first,
+    /// falling back to TOML literals for numbers, booleans and arrays,
+    /// and finally to comma-separated lists
+    fn parse<T: DeserializeOwned>(&self) -> Result<T, String> {
+        match self {
+            RawValue::Toml(value) => T::deserialize(value.clone()).map_err(|e| e.to_string()),
+            RawValue::Text(text) => {
+                T::deserialize(toml::Value::String(text.clone())).or_else(|e| {
+                    toml::from_str::<toml::Table>(&format!("value = {text}"))
+                        .ok()
+                        .and_then(|mut table| table.remove("value"))
+                        .and_then(|value| T::deserialize(value).ok())
+                        .or_else(|| {
+                            let items = text
+                                .split(',')
+                                .map(|item| toml::Value::String(item.trim().to_owned()))
+                                .collect();
+
+                            T::deserialize(toml::Value::Array(items)).ok()
+                        })
+                        .ok_or_else(|| e.to_string())
+                })
+            }
+        }
+    }
+}
+
+/// Configuration values from every source, keyed by their dotted path
+///
+/// Sources added later take priority over earlier ones, built-in defaults
+/// are only applied when reading the configuration.
+#[derive(Debug, Default)]
+pub struct ConfigLayers {
+    values: BTreeMap<String, (RawValue, ConfigSource)>,
+}
+
+impl ConfigLayers {
+    fn insert_table(&mut self, prefix: &str, table: toml::Table, path: &Path) {
+        for (key, value) in table {
+            let key = if prefix.is_empty() {
+                key
+            } else {
+                format!("{prefix}.{key}")
+            };
+
+            match value {
+                toml::Value::Table(table) => self.insert_table(&key, table, path),
+                value => {
+                    self.values.insert(
+                        key,
+                        (RawValue::Toml(value), ConfigSource::File(path.to_owned())),
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn add_file(&mut self, path: &Path, content: &str) -> Result<(), ConfigError> {
+        let table = toml::from_str::<toml::Table>(content)
+            .map_err(|e| ConfigError::Parse(path.to_owned(), e))?;
+
+        self.insert_table("", table, path);
+
+        Ok(())
+    }
+
+    pub fn add_env(&mut self, env: impl Fn(&str) -> Option<String>) {
+        for (name, key) in ENV_VARS {
+            if let Some(value) = env(name) {
+                self.values.insert(
+                    (*key).to_owned(),
+                    (RawValue::Text(value), ConfigSource::Env(name)),
+                );
+            }
+        }
+    }
+
+    pub fn add_overrides<'a>(&mut self, overrides: impl IntoIterator<Item = &'a (String, String)>) {
+        for (key, value) in overrides {
+            self.values.insert(
+                key.clone(),
+                (RawValue::Text(value.clone()), ConfigSource::Cli),
+            );
+        }
+    }
+
+    pub fn into_config(self) -> Result<MagnetarConfig, ConfigError> {
+        let mut reader = ConfigReader {
+            layers: self,
+            consumed: HashSet::new(),
+            problems: Vec::new(),
+        };
+
+        let config = MagnetarConfig::read(&mut reader);
+        reader.finish(config)
+    }
+}
+
+/// Reads typed values out of the layers while collecting every problem
+struct ConfigReader {
+    layers: ConfigLayers,
+    consumed: HashSet<String>,
+    problems: Vec<ConfigProblem>,
+}
+
+impl ConfigReader {
+    fn invalid(&mut self, key: &str, message: String) {
+        if let Some((_, source)) = self.layers.values.get(key) {
+            self.problems.push(ConfigProblem::Invalid {
+                key: key.to_owned(),
+                source: source.clone(),
+                message: message.trim_end().to_owned(),
+            });
+        }
+    }
+
+    /// `Err` if the value is set but invalid, the problem is recorded already
+    fn lookup<T: DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>, ()> {
+        self.consumed.insert(key.to_owned());
+
+        let Some((value, _)) = self.layers.values.get(key) else {
+            return Ok(None);
+        };
+
+        value.parse().map(Some).map_err(|e| self.invalid(key, e))
+    }
+
+    fn required<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        match self.lookup(key) {
+            Ok(Some(value)) => Some(value),
+            Ok(None) => {
+                self.problems.push(ConfigProblem::Missing {
+                    key: key.to_owned(),
+                });
+                None
+            }
+            Err(()) => None,
+        }
+    }
+
+    fn with_default<T: DeserializeOwned>(&mut self, key: &str, default: T) -> Option<T> {
+        self.lookup(key).ok().map(|value| value.unwrap_or(default))
+    }
+
+    fn validate<T>(
+        &mut self,
+        key: &str,
+        value: Option<T>,
+        check: impl FnOnce(&T) -> Result<(), String>,
+    ) -> Option<T> {
+        match check(value.as_ref()?) {
+            Ok(()) => value,
+            Err(e) => {
+                self.invalid(key, e);
+                None
+            }
+        }
+    }
+
+    fn finish(mut self, config: Option<MagnetarConfig>) -> Result<MagnetarConfig, ConfigError> {
+        for (key, (_, source)) in &self.layers.values {
+            if !self.consumed.contains(key) {
+                self.problems.push(ConfigProblem::Unknown {
+                    key: key.clone(),
+                    source: source.clone(),
+                });
+            }
+        }
+
+        match config {
+            Some(config) if self.problems.is_empty() => Ok(config),
+            _ => Err(ConfigError::Invalid(self.problems)),
+        }
+    }
+}
+
+/// Options for locating and overriding the configuration, usually from the command line
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOptions {
+    pub path: Option<PathBuf>,
+    pub overrides: Vec<(String, String)>,
+}
+
+/// Loads the configuration from, in increasing priority:
+/// built-in defaults, the TOML file, `MAG_C_*` environment variables and command line overrides
+pub fn load_config(options: &ConfigOptions) -> Result<MagnetarConfig, ConfigError> {
+    let explicit_path = options
+        .path
+        .clone()
+        .or_else(|| std::env::var_os("MAG_CONFIG_PATH").map(PathBuf::from));
+
+    let mut layers = ConfigLayers::default();
+
+    let path = explicit_path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+
+    match std::fs::read_to_string(&path) {
+        Ok(content) => layers.add_file(&path, &content)?,
+        // The default configuration file is optional, everything may come from the environment
+        Err(e) if explicit_path.is_none() && e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(ConfigError::Read(path, e)),
+    }
+
+    layers.add_env(|name| std::env::var(name).ok());
+    layers.add_overrides(&options.overrides);
+
+    layers.into_config()
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigReloadError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error("Changing {} requires a restart", .0.join(", "))]
+    RestartRequired(Vec<&'static str>),
+}
+
+/// The options that cannot change while the server is running
+fn restart_required(current: &MagnetarConfig, new: &MagnetarConfig) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+
+    if current.networking.bind_addr != new.networking.bind_addr {
+        changed.push("networking.bind_addr");
+    }
+
+    if current.networking.port != new.networking.port {
+        changed.push("networking.port");
+    }
+
+    if current.networking.listeners != new.networking.listeners {
+        changed.push("networking.listeners");
+    }
+
+    if current.networking.unix_socket_mode != new.networking.unix_socket_mode {
+        changed.push("networking.unix_socket_mode");
+    }
+
+    if current.data.database_url != new.data.database_url {
+        changed.push("data.database_url");
+    }
+
+    changed
+}
+
+/// A shared handle to the current configuration
+///
+/// Reloading swaps the whole configuration at once, so handlers should [`load`](Self::load)
+/// it once per request to work with a consistent snapshot.
+#[derive(Clone)]
+pub struct ConfigHandle {
+    current: Arc<ArcSwap<MagnetarConfig>>,
+    options: Arc<ConfigOptions>,
+}
+
+impl ConfigHandle {
+    pub fn new(config: MagnetarConfig, options: ConfigOptions) -> Self {
+        ConfigHandle {
+            current: Arc::new(ArcSwap::from_pointee(config)),
+            options: Arc::new(options),
+        }
+    }
+
+    pub fn load(&self) -> Arc<MagnetarConfig> {
+        self.current.load_full()
+    }
+
+    /// Replaces the configuration, unless it changes options that require a restart
+    pub fn apply(&self, config: MagnetarConfig) -> Result<(), ConfigReloadError> {
+        let changed = restart_required(&self.current.load(), &config);
+
+        if !changed.is_empty() {
+            return Err(ConfigReloadError::RestartRequired(changed));
+        }
+
+        self.current.store(Arc::new(config));
+
+        Ok(())
+    }
+
+    /// Loads the configuration again from all sources and applies it
+    pub fn reload(&self) -> Result<(), ConfigReloadError> {
+        self.apply(load_config(&self.options)?)
+    }
+
+    /// Reloads the configuration every time the process receives SIGHUP
+    #[cfg(unix)]
+    pub async fn reload_on_sighup(self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Failed to listen for SIGHUP, configuration reloading is disabled: {e}");
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            match self.reload() {
+                Ok(()) => info!("Configuration reloaded"),
+                Err(e) => error!("Configuration was not reloaded: {e}"),
+            }
+        }
+    }
 }
 
-pub fn load_config() -> anyhow::Result<MagnetarConfig> {
-    let path =
-        std::env::var("MAG_CONFIG_PATH").unwrap_or_else(|_| "config/default.toml".to_owned());
+#[cfg(test)]
+mod test {
+    use crate::config::{
+        ConfigError, ConfigHandle, ConfigLayers, ConfigOptions, ConfigProblem, ConfigReloadError,
+        ConfigSource, MagnetarConfig, MagnetarListener, MagnetarNetworkingProtocol, SecretString,
+        UnixSocketMode,
+    };
+    use ipnet::IpNet;
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::path::Path;
+
+    const TOML_CONFIG: &str = r#"
+        networking.host = "file.example.com"
+        networking.port = 8080
+
+        [data]
+        database_url = "postgres://db/calckey"
+    "#;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn should_apply_layers_in_order() {
+        let mut layers = ConfigLayers::default();
+        layers
+            .add_file(Path::new("magnetar.toml"), TOML_CONFIG)
+            .unwrap();
+        layers.add_env(env(&[
+            ("MAG_C_HOST", "env.example.com"),
+            ("MAG_C_PORT", "9090"),
+            ("MAG_C_PROTOCOL", "http"),
+        ]));
+        layers.add_overrides(&[("networking.port".to_owned(), "1234".to_owned())]);
 
-    let str_cfg =
-        std::fs::read_to_string(path).map_err(|e| anyhow!("Failed to load configuration: {e}"))?;
+        let config = layers.into_config().unwrap();
 
-    let config =
-        toml::from_str(&str_cfg).map_err(|e| anyhow!("Failed to parse configuration: {e}"))?;
+        assert_eq!(config.networking.host, "env.example.com");
+        assert_eq!(config.networking.port, 1234);
+        assert!(matches!(
+            config.networking.protocol,
+            MagnetarNetworkingProtocol::Http
+        ));
+        assert_eq!(config.data.database_url.expose(), "postgres://db/calckey");
+        assert_eq!(config.caching.nodeinfo_stats_ttl_secs, 300);
+    }
+
+    #[test]
+    fn should_prefer_prefixed_database_url() {
+        let mut layers = ConfigLayers::default();
+        layers.add_env(env(&[
+            ("MAG_C_HOST", "example.com"),
+            ("DATABASE_URL", "postgres://generic/db"),
+            ("MAG_C_DATABASE_URL", "postgres://magnetar/db"),
+        ]));
+
+        let config = layers.into_config().unwrap();
+
+        assert_eq!(config.data.database_url.expose(), "postgres://magnetar/db");
+    }
+
+    #[test]
+    fn should_list_every_problem() {
+        let mut layers = ConfigLayers::default();
+        layers
+            .add_file(
+                Path::new("magnetar.toml"),
+                r#"
+                    networking.port = "not a port"
+                    networking.hots = "example.com"
+                "#,
+            )
+            .unwrap();
+        layers.add_env(env(&[("MAG_C_BIND_ADDR", "localhost")]));
+
+        let Err(ConfigError::Invalid(problems)) = layers.into_config() else {
+            panic!("Expected the configuration to be invalid");
+        };
+
+        let file = ConfigSource::File(Path::new("magnetar.toml").to_owned());
+
+        assert_eq!(problems.len(), 5);
+        assert!(problems.contains(&ConfigProblem::Missing {
+            key: "networking.host".to_owned()
+        }));
+        assert!(problems.contains(&ConfigProblem::Missing {
+            key: "data.database_url".to_owned()
+        }));
+        assert!(problems.contains(&ConfigProblem::Unknown {
+            key: "networking.hots".to_owned(),
+            source: file.clone()
+        }));
+        assert!(problems.iter().any(|problem| matches!(
+            problem,
+            ConfigProblem::Invalid { key, source, .. }
+                if key == "networking.port" && *source == file
+        )));
+        assert!(problems.iter().any(|problem| matches!(
+            problem,
+            ConfigProblem::Invalid { key, source, .. }
+                if key == "networking.bind_addr" && *source == ConfigSource::Env("MAG_C_BIND_ADDR")
+        )));
+    }
+
+    #[test]
+    fn should_reject_host_with_scheme() {
+        let mut layers = ConfigLayers::default();
+        layers.add_overrides(&[
+            (
+                "networking.host".to_owned(),
+                "https://example.com".to_owned(),
+            ),
+            ("data.database_url".to_owned(), "postgres://db".to_owned()),
+        ]);
+
+        let Err(ConfigError::Invalid(problems)) = layers.into_config() else {
+            panic!("Expected the configuration to be invalid");
+        };
+
+        assert!(matches!(
+            problems.as_slice(),
+            [ConfigProblem::Invalid { key, source: ConfigSource::Cli, .. }] if key == "networking.host"
+        ));
+    }
 
-    info!("Loaded configuration: {config:#?}");
+    #[test]
+    fn should_redact_secrets() {
+        let mut layers = ConfigLayers::default();
+        layers.add_env(env(&[
+            ("MAG_C_HOST", "example.com"),
+            (
+                "MAG_C_DATABASE_URL",
+                "postgres://calckey:hunter2@db:5432/calckey",
+            ),
+        ]));
 
-    Ok(config)
+        let config = layers.into_config().unwrap();
+
+        assert!(!format!("{config:#?}").contains("hunter2"));
+        assert!(!format!("{config:?}").contains("hunter2"));
+
+        let secret = SecretString::new("hunter2");
+        assert_eq!(secret.to_string(), "<redacted>");
+        assert_eq!(secret.expose(), "hunter2");
+    }
+
+    fn config_with(overrides: &[(&str, &str)]) -> MagnetarConfig {
+        let mut layers = ConfigLayers::default();
+        layers.add_env(env(&[
+            ("MAG_C_HOST", "example.com"),
+            ("MAG_C_DATABASE_URL", "postgres://db/calckey"),
+        ]));
+        layers.add_overrides(
+            &overrides
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<Vec<_>>(),
+        );
+
+        layers.into_config().unwrap()
+    }
+
+    #[test]
+    fn should_apply_live_changes() {
+        let handle = ConfigHandle::new(config_with(&[]), ConfigOptions::default());
+        let before = handle.load();
+
+        handle
+            .apply(config_with(&[("branding.name", "magnetar-fork")]))
+            .unwrap();
+
+        assert_eq!(before.branding.name, "magnetar");
+        assert_eq!(handle.load().branding.name, "magnetar-fork");
+    }
+
+    #[test]
+    fn should_refuse_restart_only_changes() {
+        let handle = ConfigHandle::new(config_with(&[]), ConfigOptions::default());
+
+        let result = handle.apply(config_with(&[
+            ("networking.port", "8080"),
+            ("data.database_url", "postgres://other/calckey"),
+            ("branding.name", "magnetar-fork"),
+        ]));
+
+        assert!(matches!(
+            result,
+            Err(ConfigReloadError::RestartRequired(changed))
+                if changed == ["networking.port", "networking.listeners", "data.database_url"]
+        ));
+        assert_eq!(handle.load().branding.name, "magnetar");
+        assert_eq!(handle.load().networking.port, 4939);
+    }
+
+    #[test]
+    fn should_parse_listeners() {
+        let mut layers = ConfigLayers::default();
+        layers
+            .add_file(
+                Path::new("magnetar.toml"),
+                r#"
+                    networking.listeners = ["127.0.0.1:8080", "unix:/run/magnetar/magnetar.sock"]
+                    networking.unix_socket_mode = "600"
+                "#,
+            )
+            .unwrap();
+        layers.add_env(env(&[
+            ("MAG_C_HOST", "example.com"),
+            ("MAG_C_DATABASE_URL", "postgres://db/calckey"),
+        ]));
+
+        let config = layers.into_config().unwrap();
+
+        assert_eq!(
+            config.networking.listeners,
+            vec![
+                MagnetarListener::Tcp("127.0.0.1:8080".parse().unwrap()),
+                MagnetarListener::Unix("/run/magnetar/magnetar.sock".into()),
+            ]
+        );
+        assert_eq!(config.networking.unix_socket_mode, UnixSocketMode(0o600));
+
+        let config = config_with(&[("networking.listeners", "unix:/tmp/a.sock, [::1]:4939")]);
+
+        assert_eq!(
+            config.networking.listeners,
+            vec![
+                MagnetarListener::Unix("/tmp/a.sock".into()),
+                MagnetarListener::Tcp("[::1]:4939".parse().unwrap()),
+            ]
+        );
+
+        let config = config_with(&[("networking.port", "8080")]);
+
+        assert_eq!(
+            config.networking.listeners,
+            vec![MagnetarListener::Tcp("[::]:8080".parse().unwrap())]
+        );
+    }
+
+    #[test]
+    fn should_parse_trusted_proxies() {
+        let config = config_with(&[]);
+
+        assert!(config
+            .networking
+            .trusted_proxies
+            .iter()
+            .any(|net| net.contains(&"127.0.0.1".parse::<IpAddr>().unwrap())));
+
+        let config = config_with(&[("networking.trusted_proxies", "10.0.0.0/8, fd00::/8")]);
+
+        assert_eq!(
+            config.networking.trusted_proxies,
+            vec![
+                "10.0.0.0/8".parse::<IpNet>().unwrap(),
+                "fd00::/8".parse::<IpNet>().unwrap()
+            ]
+        );
+    }
 }
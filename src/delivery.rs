@@ -0,0 +1,819 @@
+use crate::config::ConfigHandle;
+use crate::inbox::process::is_blocked_host;
+use crate::public_address::{check_public_url, is_public_url};
+use crate::signature::signer::{authority, load_user_signer, send_signed, HostSchemes, Signer};
+use chrono::{DateTime, Duration, Utc};
+use magnetar_calckey_model::delivery_job;
+use magnetar_calckey_model::CalckeyModel;
+use magnetar_core::web_model::content_type::ContentActivityStreams;
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, warn};
+use url::Url;
+
+/// The delay before the first retry, doubled with every failed attempt
+const BACKOFF_BASE: std::time::Duration = std::time::Duration::from_secs(60);
+const BACKOFF_MAX: std::time::Duration = std::time::Duration::from_secs(6 * 60 * 60);
+
+/// Deliveries still failing this long after they were queued are given up
+const DEAD_AFTER: std::time::Duration = std::time::Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Concurrent deliveries to a single host
+const HOST_CONCURRENCY: usize = 4;
+
+/// Concurrent deliveries of a worker
+const WORKER_CONCURRENCY: usize = 32;
+
+/// How often the queue is checked for due retries when nothing was queued in between
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How long a claimed delivery is reserved for its worker, it must outlast the request timeout
+const LEASE: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// The result of a single attempt to deliver an activity
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeliveryOutcome {
+    Delivered(u16),
+    /// The inbox is temporarily unavailable, the delivery is attempted again later
+    Retry {
+        status: Option<u16>,
+        error: String,
+    },
+    /// The inbox refused the activity, attempting again would not change that
+    Failed {
+        status: Option<u16>,
+        error: String,
+    },
+}
+
+impl DeliveryOutcome {
+    fn from_status(status: StatusCode) -> Self {
+        let error = format!("status {status}");
+
+        match status {
+            status if status.is_success() => DeliveryOutcome::Delivered(status.as_u16()),
+            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => DeliveryOutcome::Retry {
+                status: Some(status.as_u16()),
+                error,
+            },
+            status if status.is_server_error() => DeliveryOutcome::Retry {
+                status: Some(status.as_u16()),
+                error,
+            },
+            status => DeliveryOutcome::Failed {
+                status: Some(status.as_u16()),
+                error,
+            },
+        }
+    }
+}
+
+impl Display for DeliveryOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryOutcome::Delivered(status) => write!(f, "delivered with status {status}"),
+            DeliveryOutcome::Retry { error, .. } | DeliveryOutcome::Failed { error, .. } => {
+                write!(f, "{error}")
+            }
+        }
+    }
+}
+
+/// What becomes of a delivery after an attempt
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NextStep {
+    Done,
+    RetryAt(DateTime<Utc>),
+    DeadLetter,
+}
+
+/// The delay before the next attempt of a delivery that failed `attempts` times before
+pub fn backoff(attempts: i32) -> Duration {
+    let delay = BACKOFF_BASE
+        .checked_mul(1 << attempts.clamp(0, 16))
+        .map_or(BACKOFF_MAX, |delay| delay.min(BACKOFF_MAX));
+
+    Duration::from_std(delay).expect("the backoff fits a chrono duration")
+}
+
+/// Decides what becomes of a delivery queued at `created_at` after an attempt
+pub fn next_step(
+    created_at: DateTime<Utc>,
+    attempts: i32,
+    now: DateTime<Utc>,
+    outcome: &DeliveryOutcome,
+) -> NextStep {
+    let dead_after = Duration::from_std(DEAD_AFTER).expect("DEAD_AFTER fits a chrono duration");
+
+    match outcome {
+        DeliveryOutcome::Delivered(_) => NextStep::Done,
+        DeliveryOutcome::Failed { .. } => NextStep::DeadLetter,
+        DeliveryOutcome::Retry { .. } => {
+            let at = now + backoff(attempts);
+
+            match at - created_at > dead_after {
+                true => NextStep::DeadLetter,
+                false => NextStep::RetryAt(at),
+            }
+        }
+    }
+}
+
+/// Sends a signed activity to an inbox
+pub async fn post_activity(
+    client: &reqwest::Client,
+    schemes: &HostSchemes,
+    signer: &Signer,
+    inbox: &str,
+    body: Vec<u8>,
+) -> DeliveryOutcome {
+    let request = client
+        .post(inbox)
+        .header(
+            reqwest::header::CONTENT_TYPE,
+            ContentActivityStreams.as_ref(),
+        )
+        .body(body)
+        .build();
+
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            return DeliveryOutcome::Failed {
+                status: None,
+                error: e.to_string(),
+            }
+        }
+    };
+
+    match send_signed(client, schemes, signer, request).await {
+        Ok(response) => DeliveryOutcome::from_status(response.status()),
+        Err(e) => DeliveryOutcome::Retry {
+            status: None,
+            error: e.to_string(),
+        },
+    }
+}
+
+/// Refuses inboxes on the local machine or network, remote actors choose their inbox URLs
+async fn check_inbox(inbox: &str) -> Result<(), DeliveryOutcome> {
+    let url = Url::parse(inbox).map_err(|e| DeliveryOutcome::Failed {
+        status: None,
+        error: e.to_string(),
+    })?;
+
+    if !is_public_url(&url) {
+        return Err(DeliveryOutcome::Failed {
+            status: None,
+            error: format!("{inbox} is not a public URL"),
+        });
+    }
+
+    // Resolving may fail temporarily
+    check_public_url(&url)
+        .await
+        .map_err(|error| DeliveryOutcome::Retry {
+            status: None,
+            error,
+        })
+}
+
+/// Inboxes paired with their host, without duplicates and inboxes that are not URLs
+fn delivery_targets<'a>(inboxes: impl IntoIterator<Item = &'a str>) -> Vec<(String, String)> {
+    let mut targets = inboxes
+        .into_iter()
+        .filter_map(|inbox| {
+            let host = Url::parse(inbox).ok().as_ref().and_then(authority);
+
+            if host.is_none() {
+                debug!("Not delivering to {inbox}, it has no host");
+            }
+
+            Some((inbox.to_owned(), host?))
+        })
+        .collect::<Vec<_>>();
+
+    targets.sort();
+    targets.dedup();
+    targets
+}
+
+/// Queues activities of local users for delivery to remote inboxes
+///
+/// Queued deliveries are stored in the database and sent by the [`DeliveryWorker`].
+#[derive(Clone)]
+pub struct Delivery {
+    ck: CalckeyModel,
+    wake: Arc<Notify>,
+}
+
+impl Delivery {
+    pub fn new(ck: CalckeyModel) -> Self {
+        Delivery {
+            ck,
+            wake: Arc::new(Notify::new()),
+        }
+    }
+
+    /// A worker sending the deliveries queued by this handle
+    pub fn worker(
+        &self,
+        config: ConfigHandle,
+        client: reqwest::Client,
+        schemes: Arc<HostSchemes>,
+    ) -> DeliveryWorker {
+        DeliveryWorker {
+            config,
+            ck: self.ck.clone(),
+            client,
+            schemes,
+            hosts: HostLimits::new(HOST_CONCURRENCY),
+            wake: self.wake.clone(),
+            allow_local: false,
+        }
+    }
+
+    /// Queues an activity of `user_id` for delivery to an inbox
+    pub async fn deliver(
+        &self,
+        user_id: &str,
+        inbox: &str,
+        activity: &impl Serialize,
+    ) -> anyhow::Result<()> {
+        self.deliver_to(user_id, [inbox], activity).await
+    }
+
+    /// Queues an activity of `user_id` for delivery to their remote followers
+    ///
+    /// Followers sharing an inbox receive the activity once.
+    pub async fn deliver_to_followers(
+        &self,
+        user_id: &str,
+        activity: &impl Serialize,
+    ) -> anyhow::Result<()> {
+        let inboxes = self.ck.get_follower_inboxes(user_id).await?;
+
+        self.deliver_to(user_id, inboxes.iter().map(String::as_str), activity)
+            .await
+    }
+
+    async fn deliver_to<'a>(
+        &self,
+        user_id: &str,
+        inboxes: impl IntoIterator<Item = &'a str>,
+        activity: &impl Serialize,
+    ) -> anyhow::Result<()> {
+        let targets = delivery_targets(inboxes);
+
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        self.ck
+            .enqueue_deliveries(user_id, &serde_json::to_value(activity)?, &targets)
+            .await?;
+
+        self.wake.notify_one();
+
+        Ok(())
+    }
+}
+
+/// Limits the concurrent deliveries to each host
+struct HostLimits {
+    limit: usize,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl HostLimits {
+    fn new(limit: usize) -> Self {
+        HostLimits {
+            limit,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A permit for one delivery to `host`, unless it already has the maximum of deliveries in flight
+    fn try_acquire(&self, host: &str) -> Option<OwnedSemaphorePermit> {
+        let mut hosts = self.hosts.lock().unwrap();
+
+        // Hosts without deliveries in flight are forgotten
+        hosts.retain(|_, semaphore| semaphore.available_permits() < self.limit);
+
+        hosts
+            .entry(host.to_owned())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+            .clone()
+            .try_acquire_owned()
+            .ok()
+    }
+
+    /// Hosts that cannot take another delivery right now
+    fn busy_hosts(&self) -> Vec<String> {
+        self.hosts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, semaphore)| semaphore.available_permits() == 0)
+            .map(|(host, _)| host.clone())
+            .collect()
+    }
+}
+
+/// Sends queued deliveries, retrying failed ones with an exponential backoff
+pub struct DeliveryWorker {
+    config: ConfigHandle,
+    ck: CalckeyModel,
+    client: reqwest::Client,
+    schemes: Arc<HostSchemes>,
+    hosts: HostLimits,
+    wake: Arc<Notify>,
+    allow_local: bool,
+}
+
+impl DeliveryWorker {
+    /// Lets the worker deliver to local addresses, like the mock inboxes of tests
+    #[cfg(test)]
+    fn allowing_local(self) -> Self {
+        DeliveryWorker {
+            allow_local: true,
+            ..self
+        }
+    }
+
+    pub async fn run(self) {
+        let worker = Arc::new(self);
+        let permits = Arc::new(Semaphore::new(WORKER_CONCURRENCY));
+
+        loop {
+            let available = permits.available_permits();
+            let claimed = match available {
+                0 => Vec::new(),
+                available => worker
+                    .ck
+                    .claim_deliveries(
+                        available as u64,
+                        &worker.hosts.busy_hosts(),
+                        Utc::now() + Duration::from_std(LEASE).unwrap(),
+                    )
+                    .await
+                    .unwrap_or_else(|e| {
+                        warn!("Failed to claim deliveries: {e}");
+                        Vec::new()
+                    }),
+            };
+
+            let saturated = available > 0 && claimed.len() == available;
+
+            for job in claimed {
+                let (Some(host_permit), Ok(permit)) = (
+                    worker.hosts.try_acquire(&job.host),
+                    permits.clone().try_acquire_owned(),
+                ) else {
+                    // Claimed together with other deliveries to a host now at its limit
+                    if let Err(e) = worker.ck.release_delivery(&job.id).await {
+                        warn!("Failed to release delivery {}: {e}", job.id);
+                    }
+                    continue;
+                };
+
+                let worker = worker.clone();
+
+                tokio::spawn(async move {
+                    worker.attempt(job).await;
+                    drop((host_permit, permit));
+                    worker.wake.notify_one();
+                });
+            }
+
+            if saturated {
+                continue;
+            }
+
+            tokio::select! {
+                _ = worker.wake.notified() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+
+    async fn attempt(&self, job: delivery_job::Model) {
+        if let Err(e) = self.try_attempt(&job).await {
+            // The delivery is claimed again once its lease runs out
+            warn!("Delivery {} to {} failed: {e}", job.id, job.inbox);
+        }
+    }
+
+    /// Why a delivery must not be sent at all, if it must not
+    async fn refusal(&self, job: &delivery_job::Model) -> anyhow::Result<Option<&'static str>> {
+        let blocked_hosts = self
+            .ck
+            .get_meta()
+            .await?
+            .map(|meta| meta.blocked_hosts)
+            .unwrap_or_default();
+
+        if is_blocked_host(&blocked_hosts, &job.host) {
+            return Ok(Some("the host is blocked"));
+        }
+
+        let suspended = self
+            .ck
+            .get_instance_by_host(&job.host)
+            .await?
+            .is_some_and(|instance| instance.is_suspended);
+
+        Ok(suspended.then_some("the instance is suspended"))
+    }
+
+    async fn try_attempt(&self, job: &delivery_job::Model) -> anyhow::Result<()> {
+        if let Some(reason) = self.refusal(job).await? {
+            return self.ck.dead_letter_delivery(&job.id, reason).await;
+        }
+
+        let config = self.config.load();
+        let Some(signer) = load_user_signer(&config, &self.ck, &job.user_id).await? else {
+            return self
+                .ck
+                .dead_letter_delivery(&job.id, "the user has no keypair")
+                .await;
+        };
+
+        let checked = match self.allow_local {
+            true => Ok(()),
+            false => check_inbox(&job.inbox).await,
+        };
+
+        let outcome = match checked {
+            Ok(()) => {
+                let body = serde_json::to_vec(&job.activity)?;
+                post_activity(&self.client, &self.schemes, &signer, &job.inbox, body).await
+            }
+            Err(outcome) => outcome,
+        };
+
+        // An inbox refusing the activity still means the instance is up
+        match outcome {
+            DeliveryOutcome::Delivered(status)
+            | DeliveryOutcome::Failed {
+                status: Some(status),
+                ..
+            } => {
+                self.ck
+                    .mark_instance_responding(&job.host, i32::from(status))
+                    .await?
+            }
+            DeliveryOutcome::Retry { status, .. } => {
+                self.ck
+                    .mark_instance_not_responding(&job.host, status.map(i32::from))
+                    .await?
+            }
+            DeliveryOutcome::Failed { status: None, .. } => {}
+        }
+
+        let created_at = job.created_at.with_timezone(&Utc);
+        let error = outcome.to_string();
+
+        match next_step(created_at, job.attempts, Utc::now(), &outcome) {
+            NextStep::Done => self.ck.complete_delivery(&job.id).await,
+            NextStep::RetryAt(at) => {
+                debug!("Delivery {} to {} failed: {error}", job.id, job.inbox);
+                self.ck.reschedule_delivery(&job.id, at, &error).await
+            }
+            NextStep::DeadLetter => {
+                warn!("Giving up delivery {} to {}: {error}", job.id, job.inbox);
+                self.ck.dead_letter_delivery(&job.id, &error).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{ConfigHandle, ConfigLayers, ConfigOptions, MagnetarConfig};
+    use crate::delivery::{
+        backoff, check_inbox, delivery_targets, next_step, post_activity, Delivery,
+        DeliveryOutcome, DeliveryWorker, HostLimits, NextStep, BACKOFF_MAX, HOST_CONCURRENCY,
+    };
+    use crate::signature::signer::{HostSchemes, PrivateKey, Signer};
+    use axum::extract::{Path, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use chrono::{Duration, TimeZone, Utc};
+    use magnetar_calckey_model::ck::instance;
+    use magnetar_calckey_model::test_support::{
+        deliveries, insert_keypair, insert_user, local_user, test_database,
+    };
+    use magnetar_calckey_model::{CalckeyModel, InstanceInfo};
+    use serde_json::json;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    type Received = Arc<Mutex<Vec<(bool, String)>>>;
+
+    fn signer() -> Signer {
+        Signer::new(
+            "https://example.com/users/9a0b1c2d3e#main-key",
+            PrivateKey::from_pem(include_str!("../fixtures/http_signatures_key.pem")).unwrap(),
+        )
+    }
+
+    /// Serves a mock inbox at `/inbox/:status`, answering with the status and recording
+    /// whether each request was signed along with its body
+    fn serve_inbox() -> (String, Received) {
+        let received = Received::default();
+
+        let app = Router::new()
+            .route(
+                "/inbox/:status",
+                post(
+                    |Path(status): Path<u16>,
+                     State(received): State<Received>,
+                     headers: HeaderMap,
+                     body: String| async move {
+                        let signed = headers.contains_key("signature")
+                            || headers.contains_key("signature-input");
+                        received.lock().unwrap().push((signed, body));
+
+                        StatusCode::from_u16(status).unwrap()
+                    },
+                ),
+            )
+            .with_state(received.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        (format!("http://{addr}/inbox"), received)
+    }
+
+    async fn deliver(inbox: &str) -> DeliveryOutcome {
+        post_activity(
+            &reqwest::Client::new(),
+            &HostSchemes::default(),
+            &signer(),
+            inbox,
+            br#"{"type":"Accept"}"#.to_vec(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn should_deliver_signed_activities() {
+        let (inbox, received) = serve_inbox();
+
+        assert_eq!(
+            deliver(&format!("{inbox}/202")).await,
+            DeliveryOutcome::Delivered(202)
+        );
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![(true, r#"{"type":"Accept"}"#.to_owned())]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_classify_failures() {
+        let (inbox, _) = serve_inbox();
+
+        for status in [500, 503, 429, 408] {
+            assert!(
+                matches!(
+                    deliver(&format!("{inbox}/{status}")).await,
+                    DeliveryOutcome::Retry { status: Some(s), .. } if s == status
+                ),
+                "{status} should be retried"
+            );
+        }
+
+        for status in [400, 403, 404, 410] {
+            assert!(
+                matches!(
+                    deliver(&format!("{inbox}/{status}")).await,
+                    DeliveryOutcome::Failed { status: Some(s), .. } if s == status
+                ),
+                "{status} should not be retried"
+            );
+        }
+
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = closed.local_addr().unwrap();
+        drop(closed);
+
+        assert!(matches!(
+            deliver(&format!("http://{addr}/inbox")).await,
+            DeliveryOutcome::Retry { status: None, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_refuse_local_inboxes() {
+        for inbox in [
+            "http://127.0.0.1:4939/inbox",
+            "http://[::1]/inbox",
+            "http://192.168.1.1/inbox",
+            "https://localhost/inbox",
+            "not an inbox",
+        ] {
+            assert!(
+                matches!(
+                    check_inbox(inbox).await,
+                    Err(DeliveryOutcome::Failed { status: None, .. })
+                ),
+                "{inbox}"
+            );
+        }
+
+        assert_eq!(check_inbox("https://1.1.1.1/inbox").await, Ok(()));
+    }
+
+    fn config() -> MagnetarConfig {
+        let mut layers = ConfigLayers::default();
+        layers.add_overrides(&[
+            ("networking.host".to_owned(), "example.com".to_owned()),
+            ("data.database_url".to_owned(), "postgres://db".to_owned()),
+        ]);
+        layers.into_config().unwrap()
+    }
+
+    /// Queues a delivery to `inbox` and has the worker attempt it once
+    async fn attempt(
+        ck: &CalckeyModel,
+        delivery: &Delivery,
+        worker: &DeliveryWorker,
+        inbox: &str,
+    ) -> instance::Model {
+        delivery
+            .deliver("9a0b1c2d3e", inbox, &json!({ "type": "Accept" }))
+            .await
+            .unwrap();
+
+        let claimed = ck
+            .claim_deliveries(1, &[], Utc::now() + Duration::minutes(1))
+            .await
+            .unwrap();
+        assert_eq!(claimed.len(), 1);
+
+        let host = claimed[0].host.clone();
+        worker.attempt(claimed.into_iter().next().unwrap()).await;
+
+        ck.get_instance_by_host(&host).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_record_attempts_of_the_worker() {
+        let ck = test_database().await.unwrap();
+        insert_user(&ck, local_user("9a0b1c2d3e", "alice"))
+            .await
+            .unwrap();
+        insert_keypair(
+            &ck,
+            "9a0b1c2d3e",
+            include_str!("../fixtures/http_signatures_key.pem"),
+        )
+        .await
+        .unwrap();
+
+        let (inbox, received) = serve_inbox();
+        let host = inbox
+            .trim_start_matches("http://")
+            .trim_end_matches("/inbox")
+            .to_owned();
+        ck.upsert_instance_info(&host, InstanceInfo::default())
+            .await
+            .unwrap();
+
+        let delivery = Delivery::new(ck.clone());
+        let worker = delivery
+            .worker(
+                ConfigHandle::new(config(), ConfigOptions::default()),
+                reqwest::Client::new(),
+                Arc::new(HostSchemes::default()),
+            )
+            .allowing_local();
+
+        let delivered = attempt(&ck, &delivery, &worker, &format!("{inbox}/202")).await;
+        assert!(!delivered.is_not_responding);
+        assert_eq!(delivered.latest_status, Some(202));
+        assert!(deliveries(&ck).await.unwrap().is_empty());
+
+        let unavailable = attempt(&ck, &delivery, &worker, &format!("{inbox}/503")).await;
+        assert!(unavailable.is_not_responding);
+        assert_eq!(unavailable.latest_status, Some(503));
+
+        let rescheduled = deliveries(&ck).await.unwrap().pop().unwrap();
+        assert_eq!(rescheduled.attempts, 1);
+        assert_eq!(rescheduled.dead_at, None);
+        assert_eq!(rescheduled.locked_until, None);
+        assert!(rescheduled.next_attempt_at > Utc::now());
+        assert!(rescheduled.last_error.unwrap().contains("503"));
+
+        // A refusal is a response, the instance is up again
+        let refused = attempt(&ck, &delivery, &worker, &format!("{inbox}/403")).await;
+        assert!(!refused.is_not_responding);
+        assert_eq!(refused.latest_status, Some(403));
+
+        let dead = deliveries(&ck).await.unwrap().pop().unwrap();
+        assert_eq!(dead.inbox, format!("{inbox}/403"));
+        assert_eq!(dead.attempts, 1);
+        assert!(dead.dead_at.is_some());
+
+        assert_eq!(received.lock().unwrap().len(), 3);
+        assert!(received.lock().unwrap().iter().all(|(signed, _)| *signed));
+    }
+
+    #[test]
+    fn should_back_off_exponentially() {
+        assert_eq!(backoff(0), Duration::minutes(1));
+        assert_eq!(backoff(1), Duration::minutes(2));
+        assert_eq!(backoff(5), Duration::minutes(32));
+        assert_eq!(backoff(9), Duration::from_std(BACKOFF_MAX).unwrap());
+        assert_eq!(backoff(i32::MAX), Duration::from_std(BACKOFF_MAX).unwrap());
+    }
+
+    #[test]
+    fn should_dead_letter_expired_deliveries() {
+        let created_at = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+        let retry = DeliveryOutcome::Retry {
+            status: Some(503),
+            error: "status 503".to_owned(),
+        };
+        let failed = DeliveryOutcome::Failed {
+            status: Some(410),
+            error: "status 410".to_owned(),
+        };
+
+        assert_eq!(
+            next_step(created_at, 0, created_at, &DeliveryOutcome::Delivered(200)),
+            NextStep::Done
+        );
+        assert_eq!(
+            next_step(created_at, 0, created_at, &retry),
+            NextStep::RetryAt(created_at + Duration::minutes(1))
+        );
+        assert_eq!(
+            next_step(created_at, 0, created_at, &failed),
+            NextStep::DeadLetter
+        );
+
+        let later = created_at + Duration::days(6) + Duration::hours(20);
+        assert_eq!(
+            next_step(created_at, 40, later, &retry),
+            NextStep::DeadLetter
+        );
+    }
+
+    #[test]
+    fn should_limit_deliveries_per_host() {
+        let limits = HostLimits::new(HOST_CONCURRENCY);
+
+        let permits = (0..HOST_CONCURRENCY)
+            .map(|_| limits.try_acquire("remote.example").unwrap())
+            .collect::<Vec<_>>();
+
+        assert!(limits.try_acquire("remote.example").is_none());
+        assert!(limits.try_acquire("other.example").is_some());
+        assert_eq!(limits.busy_hosts(), vec!["remote.example"]);
+
+        drop(permits);
+
+        assert!(limits.busy_hosts().is_empty());
+        assert!(limits.try_acquire("remote.example").is_some());
+    }
+
+    #[test]
+    fn should_deduplicate_inboxes() {
+        let targets = delivery_targets([
+            "https://remote.example/inbox",
+            "https://other.example:8443/inbox",
+            "https://remote.example/inbox",
+            "not an inbox",
+        ]);
+
+        assert_eq!(
+            targets,
+            vec![
+                (
+                    "https://other.example:8443/inbox".to_owned(),
+                    "other.example:8443".to_owned()
+                ),
+                (
+                    "https://remote.example/inbox".to_owned(),
+                    "remote.example".to_owned()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,390 @@
+use crate::config::{ConfigHandle, MagnetarNetworkingProtocol};
+use axum::async_trait;
+use axum::extract::connect_info::Connected;
+use axum::extract::{ConnectInfo, FromRequestParts, State};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use hyper::server::conn::AddrStream;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::UnixStream;
+use tracing::{debug_span, error, Span};
+
+/// The directly connected peer of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// Unix domain sockets are only reachable by local processes, peers are always trusted
+    Unix,
+}
+
+impl Connected<&AddrStream> for PeerAddr {
+    fn connect_info(target: &AddrStream) -> Self {
+        PeerAddr::Tcp(target.remote_addr())
+    }
+}
+
+impl Connected<&UnixStream> for PeerAddr {
+    fn connect_info(_target: &UnixStream) -> Self {
+        PeerAddr::Unix
+    }
+}
+
+/// The originating client of a request, as reported by trusted reverse proxies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// `None` when the request came through a Unix socket without forwarding headers
+    pub ip: Option<IpAddr>,
+    pub scheme: MagnetarNetworkingProtocol,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<ClientInfo>()
+            .cloned()
+            .ok_or_else(|| {
+                error!("Client info requested without the client info middleware");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })
+    }
+}
+
+/// One proxy hop, the address it received the request from and with which scheme
+#[derive(Debug, Default)]
+struct Hop {
+    addr: Option<IpAddr>,
+    scheme: Option<MagnetarNetworkingProtocol>,
+}
+
+/// IPv4 clients of dual-stack listeners appear as IPv4-mapped IPv6 addresses
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Parses a node like `192.0.2.43`, `"[2001:db8:cafe::17]:4711"` or `198.51.100.17:80`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    if let Ok(ip) = node.parse() {
+        return Some(canonical_ip(ip));
+    }
+
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(canonical_ip(addr.ip()));
+    }
+
+    node.strip_prefix('[')
+        .and_then(|node| node.strip_suffix(']'))
+        .and_then(|node| node.parse().ok())
+        .map(canonical_ip)
+}
+
+fn parse_scheme(scheme: &str) -> Option<MagnetarNetworkingProtocol> {
+    match scheme
+        .trim()
+        .trim_matches('"')
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "http" => Some(MagnetarNetworkingProtocol::Http),
+        "https" => Some(MagnetarNetworkingProtocol::Https),
+        _ => None,
+    }
+}
+
+/// All comma-separated values of a header, across repeated header lines
+fn header_list<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// Hops of the RFC 7239 `Forwarded` header
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Hop> {
+    header_list(headers, "forwarded")
+        .into_iter()
+        .map(|element| {
+            let mut hop = Hop::default();
+
+            for pair in element.split(';') {
+                match pair.split_once('=') {
+                    Some((key, value)) if key.trim().eq_ignore_ascii_case("for") => {
+                        hop.addr = parse_node(value)
+                    }
+                    Some((key, value)) if key.trim().eq_ignore_ascii_case("proto") => {
+                        hop.scheme = parse_scheme(value)
+                    }
+                    _ => {}
+                }
+            }
+
+            hop
+        })
+        .collect()
+}
+
+/// Hops of the `X-Forwarded-For` and `X-Forwarded-Proto` headers
+fn x_forwarded_hops(headers: &HeaderMap) -> Vec<Hop> {
+    let addrs = header_list(headers, "x-forwarded-for");
+    let schemes = header_list(headers, "x-forwarded-proto");
+
+    addrs
+        .iter()
+        .enumerate()
+        .map(|(i, addr)| Hop {
+            addr: parse_node(addr),
+            // Without one scheme per hop, the first one was set by the outermost proxy
+            scheme: if schemes.len() == addrs.len() {
+                schemes.get(i)
+            } else {
+                schemes.first()
+            }
+            .and_then(|scheme| parse_scheme(scheme)),
+        })
+        .collect()
+}
+
+/// Walks the proxy chain from the nearest hop, until reaching an address that is not trusted
+pub fn resolve_client(
+    peer: PeerAddr,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpNet],
+) -> ClientInfo {
+    let is_trusted = |ip: Option<IpAddr>| match ip {
+        Some(ip) => trusted_proxies.iter().any(|net| net.contains(&ip)),
+        None => true,
+    };
+
+    let mut client = ClientInfo {
+        ip: match peer {
+            PeerAddr::Tcp(addr) => Some(canonical_ip(addr.ip())),
+            PeerAddr::Unix => None,
+        },
+        scheme: MagnetarNetworkingProtocol::Http,
+    };
+
+    if !is_trusted(client.ip) {
+        return client;
+    }
+
+    let hops = match forwarded_hops(headers) {
+        hops if hops.is_empty() => x_forwarded_hops(headers),
+        hops => hops,
+    };
+
+    for hop in hops.iter().rev() {
+        // Obfuscated or unknown nodes end the chain at the last known proxy
+        let Some(addr) = hop.addr else {
+            break;
+        };
+
+        client.ip = Some(addr);
+
+        if let Some(scheme) = hop.scheme {
+            client.scheme = scheme;
+        }
+
+        if !is_trusted(client.ip) {
+            break;
+        }
+    }
+
+    client
+}
+
+/// Resolves the [`ClientInfo`] of every request, for handlers and the trace span
+pub async fn client_info_middleware<B>(
+    State(config): State<ConfigHandle>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let client = resolve_client(
+        peer,
+        req.headers(),
+        &config.load().networking.trusted_proxies,
+    );
+
+    req.extensions_mut().insert(client);
+
+    next.run(req).await
+}
+
+/// The default `TraceLayer` span, with the resolved client attached
+pub fn make_request_span<B>(req: &Request<B>) -> Span {
+    let client = req.extensions().get::<ClientInfo>();
+
+    debug_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        version = ?req.version(),
+        client_ip = ?client.and_then(|client| client.ip),
+        scheme = ?client.map(|client| client.scheme),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{ConfigHandle, ConfigLayers, ConfigOptions, MagnetarNetworkingProtocol};
+    use crate::forwarded::{client_info_middleware, resolve_client, ClientInfo, PeerAddr};
+    use axum::extract::connect_info::MockConnectInfo;
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use hyper::{Body, HeaderMap, Request};
+    use ipnet::IpNet;
+    use std::net::IpAddr;
+    use tower::ServiceExt;
+
+    fn trusted() -> Vec<IpNet> {
+        vec![
+            "127.0.0.0/8".parse().unwrap(),
+            "10.0.0.0/8".parse().unwrap(),
+        ]
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    fn tcp(addr: &str) -> PeerAddr {
+        PeerAddr::Tcp(addr.parse().unwrap())
+    }
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn should_ignore_headers_from_untrusted_peers() {
+        let client = resolve_client(
+            tcp("203.0.113.5:1234"),
+            &headers(&[
+                ("x-forwarded-for", "198.51.100.1"),
+                ("x-forwarded-proto", "https"),
+            ]),
+            &trusted(),
+        );
+
+        assert_eq!(
+            client,
+            ClientInfo {
+                ip: ip("203.0.113.5"),
+                scheme: MagnetarNetworkingProtocol::Http
+            }
+        );
+    }
+
+    #[test]
+    fn should_stop_at_first_untrusted_hop() {
+        let client = resolve_client(
+            tcp("127.0.0.1:1234"),
+            &headers(&[
+                ("x-forwarded-for", "192.0.2.66, 198.51.100.1, 10.1.2.3"),
+                ("x-forwarded-proto", "https"),
+            ]),
+            &trusted(),
+        );
+
+        assert_eq!(
+            client,
+            ClientInfo {
+                ip: ip("198.51.100.1"),
+                scheme: MagnetarNetworkingProtocol::Https
+            }
+        );
+    }
+
+    #[test]
+    fn should_prefer_forwarded_header() {
+        let client = resolve_client(
+            tcp("[::ffff:127.0.0.1]:1234"),
+            &headers(&[
+                (
+                    "forwarded",
+                    r#"for="[2001:db8:cafe::17]:4711";proto=https, for=10.0.0.2;proto=http"#,
+                ),
+                ("x-forwarded-for", "192.0.2.66"),
+            ]),
+            &trusted(),
+        );
+
+        assert_eq!(
+            client,
+            ClientInfo {
+                ip: ip("2001:db8:cafe::17"),
+                scheme: MagnetarNetworkingProtocol::Https
+            }
+        );
+    }
+
+    #[test]
+    fn should_trust_unix_socket_peers() {
+        let client = resolve_client(
+            PeerAddr::Unix,
+            &headers(&[("forwarded", "for=_hidden, for=192.0.2.43;proto=https")]),
+            &[],
+        );
+
+        assert_eq!(
+            client,
+            ClientInfo {
+                ip: ip("192.0.2.43"),
+                scheme: MagnetarNetworkingProtocol::Https
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn should_extract_client_info() {
+        let mut layers = ConfigLayers::default();
+        layers.add_overrides(&[
+            ("networking.host".to_owned(), "example.com".to_owned()),
+            ("data.database_url".to_owned(), "postgres://db".to_owned()),
+        ]);
+        let config = ConfigHandle::new(layers.into_config().unwrap(), ConfigOptions::default());
+
+        let app = Router::new()
+            .route(
+                "/",
+                get(|client: ClientInfo| async move { format!("{:?}", client.ip) }),
+            )
+            .layer(middleware::from_fn_with_state(
+                config,
+                client_info_middleware,
+            ))
+            .layer(MockConnectInfo(tcp("127.0.0.1:1234")));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header("x-forwarded-for", "192.0.2.1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"Some(192.0.2.1)");
+    }
+}
@@ -0,0 +1,159 @@
+use crate::config::{ConfigHandle, MagnetarConfig};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use hyper::header;
+use magnetar_core::web_model::content_type::{ContentJrdJson, ContentXrdXml};
+use magnetar_core::web_model::rel::RelLrdd;
+use magnetar_core::web_model::Rel;
+use magnetar_webfinger::host_meta::{HostMeta, Xrd, XrdLink};
+use magnetar_webfinger::webfinger::WebFingerRel;
+use tracing::error;
+
+fn lrdd_template(config: &MagnetarConfig) -> String {
+    format!(
+        "{}://{}/.well-known/webfinger?resource={{uri}}",
+        config.networking.protocol, config.networking.host
+    )
+}
+
+pub async fn handle_host_meta(
+    State(config): State<ConfigHandle>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let config = &*config.load();
+
+    let xrd = Xrd::new(vec![XrdLink {
+        rel: RelLrdd.rel().to_owned(),
+        content_type: Some(ContentJrdJson.as_ref().to_owned()),
+        href: None,
+        template: Some(lrdd_template(config)),
+    }]);
+
+    let xml = xrd.to_xml().map_err(|e| {
+        error!("Failed to serialize host-meta: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(([(header::CONTENT_TYPE, ContentXrdXml.as_ref())], xml))
+}
+
+pub async fn handle_host_meta_json(State(config): State<ConfigHandle>) -> impl IntoResponse {
+    let config = &*config.load();
+
+    (
+        [(header::CONTENT_TYPE, ContentJrdJson.as_ref())],
+        Json(HostMeta {
+            properties: None,
+            links: vec![WebFingerRel::RelOther {
+                rel: RelLrdd.rel().to_owned(),
+                content_type: Some(ContentJrdJson.as_ref().to_owned()),
+                href: None,
+                template: Some(lrdd_template(config)),
+                titles: None,
+                properties: None,
+            }],
+        }),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{ConfigHandle, ConfigLayers, ConfigOptions, MagnetarConfig};
+    use crate::host_meta::{handle_host_meta, handle_host_meta_json};
+    use axum::body::Body;
+    use axum::http::{header, Request, StatusCode};
+    use axum::routing::get;
+    use axum::Router;
+    use magnetar_webfinger::host_meta::{HostMeta, Xrd, XrdLink};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    fn config() -> MagnetarConfig {
+        let mut layers = ConfigLayers::default();
+        layers.add_overrides(&[
+            (
+                "networking.host".to_owned(),
+                "social.example.org:8080".to_owned(),
+            ),
+            ("networking.protocol".to_owned(), "http".to_owned()),
+            ("data.database_url".to_owned(), "postgres://db".to_owned()),
+        ]);
+        layers.into_config().unwrap()
+    }
+
+    fn app() -> Router {
+        let config = ConfigHandle::new(config(), ConfigOptions::default());
+
+        Router::new()
+            .route("/.well-known/host-meta", get(handle_host_meta))
+            .route("/.well-known/host-meta.json", get(handle_host_meta_json))
+            .with_state(config)
+    }
+
+    async fn get_host_meta(uri: &str) -> (StatusCode, String, String) {
+        let response = app()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let content_type = response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    const TEMPLATE: &str = "http://social.example.org:8080/.well-known/webfinger?resource={uri}";
+
+    #[tokio::test]
+    async fn should_serve_xrd() {
+        let (status, content_type, body) = get_host_meta("/.well-known/host-meta").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "application/xrd+xml");
+        assert!(body.starts_with(r#"<?xml version="1.0" encoding="UTF-8"?>"#));
+
+        let xrd = Xrd::from_xml(&body).unwrap();
+        assert_eq!(xrd.xmlns, "http://docs.oasis-open.org/ns/xri/xrd-1.0");
+        assert_eq!(
+            xrd.links,
+            vec![XrdLink {
+                rel: "lrdd".to_owned(),
+                content_type: Some("application/jrd+json".to_owned()),
+                href: None,
+                template: Some(TEMPLATE.to_owned()),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_serve_jrd() {
+        let (status, content_type, body) = get_host_meta("/.well-known/host-meta.json").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "application/jrd+json");
+
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "links": [{
+                    "rel": "lrdd",
+                    "type": "application/jrd+json",
+                    "template": TEMPLATE,
+                }]
+            })
+        );
+
+        let host_meta: HostMeta = serde_json::from_str(&body).unwrap();
+        assert_eq!(host_meta.lrdd_template(), Some(TEMPLATE));
+    }
+}
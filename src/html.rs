@@ -0,0 +1,156 @@
+use crate::config::MagnetarConfig;
+use axum::response::Html;
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Plain text as a paragraph, for software that only displays HTML
+pub fn text_to_html(text: &str) -> String {
+    format!("<p>{}</p>", escape_html(text).replace('\n', "<br>"))
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let code = entity.strip_prefix('#')?;
+            let code = match code.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => code.parse().ok()?,
+            };
+
+            char::from_u32(code)
+        }
+    }
+}
+
+/// Plain text of the HTML content of remote objects
+///
+/// Line breaks and paragraphs become newlines, every other tag is dropped.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(c) = rest.chars().next() {
+        let starts_tag =
+            rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '/' || c == '!');
+
+        match c {
+            '<' if starts_tag => {
+                let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+                let tag = rest[1..end].trim_start_matches('/').to_ascii_lowercase();
+                let name = tag
+                    .split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+                    .next()
+                    .unwrap_or_default();
+
+                match name {
+                    "br" => text.push('\n'),
+                    "p" if rest.starts_with("</") => text.push_str("\n\n"),
+                    _ => {}
+                }
+
+                rest = &rest[end..];
+            }
+            '&' => {
+                let decoded = rest
+                    .find(';')
+                    .filter(|end| *end <= 10)
+                    .and_then(|end| Some((decode_entity(&rest[1..end])?, end)));
+
+                match decoded {
+                    Some((c, end)) => {
+                        text.push(c);
+                        rest = &rest[end + 1..];
+                    }
+                    None => {
+                        text.push('&');
+                        rest = &rest[1..];
+                    }
+                }
+            }
+            c => {
+                text.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+
+    text.trim_end().to_owned()
+}
+
+/// A minimal page for browsers, pointing to the ActivityPub representation of the same object
+pub struct HtmlPage<'a> {
+    pub title: &'a str,
+    pub description: Option<&'a str>,
+    pub image: Option<&'a str>,
+    pub url: &'a str,
+    pub activity_pub_id: &'a str,
+    /// Already escaped HTML of the page body
+    pub body: String,
+}
+
+pub fn render_page(config: &MagnetarConfig, page: HtmlPage) -> Html<String> {
+    let title = escape_html(page.title);
+    let url = escape_html(page.url);
+    let mut head = format!(
+        r#"<meta property="og:site_name" content="{}"><meta property="og:title" content="{title}"><meta property="og:url" content="{url}"><link rel="alternate" type="application/activity+json" href="{}">"#,
+        escape_html(&config.networking.host),
+        escape_html(page.activity_pub_id),
+    );
+
+    if let Some(description) = page.description {
+        let description = escape_html(description);
+        head.push_str(&format!(
+            r#"<meta name="description" content="{description}"><meta property="og:description" content="{description}">"#
+        ));
+    }
+
+    if let Some(image) = page.image {
+        head.push_str(&format!(
+            r#"<meta property="og:image" content="{}">"#,
+            escape_html(image)
+        ));
+    }
+
+    Html(format!(
+        r#"<!DOCTYPE html><html><head><meta charset="utf-8"><title>{title}</title>{head}</head><body><main>{}</main></body></html>"#,
+        page.body
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::html::{html_to_text, text_to_html};
+
+    #[test]
+    fn should_convert_html_to_text() {
+        assert_eq!(
+            html_to_text(
+                r#"<p>Hello <a href="https://example.com/@bob" class="u-url mention">@<span>bob</span></a>!</p><p>Line 1<br>Line 2<br />&lt;3 &amp; &#x1F980;</p>"#
+            ),
+            "Hello @bob!\n\nLine 1\nLine 2\n<3 & \u{1f980}"
+        );
+        assert_eq!(html_to_text("AT&T &unknown; 1 < 2"), "AT&T &unknown; 1 < 2");
+        assert_eq!(html_to_text(&text_to_html("a\n<b>")), "a\n<b>");
+    }
+}
@@ -0,0 +1,346 @@
+use crate::activity_pub::local_id;
+use crate::config::ConfigHandle;
+use crate::html::html_to_text;
+use crate::inbox::{canonical, InboxError};
+use crate::signature::signer::authority;
+use magnetar_calckey_model::ck::user;
+use magnetar_calckey_model::{CalckeyModel, RemoteUser};
+use magnetar_core::web_model::content_type::{
+    ContentActivityStreams, ContentLdJsonActivityStreams,
+};
+use magnetar_webfinger::client::HttpTransport;
+use serde_json::{json, Value};
+use url::Url;
+
+const ACTOR_TYPES: &[&str] = &["Person", "Service", "Application", "Group", "Organization"];
+
+fn str_of<'a>(document: &'a Value, key: &str) -> Option<&'a str> {
+    document.get(key)?.as_str()
+}
+
+/// The IRI of a property given either as an IRI or as an embedded object
+fn iri_of<'a>(document: &'a Value, key: &str) -> Option<&'a str> {
+    match document.get(key)? {
+        Value::String(iri) => Some(iri),
+        Value::Array(values) => values.iter().find_map(|value| match value {
+            Value::String(iri) => Some(iri.as_str()),
+            value => str_of(value, "id").or_else(|| str_of(value, "href")),
+        }),
+        value => str_of(value, "id").or_else(|| str_of(value, "href")),
+    }
+}
+
+fn values_of<'a>(document: &'a Value, key: &str) -> Vec<&'a Value> {
+    match document.get(key) {
+        Some(Value::Array(values)) => values.iter().collect(),
+        Some(value) => vec![value],
+        None => Vec::new(),
+    }
+}
+
+fn tag_names(document: &Value, kind: &str, trim: &[char]) -> Vec<String> {
+    values_of(document, "tag")
+        .into_iter()
+        .filter(|tag| str_of(tag, "type") == Some(kind))
+        .filter_map(|tag| str_of(tag, "name"))
+        .map(|name| name.trim_matches(trim).to_owned())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// `alsoKnownAs` of an actor document
+pub fn also_known_as(document: &Value) -> Vec<&str> {
+    values_of(document, "alsoKnownAs")
+        .into_iter()
+        .filter_map(Value::as_str)
+        .collect()
+}
+
+/// Everything stored about a remote user from their actor document
+pub fn remote_user(document: &Value) -> Result<RemoteUser, InboxError> {
+    let malformed = |e: &str| InboxError::Malformed(e.to_owned());
+
+    let uri = str_of(document, "id").ok_or_else(|| malformed("actor without an id"))?;
+    let kind = str_of(document, "type").unwrap_or_default();
+
+    if !ACTOR_TYPES.contains(&kind) {
+        return Err(InboxError::Malformed(format!("{uri} is not an actor")));
+    }
+
+    let host = Url::parse(uri)
+        .ok()
+        .as_ref()
+        .and_then(authority)
+        .ok_or_else(|| InboxError::Malformed(format!("{uri} has no host")))?;
+
+    let username = str_of(document, "preferredUsername")
+        .filter(|username| !username.is_empty())
+        .ok_or_else(|| InboxError::Malformed(format!("{uri} has no preferredUsername")))?;
+
+    let public_key = values_of(document, "publicKey")
+        .into_iter()
+        .find(|key| str_of(key, "owner").is_none_or(|owner| owner == uri))
+        .and_then(|key| Some((str_of(key, "id")?, str_of(key, "publicKeyPem")?)))
+        .map(|(id, pem)| (id.to_owned(), pem.to_owned()));
+
+    // Key IDs are unique, a key claimed for another server would shadow the keys of its actors
+    if let Some((ref key_id, _)) = public_key {
+        let key_host = Url::parse(key_id).ok().as_ref().and_then(authority);
+
+        if key_host.as_deref() != Some(host.as_str()) {
+            return Err(InboxError::Malformed(format!(
+                "{uri} has the key {key_id} of another host"
+            )));
+        }
+    }
+
+    let fields = values_of(document, "attachment")
+        .into_iter()
+        .filter(|field| str_of(field, "type") == Some("PropertyValue"))
+        .filter_map(|field| {
+            Some(json!({
+                "name": str_of(field, "name")?,
+                "value": html_to_text(str_of(field, "value")?),
+            }))
+        })
+        .collect::<Vec<_>>();
+
+    let description = str_of(document, "_misskey_summary")
+        .map(str::to_owned)
+        .or_else(|| str_of(document, "summary").map(html_to_text))
+        .filter(|description| !description.is_empty());
+
+    let also_known_as = also_known_as(document);
+
+    Ok(RemoteUser {
+        uri: uri.to_owned(),
+        username: username.to_owned(),
+        host,
+        name: str_of(document, "name")
+            .filter(|name| !name.is_empty())
+            .map(str::to_owned),
+        inbox: iri_of(document, "inbox").map(str::to_owned),
+        shared_inbox: document
+            .get("endpoints")
+            .and_then(|endpoints| str_of(endpoints, "sharedInbox"))
+            .or_else(|| str_of(document, "sharedInbox"))
+            .map(str::to_owned),
+        featured: iri_of(document, "featured").map(str::to_owned),
+        followers_uri: iri_of(document, "followers").map(str::to_owned),
+        is_locked: document
+            .get("manuallyApprovesFollowers")
+            .and_then(Value::as_bool)
+            .unwrap_or_default(),
+        is_bot: matches!(kind, "Service" | "Application"),
+        is_cat: document
+            .get("isCat")
+            .and_then(Value::as_bool)
+            .unwrap_or_default(),
+        is_explorable: document
+            .get("discoverable")
+            .and_then(Value::as_bool)
+            .unwrap_or(true),
+        tags: tag_names(document, "Hashtag", &['#'])
+            .into_iter()
+            .map(|tag| tag.to_lowercase())
+            .collect(),
+        emojis: tag_names(document, "Emoji", &[':']),
+        moved_to_uri: iri_of(document, "movedTo").map(str::to_owned),
+        also_known_as: (!also_known_as.is_empty()).then(|| also_known_as.join(",")),
+        description,
+        url: iri_of(document, "url").map(str::to_owned),
+        fields: Value::Array(fields),
+        birthday: str_of(document, "vcard:bday").map(str::to_owned),
+        location: str_of(document, "vcard:Address").map(str::to_owned),
+        public_key,
+    })
+}
+
+/// Fetches remote objects and keeps the remote users known to the database
+pub struct RemoteActors<T: HttpTransport> {
+    config: ConfigHandle,
+    ck: CalckeyModel,
+    transport: T,
+}
+
+impl<T: HttpTransport> RemoteActors<T> {
+    pub fn new(config: ConfigHandle, ck: CalckeyModel, transport: T) -> Self {
+        RemoteActors {
+            config,
+            ck,
+            transport,
+        }
+    }
+
+    /// Fetches an object from its origin, it must be served under the requested IRI
+    pub async fn fetch(&self, iri: &str) -> Result<Value, InboxError> {
+        let error = |e: String| InboxError::Fetch(iri.to_owned(), e);
+        let accept = format!(
+            "{}, {}",
+            ContentActivityStreams.as_ref(),
+            ContentLdJsonActivityStreams.as_ref()
+        );
+
+        let response = self
+            .transport
+            .get(iri, &accept)
+            .await
+            .map_err(|e| error(e.to_string()))?;
+
+        if !response.is_success() {
+            return Err(error(format!("status {}", response.status)));
+        }
+
+        let document = canonical(
+            serde_json::from_slice::<Value>(&response.body).map_err(|e| error(e.to_string()))?,
+        )?;
+
+        if str_of(&document, "id") != Some(iri) {
+            return Err(error("the object has another id".to_owned()));
+        }
+
+        Ok(document)
+    }
+
+    /// Looks up a user by the IRI of their actor, fetching and storing unknown remote actors
+    pub async fn resolve(&self, iri: &str) -> Result<user::Model, InboxError> {
+        let config = self.config.load();
+
+        if let Some(id) = local_id(&config, iri, "users") {
+            return self
+                .ck
+                .get_user_by_id(id)
+                .await?
+                .ok_or_else(|| InboxError::Malformed(format!("unknown local user {iri}")));
+        }
+
+        if let Some(user) = self.ck.get_user_by_uri(iri).await? {
+            return Ok(user);
+        }
+
+        let is_local = Url::parse(iri)
+            .ok()
+            .and_then(|url| authority(&url))
+            .is_some_and(|host| host == config.networking.host);
+
+        if is_local {
+            return Err(InboxError::Malformed(format!("unknown local actor {iri}")));
+        }
+
+        let document = self.fetch(iri).await?;
+
+        Ok(self.ck.insert_remote_user(remote_user(&document)?).await?)
+    }
+
+    /// Replaces a stored remote user with their current actor document
+    pub async fn update(&self, user: &user::Model, document: &Value) -> Result<(), InboxError> {
+        let remote = remote_user(document)?;
+
+        if user.host.is_none() || user.uri.as_deref() != Some(remote.uri.as_str()) {
+            return Err(InboxError::Forbidden(
+                remote.uri,
+                "update another actor".to_owned(),
+            ));
+        }
+
+        Ok(self.ck.update_remote_user(&user.id, remote).await?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::inbox::actors::remote_user;
+    use serde_json::json;
+
+    #[test]
+    fn should_read_remote_user() {
+        let actor = json!({
+            "id": "https://example.com:8443/users/alice",
+            "type": "Service",
+            "preferredUsername": "Alice",
+            "name": "Alice",
+            "summary": "<p>Hello &amp; welcome</p>",
+            "inbox": "https://example.com:8443/users/alice/inbox",
+            "followers": "https://example.com:8443/users/alice/followers",
+            "endpoints": { "sharedInbox": "https://example.com:8443/inbox" },
+            "manuallyApprovesFollowers": true,
+            "url": "https://example.com:8443/@alice",
+            "tag": [
+                { "type": "Hashtag", "name": "#Rust" },
+                { "type": "Emoji", "name": ":blobcat:" }
+            ],
+            "attachment": [
+                { "type": "PropertyValue", "name": "Site", "value": "<a href=\"https://alice.example\">alice.example</a>" }
+            ],
+            "alsoKnownAs": ["https://old.example/users/alice", "https://older.example/users/alice"],
+            "publicKey": {
+                "id": "https://example.com:8443/users/alice#main-key",
+                "owner": "https://example.com:8443/users/alice",
+                "publicKeyPem": "-----BEGIN PUBLIC KEY-----\n...\n-----END PUBLIC KEY-----\n"
+            }
+        });
+
+        let user = remote_user(&actor).unwrap();
+
+        assert_eq!(user.username, "Alice");
+        assert_eq!(user.host, "example.com:8443");
+        assert_eq!(user.description.as_deref(), Some("Hello & welcome"));
+        assert_eq!(
+            user.shared_inbox.as_deref(),
+            Some("https://example.com:8443/inbox")
+        );
+        assert!(user.is_locked);
+        assert!(user.is_bot);
+        assert!(user.is_explorable);
+        assert_eq!(user.tags, vec!["rust"]);
+        assert_eq!(user.emojis, vec!["blobcat"]);
+        assert_eq!(
+            user.fields,
+            json!([{ "name": "Site", "value": "alice.example" }])
+        );
+        assert_eq!(
+            user.also_known_as.as_deref(),
+            Some("https://old.example/users/alice,https://older.example/users/alice")
+        );
+        assert_eq!(
+            user.public_key.unwrap().0,
+            "https://example.com:8443/users/alice#main-key"
+        );
+    }
+
+    #[test]
+    fn should_reject_keys_of_other_hosts() {
+        let actor = |key_id: &str| {
+            json!({
+                "id": "https://example.com/users/alice",
+                "type": "Person",
+                "preferredUsername": "alice",
+                "publicKey": {
+                    "id": key_id,
+                    "owner": "https://example.com/users/alice",
+                    "publicKeyPem": "-----BEGIN PUBLIC KEY-----\n...\n-----END PUBLIC KEY-----\n"
+                }
+            })
+        };
+
+        assert!(remote_user(&actor("https://example.com/users/alice#main-key")).is_ok());
+        assert!(remote_user(&actor("https://victim.example/users/bob#main-key")).is_err());
+        assert!(remote_user(&actor("https://example.com:8443/users/alice#main-key")).is_err());
+    }
+
+    #[test]
+    fn should_reject_non_actors() {
+        assert!(remote_user(&json!({
+            "id": "https://example.com/notes/1",
+            "type": "Note",
+            "preferredUsername": "alice"
+        }))
+        .is_err());
+
+        assert!(remote_user(&json!({
+            "id": "https://example.com/users/alice",
+            "type": "Person"
+        }))
+        .is_err());
+    }
+}
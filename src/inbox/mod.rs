@@ -0,0 +1,445 @@
+pub mod actors;
+pub mod notes;
+pub mod process;
+
+use crate::actor::is_served;
+use crate::signature::VerifiedSignature;
+use crate::util::data_error;
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use magnetar_calckey_model::inbox_job;
+use magnetar_calckey_model::CalckeyModel;
+use magnetar_core::web_model::activity_streams::context::compact_to_canonical;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::Notify;
+use tracing::{debug, error};
+
+/// How many activities may wait for processing before the inbox asks senders to retry later
+pub const QUEUE_CAPACITY: u64 = 1024;
+/// How long the IDs of received activities are remembered to drop duplicates
+const DEDUP_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+/// Expired IDs are only pruned once this many are remembered
+const DEDUP_PRUNE_THRESHOLD: usize = 16 * 1024;
+
+#[derive(Debug, Error)]
+pub enum InboxError {
+    #[error("Malformed activity: {0}")]
+    Malformed(String),
+    #[error("Failed to fetch {0}: {1}")]
+    Fetch(String, String),
+    #[error("{0} is not allowed to {1}")]
+    Forbidden(String, String),
+    #[error("Data error: {0}")]
+    Data(#[from] anyhow::Error),
+}
+
+/// An activity accepted by an inbox, waiting to be processed
+#[derive(Clone, Debug, PartialEq)]
+pub struct InboxActivity {
+    pub activity: Value,
+    /// The actor whose key signed the delivery
+    pub signer: String,
+}
+
+impl From<inbox_job::Model> for InboxActivity {
+    fn from(job: inbox_job::Model) -> Self {
+        InboxActivity {
+            activity: job.activity,
+            signer: job.signer,
+        }
+    }
+}
+
+/// Rewrites a received document to the terms of Magnetar's context
+///
+/// Documents may name properties and types after any prefix their context defines, like
+/// `toot:discoverable`, while the processor only reads Magnetar's terms.
+pub fn canonical(mut document: Value) -> Result<Value, InboxError> {
+    compact_to_canonical(&mut document).map_err(|e| InboxError::Malformed(e.to_string()))?;
+    Ok(document)
+}
+
+/// IDs of recently received activities, along with the actor that signed them
+///
+/// IDs are only duplicates when signed by the same actor, otherwise anyone could
+/// send the IDs of activities of other servers first to have them dropped.
+#[derive(Debug, Default)]
+pub struct RecentActivities(Mutex<HashMap<(String, String), Instant>>);
+
+impl RecentActivities {
+    /// Remembers an activity, returns `false` if `signer` already delivered it
+    pub fn insert(&self, signer: &str, id: &str, now: Instant) -> bool {
+        let mut recent = self.0.lock().unwrap();
+
+        if recent.len() >= DEDUP_PRUNE_THRESHOLD {
+            recent.retain(|_, received| now.duration_since(*received) < DEDUP_TTL);
+        }
+
+        let key = (signer.to_owned(), id.to_owned());
+
+        match recent.get(&key) {
+            Some(received) if now.duration_since(*received) < DEDUP_TTL => false,
+            _ => {
+                recent.insert(key, now);
+                true
+            }
+        }
+    }
+
+    /// Forgets an activity that could not be queued, so a retry is not dropped
+    pub fn remove(&self, signer: &str, id: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .remove(&(signer.to_owned(), id.to_owned()));
+    }
+}
+
+/// The receiving end of the shared and personal inboxes
+///
+/// Accepted activities are stored in the database before they are acknowledged, and
+/// processed by the [`InboxProcessor`](process::InboxProcessor).
+#[derive(Debug)]
+pub struct Inbox {
+    ck: CalckeyModel,
+    capacity: u64,
+    recent: RecentActivities,
+    wake: Arc<Notify>,
+}
+
+impl Inbox {
+    pub fn new(ck: CalckeyModel, capacity: u64) -> Self {
+        Inbox {
+            ck,
+            capacity,
+            recent: RecentActivities::default(),
+            wake: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Notified whenever an activity was queued
+    pub fn wake(&self) -> Arc<Notify> {
+        self.wake.clone()
+    }
+
+    /// Stores an activity unless the queue is full, returns whether it was stored
+    async fn enqueue(&self, signer: &str, activity: &Value) -> anyhow::Result<bool> {
+        if self.ck.count_inbox_activities().await? >= self.capacity {
+            return Ok(false);
+        }
+
+        self.ck.enqueue_inbox_activity(signer, activity).await?;
+
+        Ok(true)
+    }
+
+    /// Queues a delivered activity for processing
+    pub async fn accept(&self, signature: VerifiedSignature, body: &[u8]) -> StatusCode {
+        let Ok(activity) = serde_json::from_slice::<Value>(body) else {
+            return StatusCode::BAD_REQUEST;
+        };
+
+        if activity.get("type").and_then(Value::as_str).is_none() {
+            return StatusCode::BAD_REQUEST;
+        }
+
+        // Forwarded activities are not verified, they must be fetched from their origin instead
+        if actor_id(&activity) != Some(signature.owner.as_str()) {
+            debug!(
+                "Rejecting an activity of {:?} signed by {}",
+                actor_id(&activity),
+                signature.owner
+            );
+            return StatusCode::UNAUTHORIZED;
+        }
+
+        let id = activity
+            .get("id")
+            .and_then(Value::as_str)
+            .map(str::to_owned);
+
+        if let Some(ref id) = id {
+            if !self.recent.insert(&signature.owner, id, Instant::now()) {
+                debug!("Dropping the duplicate activity {id}");
+                return StatusCode::ACCEPTED;
+            }
+        }
+
+        let queued = match self.enqueue(&signature.owner, &activity).await {
+            Ok(queued) => queued,
+            Err(e) => {
+                error!("Failed to queue an activity: {e}");
+                false
+            }
+        };
+
+        if !queued {
+            if let Some(ref id) = id {
+                self.recent.remove(&signature.owner, id);
+            }
+
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+
+        self.wake.notify_one();
+
+        StatusCode::ACCEPTED
+    }
+}
+
+/// The ID of the `actor` of an activity, given either as an IRI or as an embedded object
+pub fn actor_id(activity: &Value) -> Option<&str> {
+    match activity.get("actor")? {
+        Value::Array(actors) if actors.len() == 1 => actor_id_of(&actors[0]),
+        actor => actor_id_of(actor),
+    }
+}
+
+fn actor_id_of(actor: &Value) -> Option<&str> {
+    match actor {
+        Value::String(id) => Some(id),
+        actor => actor.get("id")?.as_str(),
+    }
+}
+
+pub async fn handle_shared_inbox(
+    State(inbox): State<Arc<Inbox>>,
+    signature: VerifiedSignature,
+    body: Bytes,
+) -> StatusCode {
+    inbox.accept(signature, &body).await
+}
+
+pub async fn handle_user_inbox(
+    Path(id): Path<String>,
+    State((ck, inbox)): State<(CalckeyModel, Arc<Inbox>)>,
+    signature: VerifiedSignature,
+    body: Bytes,
+) -> Result<StatusCode, StatusCode> {
+    ck.get_user_by_id(&id)
+        .await
+        .map_err(data_error)?
+        .filter(is_served)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(inbox.accept(signature, &body).await)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::inbox::{actor_id, handle_shared_inbox, Inbox, RecentActivities};
+    use crate::signature::VerifiedSignature;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use chrono::Utc;
+    use magnetar_calckey_model::inbox_job;
+    use magnetar_calckey_model::test_support::test_database;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tower::ServiceExt;
+
+    const ALICE: &str = "https://example.com/users/alice";
+
+    #[test]
+    fn should_extract_actor_id() {
+        assert_eq!(
+            actor_id(&json!({ "actor": "https://example.com/users/alice" })),
+            Some("https://example.com/users/alice")
+        );
+        assert_eq!(
+            actor_id(&json!({ "actor": { "id": "https://example.com/users/alice" } })),
+            Some("https://example.com/users/alice")
+        );
+        assert_eq!(
+            actor_id(&json!({ "actor": ["https://example.com/users/alice"] })),
+            Some("https://example.com/users/alice")
+        );
+        assert_eq!(actor_id(&json!({ "actor": ["a", "b"] })), None);
+        assert_eq!(actor_id(&json!({})), None);
+    }
+
+    #[test]
+    fn should_expire_recent_activities() {
+        let recent = RecentActivities::default();
+        let now = Instant::now();
+
+        assert!(recent.insert(ALICE, "https://example.com/1", now));
+        assert!(!recent.insert(
+            ALICE,
+            "https://example.com/1",
+            now + Duration::from_secs(60)
+        ));
+        assert!(recent.insert(ALICE, "https://example.com/2", now));
+        assert!(recent.insert(
+            ALICE,
+            "https://example.com/1",
+            now + Duration::from_secs(7 * 60 * 60)
+        ));
+
+        recent.remove(ALICE, "https://example.com/2");
+        assert!(recent.insert(ALICE, "https://example.com/2", now));
+    }
+
+    #[test]
+    fn should_not_share_ids_between_signers() {
+        let recent = RecentActivities::default();
+        let now = Instant::now();
+
+        // Mallory cannot get the activity of Alice dropped by sending its ID first
+        assert!(recent.insert(
+            "https://evil.example/users/mallory",
+            "https://example.com/1",
+            now
+        ));
+        assert!(recent.insert(ALICE, "https://example.com/1", now));
+        assert!(!recent.insert(ALICE, "https://example.com/1", now));
+    }
+
+    fn request(owner: &str, body: serde_json::Value) -> Request<Body> {
+        Request::post("/inbox")
+            .extension(VerifiedSignature {
+                key_id: format!("{owner}#main-key"),
+                owner: owner.to_owned(),
+            })
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_queue_activities() {
+        let ck = test_database().await.unwrap();
+
+        let app = Router::new()
+            .route("/inbox", post(handle_shared_inbox))
+            .with_state(Arc::new(Inbox::new(ck.clone(), 1)));
+
+        let claim = || async {
+            ck.claim_inbox_activities(10, Utc::now() + chrono::Duration::minutes(1))
+                .await
+                .unwrap()
+        };
+
+        let follow = json!({
+            "id": "https://example.com/follows/1",
+            "type": "Follow",
+            "actor": "https://example.com/users/alice",
+            "object": "https://magnetar.example/users/9a0b1c2d3e",
+        });
+
+        let response = app
+            .clone()
+            .oneshot(request("https://example.com/users/bob", follow.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .clone()
+            .oneshot(request("https://example.com/users/alice", follow.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let queued = claim().await;
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].activity, follow);
+        assert_eq!(queued[0].signer, "https://example.com/users/alice");
+        ck.complete_inbox_activity(&queued[0].id).await.unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(request("https://example.com/users/alice", follow))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(ck.count_inbox_activities().await.unwrap(), 0);
+
+        let like = |id: &str| {
+            json!({
+                "id": id,
+                "type": "Like",
+                "actor": "https://example.com/users/alice",
+                "object": "https://magnetar.example/notes/9a0b1c2d3e",
+            })
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request(
+                "https://example.com/users/alice",
+                like("https://example.com/likes/1"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let response = app
+            .clone()
+            .oneshot(request(
+                "https://example.com/users/alice",
+                like("https://example.com/likes/2"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let queued = claim().await;
+        ck.complete_inbox_activity(&queued[0].id).await.unwrap();
+
+        let response = app
+            .oneshot(request(
+                "https://example.com/users/alice",
+                like("https://example.com/likes/2"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_claim_activities_once() {
+        let ck = test_database().await.unwrap();
+
+        for i in 0..3 {
+            ck.enqueue_inbox_activity(ALICE, &json!({ "id": i, "type": "Like" }))
+                .await
+                .unwrap();
+        }
+
+        let ids = |jobs: Vec<inbox_job::Model>| {
+            jobs.into_iter()
+                .map(|job| job.activity["id"].clone())
+                .collect::<Vec<_>>()
+        };
+        let lease = Utc::now() + chrono::Duration::minutes(1);
+
+        // A processor that stopped halfway leaves its activities to be claimed again
+        let abandoned = ck
+            .claim_inbox_activities(1, Utc::now() - chrono::Duration::seconds(1))
+            .await
+            .unwrap();
+        assert_eq!(ids(abandoned), vec![json!(0)]);
+
+        let first = ck.claim_inbox_activities(2, lease).await.unwrap();
+        let second = ck.claim_inbox_activities(2, lease).await.unwrap();
+
+        assert_eq!(ids(first), vec![json!(0), json!(1)]);
+        assert_eq!(ids(second), vec![json!(2)]);
+        assert!(ck
+            .claim_inbox_activities(2, lease)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(ck.count_inbox_activities().await.unwrap(), 3);
+    }
+}
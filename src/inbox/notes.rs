@@ -0,0 +1,255 @@
+use crate::activity_pub::PUBLIC_COLLECTION;
+use crate::html::html_to_text;
+use magnetar_calckey_model::ck::sea_orm_active_enums::NoteVisibilityEnum;
+use magnetar_core::web_model::activity_streams::activity::Activity;
+use magnetar_core::web_model::activity_streams::object::{Object, ObjectOrLink};
+use magnetar_core::web_model::activity_streams::property::ObjectRefs;
+use serde_json::Value;
+
+/// Object types stored as notes
+pub const NOTE_TYPES: &[&str] = &["Note", "Question", "Article", "Page"];
+
+/// The IRIs of every node referenced by a property
+pub fn ref_iris(refs: Option<&ObjectRefs>) -> Vec<&str> {
+    refs.into_iter()
+        .flat_map(|refs| refs.iter())
+        .filter_map(|node| node.iri())
+        .collect()
+}
+
+fn is_public(iri: &str) -> bool {
+    matches!(iri, PUBLIC_COLLECTION | "as:Public" | "Public")
+}
+
+/// The visibility of a note, derived from its audience
+///
+/// Public notes address the public collection in `to`, unlisted notes in `cc`.
+/// Notes addressed to neither the public nor the followers of their author are direct notes.
+pub fn note_visibility(object: &Object, followers_uri: Option<&str>) -> NoteVisibilityEnum {
+    let to = ref_iris(object.to.as_ref());
+    let cc = ref_iris(object.cc.as_ref());
+
+    if to.iter().copied().any(is_public) {
+        NoteVisibilityEnum::Public
+    } else if cc.iter().copied().any(is_public) {
+        NoteVisibilityEnum::Home
+    } else if followers_uri
+        .is_some_and(|followers| to.contains(&followers) || cc.contains(&followers))
+    {
+        NoteVisibilityEnum::Followers
+    } else {
+        NoteVisibilityEnum::Specified
+    }
+}
+
+/// The text of a note, preferring the original Misskey markdown over the rendered HTML
+pub fn note_text(object: &Object) -> Option<String> {
+    let source = object
+        .source
+        .as_ref()
+        .filter(|source| source.media_type.as_deref() == Some("text/x.misskeymarkdown"))
+        .and_then(|source| source.content.clone());
+
+    let misskey_content = || {
+        object
+            .extensions
+            .get("_misskey_content")
+            .and_then(Value::as_str)
+            .map(str::to_owned)
+    };
+
+    let content = || {
+        object
+            .content
+            .as_deref()
+            .or_else(|| {
+                object
+                    .content_map
+                    .as_ref()?
+                    .values()
+                    .next()
+                    .map(String::as_str)
+            })
+            .map(html_to_text)
+    };
+
+    source
+        .or_else(misskey_content)
+        .or_else(content)
+        .filter(|text| !text.trim().is_empty())
+}
+
+fn tags<'a>(object: &'a Object, kind: &'a str) -> impl Iterator<Item = &'a ObjectOrLink> {
+    object
+        .tag
+        .iter()
+        .flat_map(|tags| tags.iter())
+        .filter_map(|tag| tag.embedded())
+        .filter(move |tag| tag.kind() == Some(kind))
+}
+
+fn tag_name(tag: &ObjectOrLink) -> Option<&str> {
+    match tag {
+        ObjectOrLink::Other(map) => map.get("name")?.as_str(),
+        tag => tag.as_link()?.name.as_deref(),
+    }
+}
+
+/// Lowercase names of the hashtags of an object, without the `#`
+pub fn hashtags(object: &Object) -> Vec<String> {
+    tags(object, "Hashtag")
+        .filter_map(tag_name)
+        .map(|name| name.trim_start_matches('#').to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Names of the custom emojis of an object, without the colons
+pub fn emoji_names(object: &Object) -> Vec<String> {
+    tags(object, "Emoji")
+        .filter_map(tag_name)
+        .map(|name| name.trim_matches(':').to_owned())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// IRIs of the actors mentioned by an object
+pub fn mentions(object: &Object) -> Vec<&str> {
+    tags(object, "Mention")
+        .filter_map(|tag| tag.as_link()?.href.as_deref())
+        .collect()
+}
+
+/// The reaction of a `Like` or `EmojiReact`, as stored by Calckey
+///
+/// Custom emojis are stored as `:name@host:`, unknown custom emojis and likes
+/// without content fall back to `default`.
+pub fn reaction(activity: &Activity, host: &str, default: &str) -> String {
+    let object = &activity.base;
+    let content = object
+        .extensions
+        .get("_misskey_reaction")
+        .and_then(Value::as_str)
+        .or(object.content.as_deref())
+        .map(str::trim)
+        .filter(|content| !content.is_empty());
+
+    let Some(content) = content else {
+        return default.to_owned();
+    };
+
+    let custom = content
+        .strip_prefix(':')
+        .and_then(|name| name.strip_suffix(':'))
+        .filter(|name| !name.is_empty());
+
+    match custom {
+        Some(name) if emoji_names(object).iter().any(|emoji| emoji == name) => {
+            format!(":{name}@{host}:")
+        }
+        Some(_) => default.to_owned(),
+        None => content.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::inbox::notes::{hashtags, note_text, note_visibility, reaction};
+    use magnetar_calckey_model::ck::sea_orm_active_enums::NoteVisibilityEnum;
+    use magnetar_core::web_model::activity_streams::activity::Activity;
+    use magnetar_core::web_model::activity_streams::object::Object;
+    use serde_json::json;
+
+    const FOLLOWERS: &str = "https://example.com/users/alice/followers";
+
+    fn object(value: serde_json::Value) -> Object {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn should_derive_visibility() {
+        let public = object(json!({
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "cc": [FOLLOWERS],
+        }));
+        let home = object(json!({ "to": FOLLOWERS, "cc": "as:Public" }));
+        let followers = object(json!({ "to": [FOLLOWERS] }));
+        let direct = object(json!({ "to": ["https://magnetar.example/users/9a0b1c2d3e"] }));
+
+        assert_eq!(
+            note_visibility(&public, Some(FOLLOWERS)),
+            NoteVisibilityEnum::Public
+        );
+        assert_eq!(
+            note_visibility(&home, Some(FOLLOWERS)),
+            NoteVisibilityEnum::Home
+        );
+        assert_eq!(
+            note_visibility(&followers, Some(FOLLOWERS)),
+            NoteVisibilityEnum::Followers
+        );
+        assert_eq!(
+            note_visibility(&followers, None),
+            NoteVisibilityEnum::Specified
+        );
+        assert_eq!(
+            note_visibility(&direct, Some(FOLLOWERS)),
+            NoteVisibilityEnum::Specified
+        );
+    }
+
+    #[test]
+    fn should_prefer_source_text() {
+        let misskey = object(json!({
+            "content": "<p>Hello <b>world</b></p>",
+            "source": { "content": "Hello **world**", "mediaType": "text/x.misskeymarkdown" },
+        }));
+        let mastodon = object(json!({ "content": "<p>Hello</p><p>world</p>" }));
+        let empty = object(json!({ "content": "<p></p>" }));
+
+        assert_eq!(note_text(&misskey).as_deref(), Some("Hello **world**"));
+        assert_eq!(note_text(&mastodon).as_deref(), Some("Hello\n\nworld"));
+        assert_eq!(note_text(&empty), None);
+    }
+
+    #[test]
+    fn should_collect_hashtags() {
+        let note = object(json!({
+            "tag": [
+                { "type": "Hashtag", "href": "https://example.com/tags/rust", "name": "#Rust" },
+                { "type": "Mention", "href": "https://example.com/users/bob", "name": "@bob" },
+            ],
+        }));
+
+        assert_eq!(hashtags(&note), vec!["rust"]);
+    }
+
+    #[test]
+    fn should_normalize_reactions() {
+        let activity =
+            |value: serde_json::Value| serde_json::from_value::<Activity>(value).unwrap();
+
+        let like = activity(json!({ "type": "Like" }));
+        let unicode = activity(json!({ "type": "EmojiReact", "content": "🦀" }));
+        let misskey = activity(json!({ "type": "Like", "_misskey_reaction": "🦀" }));
+        let custom = activity(json!({
+            "type": "EmojiReact",
+            "content": ":blobcat:",
+            "tag": [{
+                "type": "Emoji",
+                "name": ":blobcat:",
+                "icon": { "type": "Image", "url": "https://example.com/emoji/blobcat.png" },
+            }],
+        }));
+        let unknown = activity(json!({ "type": "EmojiReact", "content": ":blobcat:" }));
+
+        assert_eq!(reaction(&like, "example.com", "⭐"), "⭐");
+        assert_eq!(reaction(&unicode, "example.com", "⭐"), "🦀");
+        assert_eq!(reaction(&misskey, "example.com", "⭐"), "🦀");
+        assert_eq!(
+            reaction(&custom, "example.com", "⭐"),
+            ":blobcat@example.com:"
+        );
+        assert_eq!(reaction(&unknown, "example.com", "⭐"), "⭐");
+    }
+}
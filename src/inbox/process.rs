@@ -0,0 +1,1320 @@
+use crate::activity_pub::{iri, local_id};
+use crate::actor::is_served;
+use crate::config::{ConfigHandle, MagnetarConfig};
+use crate::delivery::Delivery;
+use crate::html::html_to_text;
+use crate::inbox::actors::{also_known_as, RemoteActors};
+use crate::inbox::notes::{
+    emoji_names, hashtags, mentions, note_text, note_visibility, reaction, ref_iris, NOTE_TYPES,
+};
+use crate::inbox::{canonical, InboxActivity, InboxError};
+use chrono::{DateTime, Duration, Utc};
+use magnetar_calckey_model::ck::sea_orm_active_enums::NoteVisibilityEnum;
+use magnetar_calckey_model::ck::{note, user};
+use magnetar_calckey_model::id::gen_aid;
+use magnetar_calckey_model::CalckeyModel;
+use magnetar_core::web_model::activity_streams::activity::Activity;
+use magnetar_core::web_model::activity_streams::object::{Object, ObjectOrLink};
+use magnetar_core::web_model::activity_streams::property::Ref;
+use magnetar_core::web_model::activity_streams::ActivityStreamsDocument;
+use magnetar_webfinger::client::HttpTransport;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+use url::Url;
+
+/// Used when the meta table does not configure a default reaction
+const FALLBACK_REACTION: &str = "⭐";
+
+fn malformed(e: impl ToString) -> InboxError {
+    InboxError::Malformed(e.to_string())
+}
+
+fn same_origin(a: &str, b: &str) -> bool {
+    match (Url::parse(a), Url::parse(b)) {
+        (Ok(a), Ok(b)) => a.origin() == b.origin(),
+        _ => false,
+    }
+}
+
+/// Splits an activity into its `type` and its properties
+fn parse_activity(value: Value) -> Result<(String, Activity), InboxError> {
+    let Value::Object(mut map) = value else {
+        return Err(malformed("the activity is not an object"));
+    };
+
+    let kind = match map.remove("type") {
+        Some(Value::String(kind)) => kind,
+        _ => return Err(malformed("the activity has no type")),
+    };
+
+    let activity = serde_json::from_value(Value::Object(map)).map_err(malformed)?;
+
+    Ok((kind, activity))
+}
+
+/// An embedded activity of any type, including extension types like `EmojiReact`
+fn embedded_activity(node: &ObjectOrLink) -> Option<(String, Activity)> {
+    parse_activity(serde_json::to_value(node).ok()?).ok()
+}
+
+fn object_ref(activity: &Activity) -> Option<&Ref<ObjectOrLink>> {
+    activity.object.as_ref()?.first()
+}
+
+fn object_iri(activity: &Activity) -> Option<&str> {
+    object_ref(activity)?.iri()
+}
+
+fn actor_iri(activity: &Activity) -> Option<&str> {
+    activity.actor.as_ref()?.first()?.iri()
+}
+
+/// Parses dates of remote objects, dates in the future are clamped to `now`
+fn parse_date(date: Option<&str>, now: DateTime<Utc>) -> DateTime<Utc> {
+    date.and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+        .map(|date| date.with_timezone(&Utc))
+        .filter(|date| *date < now)
+        .unwrap_or(now)
+}
+
+/// Whether `host` or a domain it belongs to is blocked
+pub fn is_blocked_host(blocked_hosts: &[String], host: &str) -> bool {
+    let host = host.to_lowercase();
+
+    blocked_hosts.iter().any(|blocked| {
+        let blocked = blocked.to_lowercase();
+        host == blocked || host.ends_with(&format!(".{blocked}"))
+    })
+}
+
+/// The local follower of a follow request answered by a remote user
+///
+/// The `Follow` is either embedded or referenced by the ID Calckey gives it,
+/// `{base}/follows/{follower}/{followee}`.
+fn local_follower(
+    config: &MagnetarConfig,
+    follow: &Ref<ObjectOrLink>,
+    followee: &user::Model,
+) -> Option<String> {
+    if let Some((kind, follow)) = follow.embedded().and_then(embedded_activity) {
+        if kind != "Follow" || object_iri(&follow) != followee.uri.as_deref() {
+            return None;
+        }
+
+        return local_id(config, actor_iri(&follow)?, "users").map(str::to_owned);
+    }
+
+    let base = format!(
+        "{}://{}/follows/",
+        config.networking.protocol, config.networking.host
+    );
+    let (follower, followee_id) = follow.iri()?.strip_prefix(&base)?.split_once('/')?;
+
+    (followee_id == followee.id).then(|| follower.to_owned())
+}
+
+/// A note with every column at its default, for the given author
+fn new_note(id: String, created_at: DateTime<Utc>, author: &user::Model) -> note::Model {
+    note::Model {
+        thread_id: Some(id.clone()),
+        id,
+        created_at: created_at.into(),
+        reply_id: None,
+        renote_id: None,
+        text: None,
+        name: None,
+        cw: None,
+        user_id: author.id.clone(),
+        local_only: false,
+        renote_count: 0,
+        replies_count: 0,
+        reactions: json!({}),
+        visibility: NoteVisibilityEnum::Public,
+        uri: None,
+        score: 0,
+        file_ids: Vec::new(),
+        attached_file_types: Vec::new(),
+        visible_user_ids: Vec::new(),
+        mentions: Vec::new(),
+        mentioned_remote_users: "[]".to_owned(),
+        emojis: Vec::new(),
+        tags: Vec::new(),
+        has_poll: false,
+        user_host: author.host.clone(),
+        reply_user_id: None,
+        reply_user_host: None,
+        renote_user_id: None,
+        renote_user_host: None,
+        url: None,
+        channel_id: None,
+    }
+}
+
+/// Received activities claimed at once
+const BATCH_SIZE: u64 = 16;
+
+/// How long claimed activities are reserved for the processor, it must outlast a batch
+const LEASE: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+/// How often the queue is checked when no activity was received in between
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Applies the activities received by the inboxes to the database
+///
+/// Activities are processed one at a time, in the order they were received.
+pub struct InboxProcessor<T: HttpTransport> {
+    config: ConfigHandle,
+    ck: CalckeyModel,
+    actors: RemoteActors<T>,
+    delivery: Delivery,
+}
+
+impl<T: HttpTransport> InboxProcessor<T> {
+    pub fn new(config: ConfigHandle, ck: CalckeyModel, transport: T, delivery: Delivery) -> Self {
+        InboxProcessor {
+            actors: RemoteActors::new(config.clone(), ck.clone(), transport),
+            config,
+            ck,
+            delivery,
+        }
+    }
+
+    /// Processes the queued activities, waiting for `wake` when the queue is empty
+    pub async fn run(self, wake: Arc<Notify>) {
+        loop {
+            match self.process_queued().await {
+                Ok(0) => {}
+                Ok(_) => continue,
+                Err(e) => warn!("Failed to claim received activities: {e}"),
+            }
+
+            tokio::select! {
+                _ = wake.notified() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+
+    /// Processes a batch of queued activities, returns how many were claimed
+    ///
+    /// Activities are removed from the queue once processed, even if they failed. Activities
+    /// of a processor that stopped halfway are claimed again once their lease runs out.
+    pub async fn process_queued(&self) -> anyhow::Result<usize> {
+        let claimed = self
+            .ck
+            .claim_inbox_activities(BATCH_SIZE, Utc::now() + Duration::from_std(LEASE)?)
+            .await?;
+        let count = claimed.len();
+
+        for job in claimed {
+            let id = job.id.clone();
+            let activity_id = job
+                .activity
+                .get("id")
+                .and_then(Value::as_str)
+                .unwrap_or("(anonymous)")
+                .to_owned();
+
+            if let Err(e) = self.process(job.into()).await {
+                warn!("Failed to process the activity {activity_id}: {e}");
+            }
+
+            self.ck.complete_inbox_activity(&id).await?;
+        }
+
+        Ok(count)
+    }
+
+    pub async fn process(&self, item: InboxActivity) -> Result<(), InboxError> {
+        let config = &*self.config.load();
+        let (kind, activity) = parse_activity(canonical(item.activity)?)?;
+
+        // The actor of a deleted account can no longer be fetched
+        if kind == "Delete" && object_iri(&activity) == Some(item.signer.as_str()) {
+            return self.delete_actor(&item.signer).await;
+        }
+
+        let actor = self.actors.resolve(&item.signer).await?;
+
+        let Some(ref host) = actor.host else {
+            return Err(InboxError::Forbidden(
+                item.signer,
+                "deliver activities of a local user".to_owned(),
+            ));
+        };
+
+        let blocked_hosts = self
+            .ck
+            .get_meta()
+            .await?
+            .map(|meta| meta.blocked_hosts)
+            .unwrap_or_default();
+
+        if actor.is_suspended || is_blocked_host(&blocked_hosts, host) {
+            debug!(
+                "Dropping a {kind} activity of the blocked actor {}",
+                item.signer
+            );
+            return Ok(());
+        }
+
+        match kind.as_str() {
+            "Follow" => self.follow(config, &actor, &activity).await,
+            "Accept" => self.accept(config, &actor, &activity).await,
+            "Reject" => self.reject(config, &actor, &activity).await,
+            "Undo" => self.undo(config, &actor, &activity).await,
+            "Create" => self.create(config, &actor, &activity).await,
+            "Update" => self.update(&actor, &activity).await,
+            "Delete" => self.delete(&actor, &activity).await,
+            "Like" | "EmojiReact" => self.react(config, &actor, &activity).await,
+            "Announce" => self.announce(config, &actor, &activity).await,
+            "Block" => self.block(config, &actor, &activity).await,
+            "Flag" => self.flag(config, &actor, &activity).await,
+            "Move" => self.move_actor(&actor, &activity).await,
+            _ => {
+                debug!("Ignoring an unsupported {kind} activity");
+                Ok(())
+            }
+        }
+    }
+
+    /// A local user that can be interacted with
+    async fn local_user(
+        &self,
+        config: &MagnetarConfig,
+        iri: Option<&str>,
+    ) -> Result<Option<user::Model>, InboxError> {
+        let Some(id) = iri.and_then(|iri| local_id(config, iri, "users")) else {
+            return Ok(None);
+        };
+
+        Ok(self.ck.get_user_by_id(id).await?.filter(is_served))
+    }
+
+    /// A stored note, either local or remote
+    async fn find_note(
+        &self,
+        config: &MagnetarConfig,
+        iri: &str,
+    ) -> Result<Option<note::Model>, InboxError> {
+        match local_id(config, iri, "notes") {
+            Some(id) => Ok(self.ck.get_note_by_id(id).await?),
+            None => Ok(self.ck.get_note_by_uri(iri).await?),
+        }
+    }
+
+    /// The object of an activity, embedded objects are only trusted from the origin of the actor
+    async fn fetch_object(
+        &self,
+        actor: &user::Model,
+        object: &Ref<ObjectOrLink>,
+    ) -> Result<ObjectOrLink, InboxError> {
+        let iri = object
+            .iri()
+            .ok_or_else(|| malformed("the object has no id"))?;
+
+        match object.embedded() {
+            Some(embedded)
+                if actor
+                    .uri
+                    .as_deref()
+                    .is_some_and(|uri| same_origin(uri, iri)) =>
+            {
+                Ok(embedded.clone())
+            }
+            _ => serde_json::from_value(self.actors.fetch(iri).await?).map_err(malformed),
+        }
+    }
+
+    /// Answers a `Follow` of a remote user with an `Accept` or a `Reject`
+    async fn answer_follow(
+        &self,
+        config: &MagnetarConfig,
+        local: &user::Model,
+        remote: &user::Model,
+        kind: &str,
+        follow: &Activity,
+    ) -> Result<(), InboxError> {
+        let Some(inbox) = remote.inbox.as_deref() else {
+            debug!(
+                "Cannot answer the follow of {}, they have no inbox",
+                remote.id
+            );
+            return Ok(());
+        };
+
+        let actor_id = format!(
+            "{}://{}/users/{}",
+            config.networking.protocol, config.networking.host, local.id
+        );
+
+        let activity = Activity {
+            actor: Some(iri(actor_id.clone()).into()),
+            object: Some(Ref::from(ObjectOrLink::Follow(follow.clone())).into()),
+            base: Object {
+                id: Some(format!(
+                    "{actor_id}#{}s/follows/{}",
+                    kind.to_lowercase(),
+                    remote.id
+                )),
+                ..Object::default()
+            },
+            ..Activity::default()
+        };
+
+        let document = ActivityStreamsDocument::new(match kind {
+            "Accept" => ObjectOrLink::Accept(activity),
+            _ => ObjectOrLink::Reject(activity),
+        });
+
+        Ok(self.delivery.deliver(&local.id, inbox, &document).await?)
+    }
+
+    async fn follow(
+        &self,
+        config: &MagnetarConfig,
+        actor: &user::Model,
+        activity: &Activity,
+    ) -> Result<(), InboxError> {
+        let Some(followee) = self.local_user(config, object_iri(activity)).await? else {
+            debug!("Ignoring a follow of an unknown user");
+            return Ok(());
+        };
+
+        if self
+            .ck
+            .get_blocking(&followee.id, &actor.id)
+            .await?
+            .is_some()
+        {
+            self.answer_follow(config, &followee, actor, "Reject", activity)
+                .await?;
+            return Ok(());
+        }
+
+        if followee.is_locked
+            && self
+                .ck
+                .get_following(&actor.id, &followee.id)
+                .await?
+                .is_none()
+        {
+            self.ck
+                .create_follow_request(actor, &followee, activity.base.id.as_deref())
+                .await?;
+            return Ok(());
+        }
+
+        // Repeated follows are accepted again, the previous `Accept` may have been lost
+        self.ck.create_following(actor, &followee).await?;
+        self.answer_follow(config, &followee, actor, "Accept", activity)
+            .await
+    }
+
+    async fn accept(
+        &self,
+        config: &MagnetarConfig,
+        actor: &user::Model,
+        activity: &Activity,
+    ) -> Result<(), InboxError> {
+        let Some(follower_id) =
+            object_ref(activity).and_then(|follow| local_follower(config, follow, actor))
+        else {
+            debug!("Ignoring an Accept of an unknown follow");
+            return Ok(());
+        };
+
+        if !self
+            .ck
+            .delete_follow_request(&follower_id, &actor.id)
+            .await?
+        {
+            return Ok(());
+        }
+
+        if let Some(follower) = self.ck.get_user_by_id(&follower_id).await? {
+            self.ck.create_following(&follower, actor).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn reject(
+        &self,
+        config: &MagnetarConfig,
+        actor: &user::Model,
+        activity: &Activity,
+    ) -> Result<(), InboxError> {
+        let Some(follower_id) =
+            object_ref(activity).and_then(|follow| local_follower(config, follow, actor))
+        else {
+            debug!("Ignoring a Reject of an unknown follow");
+            return Ok(());
+        };
+
+        if !self
+            .ck
+            .delete_follow_request(&follower_id, &actor.id)
+            .await?
+        {
+            self.ck.delete_following(&follower_id, &actor.id).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn undo(
+        &self,
+        config: &MagnetarConfig,
+        actor: &user::Model,
+        activity: &Activity,
+    ) -> Result<(), InboxError> {
+        let Some(object) = object_ref(activity) else {
+            return Err(malformed("Undo without an object"));
+        };
+
+        let Some((kind, undone)) = object.embedded().and_then(embedded_activity) else {
+            return self.undo_by_iri(actor, object.iri()).await;
+        };
+
+        if actor_iri(&undone) != actor.uri.as_deref() {
+            return Err(InboxError::Forbidden(
+                actor.uri.clone().unwrap_or_default(),
+                "undo activities of another actor".to_owned(),
+            ));
+        }
+
+        match kind.as_str() {
+            "Follow" => {
+                if let Some(followee) = self.local_user(config, object_iri(&undone)).await? {
+                    if !self.ck.delete_following(&actor.id, &followee.id).await? {
+                        self.ck
+                            .delete_follow_request(&actor.id, &followee.id)
+                            .await?;
+                    }
+                }
+            }
+            "Like" | "EmojiReact" => {
+                if let Some(iri) = object_iri(&undone) {
+                    if let Some(note) = self.find_note(config, iri).await? {
+                        self.ck.delete_note_reaction(&actor.id, &note.id).await?;
+                    }
+                }
+            }
+            "Block" => {
+                if let Some(blockee) = self.local_user(config, object_iri(&undone)).await? {
+                    self.ck.delete_blocking(&actor.id, &blockee.id).await?;
+                }
+            }
+            "Announce" => return self.undo_by_iri(actor, undone.base.id.as_deref()).await,
+            _ => debug!("Ignoring an Undo of an unsupported {kind} activity"),
+        }
+
+        Ok(())
+    }
+
+    /// Undoes follows and announces known by the ID of the activity
+    async fn undo_by_iri(&self, actor: &user::Model, iri: Option<&str>) -> Result<(), InboxError> {
+        let Some(iri) = iri else {
+            return Err(malformed("the undone activity has no id"));
+        };
+
+        if let Some(request) = self.ck.get_follow_request_by_request_id(iri).await? {
+            if request.follower_id == actor.id {
+                self.ck
+                    .delete_follow_request(&request.follower_id, &request.followee_id)
+                    .await?;
+            }
+
+            return Ok(());
+        }
+
+        match self.ck.get_note_by_uri(iri).await? {
+            Some(renote) if renote.user_id == actor.id && renote.renote_id.is_some() => {
+                self.ck.delete_note(&renote).await?;
+            }
+            _ => debug!("Ignoring an Undo of the unknown activity {iri}"),
+        }
+
+        Ok(())
+    }
+
+    /// Stores a remote note, notes that are already known are returned as they are
+    ///
+    /// Attachments and polls are not downloaded, replies to unknown notes are stored as
+    /// standalone notes.
+    async fn store_note(
+        &self,
+        config: &MagnetarConfig,
+        author: &user::Model,
+        object: &Object,
+    ) -> Result<note::Model, InboxError> {
+        let uri = object
+            .id
+            .as_deref()
+            .ok_or_else(|| malformed("the note has no id"))?;
+
+        if let Some(note) = self.ck.get_note_by_uri(uri).await? {
+            return Ok(note);
+        }
+
+        if !author
+            .uri
+            .as_deref()
+            .is_some_and(|author| same_origin(author, uri))
+        {
+            return Err(InboxError::Forbidden(
+                author.uri.clone().unwrap_or_default(),
+                format!("create {uri}"),
+            ));
+        }
+
+        let reply = match ref_iris(object.in_reply_to.as_ref()).first() {
+            Some(iri) => self.find_note(config, iri).await?,
+            None => None,
+        };
+
+        let quote_iri = ["_misskey_quote", "quoteUrl", "quoteUri"]
+            .iter()
+            .find_map(|key| object.extensions.get(*key)?.as_str());
+
+        let quote = match quote_iri {
+            Some(iri) => self.find_note(config, iri).await?,
+            None => None,
+        };
+
+        let audience = ref_iris(object.to.as_ref())
+            .into_iter()
+            .chain(ref_iris(object.cc.as_ref()))
+            .filter_map(|iri| local_id(config, iri, "users"))
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+
+        let mentioned = mentions(object)
+            .into_iter()
+            .filter_map(|iri| local_id(config, iri, "users"))
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+
+        let visibility = note_visibility(object, author.followers_uri.as_deref());
+        let now = Utc::now();
+        let created_at = parse_date(object.published.as_deref(), now);
+        let mut note = new_note(gen_aid(created_at), created_at, author);
+
+        if visibility == NoteVisibilityEnum::Specified {
+            note.visible_user_ids = self
+                .ck
+                .get_users_by_ids(&audience)
+                .await?
+                .into_iter()
+                .map(|user| user.id)
+                .collect();
+        }
+
+        note.mentions = self
+            .ck
+            .get_users_by_ids(&mentioned)
+            .await?
+            .into_iter()
+            .map(|user| user.id)
+            .collect();
+
+        if let Some(reply) = reply {
+            note.thread_id = Some(reply.thread_id.unwrap_or_else(|| reply.id.clone()));
+            note.reply_id = Some(reply.id);
+            note.reply_user_id = Some(reply.user_id);
+            note.reply_user_host = reply.user_host;
+        }
+
+        if let Some(quote) = quote {
+            note.renote_id = Some(quote.id);
+            note.renote_user_id = Some(quote.user_id);
+            note.renote_user_host = quote.user_host;
+        }
+
+        note.text = note_text(object);
+        note.cw = object.summary.clone().filter(|cw| !cw.is_empty());
+        note.name = object.name.clone();
+        note.visibility = visibility;
+        note.uri = Some(uri.to_owned());
+        note.url = ref_iris(object.url.as_ref())
+            .first()
+            .filter(|url| **url != uri)
+            .map(|url| url.to_string());
+        note.tags = hashtags(object);
+        note.emojis = emoji_names(object);
+
+        self.ck.create_note(note.clone()).await?;
+
+        Ok(note)
+    }
+
+    async fn create(
+        &self,
+        config: &MagnetarConfig,
+        actor: &user::Model,
+        activity: &Activity,
+    ) -> Result<(), InboxError> {
+        let Some(object) = object_ref(activity) else {
+            return Err(malformed("Create without an object"));
+        };
+
+        let object = self.fetch_object(actor, object).await?;
+
+        if !object.kind().is_some_and(|kind| NOTE_TYPES.contains(&kind)) {
+            debug!("Ignoring the creation of a {:?}", object.kind());
+            return Ok(());
+        }
+
+        let object = object
+            .as_object()
+            .ok_or_else(|| malformed("the note is not an object"))?;
+
+        if ref_iris(object.attributed_to.as_ref()).first().copied() != actor.uri.as_deref() {
+            return Err(InboxError::Forbidden(
+                actor.uri.clone().unwrap_or_default(),
+                "create notes of another actor".to_owned(),
+            ));
+        }
+
+        self.store_note(config, actor, object).await?;
+
+        Ok(())
+    }
+
+    async fn update(&self, actor: &user::Model, activity: &Activity) -> Result<(), InboxError> {
+        let Some(object) = object_ref(activity) else {
+            return Err(malformed("Update without an object"));
+        };
+
+        let object = self.fetch_object(actor, object).await?;
+        let kind = object.kind().unwrap_or_default();
+
+        if object.iri() == actor.uri.as_deref() {
+            let document = serde_json::to_value(&object).map_err(malformed)?;
+            return self.actors.update(actor, &document).await;
+        }
+
+        if !NOTE_TYPES.contains(&kind) {
+            debug!("Ignoring the update of a {kind}");
+            return Ok(());
+        }
+
+        let (Some(object), Some(iri)) = (object.as_object(), object.iri()) else {
+            return Err(malformed("the note has no id"));
+        };
+
+        let Some(note) = self.ck.get_note_by_uri(iri).await? else {
+            debug!("Ignoring the update of the unknown note {iri}");
+            return Ok(());
+        };
+
+        if note.user_id != actor.id {
+            return Err(InboxError::Forbidden(
+                actor.uri.clone().unwrap_or_default(),
+                format!("update {iri}"),
+            ));
+        }
+
+        self.ck
+            .update_note_content(
+                &note.id,
+                note_text(object),
+                object.summary.clone().filter(|cw| !cw.is_empty()),
+                object.name.clone(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_actor(&self, iri: &str) -> Result<(), InboxError> {
+        match self.ck.get_user_by_uri(iri).await? {
+            Some(user) if user.host.is_some() => self.ck.mark_user_deleted(&user.id).await?,
+            _ => debug!("Ignoring the deletion of the unknown actor {iri}"),
+        }
+
+        Ok(())
+    }
+
+    async fn delete(&self, actor: &user::Model, activity: &Activity) -> Result<(), InboxError> {
+        let Some(iri) = object_iri(activity) else {
+            return Err(malformed("Delete without an object"));
+        };
+
+        let Some(note) = self.ck.get_note_by_uri(iri).await? else {
+            debug!("Ignoring the deletion of the unknown object {iri}");
+            return Ok(());
+        };
+
+        if note.user_id != actor.id {
+            return Err(InboxError::Forbidden(
+                actor.uri.clone().unwrap_or_default(),
+                format!("delete {iri}"),
+            ));
+        }
+
+        self.ck.delete_note(&note).await?;
+
+        Ok(())
+    }
+
+    async fn react(
+        &self,
+        config: &MagnetarConfig,
+        actor: &user::Model,
+        activity: &Activity,
+    ) -> Result<(), InboxError> {
+        let note = match object_iri(activity) {
+            Some(iri) => self.find_note(config, iri).await?,
+            None => None,
+        };
+
+        let Some(note) = note else {
+            debug!("Ignoring a reaction to an unknown note");
+            return Ok(());
+        };
+
+        let default = self
+            .ck
+            .get_meta()
+            .await?
+            .map(|meta| meta.default_reaction)
+            .filter(|reaction| !reaction.is_empty())
+            .unwrap_or_else(|| FALLBACK_REACTION.to_owned());
+
+        let host = actor.host.as_deref().unwrap_or_default();
+        let reaction = reaction(activity, host, &default);
+
+        self.ck
+            .create_note_reaction(&actor.id, &note.id, &reaction)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn announce(
+        &self,
+        config: &MagnetarConfig,
+        actor: &user::Model,
+        activity: &Activity,
+    ) -> Result<(), InboxError> {
+        let Some(uri) = activity.base.id.as_deref() else {
+            return Err(malformed("Announce without an id"));
+        };
+
+        if self.ck.get_note_by_uri(uri).await?.is_some() {
+            return Ok(());
+        }
+
+        let Some(object) = object_ref(activity) else {
+            return Err(malformed("Announce without an object"));
+        };
+
+        let iri = object
+            .iri()
+            .ok_or_else(|| malformed("the object has no id"))?;
+
+        let renoted = match self.find_note(config, iri).await? {
+            Some(note) => note,
+            None => {
+                let object = self.actors.fetch(iri).await?;
+                let object = serde_json::from_value::<ObjectOrLink>(object).map_err(malformed)?;
+
+                let Some(object) = object
+                    .as_object()
+                    .filter(|_| object.kind().is_some_and(|kind| NOTE_TYPES.contains(&kind)))
+                else {
+                    debug!("Ignoring the announce of {iri}, it is not a note");
+                    return Ok(());
+                };
+
+                let Some(author) = ref_iris(object.attributed_to.as_ref()).first().copied() else {
+                    return Err(malformed("the note has no author"));
+                };
+
+                let author = self.actors.resolve(author).await?;
+                self.store_note(config, &author, object).await?
+            }
+        };
+
+        if !matches!(
+            renoted.visibility,
+            NoteVisibilityEnum::Public | NoteVisibilityEnum::Home
+        ) {
+            debug!("Ignoring the announce of the non-public note {iri}");
+            return Ok(());
+        }
+
+        let now = Utc::now();
+        let created_at = parse_date(activity.base.published.as_deref(), now);
+        let mut renote = new_note(gen_aid(created_at), created_at, actor);
+
+        renote.renote_id = Some(renoted.id);
+        renote.renote_user_id = Some(renoted.user_id);
+        renote.renote_user_host = renoted.user_host;
+        renote.visibility = note_visibility(&activity.base, actor.followers_uri.as_deref());
+        renote.uri = Some(uri.to_owned());
+
+        self.ck.create_note(renote).await?;
+
+        Ok(())
+    }
+
+    async fn block(
+        &self,
+        config: &MagnetarConfig,
+        actor: &user::Model,
+        activity: &Activity,
+    ) -> Result<(), InboxError> {
+        let Some(blockee) = self.local_user(config, object_iri(activity)).await? else {
+            debug!("Ignoring a block of an unknown user");
+            return Ok(());
+        };
+
+        self.ck.create_blocking(&actor.id, &blockee.id).await?;
+
+        for (follower, followee) in [(&actor.id, &blockee.id), (&blockee.id, &actor.id)] {
+            self.ck.delete_following(follower, followee).await?;
+            self.ck.delete_follow_request(follower, followee).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn flag(
+        &self,
+        config: &MagnetarConfig,
+        actor: &user::Model,
+        activity: &Activity,
+    ) -> Result<(), InboxError> {
+        let objects = ref_iris(activity.object.as_ref());
+        let mut target = None;
+        let mut notes = Vec::new();
+
+        for iri in objects {
+            if target.is_none() {
+                target = self.local_user(config, Some(iri)).await?;
+
+                if target.is_some() {
+                    continue;
+                }
+            }
+
+            notes.push(iri);
+        }
+
+        let Some(target) = target else {
+            debug!("Ignoring a report of an unknown user");
+            return Ok(());
+        };
+
+        let mut comment = activity
+            .base
+            .content
+            .as_deref()
+            .map(html_to_text)
+            .unwrap_or_default();
+
+        for note in notes {
+            comment.push('\n');
+            comment.push_str(note);
+        }
+
+        self.ck
+            .create_abuse_user_report(actor, &target, comment.trim())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Records the move of a remote account, the new account must list the old one as an alias
+    async fn move_actor(&self, actor: &user::Model, activity: &Activity) -> Result<(), InboxError> {
+        let forbidden = || {
+            InboxError::Forbidden(
+                actor.uri.clone().unwrap_or_default(),
+                "move another actor".to_owned(),
+            )
+        };
+
+        if object_iri(activity) != actor.uri.as_deref() {
+            return Err(forbidden());
+        }
+
+        let Some(target) = activity
+            .target
+            .as_ref()
+            .and_then(|target| target.first()?.iri())
+        else {
+            return Err(malformed("Move without a target"));
+        };
+
+        let document = self.actors.fetch(target).await?;
+
+        if !actor
+            .uri
+            .as_deref()
+            .is_some_and(|uri| also_known_as(&document).contains(&uri))
+        {
+            return Err(forbidden());
+        }
+
+        self.actors.resolve(target).await?;
+        self.ck.set_user_moved_to(&actor.id, target).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{ConfigHandle, ConfigLayers, ConfigOptions, MagnetarConfig};
+    use crate::delivery::Delivery;
+    use crate::inbox::process::{
+        is_blocked_host, local_follower, parse_activity, parse_date, InboxProcessor,
+    };
+    use crate::inbox::{InboxActivity, InboxError};
+    use chrono::{Duration, TimeZone, Utc};
+    use magnetar_calckey_model::ck::sea_orm_active_enums::NoteVisibilityEnum;
+    use magnetar_calckey_model::ck::user;
+    use magnetar_calckey_model::test_support::{insert_user, local_user, test_database};
+    use magnetar_calckey_model::CalckeyModel;
+    use magnetar_core::web_model::activity_streams::object::ObjectOrLink;
+    use magnetar_core::web_model::activity_streams::property::Ref;
+    use magnetar_webfinger::test_support::StubTransport;
+    use serde_json::{json, Value};
+
+    fn config() -> MagnetarConfig {
+        let mut layers = ConfigLayers::default();
+        layers.add_overrides(&[
+            ("networking.host".to_owned(), "example.com".to_owned()),
+            ("data.database_url".to_owned(), "postgres://db".to_owned()),
+        ]);
+        layers.into_config().unwrap()
+    }
+
+    fn remote_user() -> user::Model {
+        user::Model {
+            uri: Some("https://remote.example/users/alice".to_owned()),
+            host: Some("remote.example".to_owned()),
+            ..local_user("9a0b1c2d3f", "alice")
+        }
+    }
+
+    #[test]
+    fn should_parse_activity() {
+        let (kind, activity) = parse_activity(json!({
+            "type": "EmojiReact",
+            "id": "https://example.com/reactions/1",
+            "actor": "https://example.com/users/alice",
+            "object": "https://magnetar.example/notes/9a0b1c2d3e",
+            "content": "🦀",
+        }))
+        .unwrap();
+
+        assert_eq!(kind, "EmojiReact");
+        assert_eq!(activity.base.content.as_deref(), Some("🦀"));
+        assert!(parse_activity(json!({ "id": "https://example.com/1" })).is_err());
+    }
+
+    #[test]
+    fn should_find_local_follower() {
+        let config = config();
+        let alice = remote_user();
+
+        let embedded = serde_json::from_value::<Ref<ObjectOrLink>>(json!({
+            "type": "Follow",
+            "id": format!("https://{}/follows/9a0b1c2d3e/9a0b1c2d3f", config.networking.host),
+            "actor": format!("https://{}/users/9a0b1c2d3e", config.networking.host),
+            "object": "https://remote.example/users/alice",
+        }))
+        .unwrap();
+
+        let by_id = Ref::Iri(format!(
+            "https://{}/follows/9a0b1c2d3e/9a0b1c2d3f",
+            config.networking.host
+        ));
+
+        let other = serde_json::from_value::<Ref<ObjectOrLink>>(json!({
+            "type": "Follow",
+            "actor": format!("https://{}/users/9a0b1c2d3e", config.networking.host),
+            "object": "https://remote.example/users/bob",
+        }))
+        .unwrap();
+
+        assert_eq!(
+            local_follower(&config, &embedded, &alice).as_deref(),
+            Some("9a0b1c2d3e")
+        );
+        assert_eq!(
+            local_follower(&config, &by_id, &alice).as_deref(),
+            Some("9a0b1c2d3e")
+        );
+        assert_eq!(local_follower(&config, &other, &alice), None);
+    }
+
+    #[test]
+    fn should_match_blocked_hosts() {
+        let blocked = vec!["Evil.example".to_owned()];
+
+        assert!(is_blocked_host(&blocked, "evil.example"));
+        assert!(is_blocked_host(&blocked, "social.evil.example"));
+        assert!(!is_blocked_host(&blocked, "notevil.example"));
+    }
+
+    const BOB: &str = "https://remote.example/users/bob";
+    const CAROL: &str = "https://remote.example/users/carol";
+    const NOTE: &str = "https://remote.example/notes/1";
+
+    fn remote_actor(iri: &str, username: &str) -> Value {
+        json!({
+            "id": iri,
+            "type": "Person",
+            "preferredUsername": username,
+            "inbox": format!("{iri}/inbox"),
+            "followers": format!("{iri}/followers"),
+            "publicKey": {
+                "id": format!("{iri}#main-key"),
+                "owner": iri,
+                "publicKeyPem": "-----BEGIN PUBLIC KEY-----\n...\n-----END PUBLIC KEY-----\n"
+            }
+        })
+    }
+
+    fn local_alice() -> user::Model {
+        local_user("9fxdl2pkn3", "alice")
+    }
+
+    fn alice_iri() -> String {
+        format!("https://example.com/users/{}", local_alice().id)
+    }
+
+    fn received(signer: &str, activity: Value) -> InboxActivity {
+        InboxActivity {
+            activity,
+            signer: signer.to_owned(),
+        }
+    }
+
+    fn follow() -> Value {
+        json!({
+            "type": "Follow",
+            "id": "https://remote.example/follows/1",
+            "actor": BOB,
+            "object": alice_iri(),
+        })
+    }
+
+    fn create_note() -> Value {
+        json!({
+            "type": "Create",
+            "id": "https://remote.example/notes/1/activity",
+            "actor": BOB,
+            "object": {
+                "type": "Note",
+                "id": NOTE,
+                "attributedTo": BOB,
+                "content": "<p>Hello</p>",
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            },
+        })
+    }
+
+    /// A processor with the local user Alice, the actors of Bob and Carol are served remotely
+    async fn processor() -> (InboxProcessor<StubTransport>, CalckeyModel) {
+        let ck = test_database().await.unwrap();
+
+        insert_user(&ck, local_alice()).await.unwrap();
+
+        let transport = StubTransport::default()
+            .with(BOB, remote_actor(BOB, "bob"))
+            .with(CAROL, remote_actor(CAROL, "carol"));
+        let processor = InboxProcessor::new(
+            ConfigHandle::new(config(), ConfigOptions::default()),
+            ck.clone(),
+            transport,
+            Delivery::new(ck.clone()),
+        );
+
+        (processor, ck)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_process_queued_activities() {
+        let (processor, ck) = processor().await;
+
+        ck.enqueue_inbox_activity(BOB, &follow()).await.unwrap();
+        ck.enqueue_inbox_activity(BOB, &json!({ "type": "Follow", "actor": BOB }))
+            .await
+            .unwrap();
+
+        // The malformed follow is dropped along with the processed one
+        assert_eq!(processor.process_queued().await.unwrap(), 2);
+        assert_eq!(ck.count_inbox_activities().await.unwrap(), 0);
+        assert_eq!(processor.process_queued().await.unwrap(), 0);
+
+        let bob = ck.get_user_by_uri(BOB).await.unwrap().unwrap();
+        assert!(ck
+            .get_following(&bob.id, &local_alice().id)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_accept_follows() {
+        let (processor, ck) = processor().await;
+
+        processor.process(received(BOB, follow())).await.unwrap();
+
+        let bob = ck.get_user_by_uri(BOB).await.unwrap().unwrap();
+        assert!(ck
+            .get_following(&bob.id, &local_alice().id)
+            .await
+            .unwrap()
+            .is_some());
+
+        let deliveries = ck
+            .claim_deliveries(10, &[], Utc::now() + Duration::minutes(1))
+            .await
+            .unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].user_id, local_alice().id);
+        assert_eq!(
+            deliveries[0].inbox,
+            "https://remote.example/users/bob/inbox"
+        );
+        assert_eq!(deliveries[0].activity["type"], "Accept");
+        assert_eq!(deliveries[0].activity["actor"], alice_iri());
+        assert_eq!(deliveries[0].activity["object"]["id"], follow()["id"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_undo_follows() {
+        let (processor, ck) = processor().await;
+
+        processor.process(received(BOB, follow())).await.unwrap();
+        processor
+            .process(received(
+                BOB,
+                json!({
+                    "type": "Undo",
+                    "id": "https://remote.example/follows/1/undo",
+                    "actor": BOB,
+                    "object": follow(),
+                }),
+            ))
+            .await
+            .unwrap();
+
+        let bob = ck.get_user_by_uri(BOB).await.unwrap().unwrap();
+        assert!(ck
+            .get_following(&bob.id, &local_alice().id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_create_notes() {
+        let (processor, ck) = processor().await;
+
+        processor
+            .process(received(BOB, create_note()))
+            .await
+            .unwrap();
+
+        let bob = ck.get_user_by_uri(BOB).await.unwrap().unwrap();
+        let note = ck.get_note_by_uri(NOTE).await.unwrap().unwrap();
+        assert_eq!(note.user_id, bob.id);
+        assert_eq!(note.user_host.as_deref(), Some("remote.example"));
+        assert_eq!(note.text.as_deref(), Some("Hello"));
+        assert_eq!(note.visibility, NoteVisibilityEnum::Public);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_read_prefixed_terms() {
+        let (processor, ck) = processor().await;
+
+        let mut activity = create_note();
+        activity["@context"] = json!([
+            "https://www.w3.org/ns/activitystreams",
+            { "misskey": "https://misskey-hub.net/ns#" }
+        ]);
+        activity["object"]["misskey:_misskey_content"] = json!("**Hello**");
+
+        processor.process(received(BOB, activity)).await.unwrap();
+
+        let note = ck.get_note_by_uri(NOTE).await.unwrap().unwrap();
+        assert_eq!(note.text.as_deref(), Some("**Hello**"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_delete_notes() {
+        let (processor, ck) = processor().await;
+
+        processor
+            .process(received(BOB, create_note()))
+            .await
+            .unwrap();
+        processor
+            .process(received(
+                BOB,
+                json!({
+                    "type": "Delete",
+                    "id": "https://remote.example/notes/1/delete",
+                    "actor": BOB,
+                    "object": { "type": "Tombstone", "id": NOTE },
+                }),
+            ))
+            .await
+            .unwrap();
+
+        assert!(ck.get_note_by_uri(NOTE).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_not_delete_notes_of_others() {
+        let (processor, ck) = processor().await;
+
+        processor
+            .process(received(BOB, create_note()))
+            .await
+            .unwrap();
+
+        let result = processor
+            .process(received(
+                CAROL,
+                json!({
+                    "type": "Delete",
+                    "actor": CAROL,
+                    "object": NOTE,
+                }),
+            ))
+            .await;
+
+        assert!(matches!(result, Err(InboxError::Forbidden(..))));
+        assert!(ck.get_note_by_uri(NOTE).await.unwrap().is_some());
+    }
+
+    #[test]
+    fn should_clamp_future_dates() {
+        let now = Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap();
+
+        assert_eq!(
+            parse_date(Some("2023-10-01T10:00:00.000Z"), now),
+            now - Duration::hours(2)
+        );
+        assert_eq!(parse_date(Some("2023-10-02T10:00:00Z"), now), now);
+        assert_eq!(parse_date(Some("yesterday"), now), now);
+    }
+}
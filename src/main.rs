@@ -1,24 +1,62 @@
+pub mod accept;
+pub mod activity_pub;
+pub mod actor;
+pub mod cli;
+pub mod collection;
 pub mod config;
+pub mod delivery;
+pub mod forwarded;
+pub mod host_meta;
+pub mod html;
+pub mod inbox;
 pub mod nodeinfo;
+pub mod nodeinfo_crawler;
+pub mod note;
+pub mod public_address;
+pub mod server;
+pub mod signature;
 pub mod util;
 pub mod webfinger;
 
-use crate::nodeinfo::{handle_nodeinfo, handle_nodeinfo_20, handle_nodeinfo_21};
-use anyhow::anyhow;
-use axum::routing::get;
+use crate::cli::{Cli, Command, ConfigCommand};
+use crate::config::ConfigHandle;
+use crate::delivery::Delivery;
+use crate::forwarded::{client_info_middleware, make_request_span};
+use crate::host_meta::{handle_host_meta, handle_host_meta_json};
+use crate::inbox::process::InboxProcessor;
+use crate::inbox::{handle_shared_inbox, handle_user_inbox, Inbox};
+use crate::nodeinfo::{
+    handle_nodeinfo, handle_nodeinfo_20, handle_nodeinfo_21, NodeInfoStatsCache,
+};
+use crate::nodeinfo_crawler::NodeInfoCrawler;
+use crate::public_address::is_public_url;
+use crate::signature::keys::{CalckeyKeyResolver, PublicKeyResolver};
+use crate::signature::middleware::verify_signature;
+use crate::signature::signer::{load_instance_signer, HostSchemes, SignedTransport};
+use axum::middleware;
+use axum::routing::{get, post};
 use axum::Router;
+use clap::Parser;
 use dotenvy::dotenv;
 use magnetar_calckey_model::{CalckeyModel, ConnectorConfig};
-use std::net::SocketAddr;
+use magnetar_webfinger::client::ReqwestTransport;
+use reqwest::redirect;
+use std::sync::Arc;
+use std::time::Duration;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{info, warn};
 use tracing_subscriber::EnvFilter;
 
+/// Redirects followed by outgoing requests, redirects to local addresses are never followed
+const MAX_REDIRECTS: usize = 10;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv().ok();
 
+    let cli = Cli::parse();
+
     let filter_layer = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new("info"))
         .unwrap();
@@ -28,41 +66,194 @@ async fn main() -> anyhow::Result<()> {
         .with_test_writer()
         .init();
 
-    let config = &*Box::leak::<'static>(Box::new(config::load_config()?));
+    if let Some(Command::Config(ConfigCommand::Check)) = cli.command {
+        return match config::load_config(&cli.config_options()) {
+            Ok(_) => {
+                println!("Configuration is valid");
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        };
+    }
+
+    let config_options = cli.config_options();
+    let config_handle = ConfigHandle::new(config::load_config(&config_options)?, config_options);
+    // Snapshot used for the startup, the database connection, listeners and the HTTP client
+    // are not reloaded
+    let config = config_handle.load();
+    info!("Loaded configuration: {config:#?}");
 
     let db = CalckeyModel::new(ConnectorConfig {
-        url: config.data.database_url.clone(),
+        url: config.data.database_url.expose().to_owned(),
     })
     .await?;
 
+    if let Some(Command::Migrate) = cli.command {
+        let applied = db.migrate().await?;
+
+        match applied.is_empty() {
+            true => println!("The database is up to date"),
+            false => println!("Applied migrations: {}", applied.join(", ")),
+        }
+
+        return Ok(());
+    }
+
+    let pending = db.pending_migrations().await?;
+
+    if !pending.is_empty() {
+        anyhow::bail!(
+            "The database has pending migrations ({}), apply them with `magnetar migrate`",
+            pending.join(", ")
+        );
+    }
+
+    // The User-Agent keeps the startup branding and host until a restart
+    let http_client = reqwest::Client::builder()
+        .user_agent(format!(
+            "{}/{} (+{}://{}/)",
+            config.branding.name,
+            config.branding.version,
+            config.networking.protocol,
+            config.networking.host
+        ))
+        .timeout(Duration::from_secs(10))
+        .redirect(redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if is_public_url(attempt.url()) {
+                attempt.follow()
+            } else {
+                attempt.stop()
+            }
+        }))
+        .build()?;
+
+    tokio::spawn(NodeInfoCrawler::new(db.clone(), ReqwestTransport(http_client.clone())).run());
+
+    let signature_schemes = Arc::new(HostSchemes::default());
+    let instance_signer = load_instance_signer(&config, &db).await?;
+
+    if instance_signer.is_none() {
+        warn!(
+            "No instance actor key found, fetches from instances with authorized fetch will fail"
+        );
+    }
+
+    let signed_transport = Arc::new(SignedTransport::new(
+        http_client.clone(),
+        instance_signer,
+        signature_schemes.clone(),
+    ));
+
+    let key_resolver: Arc<dyn PublicKeyResolver> = Arc::new(CalckeyKeyResolver::new(
+        db.clone(),
+        signed_transport.clone(),
+    ));
+
+    let delivery = Delivery::new(db.clone());
+    tokio::spawn(
+        delivery
+            .worker(config_handle.clone(), http_client, signature_schemes)
+            .run(),
+    );
+
+    let inbox = Arc::new(Inbox::new(db.clone(), inbox::QUEUE_CAPACITY));
+
+    tokio::spawn(
+        InboxProcessor::new(
+            config_handle.clone(),
+            db.clone(),
+            signed_transport,
+            delivery,
+        )
+        .run(inbox.wake()),
+    );
+
+    let nodeinfo_stats = Arc::new(NodeInfoStatsCache::new(db.clone()));
+
+    tokio::spawn(config_handle.clone().reload_on_sighup());
+
     let well_known_router = Router::new()
         .route(
             "/webfinger",
-            get(webfinger::handle_webfinger).with_state((config, db)),
+            get(webfinger::handle_webfinger).with_state((config_handle.clone(), db.clone())),
         )
-        .route("/nodeinfo", get(handle_nodeinfo));
+        .route("/nodeinfo", get(handle_nodeinfo))
+        .route("/host-meta", get(handle_host_meta))
+        .route("/host-meta.json", get(handle_host_meta_json));
 
     let nodeinfo_router = Router::new()
-        .with_state(config)
-        .route("/2.0", get(handle_nodeinfo_20))
-        .route("/2.1", get(handle_nodeinfo_21));
+        .route(
+            "/2.0",
+            get(handle_nodeinfo_20).with_state((config_handle.clone(), nodeinfo_stats.clone())),
+        )
+        .route(
+            "/2.1",
+            get(handle_nodeinfo_21).with_state((config_handle.clone(), nodeinfo_stats)),
+        );
 
     let app = Router::new()
+        .route(
+            "/users/:id",
+            get(actor::handle_actor).with_state((config_handle.clone(), db.clone())),
+        )
+        .route(
+            "/users/:id/outbox",
+            get(collection::handle_outbox).with_state((config_handle.clone(), db.clone())),
+        )
+        .route(
+            "/users/:id/followers",
+            get(collection::handle_followers).with_state((config_handle.clone(), db.clone())),
+        )
+        .route(
+            "/users/:id/following",
+            get(collection::handle_following).with_state((config_handle.clone(), db.clone())),
+        )
+        .route(
+            "/users/:id/collections/featured",
+            get(collection::handle_featured).with_state((config_handle.clone(), db.clone())),
+        )
+        .route(
+            "/users/:id/inbox",
+            post(handle_user_inbox).with_state((db.clone(), inbox.clone())),
+        )
+        .route("/inbox", post(handle_shared_inbox).with_state(inbox))
+        .route(
+            "/@:tag",
+            get(actor::handle_profile).with_state((config_handle.clone(), db.clone())),
+        )
+        .route(
+            "/notes/:id",
+            get(note::handle_note).with_state((config_handle.clone(), db)),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            key_resolver,
+            verify_signature,
+        ))
         .nest("/.well-known", well_known_router)
         .nest("/nodeinfo", nodeinfo_router)
-        .with_state(config)
+        .with_state(config_handle.clone())
         .layer(
             CorsLayer::new()
                 .allow_headers(Any)
                 .allow_methods(Any)
                 .allow_origin(Any),
         )
-        .layer(TraceLayer::new_for_http());
-
-    let addr = SocketAddr::from((config.networking.bind_addr, config.networking.port));
-    info!("Serving on: {addr}");
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .map_err(|e| anyhow!("Error running server: {}", e))
+        .layer(TraceLayer::new_for_http().make_span_with(make_request_span))
+        .layer(middleware::from_fn_with_state(
+            config_handle,
+            client_info_middleware,
+        ));
+
+    server::serve(
+        app,
+        &config.networking.listeners,
+        config.networking.unix_socket_mode,
+        server::shutdown_signal(),
+    )
+    .await
 }
@@ -1,93 +1,535 @@
-use crate::config::MagnetarConfig;
+use crate::config::{ConfigHandle, MagnetarConfig};
 use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
 use axum::Json;
+use chrono::{Duration as ChronoDuration, Utc};
+use hyper::header;
+use magnetar_calckey_model::ck::meta;
+use magnetar_calckey_model::CalckeyModel;
+use magnetar_core::web_model::content_type::{ContentNodeInfo20, ContentNodeInfo21};
 use magnetar_core::web_model::rel::{RelNodeInfo20, RelNodeInfo21};
-use magnetar_core::web_model::Rel;
+use magnetar_core::web_model::{ContentType, Rel};
 use magnetar_nodeinfo::version_1_0::{
     NodeInfo10Services, NodeInfo10Software, NodeInfo10Usage, NodeInfo10UsageUsers,
 };
 use magnetar_nodeinfo::version_2_0::NodeInfo20;
 use magnetar_nodeinfo::version_2_1::{NodeInfo21, NodeInfo21Software};
-use serde::Serialize;
+use magnetar_nodeinfo::{NodeInfo, NodeInfoDiscovery, NodeInfoDiscoveryLink};
+use serde_json::{json, Value};
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{error, warn};
 
 const NODEINFO_PATH: &str = "/nodeinfo";
 
-#[derive(Clone, Debug, Serialize)]
-pub struct NodeInfoLink {
-    rel: &'static str,
-    href: String,
+#[derive(Clone, Debug)]
+struct NodeInfoStats {
+    total_users: i64,
+    active_halfyear: i64,
+    active_month: i64,
+    local_posts: i64,
+    open_registrations: bool,
+    metadata: HashMap<String, Value>,
 }
 
-pub async fn handle_nodeinfo(
-    State(config): State<&'static MagnetarConfig>,
-) -> Json<Vec<NodeInfoLink>> {
-    Json(vec![
-        NodeInfoLink {
-            href: format!(
-                "{}://{}/nodeinfo/2.0",
-                config.networking.protocol, config.networking.host
-            ),
-            rel: RelNodeInfo20.rel(),
-        },
-        NodeInfoLink {
-            href: format!(
-                "{}://{}/nodeinfo/2.1",
-                config.networking.protocol, config.networking.host
-            ),
-            rel: RelNodeInfo21.rel(),
-        },
+/// Builds the Misskey-compatible metadata block, as read by Calckey/Misskey clients and instance directories
+fn metadata_from_meta(
+    config: &MagnetarConfig,
+    meta: &meta::Model,
+    proxy_account_name: Option<String>,
+) -> HashMap<String, Value> {
+    HashMap::from([
+        ("nodeName".to_owned(), json!(meta.name)),
+        ("nodeDescription".to_owned(), json!(meta.description)),
+        (
+            "maintainer".to_owned(),
+            json!({
+                "name": meta.maintainer_name,
+                "email": meta.maintainer_email,
+            }),
+        ),
+        ("langs".to_owned(), json!(meta.langs)),
+        ("tosUrl".to_owned(), json!(meta.to_s_url)),
+        ("repositoryUrl".to_owned(), json!(meta.repository_url)),
+        ("feedbackUrl".to_owned(), json!(meta.feedback_url)),
+        (
+            "disableRegistration".to_owned(),
+            json!(meta.disable_registration),
+        ),
+        (
+            "disableLocalTimeline".to_owned(),
+            json!(meta.disable_local_timeline),
+        ),
+        (
+            "disableGlobalTimeline".to_owned(),
+            json!(meta.disable_global_timeline),
+        ),
+        (
+            "emailRequiredForSignup".to_owned(),
+            json!(meta.email_required_for_signup),
+        ),
+        ("enableHcaptcha".to_owned(), json!(meta.enable_hcaptcha)),
+        ("enableRecaptcha".to_owned(), json!(meta.enable_recaptcha)),
+        (
+            "maxNoteTextLength".to_owned(),
+            json!(config.calckey_frontend.max_note_text_length),
+        ),
+        ("enableEmail".to_owned(), json!(meta.enable_email)),
+        (
+            "enableServiceWorker".to_owned(),
+            json!(meta.enable_service_worker),
+        ),
+        ("proxyAccountName".to_owned(), json!(proxy_account_name)),
+        ("themeColor".to_owned(), json!(meta.theme_color)),
     ])
 }
 
-pub async fn handle_nodeinfo_21(State(config): State<&'static MagnetarConfig>) -> Json<NodeInfo21> {
-    Json(NodeInfo21 {
-        software: NodeInfo21Software {
-            name: config.branding.name.clone(),
-            version: config.branding.version.clone(),
-            homepage: Some(config.branding.homepage.clone()),
-            repository: Some(config.branding.repository.clone()),
-        },
-        protocols: HashSet::from(["activitypub".to_owned()]),
-        services: NodeInfo10Services {
-            inbound: HashSet::new(),
-            outbound: HashSet::new(),
-        },
-        open_registrations: false,
-        usage: NodeInfo10Usage {
-            users: NodeInfo10UsageUsers {
-                total: Some(0),
-                active_halfyear: Some(0),
-                active_month: Some(0),
-            },
-            local_posts: Some(0),
-            local_comments: Some(0),
-        },
-        metadata: HashMap::new(),
+/// Caches the NodeInfo usage statistics and metadata, so crawlers do not hit the database on every request
+///
+/// Only one request refreshes the statistics at a time, the others are answered with the
+/// previous statistics in the meantime.
+#[derive(Debug)]
+pub struct NodeInfoStatsCache {
+    ck: CalckeyModel,
+    cached: std::sync::Mutex<Option<(Instant, NodeInfoStats)>>,
+    refresh: Mutex<()>,
+}
+
+impl NodeInfoStatsCache {
+    pub fn new(ck: CalckeyModel) -> Self {
+        NodeInfoStatsCache {
+            ck,
+            cached: std::sync::Mutex::new(None),
+            refresh: Mutex::new(()),
+        }
+    }
+
+    /// The cached statistics and whether they are older than the TTL
+    fn cached(&self, ttl: Duration) -> Option<(NodeInfoStats, bool)> {
+        let cached = self.cached.lock().unwrap();
+        let (fetched_at, ref stats) = *cached.as_ref()?;
+
+        Some((stats.clone(), fetched_at.elapsed() >= ttl))
+    }
+
+    async fn fetch(&self, config: &MagnetarConfig) -> anyhow::Result<NodeInfoStats> {
+        let now = Utc::now();
+
+        let (open_registrations, metadata) = match self.ck.get_meta().await? {
+            Some(meta) => {
+                let proxy_account_name = match meta.proxy_account_id {
+                    Some(ref id) => self.ck.get_user_by_id(id).await?.map(|u| u.username),
+                    None => None,
+                };
+
+                (
+                    !meta.disable_registration,
+                    metadata_from_meta(config, &meta, proxy_account_name),
+                )
+            }
+            None => (true, HashMap::new()),
+        };
+
+        Ok(NodeInfoStats {
+            total_users: self.ck.get_local_user_count().await? as i64,
+            active_halfyear: self
+                .ck
+                .get_local_user_count_active_since(now - ChronoDuration::days(180))
+                .await? as i64,
+            active_month: self
+                .ck
+                .get_local_user_count_active_since(now - ChronoDuration::days(30))
+                .await? as i64,
+            local_posts: self.ck.get_local_note_count().await? as i64,
+            open_registrations,
+            metadata,
+        })
+    }
+
+    async fn get(&self, config: &MagnetarConfig) -> Result<NodeInfoStats, StatusCode> {
+        let ttl = Duration::from_secs(config.caching.nodeinfo_stats_ttl_secs);
+
+        let stale = match self.cached(ttl) {
+            Some((stats, false)) => return Ok(stats),
+            Some((stats, true)) => Some(stats),
+            None => None,
+        };
+
+        let _refreshing = match (self.refresh.try_lock(), &stale) {
+            (Ok(refreshing), _) => refreshing,
+            (Err(_), Some(stale)) => return Ok(stale.clone()),
+            (Err(_), None) => self.refresh.lock().await,
+        };
+
+        // Another request may have refreshed the statistics while this one was waiting
+        if let Some((stats, false)) = self.cached(ttl) {
+            return Ok(stats);
+        }
+
+        // Outdated statistics are better than none while the database is unavailable
+        let stats = match (self.fetch(config).await, stale) {
+            (Ok(stats), _) => stats,
+            (Err(e), Some(stale)) => {
+                warn!("Failed to refresh the NodeInfo statistics, serving stale ones: {e}");
+                return Ok(stale);
+            }
+            (Err(e), None) => {
+                error!("Data error: {e}");
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+
+        *self.cached.lock().unwrap() = Some((Instant::now(), stats.clone()));
+
+        Ok(stats)
+    }
+}
+
+fn nodeinfo_link(config: &MagnetarConfig, rel: impl Rel, version: &str) -> NodeInfoDiscoveryLink {
+    NodeInfoDiscoveryLink {
+        href: format!(
+            "{}://{}{NODEINFO_PATH}/{version}",
+            config.networking.protocol, config.networking.host
+        ),
+        rel: rel.rel().to_owned(),
+    }
+}
+
+/// Serves `/.well-known/nodeinfo` as `{"links": [...]}`, as the NodeInfo protocol requires
+///
+/// Only the 2.x documents are served. The 1.x schemas only allow a fixed list of software
+/// names and protocols, neither of which includes this software or ActivityPub.
+pub async fn handle_nodeinfo(State(config): State<ConfigHandle>) -> Json<NodeInfoDiscovery> {
+    let config = &*config.load();
+
+    Json(NodeInfoDiscovery {
+        links: vec![
+            nodeinfo_link(config, RelNodeInfo20, "2.0"),
+            nodeinfo_link(config, RelNodeInfo21, "2.1"),
+        ],
     })
 }
 
-pub async fn handle_nodeinfo_20(State(config): State<&'static MagnetarConfig>) -> Json<NodeInfo20> {
-    Json(NodeInfo20 {
-        software: NodeInfo10Software {
-            name: config.branding.name.clone(),
-            version: config.branding.version.clone(),
-        },
-        protocols: HashSet::from(["activitypub".to_owned()]),
-        services: NodeInfo10Services {
-            inbound: HashSet::new(),
-            outbound: HashSet::new(),
+fn software(config: &MagnetarConfig) -> NodeInfo10Software {
+    NodeInfo10Software {
+        name: config.branding.name.clone(),
+        version: config.branding.version.clone(),
+    }
+}
+
+fn services() -> NodeInfo10Services {
+    NodeInfo10Services {
+        inbound: HashSet::new(),
+        outbound: HashSet::new(),
+    }
+}
+
+fn usage(stats: &NodeInfoStats, local_comments: Option<i64>) -> NodeInfo10Usage {
+    NodeInfo10Usage {
+        users: NodeInfo10UsageUsers {
+            total: Some(stats.total_users),
+            active_halfyear: Some(stats.active_halfyear),
+            active_month: Some(stats.active_month),
         },
-        open_registrations: false,
-        usage: NodeInfo10Usage {
-            users: NodeInfo10UsageUsers {
-                total: Some(0),
-                active_halfyear: Some(0),
-                active_month: Some(0),
+        local_posts: Some(stats.local_posts),
+        local_comments,
+    }
+}
+
+/// Responds with the versioned document and the content type of its schema profile
+fn nodeinfo_response(content_type: impl ContentType, nodeinfo: NodeInfo) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, content_type.mime_type())],
+        Json(nodeinfo),
+    )
+}
+
+pub async fn handle_nodeinfo_20(
+    State((config, stats)): State<(ConfigHandle, Arc<NodeInfoStatsCache>)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let config = &*config.load();
+
+    let stats = stats.get(config).await?;
+
+    Ok(nodeinfo_response(
+        ContentNodeInfo20,
+        NodeInfo::V2_0(NodeInfo20 {
+            software: software(config),
+            protocols: HashSet::from(["activitypub".to_owned()]),
+            services: services(),
+            open_registrations: stats.open_registrations,
+            usage: usage(&stats, None),
+            metadata: stats.metadata,
+        }),
+    ))
+}
+
+pub async fn handle_nodeinfo_21(
+    State((config, stats)): State<(ConfigHandle, Arc<NodeInfoStatsCache>)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let config = &*config.load();
+
+    let stats = stats.get(config).await?;
+
+    Ok(nodeinfo_response(
+        ContentNodeInfo21,
+        NodeInfo::V2_1(NodeInfo21 {
+            software: NodeInfo21Software {
+                name: config.branding.name.clone(),
+                version: config.branding.version.clone(),
+                homepage: Some(config.branding.homepage.clone()),
+                repository: Some(config.branding.repository.clone()),
             },
-            local_posts: Some(0),
-            local_comments: None,
-        },
-        metadata: HashMap::new(),
-    })
+            protocols: HashSet::from(["activitypub".to_owned()]),
+            services: services(),
+            open_registrations: stats.open_registrations,
+            usage: usage(&stats, Some(0)),
+            metadata: stats.metadata,
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{ConfigHandle, ConfigLayers, ConfigOptions, MagnetarConfig};
+    use crate::nodeinfo::{handle_nodeinfo, metadata_from_meta, NodeInfoStatsCache};
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use magnetar_calckey_model::ck::{meta, user};
+    use magnetar_calckey_model::test_support::{
+        drop_table, insert_meta, insert_user, local_user, meta, test_database,
+    };
+    use magnetar_calckey_model::CalckeyModel;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+
+    fn config() -> MagnetarConfig {
+        let mut layers = ConfigLayers::default();
+        layers.add_overrides(&[
+            ("networking.host".to_owned(), "example.com".to_owned()),
+            ("data.database_url".to_owned(), "postgres://db".to_owned()),
+        ]);
+        layers.into_config().unwrap()
+    }
+
+    fn config_with_ttl(ttl_secs: u64) -> MagnetarConfig {
+        let mut config = config();
+        config.caching.nodeinfo_stats_ttl_secs = ttl_secs;
+        config
+    }
+
+    fn local_alice() -> user::Model {
+        local_user("9fxdl2pkn3", "alice")
+    }
+
+    fn local_bob() -> user::Model {
+        local_user("9fxdl2pkn4", "bob")
+    }
+
+    fn populated_meta() -> meta::Model {
+        meta::Model {
+            name: Some("Example".to_owned()),
+            description: Some("An example instance".to_owned()),
+            maintainer_name: Some("Admin".to_owned()),
+            maintainer_email: Some("admin@example.com".to_owned()),
+            langs: vec!["en".to_owned(), "ja".to_owned()],
+            to_s_url: Some("https://example.com/tos".to_owned()),
+            repository_url: "https://example.com/magnetar".to_owned(),
+            feedback_url: Some("https://example.com/feedback".to_owned()),
+            disable_registration: true,
+            disable_local_timeline: true,
+            disable_global_timeline: false,
+            email_required_for_signup: true,
+            enable_hcaptcha: true,
+            enable_recaptcha: false,
+            enable_email: true,
+            enable_service_worker: true,
+            proxy_account_id: Some("9fxdl2pkn3".to_owned()),
+            theme_color: Some("#31748f".to_owned()),
+            ..meta()
+        }
+    }
+
+    #[test]
+    fn should_build_metadata_from_meta() {
+        let metadata = metadata_from_meta(&config(), &populated_meta(), Some("proxy".to_owned()));
+
+        assert_eq!(
+            metadata,
+            HashMap::from([
+                ("nodeName".to_owned(), json!("Example")),
+                ("nodeDescription".to_owned(), json!("An example instance")),
+                (
+                    "maintainer".to_owned(),
+                    json!({
+                        "name": "Admin",
+                        "email": "admin@example.com",
+                    })
+                ),
+                ("langs".to_owned(), json!(["en", "ja"])),
+                ("tosUrl".to_owned(), json!("https://example.com/tos")),
+                (
+                    "repositoryUrl".to_owned(),
+                    json!("https://example.com/magnetar")
+                ),
+                (
+                    "feedbackUrl".to_owned(),
+                    json!("https://example.com/feedback")
+                ),
+                ("disableRegistration".to_owned(), json!(true)),
+                ("disableLocalTimeline".to_owned(), json!(true)),
+                ("disableGlobalTimeline".to_owned(), json!(false)),
+                ("emailRequiredForSignup".to_owned(), json!(true)),
+                ("enableHcaptcha".to_owned(), json!(true)),
+                ("enableRecaptcha".to_owned(), json!(false)),
+                ("maxNoteTextLength".to_owned(), json!(3000)),
+                ("enableEmail".to_owned(), json!(true)),
+                ("enableServiceWorker".to_owned(), json!(true)),
+                ("proxyAccountName".to_owned(), json!("proxy")),
+                ("themeColor".to_owned(), json!("#31748f")),
+            ])
+        );
+    }
+
+    #[test]
+    fn should_leave_unset_metadata_null() {
+        let metadata = metadata_from_meta(&config(), &meta(), None);
+
+        for key in [
+            "nodeName",
+            "nodeDescription",
+            "tosUrl",
+            "feedbackUrl",
+            "proxyAccountName",
+            "themeColor",
+        ] {
+            assert_eq!(metadata[key], Value::Null, "{key}");
+        }
+
+        assert_eq!(
+            metadata["maintainer"],
+            json!({ "name": null, "email": null })
+        );
+    }
+
+    async fn stats_cache() -> (NodeInfoStatsCache, CalckeyModel) {
+        let ck = test_database().await.unwrap();
+
+        insert_user(&ck, local_alice()).await.unwrap();
+
+        (NodeInfoStatsCache::new(ck.clone()), ck)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_cache_stats_until_the_ttl() {
+        let (cache, ck) = stats_cache().await;
+        let config = config_with_ttl(300);
+
+        assert_eq!(cache.get(&config).await.unwrap().total_users, 1);
+
+        insert_user(&ck, local_bob()).await.unwrap();
+
+        assert_eq!(cache.get(&config).await.unwrap().total_users, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_refresh_expired_stats() {
+        let (cache, ck) = stats_cache().await;
+        let config = config_with_ttl(0);
+
+        assert_eq!(cache.get(&config).await.unwrap().total_users, 1);
+
+        insert_user(&ck, local_bob()).await.unwrap();
+
+        assert_eq!(cache.get(&config).await.unwrap().total_users, 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_serve_stale_stats_during_a_refresh() {
+        let (cache, ck) = stats_cache().await;
+        let config = config_with_ttl(0);
+
+        assert_eq!(cache.get(&config).await.unwrap().total_users, 1);
+
+        insert_user(&ck, local_bob()).await.unwrap();
+
+        let refreshing = cache.refresh.lock().await;
+        assert_eq!(cache.get(&config).await.unwrap().total_users, 1);
+        drop(refreshing);
+
+        assert_eq!(cache.get(&config).await.unwrap().total_users, 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_serve_stale_stats_when_the_refresh_fails() {
+        let (cache, ck) = stats_cache().await;
+        let config = config_with_ttl(0);
+
+        assert_eq!(cache.get(&config).await.unwrap().total_users, 1);
+
+        drop_table(&ck, "note").await.unwrap();
+
+        assert_eq!(cache.get(&config).await.unwrap().total_users, 1);
+        assert_eq!(
+            NodeInfoStatsCache::new(ck).get(&config).await.unwrap_err(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_read_metadata_from_the_meta_row() {
+        let (cache, ck) = stats_cache().await;
+
+        insert_meta(&ck, populated_meta()).await.unwrap();
+
+        let stats = cache.get(&config()).await.unwrap();
+
+        assert!(!stats.open_registrations);
+        assert_eq!(
+            stats.metadata,
+            metadata_from_meta(&config(), &populated_meta(), Some("alice".to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_wait_for_the_first_refresh() {
+        let (cache, _ck) = stats_cache().await;
+        let config = config_with_ttl(300);
+
+        let (first, second) = tokio::join!(cache.get(&config), cache.get(&config));
+
+        assert_eq!(first.unwrap().total_users, 1);
+        assert_eq!(second.unwrap().total_users, 1);
+    }
+
+    #[tokio::test]
+    async fn should_serve_discovery_as_object() {
+        let handle = ConfigHandle::new(config(), ConfigOptions::default());
+        let discovery = serde_json::to_value(handle_nodeinfo(State(handle)).await.0).unwrap();
+
+        assert_eq!(
+            discovery,
+            json!({
+                "links": [
+                    {
+                        "rel": "http://nodeinfo.diaspora.software/ns/schema/2.0",
+                        "href": "https://example.com/nodeinfo/2.0"
+                    },
+                    {
+                        "rel": "http://nodeinfo.diaspora.software/ns/schema/2.1",
+                        "href": "https://example.com/nodeinfo/2.1"
+                    }
+                ]
+            })
+        );
+    }
 }
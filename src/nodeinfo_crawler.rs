@@ -0,0 +1,451 @@
+use crate::public_address::check_public_url;
+use crate::signature::signer::authority;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use magnetar_calckey_model::ck::instance;
+use magnetar_calckey_model::{CalckeyModel, InstanceInfo};
+use magnetar_core::web_model::content_type::ContentJson;
+use magnetar_core::web_model::rel::{RelNodeInfo10, RelNodeInfo11, RelNodeInfo20, RelNodeInfo21};
+use magnetar_core::web_model::Rel;
+use magnetar_nodeinfo::{NodeInfo, NodeInfoDiscovery};
+use magnetar_webfinger::client::{HttpTransport, TransportError};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+/// How old the information about an instance may get before it is refreshed
+const REFRESH_AFTER_HOURS: i64 = 24;
+const CRAWL_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const CRAWL_BATCH_SIZE: u64 = 32;
+const MIN_BACKOFF_HOURS: i64 = 1;
+const MAX_BACKOFF_DAYS: i64 = 7;
+
+#[derive(Debug, Error)]
+pub enum CrawlError {
+    #[error("Request to {0} failed: {1}")]
+    Transport(String, TransportError),
+    #[error("Request to {0} failed with status {1}")]
+    Status(String, u16),
+    #[error("Failed to parse the response of {0}: {1}")]
+    Parse(String, serde_json::Error),
+    #[error("No supported NodeInfo version advertised by {0}")]
+    NoSupportedVersion(String),
+    #[error("Refusing to crawl {0}: {1}")]
+    NotPublic(String, String),
+}
+
+impl CrawlError {
+    /// The HTTP status to record, if a response was received
+    fn status(&self) -> Option<i32> {
+        match self {
+            CrawlError::Transport(..) | CrawlError::NotPublic(..) => None,
+            CrawlError::Status(_, status) => Some(*status as i32),
+            CrawlError::Parse(..) | CrawlError::NoSupportedVersion(_) => Some(200),
+        }
+    }
+}
+
+async fn get_json<T: DeserializeOwned>(
+    transport: &impl HttpTransport,
+    url: &str,
+) -> Result<T, CrawlError> {
+    let response = transport
+        .get(url, ContentJson.as_ref())
+        .await
+        .map_err(|e| CrawlError::Transport(url.to_owned(), e))?;
+
+    if !response.is_success() {
+        return Err(CrawlError::Status(url.to_owned(), response.status));
+    }
+
+    serde_json::from_slice(&response.body).map_err(|e| CrawlError::Parse(url.to_owned(), e))
+}
+
+/// The preference of a NodeInfo schema relation, higher is better
+fn nodeinfo_rel_rank(rel: &str) -> Option<u8> {
+    [
+        RelNodeInfo10.rel(),
+        RelNodeInfo11.rel(),
+        RelNodeInfo20.rel(),
+        RelNodeInfo21.rel(),
+    ]
+    .iter()
+    .position(|supported| *supported == rel.trim_end_matches('#'))
+    .map(|rank| rank as u8)
+}
+
+/// Whether a NodeInfo document is served by the crawled host itself
+///
+/// Links to other hosts are not followed, the discovery document could otherwise make us
+/// request any URL, including ones of the local network.
+fn is_on_host(href: &str, host: &str) -> bool {
+    Url::parse(href).is_ok_and(|url| {
+        matches!(url.scheme(), "http" | "https")
+            && authority(&url).is_some_and(|authority| authority.eq_ignore_ascii_case(host))
+    })
+}
+
+/// Refuses hosts on the local machine or network, any host can be reported by remote servers
+async fn check_host(host: &str) -> Result<(), CrawlError> {
+    let url = Url::parse(&format!("https://{host}/"))
+        .map_err(|e| CrawlError::NotPublic(host.to_owned(), e.to_string()))?;
+
+    check_public_url(&url)
+        .await
+        .map_err(|e| CrawlError::NotPublic(host.to_owned(), e))
+}
+
+/// Discovers and fetches the highest supported NodeInfo version of a host
+pub async fn fetch_nodeinfo(
+    transport: &impl HttpTransport,
+    host: &str,
+) -> Result<NodeInfo, CrawlError> {
+    let discovery: NodeInfoDiscovery =
+        get_json(transport, &format!("https://{host}/.well-known/nodeinfo")).await?;
+
+    let href = discovery
+        .links
+        .iter()
+        .filter(|link| {
+            let on_host = is_on_host(&link.href, host);
+
+            if !on_host {
+                debug!("Ignoring the NodeInfo link {} of {host}", link.href);
+            }
+
+            on_host
+        })
+        .filter_map(|link| Some((nodeinfo_rel_rank(&link.rel)?, &link.href)))
+        .max_by_key(|(rank, _)| *rank)
+        .map(|(_, href)| href)
+        .ok_or_else(|| CrawlError::NoSupportedVersion(host.to_owned()))?;
+
+    get_json(transport, href).await
+}
+
+#[derive(Deserialize)]
+struct WebManifestIcon {
+    src: String,
+}
+
+#[derive(Deserialize)]
+struct WebManifest {
+    #[serde(default)]
+    icons: Vec<WebManifestIcon>,
+}
+
+/// Best-effort lookup of the instance icon from its web app manifest
+async fn fetch_icon_url(transport: &impl HttpTransport, host: &str) -> Option<String> {
+    let manifest_url = format!("https://{host}/manifest.json");
+    let manifest: WebManifest = get_json(transport, &manifest_url).await.ok()?;
+    let icon = manifest.icons.first()?;
+
+    Url::parse(&manifest_url)
+        .and_then(|base| base.join(&icon.src))
+        .map(String::from)
+        .ok()
+}
+
+fn metadata_str(metadata: &HashMap<String, Value>, key: &str) -> Option<String> {
+    metadata.get(key).and_then(Value::as_str).map(str::to_owned)
+}
+
+fn maintainer_str(metadata: &HashMap<String, Value>, key: &str) -> Option<String> {
+    metadata
+        .get("maintainer")
+        .and_then(|maintainer| maintainer.get(key))
+        .and_then(Value::as_str)
+        .map(str::to_owned)
+}
+
+/// Normalizes the instance information across NodeInfo versions
+fn instance_info(nodeinfo: &NodeInfo) -> InstanceInfo {
+    let metadata = nodeinfo.metadata();
+
+    InstanceInfo {
+        software_name: Some(nodeinfo.software_name().to_lowercase()),
+        software_version: Some(nodeinfo.software_version().to_owned()),
+        open_registrations: Some(nodeinfo.open_registrations()),
+        name: metadata_str(metadata, "nodeName").or_else(|| metadata_str(metadata, "name")),
+        description: metadata_str(metadata, "nodeDescription")
+            .or_else(|| metadata_str(metadata, "description")),
+        maintainer_name: maintainer_str(metadata, "name"),
+        maintainer_email: maintainer_str(metadata, "email"),
+        icon_url: None,
+        theme_color: metadata_str(metadata, "themeColor"),
+    }
+}
+
+/// How long to wait before retrying an instance that is not responding
+///
+/// The delay grows with the time since the last successful communication,
+/// which roughly doubles it with every failed attempt.
+fn retry_backoff(
+    latest_request_sent_at: DateTime<Utc>,
+    last_communicated_at: DateTime<Utc>,
+) -> ChronoDuration {
+    (latest_request_sent_at - last_communicated_at).clamp(
+        ChronoDuration::hours(MIN_BACKOFF_HOURS),
+        ChronoDuration::days(MAX_BACKOFF_DAYS),
+    )
+}
+
+fn is_due(instance: &instance::Model, now: DateTime<Utc>) -> bool {
+    match instance.latest_request_sent_at {
+        Some(sent_at) if instance.is_not_responding => {
+            let sent_at = sent_at.with_timezone(&Utc);
+            let last_communicated_at = instance.last_communicated_at.with_timezone(&Utc);
+
+            now - sent_at >= retry_backoff(sent_at, last_communicated_at)
+        }
+        _ => true,
+    }
+}
+
+/// Periodically refreshes the software information of remote instances from their NodeInfo
+pub struct NodeInfoCrawler<T: HttpTransport> {
+    ck: CalckeyModel,
+    transport: T,
+}
+
+impl<T: HttpTransport> NodeInfoCrawler<T> {
+    pub fn new(ck: CalckeyModel, transport: T) -> Self {
+        NodeInfoCrawler { ck, transport }
+    }
+
+    pub async fn refresh_instance(&self, host: &str) -> anyhow::Result<()> {
+        let fetched = match check_host(host).await {
+            Ok(()) => fetch_nodeinfo(&self.transport, host).await,
+            Err(e) => Err(e),
+        };
+
+        match fetched {
+            Ok(nodeinfo) => {
+                let info = InstanceInfo {
+                    icon_url: fetch_icon_url(&self.transport, host).await,
+                    ..instance_info(&nodeinfo)
+                };
+
+                self.ck.upsert_instance_info(host, info).await
+            }
+            Err(e) => {
+                debug!("Failed to fetch the NodeInfo of {host}: {e}");
+
+                self.ck.mark_instance_not_responding(host, e.status()).await
+            }
+        }
+    }
+
+    async fn crawl(&self) -> anyhow::Result<()> {
+        let now = Utc::now();
+        let instances = self
+            .ck
+            .get_instances_with_stale_info(
+                now - ChronoDuration::hours(REFRESH_AFTER_HOURS),
+                CRAWL_BATCH_SIZE,
+            )
+            .await?;
+
+        for instance in instances.iter().filter(|instance| is_due(instance, now)) {
+            if let Err(e) = self.refresh_instance(&instance.host).await {
+                warn!("Failed to refresh instance {}: {e}", instance.host);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn run(self) {
+        info!("Starting the NodeInfo crawler");
+
+        let mut interval = tokio::time::interval(CRAWL_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = self.crawl().await {
+                error!("NodeInfo crawl failed: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::nodeinfo_crawler::{
+        check_host, fetch_nodeinfo, instance_info, retry_backoff, CrawlError,
+    };
+    use chrono::{Duration, TimeZone, Utc};
+    use magnetar_calckey_model::InstanceInfo;
+    use magnetar_nodeinfo::NodeInfo;
+    use magnetar_webfinger::test_support::StubTransport;
+    use serde_json::{json, Value};
+
+    fn json_calckey() -> Value {
+        json!({
+            "version": "2.1",
+            "software": {
+                "name": "Calckey",
+                "version": "13.1.2"
+            },
+            "protocols": ["activitypub"],
+            "services": {
+                "inbound": [],
+                "outbound": []
+            },
+            "openRegistrations": false,
+            "usage": {
+                "users": {}
+            },
+            "metadata": {
+                "nodeName": "Example",
+                "nodeDescription": "An example instance",
+                "maintainer": {
+                    "name": "Natty",
+                    "email": "natty@example.com"
+                },
+                "themeColor": "#31748f"
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn should_fetch_highest_nodeinfo_version() {
+        let transport = StubTransport::default()
+            .with(
+                "https://example.com/.well-known/nodeinfo",
+                json!({
+                    "links": [
+                        {
+                            "rel": "http://nodeinfo.diaspora.software/ns/schema/2.1",
+                            "href": "https://example.com/nodeinfo/2.1"
+                        },
+                        {
+                            "rel": "http://nodeinfo.diaspora.software/ns/schema/2.0",
+                            "href": "https://example.com/nodeinfo/2.0"
+                        },
+                        {
+                            "rel": "http://example.com/unsupported",
+                            "href": "https://example.com/unsupported"
+                        }
+                    ]
+                }),
+            )
+            .with("https://example.com/nodeinfo/2.1", json_calckey());
+
+        let nodeinfo = fetch_nodeinfo(&transport, "example.com").await.unwrap();
+
+        assert!(matches!(nodeinfo, NodeInfo::V2_1(_)));
+    }
+
+    #[tokio::test]
+    async fn should_refuse_local_hosts() {
+        for host in [
+            "localhost",
+            "127.0.0.1:8080",
+            "10.0.0.1",
+            "[::1]",
+            "169.254.169.254",
+        ] {
+            assert!(
+                matches!(check_host(host).await, Err(CrawlError::NotPublic(..))),
+                "{host}"
+            );
+        }
+
+        assert!(check_host("1.1.1.1").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn should_only_follow_links_to_the_host() {
+        let discovery = json!({
+            "links": [
+                {
+                    "rel": "http://nodeinfo.diaspora.software/ns/schema/2.1",
+                    "href": "http://169.254.169.254/latest/meta-data"
+                },
+                {
+                    "rel": "http://nodeinfo.diaspora.software/ns/schema/2.0",
+                    "href": "https://example.com/nodeinfo/2.0"
+                },
+                {
+                    "rel": "http://nodeinfo.diaspora.software/ns/schema/1.1",
+                    "href": "https://example.com:8443/nodeinfo/1.1"
+                }
+            ]
+        });
+        let mut nodeinfo_20 = json_calckey();
+        nodeinfo_20["version"] = json!("2.0");
+
+        let transport = StubTransport::default()
+            .with("https://example.com/.well-known/nodeinfo", discovery)
+            .with("http://169.254.169.254/latest/meta-data", json_calckey())
+            .with("https://example.com/nodeinfo/2.0", nodeinfo_20);
+
+        let nodeinfo = fetch_nodeinfo(&transport, "example.com").await.unwrap();
+        assert!(matches!(nodeinfo, NodeInfo::V2_0(_)));
+
+        let transport = StubTransport::default().with(
+            "https://example.com/.well-known/nodeinfo",
+            json!({
+                "links": [{
+                    "rel": "http://nodeinfo.diaspora.software/ns/schema/2.1",
+                    "href": "https://other.example/nodeinfo/2.1"
+                }]
+            }),
+        );
+
+        assert!(matches!(
+            fetch_nodeinfo(&transport, "example.com").await,
+            Err(CrawlError::NoSupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn should_normalize_instance_info() {
+        let nodeinfo: NodeInfo = serde_json::from_value(json_calckey()).unwrap();
+
+        assert_eq!(
+            instance_info(&nodeinfo),
+            InstanceInfo {
+                software_name: Some("calckey".to_owned()),
+                software_version: Some("13.1.2".to_owned()),
+                open_registrations: Some(false),
+                name: Some("Example".to_owned()),
+                description: Some("An example instance".to_owned()),
+                maintainer_name: Some("Natty".to_owned()),
+                maintainer_email: Some("natty@example.com".to_owned()),
+                icon_url: None,
+                theme_color: Some("#31748f".to_owned()),
+            }
+        );
+    }
+
+    #[test]
+    fn should_grow_retry_backoff() {
+        let last_communicated_at = Utc.with_ymd_and_hms(2023, 4, 1, 0, 0, 0).unwrap();
+
+        assert_eq!(
+            retry_backoff(last_communicated_at, last_communicated_at),
+            Duration::hours(1)
+        );
+        assert_eq!(
+            retry_backoff(
+                last_communicated_at + Duration::hours(5),
+                last_communicated_at
+            ),
+            Duration::hours(5)
+        );
+        assert_eq!(
+            retry_backoff(
+                last_communicated_at + Duration::days(30),
+                last_communicated_at
+            ),
+            Duration::days(7)
+        );
+    }
+}
@@ -0,0 +1,517 @@
+use crate::accept::Representation;
+use crate::activity_pub::{format_date, iri, render_emoji, render_hashtag, PUBLIC_COLLECTION};
+use crate::config::{ConfigHandle, MagnetarConfig};
+use crate::html::{escape_html, render_page, text_to_html, HtmlPage};
+use crate::util::data_error;
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::{Html, IntoResponse};
+use magnetar_calckey_model::ck::sea_orm_active_enums::NoteVisibilityEnum;
+use magnetar_calckey_model::ck::{drive_file, emoji, note, user};
+use magnetar_calckey_model::CalckeyModel;
+use magnetar_core::web_model::activity_streams::object::{Link, Object, ObjectOrLink, Source};
+use magnetar_core::web_model::activity_streams::property::{OneOrMany, Ref};
+use magnetar_core::web_model::activity_streams::ActivityStreamsDocument;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// A note of a local user, with everything referenced by its rendering
+pub struct LocalNote {
+    pub note: note::Model,
+    pub author: user::Model,
+    pub reply: Option<note::Model>,
+    pub quote: Option<note::Model>,
+    pub files: Vec<drive_file::Model>,
+    /// Local users mentioned in the note
+    pub mentions: Vec<user::Model>,
+    pub emojis: Vec<emoji::Model>,
+}
+
+/// An entry of `mentionedRemoteUsers`, which is stored as serialized JSON
+#[derive(Deserialize)]
+struct RemoteMention {
+    uri: String,
+    username: String,
+    host: String,
+}
+
+/// The IRI of a note, local or remote
+pub fn note_iri(config: &MagnetarConfig, note: &note::Model) -> String {
+    match note.uri {
+        Some(ref uri) => uri.clone(),
+        None => format!(
+            "{}://{}/notes/{}",
+            config.networking.protocol, config.networking.host, note.id
+        ),
+    }
+}
+
+fn iris(values: Vec<String>) -> Option<OneOrMany<Ref<ObjectOrLink>>> {
+    Some(OneOrMany::Many(values.into_iter().map(iri).collect()))
+}
+
+/// Notes that are served publicly, the others require authorization
+pub fn is_served(note: &note::Model) -> bool {
+    note.user_host.is_none()
+        && !note.local_only
+        && matches!(
+            note.visibility,
+            NoteVisibilityEnum::Public | NoteVisibilityEnum::Home
+        )
+}
+
+/// Renotes without content of their own, which are `Announce` activities rather than objects
+pub fn is_pure_renote(note: &note::Model) -> bool {
+    note.renote_id.is_some() && note.text.is_none() && note.file_ids.is_empty() && !note.has_poll
+}
+
+/// Renders a note of a local user as an ActivityStreams `Note`
+///
+/// Polls are rendered as plain notes.
+pub fn render_note(config: &MagnetarConfig, local: LocalNote) -> ObjectOrLink {
+    let LocalNote {
+        note,
+        author,
+        reply,
+        quote,
+        files,
+        mentions,
+        emojis,
+    } = local;
+
+    let base = format!(
+        "{}://{}",
+        config.networking.protocol, config.networking.host
+    );
+    let author_id = format!("{base}/users/{}", author.id);
+    let followers = format!("{author_id}/followers");
+
+    let mut mention_tags = mentions
+        .iter()
+        .map(|user| {
+            (
+                format!("{base}/users/{}", user.id),
+                format!("@{}@{}", user.username, config.networking.host),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    mention_tags.extend(
+        serde_json::from_str::<Vec<RemoteMention>>(&note.mentioned_remote_users)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|mention| {
+                (
+                    mention.uri,
+                    format!("@{}@{}", mention.username, mention.host),
+                )
+            }),
+    );
+
+    let mentioned = mention_tags.iter().map(|(href, _)| href.clone());
+    let (to, cc) = match note.visibility {
+        NoteVisibilityEnum::Home => (
+            vec![followers],
+            [PUBLIC_COLLECTION.to_owned()]
+                .into_iter()
+                .chain(mentioned)
+                .collect(),
+        ),
+        _ => (
+            vec![PUBLIC_COLLECTION.to_owned()],
+            [followers].into_iter().chain(mentioned).collect(),
+        ),
+    };
+
+    let mut tags = mention_tags
+        .into_iter()
+        .map(|(href, name)| {
+            Ref::from(ObjectOrLink::Mention(Link {
+                href: Some(href),
+                name: Some(name),
+                ..Link::default()
+            }))
+        })
+        .collect::<Vec<_>>();
+
+    tags.extend(note.tags.iter().map(|tag| render_hashtag(&base, tag)));
+
+    tags.extend(emojis.into_iter().map(|emoji| render_emoji(&base, emoji)));
+
+    let sensitive = note.cw.is_some() || files.iter().any(|file| file.is_sensitive);
+
+    let attachment = files
+        .into_iter()
+        .map(|file| {
+            let mut extensions = Map::new();
+            extensions.insert("sensitive".to_owned(), Value::Bool(file.is_sensitive));
+
+            if let Some(blurhash) = file.blurhash {
+                extensions.insert("blurhash".to_owned(), Value::String(blurhash));
+            }
+
+            Ref::from(ObjectOrLink::Document(Object {
+                name: file.comment,
+                url: Some(iri(file.webpublic_url.unwrap_or(file.url)).into()),
+                media_type: Some(file.webpublic_type.unwrap_or(file.r#type)),
+                extensions,
+                ..Object::default()
+            }))
+        })
+        .collect::<Vec<_>>();
+
+    let mut extensions = Map::new();
+    extensions.insert("sensitive".to_owned(), Value::Bool(sensitive));
+
+    if let Some(ref text) = note.text {
+        extensions.insert("_misskey_content".to_owned(), Value::String(text.clone()));
+    }
+
+    if let Some(ref quote) = quote {
+        let quote = Value::String(note_iri(config, quote));
+        extensions.insert("_misskey_quote".to_owned(), quote.clone());
+        extensions.insert("quoteUrl".to_owned(), quote);
+    }
+
+    ObjectOrLink::Note(Object {
+        id: Some(format!("{base}/notes/{}", note.id)),
+        attributed_to: Some(iri(author_id).into()),
+        summary: note.cw,
+        content: note.text.as_deref().map(text_to_html),
+        source: note.text.map(|text| Source {
+            content: Some(text),
+            media_type: Some("text/x.misskeymarkdown".to_owned()),
+        }),
+        published: Some(format_date(&note.created_at)),
+        in_reply_to: reply
+            .as_ref()
+            .map(|reply| iri(note_iri(config, reply)).into()),
+        to: iris(to),
+        cc: iris(cc),
+        tag: Some(OneOrMany::Many(tags)),
+        attachment: Some(OneOrMany::Many(attachment)),
+        extensions,
+        ..Object::default()
+    })
+}
+
+/// Renders a note for browsers
+pub fn render_note_html(config: &MagnetarConfig, local: &LocalNote) -> Html<String> {
+    let LocalNote { note, author, .. } = local;
+
+    let base = format!(
+        "{}://{}",
+        config.networking.protocol, config.networking.host
+    );
+    let acct = format!("@{}@{}", author.username, config.networking.host);
+    let title = format!(
+        "{} ({acct})",
+        author.name.as_deref().unwrap_or(&author.username)
+    );
+    // Content warnings hide the text in link previews as well
+    let description = note.cw.as_deref().or(note.text.as_deref());
+
+    let mut body = format!(
+        r#"<article><header><a href="{base}/@{}">{}</a></header>"#,
+        escape_html(&author.username),
+        escape_html(&title)
+    );
+
+    if let Some(ref cw) = note.cw {
+        body.push_str(&format!("<p><strong>{}</strong></p>", escape_html(cw)));
+    }
+
+    if let Some(ref text) = note.text {
+        body.push_str(&text_to_html(text));
+    }
+
+    body.push_str("</article>");
+
+    render_page(
+        config,
+        HtmlPage {
+            title: &title,
+            description,
+            image: None,
+            url: &format!("{base}/notes/{}", note.id),
+            activity_pub_id: &format!("{base}/notes/{}", note.id),
+            body,
+        },
+    )
+}
+
+pub async fn load_local_note(
+    ck: &CalckeyModel,
+    note: note::Model,
+) -> Result<LocalNote, StatusCode> {
+    let author = ck
+        .get_user_by_id(&note.user_id)
+        .await
+        .map_err(data_error)?
+        .filter(|user| !user.is_deleted && !user.is_suspended)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let reply = match note.reply_id {
+        Some(ref reply_id) => ck.get_note_by_id(reply_id).await.map_err(data_error)?,
+        None => None,
+    };
+
+    let quote = match note.renote_id {
+        Some(ref renote_id) => ck.get_note_by_id(renote_id).await.map_err(data_error)?,
+        None => None,
+    };
+
+    let files = ck
+        .get_drive_files_by_ids(&note.file_ids)
+        .await
+        .map_err(data_error)?;
+
+    let mentions = ck
+        .get_users_by_ids(&note.mentions)
+        .await
+        .map_err(data_error)?
+        .into_iter()
+        .filter(|user| user.host.is_none())
+        .collect();
+
+    let emojis = ck
+        .get_local_emojis_by_names(&note.emojis)
+        .await
+        .map_err(data_error)?;
+
+    Ok(LocalNote {
+        note,
+        author,
+        reply,
+        quote,
+        files,
+        mentions,
+        emojis,
+    })
+}
+
+pub async fn handle_note(
+    Path(id): Path<String>,
+    representation: Representation,
+    State((config, ck)): State<(ConfigHandle, CalckeyModel)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let config = &*config.load();
+
+    let note = ck
+        .get_note_by_id(&id)
+        .await
+        .map_err(data_error)?
+        .filter(is_served)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    if is_pure_renote(&note) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let note = load_local_note(&ck, note).await?;
+
+    let response = match representation {
+        Representation::Html => render_note_html(config, &note).into_response(),
+        Representation::ActivityPub => {
+            ActivityStreamsDocument::new(render_note(config, note)).into_response()
+        }
+    };
+
+    Ok(([(header::VARY, header::ACCEPT.as_str())], response))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{ConfigLayers, MagnetarConfig};
+    use crate::note::{is_served, render_note, LocalNote};
+    use chrono::{DateTime, FixedOffset};
+    use magnetar_calckey_model::ck::sea_orm_active_enums::NoteVisibilityEnum;
+    use magnetar_calckey_model::ck::{drive_file, emoji, note};
+    use magnetar_calckey_model::test_support::local_user;
+    use serde_json::json;
+
+    fn config() -> MagnetarConfig {
+        let mut layers = ConfigLayers::default();
+        layers.add_overrides(&[
+            ("networking.host".to_owned(), "example.com".to_owned()),
+            ("data.database_url".to_owned(), "postgres://db".to_owned()),
+        ]);
+        layers.into_config().unwrap()
+    }
+
+    fn date() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2023-04-01T12:00:00Z").unwrap()
+    }
+
+    fn photo() -> drive_file::Model {
+        drive_file::Model {
+            id: "9fxdl2pkn4".to_owned(),
+            created_at: date(),
+            user_id: Some("9fxdl2pkn3".to_owned()),
+            user_host: None,
+            md5: String::new(),
+            name: "photo.png".to_owned(),
+            r#type: "image/png".to_owned(),
+            size: 0,
+            comment: None,
+            properties: json!({}),
+            stored_internal: true,
+            url: "https://example.com/files/photo.png".to_owned(),
+            thumbnail_url: None,
+            webpublic_url: None,
+            access_key: None,
+            thumbnail_access_key: None,
+            webpublic_access_key: None,
+            uri: None,
+            src: None,
+            folder_id: None,
+            is_sensitive: false,
+            is_link: false,
+            blurhash: None,
+            webpublic_type: None,
+            request_headers: None,
+            request_ip: None,
+            maybe_sensitive: false,
+            maybe_porn: false,
+        }
+    }
+
+    fn blobcat() -> emoji::Model {
+        emoji::Model {
+            id: "9fxdl2pkn5".to_owned(),
+            updated_at: Some(date()),
+            name: "blobcat".to_owned(),
+            host: None,
+            original_url: "https://example.com/files/blobcat.png".to_owned(),
+            uri: None,
+            r#type: Some("image/png".to_owned()),
+            aliases: Vec::new(),
+            category: None,
+            public_url: String::new(),
+            license: None,
+        }
+    }
+
+    fn note(visibility: NoteVisibilityEnum) -> note::Model {
+        note::Model {
+            id: "9fxdl3aaaa".to_owned(),
+            created_at: date(),
+            reply_id: None,
+            renote_id: None,
+            text: Some("Hello #rust :blobcat:".to_owned()),
+            name: None,
+            cw: None,
+            user_id: "9fxdl2pkn3".to_owned(),
+            local_only: false,
+            renote_count: 0,
+            replies_count: 0,
+            reactions: json!({}),
+            visibility,
+            uri: None,
+            score: 0,
+            file_ids: vec!["9fxdl2pkn4".to_owned()],
+            attached_file_types: vec!["image/png".to_owned()],
+            visible_user_ids: Vec::new(),
+            mentions: Vec::new(),
+            mentioned_remote_users: json!([{
+                "uri": "https://remote.example/users/bob",
+                "url": "https://remote.example/@bob",
+                "username": "bob",
+                "host": "remote.example"
+            }])
+            .to_string(),
+            emojis: vec!["blobcat".to_owned()],
+            tags: vec!["rust".to_owned()],
+            has_poll: false,
+            user_host: None,
+            reply_user_id: None,
+            reply_user_host: None,
+            renote_user_id: None,
+            renote_user_host: None,
+            url: None,
+            channel_id: None,
+            thread_id: None,
+        }
+    }
+
+    fn local_note(note: note::Model) -> LocalNote {
+        LocalNote {
+            note,
+            author: local_user("9fxdl2pkn3", "alice"),
+            reply: None,
+            quote: None,
+            files: vec![photo()],
+            mentions: Vec::new(),
+            emojis: vec![blobcat()],
+        }
+    }
+
+    #[test]
+    fn should_render_public_note() {
+        let json = serde_json::to_value(render_note(
+            &config(),
+            local_note(note(NoteVisibilityEnum::Public)),
+        ))
+        .unwrap();
+
+        assert_eq!(json["type"], "Note");
+        assert_eq!(json["id"], "https://example.com/notes/9fxdl3aaaa");
+        assert_eq!(json["attributedTo"], "https://example.com/users/9fxdl2pkn3");
+        assert_eq!(
+            json["to"],
+            json!(["https://www.w3.org/ns/activitystreams#Public"])
+        );
+        assert_eq!(
+            json["cc"],
+            json!([
+                "https://example.com/users/9fxdl2pkn3/followers",
+                "https://remote.example/users/bob"
+            ])
+        );
+        assert_eq!(json["content"], "<p>Hello #rust :blobcat:</p>");
+        assert_eq!(json["_misskey_content"], "Hello #rust :blobcat:");
+        assert_eq!(json["published"], "2023-04-01T12:00:00.000Z");
+        assert_eq!(json["sensitive"], false);
+        assert_eq!(json["attachment"][0]["type"], "Document");
+        assert_eq!(
+            json["tag"][0],
+            json!({
+                "type": "Mention",
+                "href": "https://remote.example/users/bob",
+                "name": "@bob@remote.example"
+            })
+        );
+        assert_eq!(json["tag"][1]["type"], "Hashtag");
+        assert_eq!(json["tag"][2]["type"], "Emoji");
+    }
+
+    #[test]
+    fn should_address_home_notes_to_followers() {
+        let json = serde_json::to_value(render_note(
+            &config(),
+            local_note(note(NoteVisibilityEnum::Home)),
+        ))
+        .unwrap();
+
+        assert_eq!(
+            json["to"],
+            json!(["https://example.com/users/9fxdl2pkn3/followers"])
+        );
+        assert_eq!(
+            json["cc"][0],
+            "https://www.w3.org/ns/activitystreams#Public"
+        );
+    }
+
+    #[test]
+    fn should_only_serve_public_notes() {
+        assert!(is_served(&note(NoteVisibilityEnum::Public)));
+        assert!(is_served(&note(NoteVisibilityEnum::Home)));
+        assert!(!is_served(&note(NoteVisibilityEnum::Followers)));
+        assert!(!is_served(&note(NoteVisibilityEnum::Specified)));
+        assert!(!is_served(&note::Model {
+            local_only: true,
+            ..note(NoteVisibilityEnum::Public)
+        }));
+    }
+}
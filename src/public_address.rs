@@ -0,0 +1,161 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use url::{Host, Url};
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network", shared address space, IETF protocol assignments and benchmarking
+        || a == 0
+        || a == 100 && (64..128).contains(&b)
+        || a == 192 && b == 0 && c == 0
+        || a == 198 && (18..20).contains(&b)
+        // Reserved
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return is_public_ipv4(ip);
+    }
+
+    let [first, second, ..] = ip.segments();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, link-local and site-local
+        || first & 0xfe00 == 0xfc00
+        || first & 0xffc0 == 0xfe80
+        || first & 0xffc0 == 0xfec0
+        // Documentation
+        || first == 0x2001 && second == 0x0db8)
+}
+
+/// Whether an address can be reached over the internet, rather than only locally
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => is_public_ipv6(ip),
+    }
+}
+
+/// Whether a URL may be fetched on behalf of remote servers, without resolving its host
+///
+/// Only HTTP(S) URLs whose host is a domain other than `localhost`, or a public IP, qualify.
+pub fn is_public_url(url: &Url) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+
+    match url.host() {
+        Some(Host::Domain(domain)) => {
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            domain != "localhost" && !domain.ends_with(".localhost")
+        }
+        Some(Host::Ipv4(ip)) => is_public_ipv4(ip),
+        Some(Host::Ipv6(ip)) => is_public_ipv6(ip),
+        None => false,
+    }
+}
+
+/// Refuses URLs that point to the local machine or network, so remote servers cannot
+/// make us request internal services
+///
+/// Every address the host resolves to must be public.
+pub async fn check_public_url(url: &Url) -> Result<(), String> {
+    if !is_public_url(url) {
+        return Err(format!("{url} is not a public URL"));
+    }
+
+    let Some(Host::Domain(domain)) = url.host() else {
+        return Ok(());
+    };
+
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addresses = tokio::net::lookup_host((domain, port))
+        .await
+        .map_err(|e| format!("failed to resolve {domain}: {e}"))?;
+
+    for address in addresses {
+        if !is_public_ip(address.ip()) {
+            return Err(format!(
+                "{domain} resolves to the non-public {}",
+                address.ip()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::public_address::{check_public_url, is_public_ip, is_public_url};
+    use std::net::IpAddr;
+    use url::Url;
+
+    #[test]
+    fn should_detect_public_ips() {
+        for public in [
+            "93.184.216.34",
+            "2606:2800:220:1:248:1893:25c8:1946",
+            "1.1.1.1",
+        ] {
+            assert!(is_public_ip(public.parse().unwrap()), "{public}");
+        }
+
+        for local in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+        ] {
+            assert!(!is_public_ip(local.parse::<IpAddr>().unwrap()), "{local}");
+        }
+    }
+
+    #[test]
+    fn should_detect_public_urls() {
+        let public = |url: &str| is_public_url(&Url::parse(url).unwrap());
+
+        assert!(public("https://example.com/users/alice#main-key"));
+        assert!(public("http://93.184.216.34/actor"));
+        assert!(!public("https://localhost/actor"));
+        assert!(!public("https://LOCALHOST./actor"));
+        assert!(!public("https://api.localhost/actor"));
+        assert!(!public("http://127.0.0.1:8080/actor"));
+        assert!(!public("http://[::1]/actor"));
+        assert!(!public("http://169.254.169.254/latest/meta-data"));
+        assert!(!public("file:///etc/passwd"));
+    }
+
+    #[tokio::test]
+    async fn should_refuse_local_urls_without_resolving() {
+        for url in ["http://10.0.0.1/actor", "https://localhost/actor"] {
+            assert!(check_public_url(&Url::parse(url).unwrap()).await.is_err());
+        }
+
+        assert!(
+            check_public_url(&Url::parse("https://1.1.1.1/actor").unwrap())
+                .await
+                .is_ok()
+        );
+    }
+}
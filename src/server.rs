@@ -0,0 +1,361 @@
+use crate::config::{MagnetarListener, UnixSocketMode};
+use crate::forwarded::PeerAddr;
+use axum::Router;
+use hyper::server::accept::Accept;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::Sleep;
+use tracing::{debug, error, info, warn};
+
+/// How long to wait before accepting again after an error like running out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Errors of a single connection, which do not affect the connections after it
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Accepts connections on a Unix socket
+///
+/// Accept errors are logged and retried like hyper's `AddrIncoming` does, returning them
+/// would end the server and with it every other listener.
+struct UnixAccept {
+    listener: UnixListener,
+    backoff: Option<Pin<Box<Sleep>>>,
+}
+
+impl UnixAccept {
+    fn new(listener: UnixListener) -> Self {
+        UnixAccept {
+            listener,
+            backoff: None,
+        }
+    }
+}
+
+impl Accept for UnixAccept {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(backoff) = this.backoff.as_mut() {
+                ready!(backoff.as_mut().poll(cx));
+                this.backoff = None;
+            }
+
+            match ready!(this.listener.poll_accept(cx)) {
+                Ok((stream, _)) => return Poll::Ready(Some(Ok(stream))),
+                Err(e) if is_connection_error(&e) => {
+                    debug!("Accepted connection already closed: {e}");
+                }
+                Err(e) => {
+                    error!("Failed to accept a connection, retrying in {ACCEPT_BACKOFF:?}: {e}");
+                    this.backoff = Some(Box::pin(tokio::time::sleep(ACCEPT_BACKOFF)));
+                }
+            }
+        }
+    }
+}
+
+/// Removes a socket left behind by an unclean shutdown, which would make the bind fail
+///
+/// Sockets somebody still listens on are left alone, as is anything that is not a socket.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another process", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// Binds a socket at `path` that is never reachable with broader permissions than `mode`
+///
+/// The socket is bound in a directory only we can access, restricted, and then moved in place.
+fn bind_unix(path: &Path, mode: UnixSocketMode) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    remove_stale_socket(path)?;
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a file path", path.display()),
+        )
+    })?;
+
+    let private_dir = parent.join(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)?;
+
+    let private_path = private_dir.join(file_name);
+    let bound = UnixListener::bind(&private_path).and_then(|listener| {
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(mode.0))?;
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+
+    if bound.is_err() {
+        std::fs::remove_file(&private_path).ok();
+    }
+    if let Err(e) = std::fs::remove_dir(&private_dir) {
+        warn!("Failed to remove {}: {e}", private_dir.display());
+    }
+
+    bound
+}
+
+/// Resolves once SIGTERM or Ctrl+C is received
+pub async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = terminate => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
+}
+
+/// Serves the app on every listener until `shutdown` resolves
+///
+/// All listeners are bound before any of them starts serving, so a bad listener
+/// fails the startup as a whole. On shutdown the listeners stop accepting connections
+/// and in-flight requests are drained before this returns.
+pub async fn serve(
+    app: Router,
+    listeners: &[MagnetarListener],
+    unix_socket_mode: UnixSocketMode,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let mut servers = JoinSet::new();
+
+    for listener in listeners {
+        let mut shutdown_rx = shutdown_rx.clone();
+        let graceful = async move {
+            shutdown_rx.changed().await.ok();
+        };
+
+        match listener {
+            MagnetarListener::Tcp(addr) => {
+                let server = axum::Server::try_bind(addr)
+                    .map_err(|e| anyhow::anyhow!("Failed to bind {listener}: {e}"))?
+                    .serve(
+                        app.clone()
+                            .into_make_service_with_connect_info::<PeerAddr>(),
+                    )
+                    .with_graceful_shutdown(graceful);
+
+                servers.spawn(server);
+            }
+            MagnetarListener::Unix(path) => {
+                let unix_listener = bind_unix(path, unix_socket_mode)
+                    .map_err(|e| anyhow::anyhow!("Failed to bind {listener}: {e}"))?;
+
+                let server = axum::Server::builder(UnixAccept::new(unix_listener))
+                    .serve(
+                        app.clone()
+                            .into_make_service_with_connect_info::<PeerAddr>(),
+                    )
+                    .with_graceful_shutdown(graceful);
+
+                servers.spawn(server);
+            }
+        }
+
+        info!("Serving on: {listener}");
+    }
+
+    drop(shutdown_rx);
+
+    let mut result = Ok(());
+
+    tokio::select! {
+        _ = shutdown => {
+            info!("Shutting down, draining in-flight requests");
+            shutdown_tx.send(()).ok();
+        }
+        Some(finished) = servers.join_next() => {
+            result = Err(anyhow::anyhow!("A listener stopped unexpectedly: {finished:?}"));
+            shutdown_tx.send(()).ok();
+        }
+    }
+
+    while let Some(finished) = servers.join_next().await {
+        match finished {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Error while shutting down a listener: {e}"),
+            Err(e) => warn!("Listener task failed: {e}"),
+        }
+    }
+
+    for listener in listeners {
+        if let MagnetarListener::Unix(path) = listener {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Failed to remove the socket {}: {e}", path.display());
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{MagnetarListener, UnixSocketMode};
+    use crate::server::{bind_unix, serve};
+    use axum::routing::get;
+    use axum::Router;
+    use hyper::{Body, Request, StatusCode};
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::UnixStream;
+    use tokio::sync::{oneshot, Notify};
+
+    #[tokio::test]
+    async fn should_drain_unix_listener_on_shutdown() {
+        let path = std::env::temp_dir().join(format!("magnetar-test-{}.sock", std::process::id()));
+        let started = Arc::new(Notify::new());
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        let app = Router::new().route(
+            "/slow",
+            get({
+                let started = started.clone();
+                || async move {
+                    started.notify_one();
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    "done"
+                }
+            }),
+        );
+
+        let server = tokio::spawn({
+            let listeners = vec![MagnetarListener::Unix(path.clone())];
+            async move {
+                serve(app, &listeners, UnixSocketMode(0o600), async {
+                    shutdown_rx.await.ok();
+                })
+                .await
+            }
+        });
+
+        let stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+
+        let response = tokio::spawn(
+            sender.send_request(
+                Request::builder()
+                    .uri("/slow")
+                    .header("Host", "localhost")
+                    .body(Body::empty())
+                    .unwrap(),
+            ),
+        );
+
+        // Shut down while the request is in flight
+        started.notified().await;
+        shutdown_tx.send(()).unwrap();
+
+        let response = response.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"done");
+
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn should_only_replace_stale_sockets() {
+        let dir = std::env::temp_dir().join(format!("magnetar-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("stale.sock");
+
+        let live = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let error = bind_unix(&path, UnixSocketMode(0o600)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AddrInUse);
+        assert!(path.exists());
+
+        // The socket of a process that is gone
+        drop(live);
+        let listener = bind_unix(&path, UnixSocketMode(0o660)).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        assert!(UnixStream::connect(&path).await.is_ok());
+        drop(listener);
+
+        let file = dir.join("file");
+        std::fs::write(&file, "").unwrap();
+        let error = bind_unix(&file, UnixSocketMode(0o600)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+
+        // Only the socket and the file are left, not the private directories
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,282 @@
+//! HTTP Signatures as specified by draft-cavage-http-signatures-12, used by most of the fediverse
+
+use crate::signature::{SignatureAlgorithm, SignatureError};
+use axum::http::{header, HeaderMap, Method, Uri};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+/// A parsed `Signature` header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignatureHeader {
+    pub key_id: String,
+    pub algorithm: Option<String>,
+    /// Lowercase names of the signed headers, in signing order
+    pub headers: Vec<String>,
+    pub signature: Vec<u8>,
+    pub created: Option<i64>,
+    pub expires: Option<i64>,
+}
+
+/// Splits `key="value",key=value` parameters, quoted values may contain commas and escapes
+fn parse_params(value: &str) -> Result<Vec<(String, String)>, SignatureError> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+
+        if chars.peek().is_none() {
+            return Ok(params);
+        }
+
+        let name = chars
+            .by_ref()
+            .take_while(|c| *c != '=')
+            .collect::<String>()
+            .trim()
+            .to_ascii_lowercase();
+
+        if name.is_empty() {
+            return Err(SignatureError::Malformed(value.to_owned()));
+        }
+
+        let mut param = String::new();
+
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => param.extend(chars.next()),
+                    Some(c) => param.push(c),
+                    None => return Err(SignatureError::Malformed(value.to_owned())),
+                }
+            }
+        } else {
+            param.extend(chars.by_ref().take_while(|c| *c != ','));
+        }
+
+        params.push((name, param.trim().to_owned()));
+    }
+}
+
+impl SignatureHeader {
+    pub fn parse(value: &str) -> Result<Self, SignatureError> {
+        let mut key_id = None;
+        let mut algorithm = None;
+        let mut headers = None;
+        let mut signature = None;
+        let mut created = None;
+        let mut expires = None;
+
+        let parse_time = |value: String| {
+            value
+                .parse::<i64>()
+                .map_err(|_| SignatureError::Malformed(format!("invalid timestamp {value}")))
+        };
+
+        for (name, param) in parse_params(value)? {
+            match name.as_str() {
+                "keyid" => key_id = Some(param),
+                "algorithm" => algorithm = Some(param),
+                "headers" => {
+                    headers = Some(
+                        param
+                            .split_whitespace()
+                            .map(str::to_ascii_lowercase)
+                            .collect::<Vec<_>>(),
+                    )
+                }
+                "signature" => {
+                    signature = Some(STANDARD.decode(param).map_err(|e| {
+                        SignatureError::Malformed(format!("invalid signature encoding: {e}"))
+                    })?)
+                }
+                "created" => created = Some(parse_time(param)?),
+                "expires" => expires = Some(parse_time(param)?),
+                _ => {}
+            }
+        }
+
+        Ok(SignatureHeader {
+            key_id: key_id.ok_or_else(|| SignatureError::Malformed("missing keyId".to_owned()))?,
+            algorithm,
+            // The specification defaults to the date alone
+            headers: headers.unwrap_or_else(|| vec!["date".to_owned()]),
+            signature: signature
+                .ok_or_else(|| SignatureError::Malformed("missing signature".to_owned()))?,
+            created,
+            expires,
+        })
+    }
+
+    /// The signature of a request, from either the `Signature` or the `Authorization` header
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, SignatureError> {
+        if let Some(value) = headers.get("signature") {
+            let value = value
+                .to_str()
+                .map_err(|e| SignatureError::Malformed(e.to_string()))?;
+
+            return Self::parse(value).map(Some);
+        }
+
+        let authorization = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Signature "));
+
+        authorization.map(Self::parse).transpose()
+    }
+
+    pub fn algorithm(&self) -> Result<SignatureAlgorithm, SignatureError> {
+        match self.algorithm {
+            Some(ref algorithm) => SignatureAlgorithm::parse(algorithm),
+            None => Ok(SignatureAlgorithm::Hs2019),
+        }
+    }
+
+    /// Renders the parameters as the value of a `Signature` header
+    pub fn to_header_value(&self) -> String {
+        let mut params = vec![format!("keyId=\"{}\"", self.key_id)];
+
+        if let Some(ref algorithm) = self.algorithm {
+            params.push(format!("algorithm=\"{algorithm}\""));
+        }
+
+        if let Some(created) = self.created {
+            params.push(format!("created={created}"));
+        }
+
+        if let Some(expires) = self.expires {
+            params.push(format!("expires={expires}"));
+        }
+
+        params.push(format!("headers=\"{}\"", self.headers.join(" ")));
+        params.push(format!(
+            "signature=\"{}\"",
+            STANDARD.encode(&self.signature)
+        ));
+
+        params.join(",")
+    }
+
+    pub fn covers(&self, name: &str) -> bool {
+        self.headers.iter().any(|header| header == name)
+    }
+
+    /// Rebuilds the string that was signed, from the request as received
+    pub fn signing_string(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+    ) -> Result<String, SignatureError> {
+        let lines = self
+            .headers
+            .iter()
+            .map(|name| {
+                let value = match name.as_str() {
+                    "(request-target)" => format!(
+                        "{} {}",
+                        method.as_str().to_ascii_lowercase(),
+                        uri.path_and_query().map_or("/", |path| path.as_str())
+                    ),
+                    "(created)" => self
+                        .created
+                        .ok_or_else(|| SignatureError::MissingHeader(name.clone()))?
+                        .to_string(),
+                    "(expires)" => self
+                        .expires
+                        .ok_or_else(|| SignatureError::MissingHeader(name.clone()))?
+                        .to_string(),
+                    // HTTP/2 requests carry the host in the URI authority
+                    "host" if !headers.contains_key(header::HOST) => uri
+                        .authority()
+                        .ok_or_else(|| SignatureError::MissingHeader(name.clone()))?
+                        .to_string(),
+                    _ => {
+                        let values = headers
+                            .get_all(name.as_str())
+                            .iter()
+                            .map(|value| {
+                                value
+                                    .to_str()
+                                    .map(str::trim)
+                                    .map_err(|_| SignatureError::MissingHeader(name.clone()))
+                            })
+                            .collect::<Result<Vec<_>, _>>()?;
+
+                        if values.is_empty() {
+                            return Err(SignatureError::MissingHeader(name.clone()));
+                        }
+
+                        values.join(", ")
+                    }
+                };
+
+                Ok(format!("{name}: {value}"))
+            })
+            .collect::<Result<Vec<_>, SignatureError>>()?;
+
+        Ok(lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::signature::cavage::SignatureHeader;
+    use crate::signature::SignatureAlgorithm;
+    use axum::http::{HeaderMap, Method, Uri};
+
+    #[test]
+    fn should_parse_signature_header() {
+        let header = SignatureHeader::parse(
+            r#"keyId="https://example.com/users/a,b#main-key", algorithm="hs2019",headers="(request-target) Host date",signature="AQID",created=1402170695"#,
+        )
+        .unwrap();
+
+        assert_eq!(header.key_id, "https://example.com/users/a,b#main-key");
+        assert_eq!(header.algorithm().unwrap(), SignatureAlgorithm::Hs2019);
+        assert_eq!(header.headers, vec!["(request-target)", "host", "date"]);
+        assert_eq!(header.signature, vec![1, 2, 3]);
+        assert_eq!(header.created, Some(1402170695));
+
+        assert_eq!(
+            SignatureHeader::parse(&header.to_header_value()).unwrap(),
+            header
+        );
+    }
+
+    #[test]
+    fn should_reject_incomplete_signature_header() {
+        assert!(SignatureHeader::parse(r#"keyId="a",headers="date""#).is_err());
+        assert!(SignatureHeader::parse(r#"signature="AQID""#).is_err());
+        assert!(SignatureHeader::parse(r#"keyId="a,signature="AQID""#).is_err());
+    }
+
+    #[test]
+    fn should_build_signing_string() {
+        let header = SignatureHeader::parse(
+            r#"keyId="k",headers="(request-target) host date cache-control",signature="AQID""#,
+        )
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("date", "Sun, 01 Oct 2023 12:00:00 GMT".parse().unwrap());
+        headers.append("cache-control", "max-age=60".parse().unwrap());
+        headers.append("cache-control", "must-revalidate".parse().unwrap());
+
+        let uri: Uri = "https://example.com/inbox?page=1".parse().unwrap();
+
+        assert_eq!(
+            header
+                .signing_string(&Method::POST, &uri, &headers)
+                .unwrap(),
+            "(request-target): post /inbox?page=1\nhost: example.com\ndate: Sun, 01 Oct 2023 12:00:00 GMT\ncache-control: max-age=60, must-revalidate"
+        );
+
+        headers.remove("date");
+        assert!(header
+            .signing_string(&Method::POST, &uri, &headers)
+            .is_err());
+    }
+}
@@ -0,0 +1,498 @@
+use crate::public_address::check_public_url;
+use crate::signature::{PublicKey, SignatureError};
+use axum::async_trait;
+use magnetar_calckey_model::CalckeyModel;
+use magnetar_core::web_model::content_type::{
+    ContentActivityStreams, ContentLdJsonActivityStreams,
+};
+use magnetar_webfinger::client::HttpTransport;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::warn;
+use url::Url;
+
+/// Each key is fetched at most once in this interval, however many requests it signs
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Refreshes older than [`REFRESH_INTERVAL`] are forgotten once this many are remembered
+const REFRESH_PRUNE_THRESHOLD: usize = 4096;
+
+/// A public key together with the actor that owns it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolvedKey {
+    pub owner: String,
+    pub key: PublicKey,
+    /// Whether the key was read from the database, and may have been rotated since
+    pub cached: bool,
+}
+
+#[async_trait]
+pub trait PublicKeyResolver: Send + Sync {
+    /// Looks up a key, preferring stored keys
+    async fn resolve(&self, key_id: &str) -> Result<ResolvedKey, SignatureError>;
+
+    /// Looks up the current version of a key, bypassing stored keys
+    async fn refresh(&self, key_id: &str) -> Result<ResolvedKey, SignatureError>;
+}
+
+fn same_origin(a: &Url, b: &str) -> bool {
+    Url::parse(b).is_ok_and(|b| a.origin() == b.origin())
+}
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+fn decode_base58(input: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::<u8>::new();
+
+    for c in input.bytes() {
+        let mut carry = BASE58_ALPHABET.iter().position(|a| *a == c)? as u32;
+
+        for byte in bytes.iter_mut().rev() {
+            carry += *byte as u32 * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+
+        while carry > 0 {
+            bytes.insert(0, carry as u8);
+            carry >>= 8;
+        }
+    }
+
+    let zeros = input.bytes().take_while(|c| *c == b'1').count();
+
+    Some([vec![0; zeros], bytes].concat())
+}
+
+/// Decodes the `publicKeyMultibase` of an Ed25519 Multikey (FEP-521a) into a PEM
+fn multikey_to_pem(multibase: &str) -> Option<String> {
+    let bytes = decode_base58(multibase.strip_prefix('z')?)?;
+
+    match bytes.as_slice() {
+        [0xed, 0x01, key @ ..] if key.len() == 32 => Some(PublicKey::ed25519_pem(key)),
+        _ => None,
+    }
+}
+
+fn embedded_keys<'a>(document: &'a Value, property: &str) -> Vec<&'a Value> {
+    match document.get(property) {
+        Some(Value::Array(keys)) => keys.iter().collect(),
+        Some(key @ Value::Object(_)) => vec![key],
+        _ => Vec::new(),
+    }
+}
+
+/// Finds the key `key_id` in a fetched actor or standalone key document
+///
+/// Keys are looked up in `publicKey` and, for Ed25519 Multikeys, in `assertionMethod`.
+/// Returns the owner and the PEM of the key. The owner must be served from the
+/// same origin as the key, otherwise any server could claim keys of other actors.
+pub fn extract_public_key(
+    document: &Value,
+    key_id: &str,
+) -> Result<(String, String), SignatureError> {
+    let not_found = || SignatureError::UnknownKey(key_id.to_owned());
+    let key_url = Url::parse(key_id).map_err(|_| not_found())?;
+    let document_id = document.get("id").and_then(Value::as_str);
+
+    let key = embedded_keys(document, "publicKey")
+        .into_iter()
+        .chain(embedded_keys(document, "assertionMethod"))
+        .find(|key| key.get("id").and_then(Value::as_str) == Some(key_id))
+        .or_else(|| (document_id == Some(key_id)).then_some(document))
+        .ok_or_else(not_found)?;
+
+    let owner = key
+        .get("owner")
+        .or_else(|| key.get("controller"))
+        .and_then(Value::as_str)
+        .or(document_id.filter(|id| *id != key_id))
+        .ok_or_else(not_found)?;
+
+    // Keys embedded in an actor must belong to that very actor
+    if document_id != Some(key_id) && document_id != Some(owner) {
+        return Err(not_found());
+    }
+
+    if !same_origin(&key_url, owner) {
+        return Err(not_found());
+    }
+
+    let pem = match key.get("publicKeyPem").and_then(Value::as_str) {
+        Some(pem) => pem.to_owned(),
+        None => key
+            .get("publicKeyMultibase")
+            .and_then(Value::as_str)
+            .and_then(multikey_to_pem)
+            .ok_or_else(not_found)?,
+    };
+
+    Ok((owner.to_owned(), pem))
+}
+
+/// The keys recently fetched by key ID, and the fetches in progress
+///
+/// Without them, every request signed with a rotated key would make us fetch the key again.
+/// Only successful fetches are remembered, so a failed fetch does not lock a key out, and
+/// concurrent refreshes of a key wait for the one in progress instead of fetching it again.
+#[derive(Debug, Default)]
+struct RecentRefreshes {
+    fetched: Mutex<HashMap<String, (Instant, ResolvedKey)>>,
+    in_flight: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+}
+
+impl RecentRefreshes {
+    /// The key fetched for `key_id` within [`REFRESH_INTERVAL`] of `now`, if any
+    fn recent(&self, key_id: &str, now: Instant) -> Option<ResolvedKey> {
+        let mut fetched = self.fetched.lock().unwrap();
+
+        if fetched.len() >= REFRESH_PRUNE_THRESHOLD {
+            fetched.retain(|_, (refreshed, _)| now.duration_since(*refreshed) < REFRESH_INTERVAL);
+        }
+
+        fetched
+            .get(key_id)
+            .filter(|(refreshed, _)| now.duration_since(*refreshed) < REFRESH_INTERVAL)
+            .map(|(_, key)| key.clone())
+    }
+
+    /// Returns the key recently fetched for `key_id`, or runs `fetch` and remembers its key
+    /// when it succeeds
+    ///
+    /// Only one fetch of a key runs at a time, the others wait for it and use its key.
+    async fn refresh<F, Fut>(
+        &self,
+        key_id: &str,
+        now: Instant,
+        fetch: F,
+    ) -> Result<ResolvedKey, SignatureError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<ResolvedKey, SignatureError>>,
+    {
+        if let Some(key) = self.recent(key_id, now) {
+            return Ok(key);
+        }
+
+        let lock = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            in_flight.retain(|_, lock| Arc::strong_count(lock) > 1);
+            in_flight.entry(key_id.to_owned()).or_default().clone()
+        };
+        let _guard = lock.lock().await;
+
+        if let Some(key) = self.recent(key_id, now) {
+            return Ok(key);
+        }
+
+        let key = fetch().await?;
+        self.fetched
+            .lock()
+            .unwrap()
+            .insert(key_id.to_owned(), (now, key.clone()));
+
+        Ok(key)
+    }
+}
+
+/// Resolves keys from the `user_publickey` table, fetching the remote actor when unknown
+pub struct CalckeyKeyResolver<T: HttpTransport> {
+    ck: CalckeyModel,
+    transport: T,
+    refreshes: RecentRefreshes,
+}
+
+impl<T: HttpTransport> CalckeyKeyResolver<T> {
+    pub fn new(ck: CalckeyModel, transport: T) -> Self {
+        CalckeyKeyResolver {
+            ck,
+            transport,
+            refreshes: RecentRefreshes::default(),
+        }
+    }
+
+    async fn fetch_document(&self, key_id: &str) -> Result<Value, SignatureError> {
+        let error = |e: String| SignatureError::KeyResolution(key_id.to_owned(), e);
+
+        let mut url = Url::parse(key_id).map_err(|e| error(e.to_string()))?;
+        url.set_fragment(None);
+        check_public_url(&url).await.map_err(error)?;
+
+        let accept = format!(
+            "{}, {}",
+            ContentActivityStreams.as_ref(),
+            ContentLdJsonActivityStreams.as_ref()
+        );
+
+        let response = self
+            .transport
+            .get(url.as_str(), &accept)
+            .await
+            .map_err(|e| error(e.to_string()))?;
+
+        if !response.is_success() {
+            return Err(error(format!("status {}", response.status)));
+        }
+
+        serde_json::from_slice(&response.body).map_err(|e| error(e.to_string()))
+    }
+
+    async fn fetch_key(&self, key_id: &str) -> Result<ResolvedKey, SignatureError> {
+        let document = self.fetch_document(key_id).await?;
+        let (owner, pem) = extract_public_key(&document, key_id)?;
+        let key = PublicKey::from_pem(&pem)?;
+
+        // Only known remote users get their key stored, fetching new actors is up to the inbox
+        match self.ck.get_user_by_uri(&owner).await {
+            Ok(Some(user)) if user.host.is_some() => {
+                if let Err(e) = self.ck.upsert_user_publickey(&user.id, key_id, &pem).await {
+                    warn!("Failed to store the public key {key_id}: {e}");
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to look up the owner of {key_id}: {e}"),
+        }
+
+        Ok(ResolvedKey {
+            owner,
+            key,
+            cached: false,
+        })
+    }
+}
+
+#[async_trait]
+impl<T: HttpTransport> PublicKeyResolver for CalckeyKeyResolver<T> {
+    async fn resolve(&self, key_id: &str) -> Result<ResolvedKey, SignatureError> {
+        let lookup_error = |e: anyhow::Error| {
+            SignatureError::KeyResolution(key_id.to_owned(), format!("data error: {e}"))
+        };
+
+        let Some(stored) = self
+            .ck
+            .get_user_publickey_by_key_id(key_id)
+            .await
+            .map_err(lookup_error)?
+        else {
+            return self.refresh(key_id).await;
+        };
+
+        let owner = self
+            .ck
+            .get_user_by_id(&stored.user_id)
+            .await
+            .map_err(lookup_error)?
+            .and_then(|user| user.uri);
+
+        match owner {
+            Some(owner) => Ok(ResolvedKey {
+                owner,
+                key: PublicKey::from_pem(&stored.key_pem)?,
+                cached: true,
+            }),
+            None => self.refresh(key_id).await,
+        }
+    }
+
+    async fn refresh(&self, key_id: &str) -> Result<ResolvedKey, SignatureError> {
+        self.refreshes
+            .refresh(key_id, Instant::now(), || self.fetch_key(key_id))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::signature::keys::{
+        decode_base58, extract_public_key, RecentRefreshes, ResolvedKey, REFRESH_INTERVAL,
+    };
+    use crate::signature::{PublicKey, SignatureError};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    const PEM: &str = "-----BEGIN PUBLIC KEY-----\n...\n-----END PUBLIC KEY-----\n";
+
+    #[test]
+    fn should_extract_embedded_key() {
+        let actor = json!({
+            "id": "https://example.com/users/alice",
+            "type": "Person",
+            "publicKey": {
+                "id": "https://example.com/users/alice#main-key",
+                "owner": "https://example.com/users/alice",
+                "publicKeyPem": PEM
+            }
+        });
+
+        assert_eq!(
+            extract_public_key(&actor, "https://example.com/users/alice#main-key").unwrap(),
+            ("https://example.com/users/alice".to_owned(), PEM.to_owned())
+        );
+        assert!(extract_public_key(&actor, "https://example.com/users/alice#other-key").is_err());
+    }
+
+    #[test]
+    fn should_extract_standalone_key() {
+        let key = json!({
+            "id": "https://example.com/keys/1",
+            "type": "Key",
+            "owner": "https://example.com/users/alice",
+            "publicKeyPem": PEM
+        });
+
+        let (owner, _) = extract_public_key(&key, "https://example.com/keys/1").unwrap();
+        assert_eq!(owner, "https://example.com/users/alice");
+    }
+
+    #[test]
+    fn should_reject_foreign_owner() {
+        let key = json!({
+            "id": "https://evil.example/keys/1",
+            "type": "Key",
+            "owner": "https://example.com/users/alice",
+            "publicKeyPem": PEM
+        });
+
+        assert!(extract_public_key(&key, "https://evil.example/keys/1").is_err());
+
+        let actor = json!({
+            "id": "https://example.com/users/alice",
+            "publicKey": [{
+                "id": "https://example.com/users/alice#main-key",
+                "owner": "https://example.com/users/bob",
+                "publicKeyPem": PEM
+            }]
+        });
+
+        assert!(extract_public_key(&actor, "https://example.com/users/alice#main-key").is_err());
+    }
+
+    #[test]
+    fn should_decode_base58() {
+        assert_eq!(decode_base58("").unwrap(), Vec::<u8>::new());
+        assert_eq!(decode_base58("11").unwrap(), vec![0, 0]);
+        assert_eq!(decode_base58("5Q").unwrap(), vec![0xff]);
+        assert_eq!(decode_base58("2NEpo7TZRRrLZSi2U").unwrap(), b"Hello World!");
+        assert!(decode_base58("0OIl").is_none());
+    }
+
+    #[test]
+    fn should_extract_multikey() {
+        let actor = json!({
+            "id": "https://fedify.example/users/carol",
+            "publicKey": {
+                "id": "https://fedify.example/users/carol#main-key",
+                "owner": "https://fedify.example/users/carol",
+                "publicKeyPem": PEM
+            },
+            "assertionMethod": [{
+                "id": "https://fedify.example/users/carol#ed25519-key",
+                "type": "Multikey",
+                "controller": "https://fedify.example/users/carol",
+                "publicKeyMultibase": "z6Mkh4LmfP1ev9MNPGr7JbEbtD6BD4fsu1duEj83PMCs3xHG"
+            }]
+        });
+
+        let (owner, pem) =
+            extract_public_key(&actor, "https://fedify.example/users/carol#ed25519-key").unwrap();
+
+        assert_eq!(owner, "https://fedify.example/users/carol");
+        assert_eq!(
+            PublicKey::from_pem(&pem).unwrap(),
+            PublicKey::from_pem(
+                "-----BEGIN PUBLIC KEY-----\nMCowBQYDK2VwAyEAJrQLj5P/89iXES9+vFgrIy29clF9CC/oPPsw3c5D0bs=\n-----END PUBLIC KEY-----"
+            )
+            .unwrap()
+        );
+    }
+
+    fn key() -> ResolvedKey {
+        ResolvedKey {
+            owner: "https://fedify.example/users/carol".to_owned(),
+            key: PublicKey::from_pem(
+                "-----BEGIN PUBLIC KEY-----\nMCowBQYDK2VwAyEAJrQLj5P/89iXES9+vFgrIy29clF9CC/oPPsw3c5D0bs=\n-----END PUBLIC KEY-----"
+            )
+            .unwrap(),
+            cached: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn should_limit_refreshes() {
+        let recent = RecentRefreshes::default();
+        let now = Instant::now();
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            Ok(key())
+        };
+        let fail = || async {
+            Err(SignatureError::KeyResolution(
+                "https://fedify.example/keys/1".to_owned(),
+                "status 503".to_owned(),
+            ))
+        };
+
+        // Failed fetches are not remembered
+        assert!(recent
+            .refresh("https://fedify.example/keys/1", now, fail)
+            .await
+            .is_err());
+        assert_eq!(
+            recent
+                .refresh("https://fedify.example/keys/1", now, fetch)
+                .await
+                .unwrap(),
+            key()
+        );
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        assert_eq!(
+            recent
+                .refresh(
+                    "https://fedify.example/keys/1",
+                    now + Duration::from_secs(1),
+                    fail
+                )
+                .await
+                .unwrap(),
+            key()
+        );
+
+        recent
+            .refresh(
+                "https://fedify.example/keys/1",
+                now + REFRESH_INTERVAL,
+                fetch,
+            )
+            .await
+            .unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn should_coalesce_concurrent_refreshes() {
+        let recent = RecentRefreshes::default();
+        let now = Instant::now();
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(key())
+        };
+
+        let (first, second) = tokio::join!(
+            recent.refresh("https://fedify.example/keys/1", now, fetch),
+            recent.refresh("https://fedify.example/keys/1", now, fetch)
+        );
+
+        assert_eq!(first.unwrap(), key());
+        assert_eq!(second.unwrap(), key());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,207 @@
+pub mod cavage;
+pub mod keys;
+pub mod middleware;
+pub mod rfc9421;
+pub mod signer;
+
+use crate::signature::cavage::SignatureHeader;
+use crate::signature::rfc9421::MessageSignature;
+
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::signature::{
+    UnparsedPublicKey, ED25519, RSA_PKCS1_2048_8192_SHA256, RSA_PKCS1_2048_8192_SHA512,
+    RSA_PSS_2048_8192_SHA512,
+};
+use rsa::pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey};
+use rsa::pkcs8::{DecodePublicKey, Document, ObjectIdentifier, SubjectPublicKeyInfo};
+use rsa::RsaPublicKey;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SignatureError {
+    #[error("Malformed signature: {0}")]
+    Malformed(String),
+    #[error("Unsupported signature algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    #[error("The signed header {0} is missing from the request")]
+    MissingHeader(String),
+    #[error("The signature does not cover {0}")]
+    NotCovered(&'static str),
+    #[error("The signature was created outside of the allowed clock skew")]
+    ClockSkew,
+    #[error("The digest does not match the body")]
+    DigestMismatch,
+    #[error("Invalid public key: {0}")]
+    InvalidKey(String),
+    #[error("Unknown key: {0}")]
+    UnknownKey(String),
+    #[error("Failed to resolve the key {0}: {1}")]
+    KeyResolution(String, String),
+    #[error("Failed to sign the request: {0}")]
+    Signing(String),
+    #[error("The signature does not match")]
+    Invalid,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    RsaSha256,
+    RsaSha512,
+    RsaPssSha512,
+    Ed25519,
+    /// The algorithm is derived from the key, RSA keys are used with SHA-256 across the fediverse
+    Hs2019,
+}
+
+impl SignatureAlgorithm {
+    /// Parses the `algorithm` of a draft-cavage signature
+    pub fn parse(algorithm: &str) -> Result<Self, SignatureError> {
+        match algorithm.to_ascii_lowercase().as_str() {
+            "rsa-sha256" => Ok(SignatureAlgorithm::RsaSha256),
+            "rsa-sha512" => Ok(SignatureAlgorithm::RsaSha512),
+            "ed25519" => Ok(SignatureAlgorithm::Ed25519),
+            "hs2019" => Ok(SignatureAlgorithm::Hs2019),
+            _ => Err(SignatureError::UnsupportedAlgorithm(algorithm.to_owned())),
+        }
+    }
+
+    /// Parses the `alg` of an RFC 9421 signature, as registered in section 6.2.2
+    pub fn parse_rfc9421(algorithm: &str) -> Result<Self, SignatureError> {
+        match algorithm {
+            "rsa-v1_5-sha256" => Ok(SignatureAlgorithm::RsaSha256),
+            "rsa-pss-sha512" => Ok(SignatureAlgorithm::RsaPssSha512),
+            "ed25519" => Ok(SignatureAlgorithm::Ed25519),
+            _ => Err(SignatureError::UnsupportedAlgorithm(algorithm.to_owned())),
+        }
+    }
+}
+
+const ED25519_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+/// The DER encoding of an Ed25519 SubjectPublicKeyInfo, without the key itself
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// A public key of an actor, used to verify their signatures
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PublicKey {
+    /// The PKCS#1 DER encoding of an RSA public key
+    Rsa(Vec<u8>),
+    Ed25519(Vec<u8>),
+}
+
+impl PublicKey {
+    /// Parses a SubjectPublicKeyInfo or PKCS#1 PEM, as found in `publicKeyPem`
+    pub fn from_pem(pem: &str) -> Result<Self, SignatureError> {
+        let pem = pem.trim();
+
+        if let Ok((_, document)) = Document::from_pem(pem) {
+            if let Ok(spki) = SubjectPublicKeyInfo::try_from(document.as_bytes()) {
+                if spki.algorithm.oid == ED25519_OID {
+                    return Ok(PublicKey::Ed25519(spki.subject_public_key.to_vec()));
+                }
+            }
+        }
+
+        let key = RsaPublicKey::from_public_key_pem(pem)
+            .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+            .map_err(|e| SignatureError::InvalidKey(e.to_string()))?;
+
+        let der = key
+            .to_pkcs1_der()
+            .map_err(|e| SignatureError::InvalidKey(e.to_string()))?;
+
+        Ok(PublicKey::Rsa(der.as_bytes().to_vec()))
+    }
+
+    /// Encodes a raw Ed25519 key as a SubjectPublicKeyInfo PEM, the way keys are stored
+    pub fn ed25519_pem(key: &[u8]) -> String {
+        let der = [&ED25519_SPKI_PREFIX[..], key].concat();
+
+        format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            STANDARD.encode(der)
+        )
+    }
+
+    pub fn verify(
+        &self,
+        algorithm: SignatureAlgorithm,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<(), SignatureError> {
+        match (self, algorithm) {
+            (PublicKey::Rsa(der), SignatureAlgorithm::RsaSha256 | SignatureAlgorithm::Hs2019) => {
+                UnparsedPublicKey::new(&RSA_PKCS1_2048_8192_SHA256, der).verify(message, signature)
+            }
+            (PublicKey::Rsa(der), SignatureAlgorithm::RsaSha512) => {
+                UnparsedPublicKey::new(&RSA_PKCS1_2048_8192_SHA512, der).verify(message, signature)
+            }
+            (PublicKey::Rsa(der), SignatureAlgorithm::RsaPssSha512) => {
+                UnparsedPublicKey::new(&RSA_PSS_2048_8192_SHA512, der).verify(message, signature)
+            }
+            (PublicKey::Ed25519(key), SignatureAlgorithm::Ed25519 | SignatureAlgorithm::Hs2019) => {
+                UnparsedPublicKey::new(&ED25519, key).verify(message, signature)
+            }
+            (_, algorithm) => {
+                return Err(SignatureError::UnsupportedAlgorithm(format!(
+                    "{algorithm:?} with this key type"
+                )))
+            }
+        }
+        .map_err(|_| SignatureError::Invalid)
+    }
+}
+
+/// A signature of an inbound request, in either of the supported schemes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RequestSignature {
+    Cavage(SignatureHeader),
+    Rfc9421(MessageSignature),
+}
+
+impl RequestSignature {
+    /// RFC 9421 signatures take precedence, as they share the `Signature` header
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, SignatureError> {
+        if let Some(signature) = MessageSignature::from_headers(headers)? {
+            return Ok(Some(RequestSignature::Rfc9421(signature)));
+        }
+
+        Ok(SignatureHeader::from_headers(headers)?.map(RequestSignature::Cavage))
+    }
+
+    pub fn key_id(&self) -> Result<&str, SignatureError> {
+        match self {
+            RequestSignature::Cavage(signature) => Ok(&signature.key_id),
+            RequestSignature::Rfc9421(signature) => signature.key_id(),
+        }
+    }
+}
+
+/// The key and actor a request was signed by, set by the signature verification middleware
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifiedSignature {
+    pub key_id: String,
+    /// The ID of the actor owning the key
+    pub owner: String,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for VerifiedSignature {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<VerifiedSignature>()
+            .cloned()
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+}
@@ -0,0 +1,707 @@
+//! HTTP Message Signatures as specified by RFC 9421
+//!
+//! Only the subset of RFC 8941 structured fields used by `Signature-Input`, `Signature`
+//! and `Content-Digest` is supported, derived components and field parameters are not.
+
+use crate::signature::{SignatureAlgorithm, SignatureError};
+use axum::http::{header, HeaderMap, Method, Uri};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+/// A bare item of a structured field, see RFC 8941 section 3.3
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BareItem {
+    Integer(i64),
+    String(String),
+    Token(String),
+    Bytes(Vec<u8>),
+    Boolean(bool),
+}
+
+pub type Parameters = Vec<(String, BareItem)>;
+
+/// A member of a structured field dictionary
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Member {
+    Item(BareItem, Parameters),
+    InnerList(Vec<(BareItem, Parameters)>, Parameters),
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, what: &str) -> SignatureError {
+        SignatureError::Malformed(format!("{what} at position {}", self.pos))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), SignatureError> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err(self.error(&format!("expected '{}'", expected as char))),
+        }
+    }
+
+    fn skip_while(&mut self, predicate: impl Fn(u8) -> bool) -> &'a str {
+        let start = self.pos;
+
+        while self.peek().is_some_and(&predicate) {
+            self.pos += 1;
+        }
+
+        // Only ASCII is consumed by the predicates used
+        std::str::from_utf8(&self.input[start..self.pos]).unwrap_or_default()
+    }
+
+    fn skip_spaces(&mut self) {
+        self.skip_while(|c| c == b' ');
+    }
+
+    fn skip_ows(&mut self) {
+        self.skip_while(|c| c == b' ' || c == b'\t');
+    }
+
+    fn parse_key(&mut self) -> Result<String, SignatureError> {
+        if !self
+            .peek()
+            .is_some_and(|c| c.is_ascii_lowercase() || c == b'*')
+        {
+            return Err(self.error("expected a key"));
+        }
+
+        Ok(self
+            .skip_while(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || b"_-.*".contains(&c))
+            .to_owned())
+    }
+
+    fn parse_bare_item(&mut self) -> Result<BareItem, SignatureError> {
+        match self.peek() {
+            Some(b'-' | b'0'..=b'9') => {
+                let negative = self.peek() == Some(b'-');
+                if negative {
+                    self.pos += 1;
+                }
+
+                let digits = self.skip_while(|c| c.is_ascii_digit());
+
+                if digits.is_empty() || digits.len() > 15 || self.peek() == Some(b'.') {
+                    return Err(self.error("unsupported number"));
+                }
+
+                let value = digits
+                    .parse::<i64>()
+                    .map_err(|_| self.error("invalid integer"))?;
+
+                Ok(BareItem::Integer(if negative { -value } else { value }))
+            }
+            Some(b'"') => {
+                self.pos += 1;
+                let mut value = String::new();
+
+                loop {
+                    match self.next() {
+                        Some(b'"') => return Ok(BareItem::String(value)),
+                        Some(b'\\') => match self.next() {
+                            Some(c @ (b'"' | b'\\')) => value.push(c as char),
+                            _ => return Err(self.error("invalid escape")),
+                        },
+                        Some(c @ 0x20..=0x7e) => value.push(c as char),
+                        _ => return Err(self.error("unterminated string")),
+                    }
+                }
+            }
+            Some(b':') => {
+                self.pos += 1;
+                let encoded = self.skip_while(|c| c.is_ascii_alphanumeric() || b"+/=".contains(&c));
+                let value = STANDARD
+                    .decode(encoded)
+                    .map_err(|_| self.error("invalid byte sequence"))?;
+                self.expect(b':')?;
+
+                Ok(BareItem::Bytes(value))
+            }
+            Some(b'?') => {
+                self.pos += 1;
+
+                match self.next() {
+                    Some(b'0') => Ok(BareItem::Boolean(false)),
+                    Some(b'1') => Ok(BareItem::Boolean(true)),
+                    _ => Err(self.error("invalid boolean")),
+                }
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == b'*' => Ok(BareItem::Token(
+                self.skip_while(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~:/".contains(&c))
+                    .to_owned(),
+            )),
+            _ => Err(self.error("expected an item")),
+        }
+    }
+
+    fn parse_parameters(&mut self) -> Result<Parameters, SignatureError> {
+        let mut params = Parameters::new();
+
+        while self.peek() == Some(b';') {
+            self.pos += 1;
+            self.skip_spaces();
+
+            let key = self.parse_key()?;
+            let value = if self.peek() == Some(b'=') {
+                self.pos += 1;
+                self.parse_bare_item()?
+            } else {
+                BareItem::Boolean(true)
+            };
+
+            params.retain(|(existing, _)| *existing != key);
+            params.push((key, value));
+        }
+
+        Ok(params)
+    }
+
+    fn parse_member(&mut self) -> Result<Member, SignatureError> {
+        if self.peek() != Some(b'(') {
+            return Ok(Member::Item(
+                self.parse_bare_item()?,
+                self.parse_parameters()?,
+            ));
+        }
+
+        self.pos += 1;
+        let mut items = Vec::new();
+
+        loop {
+            self.skip_spaces();
+
+            if self.peek() == Some(b')') {
+                self.pos += 1;
+                return Ok(Member::InnerList(items, self.parse_parameters()?));
+            }
+
+            items.push((self.parse_bare_item()?, self.parse_parameters()?));
+
+            if !matches!(self.peek(), Some(b' ' | b')')) {
+                return Err(self.error("expected ' ' or ')'"));
+            }
+        }
+    }
+}
+
+/// Parses a structured field dictionary, see RFC 8941 section 4.2.2
+pub fn parse_dictionary(input: &str) -> Result<Vec<(String, Member)>, SignatureError> {
+    let mut parser = Parser {
+        input: input.trim().as_bytes(),
+        pos: 0,
+    };
+    let mut members: Vec<(String, Member)> = Vec::new();
+
+    while parser.peek().is_some() {
+        let key = parser.parse_key()?;
+        let member = if parser.peek() == Some(b'=') {
+            parser.pos += 1;
+            parser.parse_member()?
+        } else {
+            Member::Item(BareItem::Boolean(true), parser.parse_parameters()?)
+        };
+
+        members.retain(|(existing, _)| *existing != key);
+        members.push((key, member));
+
+        parser.skip_ows();
+
+        if parser.peek().is_none() {
+            break;
+        }
+
+        parser.expect(b',')?;
+        parser.skip_ows();
+
+        if parser.peek().is_none() {
+            return Err(parser.error("trailing comma"));
+        }
+    }
+
+    Ok(members)
+}
+
+/// Parses all of the dictionary fields of a name, which are combined as if they were one
+fn dictionary_header(
+    headers: &HeaderMap,
+    name: &str,
+) -> Result<Option<Vec<(String, Member)>>, SignatureError> {
+    let values = headers
+        .get_all(name)
+        .iter()
+        .map(|value| {
+            value
+                .to_str()
+                .map_err(|e| SignatureError::Malformed(e.to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if values.is_empty() {
+        return Ok(None);
+    }
+
+    parse_dictionary(&values.join(", ")).map(Some)
+}
+
+fn serialize_bare_item(item: &BareItem) -> String {
+    match item {
+        BareItem::Integer(value) => value.to_string(),
+        BareItem::String(value) => {
+            format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+        }
+        BareItem::Token(value) => value.clone(),
+        BareItem::Bytes(value) => format!(":{}:", STANDARD.encode(value)),
+        BareItem::Boolean(true) => "?1".to_owned(),
+        BareItem::Boolean(false) => "?0".to_owned(),
+    }
+}
+
+fn serialize_parameters(params: &Parameters) -> String {
+    params
+        .iter()
+        .map(|(key, value)| match value {
+            BareItem::Boolean(true) => format!(";{key}"),
+            value => format!(";{key}={}", serialize_bare_item(value)),
+        })
+        .collect()
+}
+
+/// The digests of a `Content-Digest` header, see RFC 9530
+pub fn parse_content_digest(value: &str) -> Result<Vec<(String, Vec<u8>)>, SignatureError> {
+    parse_dictionary(value)?
+        .into_iter()
+        .map(|(algorithm, member)| match member {
+            Member::Item(BareItem::Bytes(digest), _) => Ok((algorithm, digest)),
+            _ => Err(SignatureError::Malformed(format!(
+                "invalid {algorithm} content digest"
+            ))),
+        })
+        .collect()
+}
+
+pub fn content_digest_value(algorithm: &str, digest: &[u8]) -> String {
+    format!(
+        "{algorithm}={}",
+        serialize_bare_item(&BareItem::Bytes(digest.to_vec()))
+    )
+}
+
+/// One signature of a request, from the `Signature-Input` and `Signature` headers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageSignature {
+    pub label: String,
+    /// Names of the covered components, in signing order
+    pub components: Vec<String>,
+    /// The signature parameters, in the order they are serialized
+    pub params: Parameters,
+    pub signature: Vec<u8>,
+}
+
+impl MessageSignature {
+    pub fn new(
+        label: impl Into<String>,
+        components: Vec<String>,
+        key_id: &str,
+        algorithm: Option<&str>,
+        created: i64,
+    ) -> Self {
+        let mut params = vec![
+            ("created".to_owned(), BareItem::Integer(created)),
+            ("keyid".to_owned(), BareItem::String(key_id.to_owned())),
+        ];
+
+        if let Some(algorithm) = algorithm {
+            params.push(("alg".to_owned(), BareItem::String(algorithm.to_owned())));
+        }
+
+        MessageSignature {
+            label: label.into(),
+            components,
+            params,
+            signature: Vec::new(),
+        }
+    }
+
+    /// The first signature of a request that has both an input and a value
+    ///
+    /// Requests carrying several signatures, e.g. one added by a proxy, are verified by
+    /// their first one.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, SignatureError> {
+        let Some(inputs) = dictionary_header(headers, "signature-input")? else {
+            return Ok(None);
+        };
+
+        let signatures = dictionary_header(headers, "signature")?.unwrap_or_default();
+
+        for (label, input) in inputs {
+            let Some(signature) = signatures
+                .iter()
+                .find_map(|(name, member)| (*name == label).then_some(member))
+            else {
+                continue;
+            };
+
+            let Member::InnerList(items, params) = input else {
+                return Err(SignatureError::Malformed(format!(
+                    "signature input {label} is not an inner list"
+                )));
+            };
+
+            let Member::Item(BareItem::Bytes(signature), _) = signature else {
+                return Err(SignatureError::Malformed(format!(
+                    "signature {label} is not a byte sequence"
+                )));
+            };
+
+            let components = items
+                .into_iter()
+                .map(|item| match item {
+                    (BareItem::String(name), params) if params.is_empty() => Ok(name),
+                    (item, _) => Err(SignatureError::Malformed(format!(
+                        "unsupported component {}",
+                        serialize_bare_item(&item)
+                    ))),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            return Ok(Some(MessageSignature {
+                label,
+                components,
+                params,
+                signature: signature.clone(),
+            }));
+        }
+
+        Err(SignatureError::Malformed(
+            "no signature for any signature input".to_owned(),
+        ))
+    }
+
+    fn param(&self, name: &str) -> Option<&BareItem> {
+        self.params
+            .iter()
+            .find_map(|(key, value)| (key == name).then_some(value))
+    }
+
+    fn integer_param(&self, name: &str) -> Option<i64> {
+        match self.param(name) {
+            Some(BareItem::Integer(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn key_id(&self) -> Result<&str, SignatureError> {
+        match self.param("keyid") {
+            Some(BareItem::String(key_id)) => Ok(key_id),
+            _ => Err(SignatureError::Malformed("missing keyid".to_owned())),
+        }
+    }
+
+    pub fn created(&self) -> Option<i64> {
+        self.integer_param("created")
+    }
+
+    pub fn expires(&self) -> Option<i64> {
+        self.integer_param("expires")
+    }
+
+    pub fn algorithm(&self) -> Result<SignatureAlgorithm, SignatureError> {
+        match self.param("alg") {
+            Some(BareItem::String(algorithm)) => SignatureAlgorithm::parse_rfc9421(algorithm),
+            Some(_) => Err(SignatureError::Malformed("invalid alg".to_owned())),
+            None => Ok(SignatureAlgorithm::Hs2019),
+        }
+    }
+
+    pub fn covers(&self, name: &str) -> bool {
+        self.components.iter().any(|component| component == name)
+    }
+
+    /// The value of the `@signature-params` component
+    pub fn signature_params(&self) -> String {
+        let components = self
+            .components
+            .iter()
+            .map(|name| serialize_bare_item(&BareItem::String(name.clone())))
+            .collect::<Vec<_>>();
+
+        format!(
+            "({}){}",
+            components.join(" "),
+            serialize_parameters(&self.params)
+        )
+    }
+
+    pub fn signature_input_value(&self) -> String {
+        format!("{}={}", self.label, self.signature_params())
+    }
+
+    pub fn signature_value(&self) -> String {
+        format!(
+            "{}={}",
+            self.label,
+            serialize_bare_item(&BareItem::Bytes(self.signature.clone()))
+        )
+    }
+
+    fn component_value(
+        &self,
+        name: &str,
+        method: &Method,
+        target: &Uri,
+        headers: &HeaderMap,
+    ) -> Result<String, SignatureError> {
+        let missing = || SignatureError::MissingHeader(name.to_owned());
+
+        let authority = || {
+            target
+                .authority()
+                .map(|authority| authority.as_str().to_owned())
+                .or_else(|| {
+                    headers
+                        .get(header::HOST)
+                        .and_then(|host| host.to_str().ok())
+                        .map(str::to_owned)
+                })
+                .map(|authority| authority.to_ascii_lowercase())
+                .ok_or_else(missing)
+        };
+
+        Ok(match name {
+            "@method" => method.as_str().to_owned(),
+            "@target-uri" => {
+                let scheme = target.scheme_str().ok_or_else(missing)?;
+                let path = target.path_and_query().map_or("/", |path| path.as_str());
+
+                format!("{}://{}{path}", scheme.to_ascii_lowercase(), authority()?)
+            }
+            "@authority" => authority()?,
+            "@scheme" => target
+                .scheme_str()
+                .ok_or_else(missing)?
+                .to_ascii_lowercase(),
+            "@request-target" => target
+                .path_and_query()
+                .map_or("/", |path| path.as_str())
+                .to_owned(),
+            "@path" => match target.path() {
+                "" => "/".to_owned(),
+                path => path.to_owned(),
+            },
+            "@query" => format!("?{}", target.query().unwrap_or_default()),
+            name if name.starts_with('@') => {
+                return Err(SignatureError::Malformed(format!(
+                    "unsupported component {name}"
+                )))
+            }
+            name => {
+                let values = headers
+                    .get_all(name)
+                    .iter()
+                    .map(|value| value.to_str().map(str::trim).map_err(|_| missing()))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                if values.is_empty() {
+                    return Err(missing());
+                }
+
+                values.join(", ")
+            }
+        })
+    }
+
+    /// Rebuilds the signature base, `target` has to be the absolute URI of the request
+    pub fn signature_base(
+        &self,
+        method: &Method,
+        target: &Uri,
+        headers: &HeaderMap,
+    ) -> Result<String, SignatureError> {
+        let mut lines = self
+            .components
+            .iter()
+            .map(|name| {
+                let value = self.component_value(name, method, target, headers)?;
+                Ok(format!("\"{name}\": {value}"))
+            })
+            .collect::<Result<Vec<_>, SignatureError>>()?;
+
+        lines.push(format!(
+            "\"@signature-params\": {}",
+            self.signature_params()
+        ));
+
+        Ok(lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::signature::rfc9421::{
+        parse_content_digest, parse_dictionary, BareItem, Member, MessageSignature,
+    };
+    use crate::signature::{PublicKey, SignatureAlgorithm};
+    use axum::http::{HeaderMap, Method};
+
+    const ED25519_TEST_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MCowBQYDK2VwAyEAJrQLj5P/89iXES9+vFgrIy29clF9CC/oPPsw3c5D0bs=
+-----END PUBLIC KEY-----";
+
+    #[test]
+    fn should_parse_dictionary() {
+        let dictionary =
+            parse_dictionary(r#"sig1=("@method" "@target-uri");created=1;keyid="a\"b", b=?0, c"#)
+                .unwrap();
+
+        assert_eq!(
+            dictionary,
+            vec![
+                (
+                    "sig1".to_owned(),
+                    Member::InnerList(
+                        vec![
+                            (BareItem::String("@method".to_owned()), vec![]),
+                            (BareItem::String("@target-uri".to_owned()), vec![]),
+                        ],
+                        vec![
+                            ("created".to_owned(), BareItem::Integer(1)),
+                            ("keyid".to_owned(), BareItem::String("a\"b".to_owned())),
+                        ]
+                    )
+                ),
+                (
+                    "b".to_owned(),
+                    Member::Item(BareItem::Boolean(false), vec![])
+                ),
+                (
+                    "c".to_owned(),
+                    Member::Item(BareItem::Boolean(true), vec![])
+                ),
+            ]
+        );
+
+        assert!(parse_dictionary("a=1,").is_err());
+        assert!(parse_dictionary("a=(1 2").is_err());
+        assert!(parse_dictionary("A=1").is_err());
+        assert!(parse_dictionary("a=1.5").is_err());
+    }
+
+    #[test]
+    fn should_parse_content_digest() {
+        assert_eq!(
+            parse_content_digest("sha-256=:AQID:, sha-512=:BAUG:").unwrap(),
+            vec![
+                ("sha-256".to_owned(), vec![1, 2, 3]),
+                ("sha-512".to_owned(), vec![4, 5, 6]),
+            ]
+        );
+        assert!(parse_content_digest("sha-256=AQID").is_err());
+    }
+
+    /// The Ed25519 example of RFC 9421 appendix B.2.6
+    #[test]
+    fn should_verify_rfc_example() {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "example.com".parse().unwrap());
+        headers.insert("date", "Tue, 20 Apr 2021 02:07:55 GMT".parse().unwrap());
+        headers.insert("content-type", "application/json".parse().unwrap());
+        headers.insert("content-length", "18".parse().unwrap());
+        headers.insert(
+            "signature-input",
+            r#"sig-b26=("date" "@method" "@path" "@authority" "content-type" "content-length");created=1618884473;keyid="test-key-ed25519""#
+                .parse()
+                .unwrap(),
+        );
+        headers.insert(
+            "signature",
+            "sig-b26=:wqcAqbmYJ2ji2glfAMaRy4gruYYnx2nEFN2HN6jrnDnQCK1u02Gb04v9EDgwUPiu4A0w6vuQv5lIp5WPpBKRCw==:"
+                .parse()
+                .unwrap(),
+        );
+
+        let signature = MessageSignature::from_headers(&headers).unwrap().unwrap();
+        assert_eq!(signature.key_id().unwrap(), "test-key-ed25519");
+        assert_eq!(signature.created(), Some(1618884473));
+        assert_eq!(signature.algorithm().unwrap(), SignatureAlgorithm::Hs2019);
+
+        let base = signature
+            .signature_base(
+                &Method::POST,
+                &"/foo?param=Value&Pet=dog".parse().unwrap(),
+                &headers,
+            )
+            .unwrap();
+
+        assert_eq!(
+            base,
+            r#""date": Tue, 20 Apr 2021 02:07:55 GMT
+"@method": POST
+"@path": /foo
+"@authority": example.com
+"content-type": application/json
+"content-length": 18
+"@signature-params": ("date" "@method" "@path" "@authority" "content-type" "content-length");created=1618884473;keyid="test-key-ed25519""#
+        );
+
+        let key = PublicKey::from_pem(ED25519_TEST_KEY).unwrap();
+        key.verify(
+            signature.algorithm().unwrap(),
+            base.as_bytes(),
+            &signature.signature,
+        )
+        .unwrap();
+        assert!(key
+            .verify(
+                SignatureAlgorithm::Hs2019,
+                b"tampered",
+                &signature.signature
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn should_build_target_uri() {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "Example.com".parse().unwrap());
+
+        let signature = MessageSignature::new(
+            "sig1",
+            vec!["@method".to_owned(), "@target-uri".to_owned()],
+            "https://example.com/users/a#main-key",
+            Some("rsa-v1_5-sha256"),
+            1696161600,
+        );
+
+        assert_eq!(
+            signature
+                .signature_base(
+                    &Method::GET,
+                    &"https://Example.com/users/a?b=1".parse().unwrap(),
+                    &headers
+                )
+                .unwrap(),
+            r#""@method": GET
+"@target-uri": https://example.com/users/a?b=1
+"@signature-params": ("@method" "@target-uri");created=1696161600;keyid="https://example.com/users/a#main-key";alg="rsa-v1_5-sha256""#
+        );
+
+        assert!(signature
+            .signature_base(&Method::GET, &"/users/a".parse().unwrap(), &headers)
+            .is_err());
+    }
+}
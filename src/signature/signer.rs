@@ -0,0 +1,735 @@
+use crate::config::MagnetarConfig;
+use crate::public_address::check_public_url;
+use crate::signature::cavage::SignatureHeader;
+use crate::signature::rfc9421::{content_digest_value, MessageSignature};
+use crate::signature::SignatureError;
+use axum::async_trait;
+use axum::http::{header, HeaderMap, HeaderValue, Method, Uri};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use magnetar_calckey_model::CalckeyModel;
+use magnetar_webfinger::client::{HttpTransport, TransportError, TransportResponse};
+use reqwest::StatusCode;
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, RsaKeyPair, RSA_PKCS1_SHA256};
+use rsa::pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey};
+use rsa::pkcs8::{DecodePrivateKey, SecretDocument};
+use rsa::RsaPrivateKey;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use url::Url;
+
+/// A private key of a local actor, used to sign requests on their behalf
+#[derive(Debug)]
+pub enum PrivateKey {
+    Rsa(RsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+impl PrivateKey {
+    /// Parses a PKCS#8 or PKCS#1 PEM, as stored in `user_keypair.privateKey`
+    pub fn from_pem(pem: &str) -> Result<Self, SignatureError> {
+        let pem = pem.trim();
+
+        if let Ok((_, document)) = SecretDocument::from_pem(pem) {
+            if let Ok(key) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(document.as_bytes()) {
+                return Ok(PrivateKey::Ed25519(key));
+            }
+        }
+
+        let key = RsaPrivateKey::from_pkcs8_pem(pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+            .map_err(|e| SignatureError::InvalidKey(e.to_string()))?;
+
+        let der = key
+            .to_pkcs1_der()
+            .map_err(|e| SignatureError::InvalidKey(e.to_string()))?;
+
+        RsaKeyPair::from_der(der.as_bytes())
+            .map(PrivateKey::Rsa)
+            .map_err(|e| SignatureError::InvalidKey(e.to_string()))
+    }
+
+    fn algorithm(&self, scheme: SignatureScheme) -> &'static str {
+        match (self, scheme) {
+            (PrivateKey::Rsa(_), SignatureScheme::Cavage) => "rsa-sha256",
+            (PrivateKey::Ed25519(_), SignatureScheme::Cavage) => "hs2019",
+            (PrivateKey::Rsa(_), SignatureScheme::Rfc9421) => "rsa-v1_5-sha256",
+            (PrivateKey::Ed25519(_), SignatureScheme::Rfc9421) => "ed25519",
+        }
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, SignatureError> {
+        match self {
+            PrivateKey::Rsa(key) => {
+                let mut signature = vec![0; key.public_modulus_len()];
+
+                key.sign(
+                    &RSA_PKCS1_SHA256,
+                    &SystemRandom::new(),
+                    message,
+                    &mut signature,
+                )
+                .map_err(|_| SignatureError::Signing("RSA signing failed".to_owned()))?;
+
+                Ok(signature)
+            }
+            PrivateKey::Ed25519(key) => Ok(key.sign(message).as_ref().to_vec()),
+        }
+    }
+}
+
+/// The ways of signing a request, remote instances may support either or both
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureScheme {
+    /// draft-cavage-http-signatures-12
+    Cavage,
+    /// RFC 9421 HTTP Message Signatures
+    Rfc9421,
+}
+
+impl SignatureScheme {
+    pub fn other(self) -> Self {
+        match self {
+            SignatureScheme::Cavage => SignatureScheme::Rfc9421,
+            SignatureScheme::Rfc9421 => SignatureScheme::Cavage,
+        }
+    }
+}
+
+/// The host and non-default port of a URL, as sent in the `Host` header
+pub fn authority(url: &Url) -> Option<String> {
+    match (url.host_str()?, url.port()) {
+        (host, Some(port)) => Some(format!("{host}:{port}")),
+        (host, None) => Some(host.to_owned()),
+    }
+}
+
+fn header_value(value: &str) -> Result<HeaderValue, SignatureError> {
+    HeaderValue::from_str(value).map_err(|e| SignatureError::Signing(e.to_string()))
+}
+
+/// Signs requests with HTTP Signatures on behalf of an actor
+#[derive(Debug)]
+pub struct Signer {
+    key_id: String,
+    key: PrivateKey,
+}
+
+impl Signer {
+    pub fn new(key_id: impl Into<String>, key: PrivateKey) -> Self {
+        Signer {
+            key_id: key_id.into(),
+            key,
+        }
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// The headers that sign a request with the given scheme
+    ///
+    /// draft-cavage signatures cover `(request-target) host date digest` and come with
+    /// the `Host`, `Date` and `Digest` headers. RFC 9421 signatures cover `@method`,
+    /// `@target-uri` and `content-digest`. Requests without a body carry no digest.
+    pub fn signature_headers(
+        &self,
+        scheme: SignatureScheme,
+        method: &Method,
+        url: &Url,
+        body: Option<&[u8]>,
+        now: DateTime<Utc>,
+    ) -> Result<HeaderMap, SignatureError> {
+        let uri = url
+            .as_str()
+            .parse::<Uri>()
+            .map_err(|e| SignatureError::Signing(format!("{url}: {e}")))?;
+
+        match scheme {
+            SignatureScheme::Cavage => self.cavage_headers(method, url, &uri, body, now),
+            SignatureScheme::Rfc9421 => self.rfc9421_headers(method, &uri, body, now),
+        }
+    }
+
+    fn cavage_headers(
+        &self,
+        method: &Method,
+        url: &Url,
+        uri: &Uri,
+        body: Option<&[u8]>,
+        now: DateTime<Utc>,
+    ) -> Result<HeaderMap, SignatureError> {
+        let host =
+            authority(url).ok_or_else(|| SignatureError::Signing(format!("{url}: no host")))?;
+
+        let mut headers = HeaderMap::new();
+        let mut covered = vec!["(request-target)", "host", "date"];
+
+        headers.insert(header::HOST, header_value(&host)?);
+        headers.insert(
+            header::DATE,
+            header_value(&now.format("%a, %d %b %Y %H:%M:%S GMT").to_string())?,
+        );
+
+        if let Some(body) = body {
+            let digest = STANDARD.encode(digest::digest(&digest::SHA256, body));
+
+            headers.insert("digest", header_value(&format!("SHA-256={digest}"))?);
+            covered.push("digest");
+        }
+
+        let mut signature = SignatureHeader {
+            key_id: self.key_id.clone(),
+            algorithm: Some(self.key.algorithm(SignatureScheme::Cavage).to_owned()),
+            headers: covered.into_iter().map(str::to_owned).collect(),
+            signature: Vec::new(),
+            created: None,
+            expires: None,
+        };
+
+        let signing_string = signature.signing_string(method, uri, &headers)?;
+        signature.signature = self.key.sign(signing_string.as_bytes())?;
+
+        headers.insert("signature", header_value(&signature.to_header_value())?);
+
+        Ok(headers)
+    }
+
+    fn rfc9421_headers(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        body: Option<&[u8]>,
+        now: DateTime<Utc>,
+    ) -> Result<HeaderMap, SignatureError> {
+        let mut headers = HeaderMap::new();
+        let mut components = vec!["@method".to_owned(), "@target-uri".to_owned()];
+
+        if let Some(body) = body {
+            let digest = digest::digest(&digest::SHA256, body);
+
+            headers.insert(
+                "content-digest",
+                header_value(&content_digest_value("sha-256", digest.as_ref()))?,
+            );
+            components.push("content-digest".to_owned());
+        }
+
+        let mut signature = MessageSignature::new(
+            "sig1",
+            components,
+            &self.key_id,
+            Some(self.key.algorithm(SignatureScheme::Rfc9421)),
+            now.timestamp(),
+        );
+
+        let signature_base = signature.signature_base(method, uri, &headers)?;
+        signature.signature = self.key.sign(signature_base.as_bytes())?;
+
+        headers.insert(
+            "signature-input",
+            header_value(&signature.signature_input_value())?,
+        );
+        headers.insert("signature", header_value(&signature.signature_value())?);
+
+        Ok(headers)
+    }
+}
+
+/// Requests of an HTTP client that can be signed in place
+pub trait SignRequest: Sized {
+    fn sign(self, signer: &Signer, scheme: SignatureScheme) -> Result<Self, SignatureError>;
+}
+
+impl SignRequest for reqwest::Request {
+    fn sign(mut self, signer: &Signer, scheme: SignatureScheme) -> Result<Self, SignatureError> {
+        let body = match self.body() {
+            Some(body) => Some(body.as_bytes().ok_or_else(|| {
+                SignatureError::Signing("streaming bodies cannot be signed".to_owned())
+            })?),
+            None => None,
+        };
+
+        let headers =
+            signer.signature_headers(scheme, self.method(), self.url(), body, Utc::now())?;
+        self.headers_mut().extend(headers);
+
+        Ok(self)
+    }
+}
+
+impl SignRequest for reqwest::RequestBuilder {
+    fn sign(self, signer: &Signer, scheme: SignatureScheme) -> Result<Self, SignatureError> {
+        let (client, request) = self.build_split();
+        let request = request
+            .map_err(|e| SignatureError::Signing(e.to_string()))?
+            .sign(signer, scheme)?;
+
+        Ok(reqwest::RequestBuilder::from_parts(client, request))
+    }
+}
+
+/// The signature scheme each remote host last accepted
+///
+/// Hosts are tried with RFC 9421 first. The choice is kept in memory and learnt
+/// again after a restart.
+#[derive(Debug, Default)]
+pub struct HostSchemes(RwLock<HashMap<String, SignatureScheme>>);
+
+impl HostSchemes {
+    const DEFAULT: SignatureScheme = SignatureScheme::Rfc9421;
+
+    pub fn get(&self, host: &str) -> SignatureScheme {
+        self.0
+            .read()
+            .ok()
+            .and_then(|schemes| schemes.get(host).copied())
+            .unwrap_or(Self::DEFAULT)
+    }
+
+    pub fn record(&self, host: &str, scheme: SignatureScheme) {
+        if let Ok(mut schemes) = self.0.write() {
+            schemes.insert(host.to_owned(), scheme);
+        }
+    }
+}
+
+/// Sends a request signed with the scheme its host accepts
+///
+/// Requests rejected with 401 Unauthorized are signed again with the other scheme
+/// and sent once more ("double-knocking"), the scheme that worked is recorded.
+pub async fn send_signed(
+    client: &reqwest::Client,
+    schemes: &HostSchemes,
+    signer: &Signer,
+    request: reqwest::Request,
+) -> Result<reqwest::Response, TransportError> {
+    let host = authority(request.url())
+        .ok_or_else(|| SignatureError::Signing(format!("{}: no host", request.url())))?;
+    let scheme = schemes.get(&host);
+    let retry = request.try_clone();
+
+    let response = client.execute(request.sign(signer, scheme)?).await?;
+
+    if response.status() != StatusCode::UNAUTHORIZED {
+        schemes.record(&host, scheme);
+        return Ok(response);
+    }
+
+    let Some(retry) = retry else {
+        return Ok(response);
+    };
+
+    let response = client.execute(retry.sign(signer, scheme.other())?).await?;
+
+    if response.status() != StatusCode::UNAUTHORIZED {
+        schemes.record(&host, scheme.other());
+    }
+
+    Ok(response)
+}
+
+/// The signer of a local user, using the key published on their actor
+pub async fn load_user_signer(
+    config: &MagnetarConfig,
+    ck: &CalckeyModel,
+    user_id: &str,
+) -> anyhow::Result<Option<Signer>> {
+    let Some(keypair) = ck.get_user_keypair_by_id(user_id).await? else {
+        return Ok(None);
+    };
+
+    let key_id = format!(
+        "{}://{}/users/{user_id}#main-key",
+        config.networking.protocol, config.networking.host
+    );
+
+    Ok(Some(Signer::new(
+        key_id,
+        PrivateKey::from_pem(&keypair.private_key)?,
+    )))
+}
+
+/// The signer of the instance actor, for fetches that are not made on behalf of a user
+pub async fn load_instance_signer(
+    config: &MagnetarConfig,
+    ck: &CalckeyModel,
+) -> anyhow::Result<Option<Signer>> {
+    let Some(user_id) = ck.get_meta().await?.and_then(|meta| meta.proxy_account_id) else {
+        return Ok(None);
+    };
+
+    load_user_signer(config, ck, &user_id).await
+}
+
+/// An HTTP transport signing its fetches as the instance actor, if there is one
+///
+/// Required to fetch from instances with authorized fetch (secure mode) enabled.
+/// The URLs come from remote documents, so only public addresses are fetched.
+#[derive(Debug)]
+pub struct SignedTransport {
+    client: reqwest::Client,
+    signer: Option<Signer>,
+    schemes: Arc<HostSchemes>,
+    allow_local: bool,
+}
+
+impl SignedTransport {
+    pub fn new(client: reqwest::Client, signer: Option<Signer>, schemes: Arc<HostSchemes>) -> Self {
+        SignedTransport {
+            client,
+            signer,
+            schemes,
+            allow_local: false,
+        }
+    }
+
+    /// Lets tests fetch from servers on the loopback interface
+    #[cfg(test)]
+    fn allowing_local(self) -> Self {
+        SignedTransport {
+            allow_local: true,
+            ..self
+        }
+    }
+}
+
+#[async_trait]
+impl HttpTransport for SignedTransport {
+    async fn get(&self, url: &str, accept: &str) -> Result<TransportResponse, TransportError> {
+        if !self.allow_local {
+            check_public_url(&Url::parse(url)?).await?;
+        }
+
+        let request = self
+            .client
+            .get(url)
+            .header(reqwest::header::ACCEPT, accept)
+            .build()?;
+
+        let response = match self.signer {
+            Some(ref signer) => send_signed(&self.client, &self.schemes, signer, request).await?,
+            None => self.client.execute(request).await?,
+        };
+
+        Ok(TransportResponse {
+            status: response.status().as_u16(),
+            body: response.bytes().await?.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::signature::cavage::SignatureHeader;
+    use crate::signature::keys::{PublicKeyResolver, ResolvedKey};
+    use crate::signature::middleware::verify_request;
+    use crate::signature::rfc9421::MessageSignature;
+    use crate::signature::signer::{
+        send_signed, HostSchemes, PrivateKey, SignRequest, SignatureScheme, SignedTransport, Signer,
+    };
+    use crate::signature::{PublicKey, RequestSignature, SignatureError};
+    use axum::async_trait;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, Method, StatusCode};
+    use axum::routing::get;
+    use axum::Router;
+    use chrono::{TimeZone, Utc};
+    use magnetar_webfinger::client::HttpTransport;
+    use serde_json::Value;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use url::Url;
+
+    const KEY_ID: &str = "https://example.com/users/9fxdl2pkn3#main-key";
+
+    struct SingleKey(PublicKey);
+
+    #[async_trait]
+    impl PublicKeyResolver for SingleKey {
+        async fn resolve(&self, key_id: &str) -> Result<ResolvedKey, SignatureError> {
+            self.refresh(key_id).await
+        }
+
+        async fn refresh(&self, key_id: &str) -> Result<ResolvedKey, SignatureError> {
+            if key_id != KEY_ID {
+                return Err(SignatureError::UnknownKey(key_id.to_owned()));
+            }
+
+            Ok(ResolvedKey {
+                owner: "https://example.com/users/9fxdl2pkn3".to_owned(),
+                key: self.0.clone(),
+                cached: false,
+            })
+        }
+    }
+
+    fn public_key_pem(fixture: &str, name: &str) -> String {
+        let vectors: Value = serde_json::from_str(fixture).unwrap();
+
+        vectors
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|vector| vector["name"] == name)
+            .and_then(|vector| vector["public_key_pem"].as_str())
+            .unwrap()
+            .to_owned()
+    }
+
+    /// An RSA signer, whose public key is used by the Misskey vectors
+    fn rsa() -> (Signer, SingleKey) {
+        let key = PrivateKey::from_pem(include_str!("../../fixtures/http_signatures_key.pem"));
+        let pem = public_key_pem(
+            include_str!("../../fixtures/http_signatures.json"),
+            "misskey_post",
+        );
+
+        (
+            Signer::new(KEY_ID, key.unwrap()),
+            SingleKey(PublicKey::from_pem(&pem).unwrap()),
+        )
+    }
+
+    /// An Ed25519 signer, with the test key of RFC 9421
+    fn ed25519() -> (Signer, SingleKey) {
+        let key = PrivateKey::from_pem(include_str!(
+            "../../fixtures/http_message_signatures_key.pem"
+        ));
+        let pem = public_key_pem(
+            include_str!("../../fixtures/http_message_signatures.json"),
+            "ed25519_rfc9421_post",
+        );
+
+        (
+            Signer::new(KEY_ID, key.unwrap()),
+            SingleKey(PublicKey::from_pem(&pem).unwrap()),
+        )
+    }
+
+    async fn verify(
+        resolver: &SingleKey,
+        method: Method,
+        url: &str,
+        headers: HeaderMap,
+        body: &[u8],
+    ) -> Result<(), SignatureError> {
+        let now = Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap();
+        let uri = url.parse().unwrap();
+        let signature = RequestSignature::from_headers(&headers)?.unwrap();
+
+        verify_request(resolver, &signature, &method, &uri, &headers, body, now)
+            .await
+            .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn should_sign_requests() {
+        let now = Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap();
+        let url = Url::parse("https://remote.example:8443/inbox?x=1").unwrap();
+        let body = br#"{"type":"Follow"}"#;
+        let (signer, resolver) = rsa();
+
+        let headers = signer
+            .signature_headers(
+                SignatureScheme::Cavage,
+                &Method::POST,
+                &url,
+                Some(body),
+                now,
+            )
+            .unwrap();
+
+        assert_eq!(headers["host"], "remote.example:8443");
+        assert_eq!(headers["date"], "Sun, 01 Oct 2023 12:00:00 GMT");
+        assert!(headers["digest"].to_str().unwrap().starts_with("SHA-256="));
+
+        let signature = SignatureHeader::from_headers(&headers).unwrap().unwrap();
+        assert_eq!(
+            signature.headers,
+            vec!["(request-target)", "host", "date", "digest"]
+        );
+        assert_eq!(signature.algorithm.as_deref(), Some("rsa-sha256"));
+
+        verify(&resolver, Method::POST, url.as_str(), headers.clone(), body)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            verify(&resolver, Method::POST, url.as_str(), headers, b"{}").await,
+            Err(SignatureError::DigestMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_sign_rfc9421_requests() {
+        let now = Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap();
+        let url = Url::parse("https://remote.example/inbox").unwrap();
+        let body = br#"{"type":"Follow"}"#;
+
+        for (signer, resolver) in [rsa(), ed25519()] {
+            let headers = signer
+                .signature_headers(
+                    SignatureScheme::Rfc9421,
+                    &Method::POST,
+                    &url,
+                    Some(body),
+                    now,
+                )
+                .unwrap();
+
+            assert!(!headers.contains_key("date"));
+            assert!(headers["content-digest"]
+                .to_str()
+                .unwrap()
+                .starts_with("sha-256=:"));
+
+            let signature = MessageSignature::from_headers(&headers).unwrap().unwrap();
+            assert_eq!(
+                signature.components,
+                vec!["@method", "@target-uri", "content-digest"]
+            );
+            assert_eq!(signature.created(), Some(now.timestamp()));
+            assert_eq!(signature.key_id().unwrap(), KEY_ID);
+
+            verify(&resolver, Method::POST, url.as_str(), headers.clone(), body)
+                .await
+                .unwrap();
+
+            assert!(matches!(
+                verify(&resolver, Method::POST, url.as_str(), headers, b"{}").await,
+                Err(SignatureError::DigestMismatch)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn should_sign_fetches_without_digest() {
+        let now = Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap();
+        let url = Url::parse("https://remote.example/users/alice").unwrap();
+        let (signer, resolver) = rsa();
+
+        for scheme in [SignatureScheme::Cavage, SignatureScheme::Rfc9421] {
+            let headers = signer
+                .signature_headers(scheme, &Method::GET, &url, None, now)
+                .unwrap();
+
+            assert!(!headers.contains_key("digest"));
+            assert!(!headers.contains_key("content-digest"));
+            verify(&resolver, Method::GET, url.as_str(), headers, b"")
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn should_sign_reqwest_requests() {
+        let (signer, _) = rsa();
+        let request = reqwest::Client::new()
+            .post("https://remote.example/inbox")
+            .body(r#"{"type":"Like"}"#)
+            .build()
+            .unwrap()
+            .sign(&signer, SignatureScheme::Cavage)
+            .unwrap();
+
+        assert!(request.headers().contains_key("signature"));
+        assert!(request.headers().contains_key("digest"));
+        assert_eq!(request.headers()["host"], "remote.example");
+    }
+
+    /// Serves a host that only understands draft-cavage signatures, counting requests
+    fn serve_cavage_only() -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let app = Router::new()
+            .route(
+                "/users/alice",
+                get(
+                    |State(requests): State<Arc<AtomicUsize>>, headers: HeaderMap| async move {
+                        requests.fetch_add(1, Ordering::SeqCst);
+
+                        match headers.contains_key("signature-input") {
+                            true => StatusCode::UNAUTHORIZED,
+                            false => StatusCode::OK,
+                        }
+                    },
+                ),
+            )
+            .with_state(requests.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        (format!("http://{addr}/users/alice"), requests)
+    }
+
+    #[tokio::test]
+    async fn should_double_knock() {
+        let (url, requests) = serve_cavage_only();
+        let host = Url::parse(&url).unwrap();
+        let host = format!("{}:{}", host.host_str().unwrap(), host.port().unwrap());
+
+        let client = reqwest::Client::new();
+        let schemes = HostSchemes::default();
+        let (signer, _) = rsa();
+
+        let response = send_signed(
+            &client,
+            &schemes,
+            &signer,
+            client.get(&url).build().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(schemes.get(&host), SignatureScheme::Cavage);
+
+        let transport =
+            SignedTransport::new(client, Some(signer), Arc::new(schemes)).allowing_local();
+        let response = transport
+            .get(&url, "application/activity+json")
+            .await
+            .unwrap();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn should_refuse_local_addresses() {
+        let (signer, _) = rsa();
+        let transport = SignedTransport::new(
+            reqwest::Client::new(),
+            Some(signer),
+            Arc::new(HostSchemes::default()),
+        );
+
+        for url in [
+            "http://127.0.0.1:4939/users/alice",
+            "http://[::1]/users/alice",
+            "http://10.0.0.1/users/alice",
+            "http://169.254.169.254/latest/meta-data",
+            "https://localhost/users/alice",
+        ] {
+            let error = transport
+                .get(url, "application/activity+json")
+                .await
+                .unwrap_err();
+
+            assert!(error.to_string().contains("not a public URL"), "{url}");
+        }
+    }
+}
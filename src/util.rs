@@ -1,7 +1,10 @@
 use anyhow::anyhow;
+use axum::http::StatusCode;
 use magnetar_core::web_model::acct::Acct;
 use percent_encoding::percent_decode_str;
 use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use tracing::error;
 
 #[derive(Clone, Debug)]
 pub struct FediverseTag {
@@ -24,16 +27,22 @@ impl From<FediverseTag> for Acct {
     }
 }
 
-impl ToString for FediverseTag {
-    fn to_string(&self) -> String {
+impl Display for FediverseTag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         if let Some(ref host) = self.host {
-            format!("{}@{host}", self.name)
+            write!(f, "{}@{host}", self.name)
         } else {
-            self.name.clone()
+            write!(f, "{}", self.name)
         }
     }
 }
 
+/// Logs a database error, which is reported to the client as an internal error
+pub fn data_error(e: anyhow::Error) -> StatusCode {
+    error!("Data error: {e}");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
 pub fn lenient_parse_acct(acct: &Acct) -> anyhow::Result<FediverseTag> {
     lenient_parse_tag(acct.as_ref())
 }
@@ -43,7 +52,7 @@ fn split_tag_inner(tag: impl AsRef<str>) -> (String, Option<String>) {
     let tag = tag.strip_prefix('@').unwrap_or(tag.as_ref());
 
     match tag.split_once('@') {
-        Some((name, host)) if name.is_empty() => (host.to_owned(), None),
+        Some(("", host)) => (host.to_owned(), None),
         Some((name, host)) => (name.to_owned(), Some(host.to_owned())),
         None => (tag.to_owned(), None),
     }
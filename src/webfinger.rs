@@ -1,14 +1,18 @@
-use crate::config::MagnetarConfig;
+use crate::config::ConfigHandle;
 use crate::util::{lenient_parse_acct_decode, FediverseTag};
-use axum::extract::{Query, State};
+use axum::extract::State;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::Json;
+use axum_extra::extract::Query;
 use hyper::header;
 use magnetar_calckey_model::CalckeyModel;
 use magnetar_core::web_model::acct::Acct;
 use magnetar_core::web_model::content_type::{ContentActivityStreams, ContentHtml, ContentJrdJson};
-use magnetar_core::web_model::rel::{RelOStatusSubscribe, RelSelf, RelWebFingerProfilePage};
+use magnetar_core::web_model::rel::{
+    RelOStatusSubscribe, RelSelf, RelWebFingerAvatar, RelWebFingerProfilePage,
+};
+use magnetar_core::web_model::Rel;
 use magnetar_webfinger::webfinger::{WebFinger, WebFingerRel, WebFingerSubject};
 use serde::Deserialize;
 use tracing::error;
@@ -16,14 +20,27 @@ use tracing::error;
 #[derive(Deserialize)]
 pub struct WebFingerQuery {
     resource: WebFingerSubject,
-    rel: Option<Vec<String>>,
+    #[serde(default)]
+    rel: Vec<String>,
+}
+
+/// Restricts the links to the requested relations, as described in RFC 7033 section 4.3
+///
+/// An empty list of relations leaves the links untouched.
+fn filter_rels(links: &mut Vec<WebFingerRel>, rels: &[String]) {
+    if rels.is_empty() {
+        return;
+    }
+
+    links.retain(|link| rels.iter().any(|rel| rel == link.rel()));
 }
 
-// TODO: Filter by rel
 pub async fn handle_webfinger(
     Query(WebFingerQuery { resource, rel, .. }): Query<WebFingerQuery>,
-    State((config, ck)): State<(&'static MagnetarConfig, CalckeyModel)>,
+    State((config, ck)): State<(ConfigHandle, CalckeyModel)>,
 ) -> Result<impl IntoResponse, StatusCode> {
+    let config = &*config.load();
+
     let resource = match resource {
         acct @ WebFingerSubject::Acct(_) => acct,
         // Leniently re-add the acct
@@ -112,15 +129,200 @@ pub async fn handle_webfinger(
                     config.networking.protocol, config.networking.host, user.id
                 ),
             });
+
+            if let Some(ref avatar_id) = user.avatar_id {
+                let avatar = ck.get_drive_file_by_id(avatar_id).await.map_err(|e| {
+                    error!("Data error: {e}");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+
+                if let Some(avatar) = avatar {
+                    links.push(WebFingerRel::RelOther {
+                        rel: RelWebFingerAvatar.rel().to_owned(),
+                        content_type: Some(avatar.webpublic_type.unwrap_or(avatar.r#type)),
+                        href: Some(avatar.webpublic_url.unwrap_or(avatar.url)),
+                        template: None,
+                        titles: None,
+                        properties: None,
+                    });
+                }
+            }
         }
     }
 
+    filter_rels(&mut links, &rel);
+
     Ok((
         [(header::CONTENT_TYPE, ContentJrdJson.as_ref())],
         Json(WebFinger {
             subject: WebFingerSubject::Acct(tag.into()),
             aliases: Some(aliases),
+            properties: None,
             links,
         }),
     ))
 }
+
+#[cfg(test)]
+mod test {
+    use crate::config::{ConfigHandle, ConfigLayers, ConfigOptions, MagnetarConfig};
+    use crate::webfinger::handle_webfinger;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use axum::routing::get;
+    use axum::Router;
+    use magnetar_calckey_model::test_support::{insert_user, local_user, test_database};
+    use magnetar_core::web_model::acct::Acct;
+    use magnetar_core::web_model::content_type::{ContentActivityStreams, ContentHtml};
+    use magnetar_core::web_model::rel::{RelOStatusSubscribe, RelSelf, RelWebFingerProfilePage};
+    use magnetar_webfinger::webfinger::{WebFinger, WebFingerRel, WebFingerSubject};
+    use tower::ServiceExt;
+
+    fn config() -> MagnetarConfig {
+        let mut layers = ConfigLayers::default();
+        layers.add_overrides(&[
+            ("networking.host".to_owned(), "example.com".to_owned()),
+            ("data.database_url".to_owned(), "postgres://db".to_owned()),
+        ]);
+        layers.into_config().unwrap()
+    }
+
+    fn webfinger_alice() -> WebFinger {
+        WebFinger {
+            subject: WebFingerSubject::Acct(Acct::new("alice@example.com".into())),
+            aliases: Some(vec![WebFingerSubject::Url(
+                "https://example.com/@alice".to_owned(),
+            )]),
+            properties: None,
+            links: vec![
+                WebFingerRel::RelOStatusSubscribe {
+                    rel: RelOStatusSubscribe,
+                    template: "https://example.com/authorize-follow?acct={uri}".to_owned(),
+                },
+                WebFingerRel::RelWebFingerProfilePage {
+                    rel: RelWebFingerProfilePage,
+                    content_type: ContentHtml,
+                    href: "https://example.com/@alice".to_owned(),
+                },
+                WebFingerRel::RelSelf {
+                    rel: RelSelf,
+                    content_type: ContentActivityStreams,
+                    href: "https://example.com/users/9fxdl2pkn3".to_owned(),
+                },
+            ],
+        }
+    }
+
+    async fn app() -> Router {
+        let ck = test_database().await.unwrap();
+
+        insert_user(&ck, local_user("9fxdl2pkn3", "alice"))
+            .await
+            .unwrap();
+
+        let config = ConfigHandle::new(config(), ConfigOptions::default());
+
+        Router::new().route(
+            "/.well-known/webfinger",
+            get(handle_webfinger).with_state((config, ck)),
+        )
+    }
+
+    async fn query_webfinger(app: Router, query: &str) -> (StatusCode, Option<WebFinger>) {
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/.well-known/webfinger?{query}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).ok())
+    }
+
+    fn rels(webfinger: &WebFinger) -> Vec<&str> {
+        webfinger.links.iter().map(WebFingerRel::rel).collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_not_filter_without_rel() {
+        let app = app().await;
+
+        let (status, webfinger) = query_webfinger(app, "resource=acct:alice@example.com").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(webfinger.unwrap(), webfinger_alice());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_filter_single_rel() {
+        let app = app().await;
+
+        let (status, webfinger) =
+            query_webfinger(app, "resource=acct:alice@example.com&rel=self").await;
+        let webfinger = webfinger.unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(webfinger.subject, webfinger_alice().subject);
+        assert_eq!(webfinger.aliases, webfinger_alice().aliases);
+        assert_eq!(rels(&webfinger), vec!["self"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_filter_multiple_rels() {
+        let app = app().await;
+
+        let (status, webfinger) = query_webfinger(
+            app,
+            "resource=acct:alice@example.com\
+            &rel=self\
+            &rel=http%3A%2F%2Fwebfinger.net%2Frel%2Fprofile-page",
+        )
+        .await;
+        let webfinger = webfinger.unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(webfinger.subject, webfinger_alice().subject);
+        assert_eq!(webfinger.aliases, webfinger_alice().aliases);
+        assert_eq!(
+            rels(&webfinger),
+            vec!["http://webfinger.net/rel/profile-page", "self"]
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_filter_unknown_rel() {
+        let app = app().await;
+
+        let (status, webfinger) = query_webfinger(
+            app,
+            "resource=acct:alice@example.com&rel=http%3A%2F%2Fexample.com%2Funknown",
+        )
+        .await;
+        let webfinger = webfinger.unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(webfinger.subject, webfinger_alice().subject);
+        assert_eq!(webfinger.aliases, webfinger_alice().aliases);
+        assert!(webfinger.links.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database, set MAG_TEST_DATABASE_URL"]
+    async fn should_not_find_unknown_users() {
+        let app = app().await;
+
+        let (status, _) = query_webfinger(app, "resource=acct:bob@example.com&rel=self").await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}